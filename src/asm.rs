@@ -1,20 +1,26 @@
 //! The assembler.
 
-use std::collections::HashMap;
-use std::io::{Read, BufRead, BufReader};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, BufRead, BufReader, BufWriter, Write};
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
 
 use instruction::{Instruction, IoOp, ShiftOp};
 use errors::*;
+use source_map::{DebugInfo, WordInfo, WordKind};
 
 /// A single statement, which may have as its argument a label
 /// whose position is not yet known.
-enum Stmt {
+pub enum Stmt {
     /// An instruction with optional argument.
     Instr {
         instr: Instruction,
         addr: Option<String>,
     },
-    /// A `dw` statement.
+    /// A data word (from `dw` or another data directive).
     Data(String),
 }
 
@@ -61,14 +67,129 @@ enum Stmt {
 ///
 /// As can be seen in the example above, the only requirement for a label is that it not
 /// contain any whitespace or colons (I might change this later, but this is unlikely),
-/// and that it must be valid UTF8. This also means that all arguments to opcodes expecting
-/// an address must be labels, and cannot be (say) references to a specific memory location.
-/// However, since this isn't actual assembly where such things are useful, this shouldn't
-/// be a problem.
+/// and that it must be valid UTF8.
+///
+/// The argument of an instruction expecting an address is an *expression*, made up of terms
+/// added or subtracted using `+` and `-`, where each term is a label, the symbol `$` (the
+/// address of the current word) or a number (in decimal, or in hexadecimal with a `0x`
+/// prefix). For example:
+///
+/// ```text
+/// load    table + 2
+/// jmp     $ + 3
+/// load    0x10
+/// add     end - start
+/// ```
+///
+/// The result must fit in the 12-bit address field of the instruction (i.e. it must be between
+/// 0 and `fff`). Since labels can contain almost any character, an argument which is exactly the
+/// name of a label always refers to that label, and each term is also looked up as a label
+/// before it is treated as a number: in a program with a label named `1`, `load 1` loads from
+/// that label rather than from address 1.
 ///
 /// Indentation and whitespace within a line is ignored, allowing for clearer formatting.
 /// Additionally, comments may appear in the code: the characters `//` will cause the
-/// rest of the line to be treated as a comment, as in C++.
+/// rest of the line to be treated as a comment, as in C++ (unless they are inside a string
+/// or character literal).
+///
+/// # Directives
+///
+/// Besides the instructions, the following *directives* may be used:
+///
+/// * `dw a, b, ...` adds one data word for each value. For compatibility with the example
+///   programs, a value which is a plain hexadecimal word (e.g. `000A` or `ffff`) is always
+///   read as one; otherwise, it may be a character literal (e.g. `'a'` or `'\n'`) or an
+///   expression, as for address arguments (so `-1` and `+10` are decimal, and `dw table`
+///   gives the address of `table`). The value must fit in 16 bits, as either a signed or an
+///   unsigned number. A label or constant whose name is a hexadecimal word (e.g. `beef`) is
+///   therefore only used if it is part of an expression (e.g. `dw beef + 0`), and a warning is
+///   given when such a name is read as a number.
+/// * `string "text"` adds one data word for each character of the string, which may use the
+///   escapes `\n`, `\t`, `\r`, `\0`, `\\`, `\'`, `\"` and `\xHH`. No terminator is added, so
+///   follow it with `dw 0` if one is needed.
+/// * `space n` adds `n` zero words, and `fill n, value` adds `n` copies of a `dw` value.
+/// * `org addr` fills the program with zero words up to the given address, so that the next
+///   statement is placed there. It cannot move backwards.
+/// * `name: equ value` defines a constant, which can be used anywhere a label can. The
+///   value is an expression (or a character literal). Constants defined with `set` instead
+///   of `equ` may be redefined (also using `set`), and each use refers to the most recent
+///   definition before it.
+///
+/// The arguments of `equ`, `set`, `org`, `space` and `fill` (apart from the value to fill
+/// with) are evaluated as soon as they are read, so they may only use labels and constants
+/// defined earlier in the program. For example:
+///
+/// ```
+/// use ibcm::Assembler;
+///
+/// let program = "len:    equ     3
+///         jmp     start
+/// table:  dw      1, -1, 'a'
+/// msg:    string  \"hi\\n\"
+///         org     0x10
+/// start:  load    table + len - 1
+///         halt";
+///
+/// let assembled = Assembler::assemble(program.as_bytes()).unwrap();
+///
+/// assert_eq!(&[0xc010, 0x0001, 0xffff, 0x0061, 0x0068, 0x0069, 0x000a],
+///            &assembled.data()[..7]);
+/// assert_eq!(&[0x3003, 0x0000], &assembled.data()[0x10..]);
+/// ```
+///
+/// # Macros
+///
+/// A *macro* is a named sequence of lines, defined between `macro NAME params` and `endm`
+/// (where the parameters are separated by commas), which is substituted wherever `NAME` is
+/// used as an instruction. Each parameter is replaced by the corresponding argument wherever it
+/// appears as a whole word in the body (not counting `+`, `-`, `,` and `:`, so a parameter may
+/// be used in an expression or as a label). Labels in the body starting with `%%` are local to
+/// each expansion of the macro; any other label in the body is defined each time the macro is
+/// used, which will usually be an error.
+///
+/// Macros may use other macros (which only need to be defined before the outer macro is used),
+/// up to a depth of 16. An error in a line produced by a macro is reported at the line where
+/// the macro was used, along with the line of the macro definition it came from. For example:
+///
+/// ```
+/// use ibcm::{Assembler, Simulator};
+///
+/// let program = "macro inc var
+///         load    var
+///         add     one
+///         store   var
+/// endm
+/// macro add2 var
+///         inc     var
+///         inc     var
+/// endm
+///
+///         add2    x
+///         halt
+/// one:    dw      1
+/// x:      dw      5";
+///
+/// let assembled = Assembler::assemble(program.as_bytes()).unwrap();
+/// let mut sim = Simulator::from_instructions(assembled.data()).unwrap();
+/// sim.run().unwrap();
+///
+/// assert_eq!(7, sim.memory()[assembled.labels()["x"] as usize]);
+/// ```
+///
+/// # Including files
+///
+/// The directive `include "file"` reads the statements of another file as if they appeared in
+/// place of the directive, so routines and macros can be shared between programs. The file is
+/// found relative to the directory of the file including it (see `assemble_file` and
+/// `assemble_with`), and may itself include other files, as long as no file ends up including
+/// itself. Errors in an included file are reported along with its name.
+///
+/// # Errors and warnings
+///
+/// The assembler doesn't stop at the first error it finds: `assemble_recovering` and
+/// `assemble_file_recovering` return every error in the program, along with warnings about code
+/// which is probably a mistake, each pointing to the text in the source which caused it (see
+/// `Diagnostic`). The other functions just return the first error.
 ///
 /// For examples of IBCM assembly, see the section below, as well as the examples in the
 /// `tests` directory of this project.
@@ -149,20 +270,250 @@ enum Stmt {
 /// has been transcribed from the official IBCM documentation and can be found in the `tests`
 /// directory.
 pub struct Assembler {
-    /// The statements that have been processed, along with their line numbers.
-    stmts: Vec<(usize, Stmt)>,
-    /// A map giving the position of labels.
+    /// The statements that have been processed, along with their locations.
+    stmts: Vec<(Loc, Stmt)>,
+    /// A map giving the position of labels (and the values of constants).
     labels: HashMap<String, u16>,
+    /// The definitions of constants using `set`, along with the address from which each one
+    /// applies.
+    sets: Vec<(usize, String, u16)>,
+    /// Labels waiting for the next word of the program, along with their locations and columns.
+    pending: Vec<(String, Loc, Option<Span>)>,
+    /// The labels and constants which have been defined, along with the locations and columns of
+    /// their first definitions.
+    symbols: Vec<(String, Loc, Option<Span>)>,
+    /// The labels and constants which have been used.
+    used: HashSet<String>,
+    /// The names of the constants which have been defined.
+    constants: HashSet<String>,
+    /// The macros that have been defined.
+    macros: HashMap<String, Rc<Macro>>,
+    /// The name, starting location and contents of the macro currently being defined.
+    definition: Option<(String, Loc, Macro)>,
+    /// The number of macro expansions so far (used to make local labels unique).
+    expansions: usize,
+    /// Whether a statement has been dropped because the program doesn't fit in memory.
+    overflowed: bool,
+    /// The errors and warnings found so far.
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// The result of a step of assembly which can fail with an error in the source.
+type AsmResult<T> = ::std::result::Result<T, Diagnostic>;
+
+/// The columns of a piece of a line (see `Diagnostic::span`).
+type Span = (usize, usize);
+
+/// The severity of a `Diagnostic`.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Severity {
+    /// An error, which prevents the program from being assembled.
+    Error,
+    /// A warning about code which is probably a mistake, but can still be assembled.
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// An error or warning found while assembling a program (see
+/// `Assembler::assemble_recovering`).
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Diagnostic {
+    /// Whether this is an error or a warning.
+    pub severity: Severity,
+    /// The description of the problem.
+    pub message: String,
+    /// The file in which the problem was found (`None` for the main input, if it wasn't read
+    /// from a file).
+    pub file: Option<PathBuf>,
+    /// The line on which the problem was found. For lines produced by a macro, this is the line
+    /// where the macro was used, and the message gives the line in the macro.
+    pub line: usize,
+    /// The columns of the text which caused the problem (starting from 1 and counted in bytes,
+    /// with the end excluded), if they are known.
+    pub span: Option<(usize, usize)>,
 }
 
+impl Diagnostic {
+    /// Creates a diagnostic about the columns `span` of the statement at `loc`.
+    fn new(severity: Severity, message: String, loc: &Loc, span: Option<Span>) -> Diagnostic {
+        let mut diagnostic = Diagnostic {
+            severity,
+            message,
+            file: loc.file.as_ref().map(|file| file.to_path_buf()),
+            line: loc.line,
+            span,
+        };
+        // The columns of a line produced by a macro refer to the expanded line, which isn't in
+        // the source, so the message points to the line in the macro instead (e.g. `... (at line
+        // 3 of macro 'a', expanded from line 7 of macro 'b')`)
+        if !loc.macros.is_empty() {
+            let lines = loc.macros
+                .iter()
+                .map(|(name, n)| format!("line {} of macro '{}'", n, name))
+                .collect::<Vec<_>>();
+            diagnostic.message = format!("{} (at {})",
+                                         diagnostic.message,
+                                         lines.join(", expanded from "));
+            diagnostic.span = None;
+        }
+        diagnostic
+    }
+
+    /// Formats the diagnostic for display, showing the offending line of `source` (the contents
+    /// of the file in which the problem was found) with carets under the text which caused it.
+    ///
+    /// If the columns aren't known, the carets point to the start of the statement on the line.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ibcm::Assembler;
+    ///
+    /// let program = "        halt
+    ///         load    data + 1";
+    ///
+    /// let (_, diagnostics) = Assembler::assemble_recovering(program.as_bytes());
+    /// assert_eq!("error: label 'data' is undefined
+    ///  --> <input>:2:17
+    ///   |
+    /// 2 |         load    data + 1
+    ///   |                 ^^^^
+    /// ",
+    ///            diagnostics[0].format(program));
+    /// ```
+    pub fn format(&self, source: &str) -> String {
+        let file = self.file
+            .as_ref()
+            .map_or("<input>".to_owned(), |file| file.display().to_string());
+        ::format_snippet(&self.severity.to_string(),
+                         &self.message,
+                         &file,
+                         self.line,
+                         self.span,
+                         source)
+    }
+
+    /// Converts the diagnostic into an assembly error.
+    fn into_error(self) -> Error {
+        let file = self.file.map(|file| file.display().to_string());
+        ErrorKind::Asm(self.message, file, self.line).into()
+    }
+}
+
+/// The location of a statement in the source.
+#[derive(Clone,Debug)]
+struct Loc {
+    /// The file containing the statement (if the assembly wasn't read from a file, this is
+    /// `None` for statements in the main input).
+    file: Option<Rc<PathBuf>>,
+    /// The line number of the statement, or of the outermost macro invocation which produced it.
+    line: usize,
+    /// The text of the line containing the statement (after substituting the arguments of any
+    /// macro which produced it).
+    text: Rc<str>,
+    /// The macro expansions which produced the statement (innermost first), each given by the
+    /// name of the macro and the line number of the statement in its definition.
+    macros: Rc<Vec<(String, usize)>>,
+    /// The byte offset in `text` of the argument of the statement (used to point to errors in
+    /// it).
+    arg: usize,
+}
+
+/// A piece of a line of source, used to point diagnostics to the text which caused them.
+#[derive(Clone,Copy)]
+struct At<'a> {
+    /// The location of the line.
+    loc: &'a Loc,
+    /// The piece of the line (or a copy of it).
+    text: &'a str,
+    /// The byte offset of `text` in the line.
+    start: usize,
+}
+
+impl<'a> At<'a> {
+    /// Returns the byte offset in the line of `s`, which must be a slice of `text` (otherwise,
+    /// the offset is unknown).
+    fn offset(&self, s: &str) -> Option<usize> {
+        let offset = (s.as_ptr() as usize).checked_sub(self.text.as_ptr() as usize)?;
+        if offset + s.len() > self.text.len() {
+            return None;
+        }
+        Some(self.start + offset)
+    }
+
+    /// Returns the columns of `s` in the line (see `offset`).
+    fn span(&self, s: &str) -> Option<Span> {
+        self.offset(s).map(|offset| (offset + 1, offset + 1 + s.len()))
+    }
+
+    /// Returns the location of a statement on the line with the given argument (see `offset`).
+    fn loc_of(&self, arg: &str) -> Loc {
+        Loc { arg: self.offset(arg).unwrap_or(0), ..self.loc.clone() }
+    }
+
+    /// Creates an error about `s` (see `offset`).
+    fn error<S: Into<String>>(&self, msg: S, s: &str) -> Diagnostic {
+        Diagnostic::new(Severity::Error, msg.into(), self.loc, self.span(s))
+    }
+}
+
+/// The state needed to process included files.
+struct Includes<'a> {
+    /// Reads the contents of a file.
+    read: &'a mut dyn FnMut(&Path) -> io::Result<String>,
+    /// The files currently being read (innermost last).
+    stack: Vec<Rc<PathBuf>>,
+}
+
+impl<'a> Includes<'a> {
+    /// Returns the file currently being read, or `None` for the main input (if it isn't a file).
+    fn current(&self) -> Option<Rc<PathBuf>> {
+        self.stack.last().cloned()
+    }
+}
+
+/// A macro definition.
+struct Macro {
+    /// The names of the parameters.
+    params: Vec<String>,
+    /// The lines of the body, along with their line numbers.
+    body: Vec<(usize, String)>,
+}
+
+/// The maximum depth of nested macro expansions.
+const MAX_MACRO_DEPTH: usize = 16;
+
+/// The number of words of memory in the machine, which limits the length of a program.
+const MEMORY_WORDS: usize = 4096;
+
+/// The names of the assembler directives (which can't be used as macro names).
+const DIRECTIVES: &[&str] = &["dw", "string", "space", "fill", "org", "equ", "set", "macro",
+                              "endm", "include"];
+
 /// Represents an assembled program.
 ///
 /// Currently, this contains the actual assembled program as a list of
 /// `u16` instructions, as well as a `HashMap` which gives the position
-/// of labels in the code.
+/// of labels in the code (and the values of constants), and the source
+/// of each word (see `debug_info`).
+#[derive(Debug)]
 pub struct Program {
     data: Vec<u16>,
     labels: HashMap<String, u16>,
+    /// The names of the constants in `labels` (defined using `equ` or `set`).
+    constants: HashSet<String>,
+    /// The location of the statement which produced each word.
+    locs: Vec<Loc>,
+    /// Whether each word is an instruction or data.
+    kinds: Vec<WordKind>,
 }
 
 impl Program {
@@ -175,159 +526,1037 @@ impl Program {
     pub fn labels(&self) -> &HashMap<String, u16> {
         &self.labels
     }
+
+    /// Returns the debug information of the program, which gives the file, line and text of the
+    /// statement which produced each word, and whether the word is an instruction or data.
+    ///
+    /// For words produced by a macro, the line is the one where the macro was used, and the text
+    /// is the line of the macro with its arguments substituted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ibcm::{Assembler, WordKind};
+    ///
+    /// let program = "        jmp     init
+    /// msg:    string  \"hi\"
+    /// init:   halt";
+    ///
+    /// let info = Assembler::assemble(program.as_bytes()).unwrap().debug_info();
+    /// let words = info.words()
+    ///     .iter()
+    ///     .map(|word| (word.line.unwrap(), word.kind, word.text.as_str()))
+    ///     .collect::<Vec<_>>();
+    ///
+    /// assert_eq!(vec![(1, WordKind::Code, "        jmp     init"),
+    ///                 (2, WordKind::Data, "msg:    string  \"hi\""),
+    ///                 (2, WordKind::Data, "msg:    string  \"hi\""),
+    ///                 (3, WordKind::Code, "init:   halt")],
+    ///            words);
+    /// ```
+    pub fn debug_info(&self) -> DebugInfo {
+        let words = self.locs
+            .iter()
+            .zip(&self.kinds)
+            .zip(&self.data)
+            .map(|((loc, &kind), &value)| {
+                WordInfo {
+                    value,
+                    file: loc.file.as_ref().map(|file| file.display().to_string()),
+                    line: Some(loc.line),
+                    kind,
+                    text: loc.text.to_string(),
+                }
+            })
+            .collect();
+        DebugInfo::new(words)
+    }
+
+    /// Writes a listing of the program to the given writer.
+    ///
+    /// Each word of the program is listed on its own line, giving its address, its value, the
+    /// instruction it decodes to (or `dw` and its value, for data), any labels referring to it
+    /// and the source line which produced
+    /// it (this is only shown for the first word of a statement producing several words, such as
+    /// `dw 1, 2, 3`). The words are followed by a table of the labels and constants, sorted
+    /// first by name and then by value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ibcm::Assembler;
+    ///
+    /// let program = "        jmp     init
+    /// one:    dw      1       // Constant
+    /// init:   load    one
+    ///         halt";
+    ///
+    /// let mut listing = Vec::new();
+    /// Assembler::assemble(program.as_bytes()).unwrap().to_listing(&mut listing).unwrap();
+    ///
+    /// assert_eq!("addr  word  instr      label  line  source
+    /// 000   c002  jmp 0002             1          jmp     init
+    /// 001   0001  dw 0001    one       2  one:    dw      1       // Constant
+    /// 002   3001  load 0001  init      3  init:   load    one
+    /// 003   0000  halt                 4          halt
+    ///
+    /// symbols by name:
+    /// init  0002
+    /// one   0001
+    ///
+    /// symbols by value:
+    /// 0001  one
+    /// 0002  init
+    /// ",
+    ///            String::from_utf8(listing).unwrap());
+    /// ```
+    pub fn to_listing<W: Write>(&self, output: W) -> Result<()> {
+        let mut bw = BufWriter::new(output);
+        self.write_listing(&mut bw)
+            .and_then(|_| bw.flush())
+            .chain_err(|| ErrorKind::Io("could not write to file".into()))
+    }
+
+    /// Writes the listing described in `to_listing`.
+    fn write_listing<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        // The labels referring to each word (constants are left out, since their values aren't
+        // really addresses)
+        let mut labels = HashMap::new();
+        for (name, &addr) in &self.labels {
+            if !self.constants.contains(name) {
+                labels.entry(addr).or_insert_with(Vec::new).push(name.as_str());
+            }
+        }
+        let labels = labels.into_iter()
+            .map(|(addr, mut names)| {
+                names.sort();
+                (addr, names.join(" "))
+            })
+            .collect::<HashMap<_, _>>();
+        let width = (0..self.data.len() as u16)
+            .filter_map(|addr| labels.get(&addr).map(|names| names.len()))
+            .chain(Some("label".len()))
+            .max()
+            .unwrap_or(0);
+        // Data words aren't decoded, since they aren't meant to be executed
+        let instrs = self.data
+            .iter()
+            .zip(&self.kinds)
+            .map(|(&word, &kind)| match kind {
+                     WordKind::Code => Instruction::from_u16(word).to_string(),
+                     WordKind::Data => format!("dw {:04x}", word),
+                 })
+            .collect::<Vec<_>>();
+        let instr_width = instrs.iter()
+            .map(|instr| instr.len())
+            .chain(Some("instr".len()))
+            .max()
+            .unwrap_or(0);
+
+        writeln!(w,
+                 "addr  word  {:<instr_width$}  {:<width$}  line  source",
+                 "instr",
+                 "label",
+                 instr_width = instr_width,
+                 width = width)?;
+        let mut prev: Option<&Loc> = None;
+        for (addr, ((&word, loc), instr)) in
+            self.data.iter().zip(&self.locs).zip(&instrs).enumerate() {
+            // Mark the start of each file
+            if prev.map(|prev| &prev.file) != Some(&loc.file) {
+                if let Some(ref file) = loc.file {
+                    writeln!(w, "; {}", file.display())?;
+                }
+            }
+            let label = labels.get(&(addr as u16)).map_or("", |names| names.as_str());
+            let mut line = format!("{:03x}   {:04x}  {:<instr_width$}  {:<width$}",
+                                   addr,
+                                   word,
+                                   instr,
+                                   label,
+                                   instr_width = instr_width,
+                                   width = width);
+            if prev.is_none_or(|prev| !Rc::ptr_eq(&prev.text, &loc.text)) {
+                line.push_str(&format!("  {:>4}  {}", loc.line, loc.text));
+            }
+            writeln!(w, "{}", line.trim_end())?;
+            prev = Some(loc);
+        }
+
+        let mut symbols = self.labels.iter().collect::<Vec<_>>();
+        let width = symbols.iter().map(|&(name, _)| name.len()).max().unwrap_or(0);
+        let kind = |name: &str| if self.constants.contains(name) { "  constant" } else { "" };
+        symbols.sort();
+        writeln!(w, "\nsymbols by name:")?;
+        for &(name, value) in &symbols {
+            writeln!(w, "{:<width$}  {:04x}{}", name, value, kind(name), width = width)?;
+        }
+        symbols.sort_by_key(|&(name, &value)| (value, name));
+        writeln!(w, "\nsymbols by value:")?;
+        for &(name, value) in &symbols {
+            writeln!(w, "{:04x}  {}{}", value, name, kind(name))?;
+        }
+        Ok(())
+    }
 }
 
 impl Assembler {
     /// Assembles the assembly code from the given reader.
     ///
-    /// Returns a vector of IBCM instructions, or an error. See the documentation
-    /// for the `Assembler` struct for a description of the assembly code format
-    /// and examples.
+    /// Returns a vector of IBCM instructions, or the first error in the code. See the
+    /// documentation for the `Assembler` struct for a description of the assembly code format
+    /// and examples, and `assemble_recovering` to get all the errors at once.
+    ///
+    /// Any included files are read from the filesystem, relative to the current directory.
     pub fn assemble<R: Read>(input: R) -> Result<Program> {
-        let asm = Assembler::first_pass(input)?;
-        asm.second_pass()
+        first_error(Assembler::assemble_recovering(input))
     }
 
-    /// First pass: parse the input to get the initial list of statements and labels
-    fn first_pass<R: Read>(input: R) -> Result<Assembler> {
+    /// Assembles the assembly code from the given reader, recovering from errors so that all of
+    /// them can be reported at once.
+    ///
+    /// Returns the program along with every error and warning found in it (see `Diagnostic`),
+    /// in the order in which they were found. The warnings point out code which is probably a
+    /// mistake: labels and constants which are never used (except those defined in included
+    /// files or macros, and internal labels, which have a `.`-separated part starting with `_`
+    /// like those generated by the IBCMC compiler, e.g. `_L1` or `f._ret`), instructions after
+    /// which execution can continue into data (except a `nop` which the program stores an
+    /// instruction into), and programs without a `halt` instruction. If there are any errors,
+    /// the words which could not be assembled are left as zero and statements which could not be
+    /// parsed at all are left out, so the program should only be used for tooling.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ibcm::{Assembler, Severity};
+    ///
+    /// let program = "        load    nowhere
+    ///         frob
+    ///         shiftL  16
+    /// x:      dw      1";
+    ///
+    /// let (_, diagnostics) = Assembler::assemble_recovering(program.as_bytes());
+    /// let found = diagnostics.iter()
+    ///     .map(|d| (d.severity, d.line, d.span, d.message.as_str()))
+    ///     .collect::<Vec<_>>();
+    ///
+    /// assert_eq!(vec![(Severity::Error, 2, Some((9, 13)), "unknown instruction 'frob'"),
+    ///                 (Severity::Error, 3, Some((17, 19)),
+    ///                  "invalid shift amount (must be between 0 and 15, inclusive)"),
+    ///                 (Severity::Error, 1, Some((17, 24)), "label 'nowhere' is undefined"),
+    ///                 (Severity::Warning, 4, Some((1, 2)), "label 'x' is never used"),
+    ///                 (Severity::Warning, 1, None,
+    ///                  "execution can continue past this instruction into the data at 001"),
+    ///                 (Severity::Warning, 4, None, "the program has no 'halt' instruction")],
+    ///            found);
+    /// ```
+    pub fn assemble_recovering<R: Read>(input: R) -> (Program, Vec<Diagnostic>) {
+        let mut read = |path: &Path| fs::read_to_string(path);
+        let mut includes = Includes {
+            read: &mut read,
+            stack: Vec::new(),
+        };
+        Assembler::new().run(input, &mut includes)
+    }
+
+    /// Assembles the assembly file at the given path.
+    ///
+    /// Included files are read relative to the directory of the file including them, and errors
+    /// are reported along with the name of the file in which they occurred.
+    pub fn assemble_file<P: AsRef<Path>>(path: P) -> Result<Program> {
+        first_error(Assembler::assemble_file_recovering(path)?)
+    }
+
+    /// Assembles the assembly file at the given path, recovering from errors like
+    /// `assemble_recovering`.
+    ///
+    /// An error is only returned if the file itself can't be read.
+    pub fn assemble_file_recovering<P: AsRef<Path>>(path: P)
+                                                    -> Result<(Program, Vec<Diagnostic>)> {
+        Assembler::assemble_with_recovering(path, |path| fs::read_to_string(path))
+    }
+
+    /// Assembles the assembly file at the given path, using `read` to get the contents of that
+    /// file and of any included files.
+    ///
+    /// This works like `assemble_file`, but doesn't touch the filesystem itself, so the files
+    /// can come from anywhere (the paths given to `read` are those of `path` and the included
+    /// files, resolved relative to the files including them).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use std::io::{Error, ErrorKind};
+    /// use std::path::Path;
+    ///
+    /// use ibcm::Assembler;
+    ///
+    /// let mut files = HashMap::new();
+    /// files.insert(Path::new("src/main.ibcmasm"), "include \"lib/one.ibcmasm\"\nload one\nhalt");
+    /// files.insert(Path::new("src/lib/one.ibcmasm"), "jmp end\none: dw 1\nend:");
+    ///
+    /// let assembled = Assembler::assemble_with("src/main.ibcmasm", |path| {
+    ///     files.get(path)
+    ///         .map(|s| s.to_string())
+    ///         .ok_or_else(|| Error::new(ErrorKind::NotFound, "no such file"))
+    /// }).unwrap();
+    ///
+    /// assert_eq!(&[0xc002, 0x0001, 0x3001, 0x0000], assembled.data());
+    /// ```
+    pub fn assemble_with<P, F>(path: P, read: F) -> Result<Program>
+        where P: AsRef<Path>,
+              F: FnMut(&Path) -> io::Result<String>
+    {
+        first_error(Assembler::assemble_with_recovering(path, read)?)
+    }
+
+    /// Assembles the assembly file at the given path, using `read` to get the contents of the
+    /// files like `assemble_with`, and recovering from errors like `assemble_recovering`.
+    ///
+    /// An error is only returned if the file itself can't be read.
+    pub fn assemble_with_recovering<P, F>(path: P,
+                                          mut read: F)
+                                          -> Result<(Program, Vec<Diagnostic>)>
+        where P: AsRef<Path>,
+              F: FnMut(&Path) -> io::Result<String>
+    {
+        let path = normalize(path.as_ref());
+        let input = read(&path).chain_err(|| {
+                ErrorKind::Io(format!("could not read input file `{}`", path.display()))
+            })?;
+        let mut includes = Includes {
+            read: &mut read,
+            stack: vec![Rc::new(path)],
+        };
+        Ok(Assembler::new().run(input.as_bytes(), &mut includes))
+    }
+
+    /// Creates an empty assembler.
+    fn new() -> Assembler {
+        Assembler {
+            stmts: Vec::new(),
+            labels: HashMap::new(),
+            sets: Vec::new(),
+            constants: HashSet::new(),
+            pending: Vec::new(),
+            symbols: Vec::new(),
+            used: HashSet::new(),
+            macros: HashMap::new(),
+            definition: None,
+            expansions: 0,
+            overflowed: false,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Assembles the given input (the innermost file in `includes`, or the main input if
+    /// `includes` is empty), returning the program and everything found wrong with it.
+    fn run<R: Read>(mut self, input: R, includes: &mut Includes) -> (Program, Vec<Diagnostic>) {
+        let main = includes.current();
+        self.first_pass(input, includes);
+        self.second_pass(&main)
+    }
+
+    /// First pass: parse the input to get the initial list of statements and labels, and process
+    /// any directives and macros.
+    fn first_pass<R: Read>(&mut self, input: R, includes: &mut Includes) {
+        self.read(input, includes);
+        // Labels at the end of the program refer to the first unused address
+        self.flush_pending();
+    }
+
+    /// Processes the lines of a file (the innermost one in `includes`, or the main input if
+    /// `includes` is empty).
+    fn read<R: Read>(&mut self, input: R, includes: &mut Includes) {
         let br = BufReader::new(input);
-        let mut stmts = Vec::new();
-        let mut labels = HashMap::new();
+        let top = Rc::new(Vec::new());
 
         for (n, l) in br.lines().enumerate() {
             // Adjust line number
             let n = n + 1;
-            let l = l.chain_err(|| ErrorKind::Io("could not read line".into()))?;
-            
-            // Get rid of any comments
-            let l = if let Some(n) = l.find("//") {
-                &l[..n]
-            } else {
-                l.as_str()
+            let l = match l {
+                Ok(l) => l,
+                Err(e) => {
+                    let loc = Loc {
+                        file: includes.current(),
+                        line: n,
+                        text: "".into(),
+                        macros: top,
+                        arg: 0,
+                    };
+                    let msg = format!("could not read line: {}", e);
+                    self.diagnostics.push(Diagnostic::new(Severity::Error, msg, &loc, None));
+                    return;
+                }
             };
+            if let Err(d) = self.line(n, &l, &top, includes) {
+                self.diagnostics.push(d);
+            }
+        }
+        // Macro definitions can't span files
+        if let Some((name, loc, _)) = self.definition.take() {
+            let msg = format!("macro '{}' is missing 'endm'", name);
+            self.diagnostics.push(Diagnostic::new(Severity::Error, msg, &loc, None));
+        }
+    }
 
-            // Try to get the label/instruction
-            let mut iter = l.split_whitespace();
-            let mut part = match iter.next() {
-                Some(s) => s,
-                None => continue,
-            };
+    /// Processes a single line of input, from line `n` of the current file, within the given
+    /// macro expansions.
+    fn line(&mut self,
+            n: usize,
+            text: &str,
+            macros: &Rc<Vec<(String, usize)>>,
+            includes: &mut Includes)
+            -> AsmResult<()> {
+        let loc = Loc {
+            file: includes.current(),
+            line: n,
+            text: text.trim_end().into(),
+            macros: macros.clone(),
+            arg: 0,
+        };
+        let at = At {
+            loc: &loc,
+            text,
+            start: 0,
+        };
 
-            // See if we have a label
-            if let Some(idx) = part.find(':') {
-                // Add the label to the label table
-                let label = (&part[..idx]).trim();
-                if label.is_empty() {
-                    return Err(ErrorKind::Asm("found empty label".into(), n).into());
-                }
+        // Get rid of any comments
+        let l = if let Some(n) = find_unquoted(text, "//") {
+            &text[..n]
+        } else {
+            text
+        };
+
+        // Try to get the label/instruction
+        let (mut part, mut rest) = split_word(l);
+        if part.is_empty() {
+            return Ok(());
+        }
 
-                let label = label.to_owned();
-                if labels.contains_key(&label) {
-                    return Err(ErrorKind::Asm(format!("found duplicate label: '{}'", label), n).into());
+        // Lines inside a macro definition are saved for later
+        if let Some((name, start, mut def)) = self.definition.take() {
+            match part {
+                "endm" => {
+                    self.macros.insert(name, Rc::new(def));
                 }
-                labels.insert(label, stmts.len() as u16);
-
-                // Get next part (the actual instruction)
-                if idx == part.len() - 1 {
-                    // Get the next part from the iterator
-                    part = match iter.next() {
-                        Some(s) => s,
-                        None => continue,
-                    };
-                } else {
-                    // Use the rest of this part
-                    part = &part[idx + 1..];
+                "macro" => {
+                    self.definition = Some((name, start, def));
+                    return Err(at.error("macros cannot be defined inside other macros", part));
+                }
+                _ => {
+                    def.body.push((n, l.to_owned()));
+                    self.definition = Some((name, start, def));
                 }
             }
+            return Ok(());
+        }
+
+        // See if we have a label
+        let mut label = None;
+        if let Some(idx) = part.find(':') {
+            let name = part[..idx].trim();
+            if name.is_empty() {
+                return Err(at.error("found empty label", part));
+            }
+            label = Some(name);
+
+            // Get next part (the actual instruction)
+            if idx == part.len() - 1 {
+                // Get the next part from the rest of the line
+                let (next, after) = split_word(rest);
+                if next.is_empty() {
+                    self.pending.push((name.to_owned(), loc.clone(), at.span(name)));
+                    return Ok(());
+                }
+                part = next;
+                rest = after;
+            } else {
+                // Use the rest of this part
+                part = &part[idx + 1..];
+            }
+        }
+
+        // Get the instruction and its argument (the rest of the line, which may contain
+        // whitespace if it is an expression)
+        let instr = part;
+        let arg = Some(rest.trim()).filter(|arg| !arg.is_empty());
+
+        // The label of a constant definition is the name of the constant, but any other
+        // label refers to the next word of the program
+        if instr == "equ" || instr == "set" {
+            let name = match label {
+                Some(name) => name,
+                None => {
+                    let msg = format!("'{}' must be preceded by the name of the constant \
+                                       (e.g. 'size: {} 10')",
+                                      instr,
+                                      instr);
+                    return Err(at.error(msg, instr));
+                }
+            };
+            let value = self.resolve_word(require_directive_arg(instr, arg, &at)?, &at)?;
+            self.define(name, value, instr == "set", &loc, at.span(name))?;
+            self.constants.insert(name.to_owned());
+            return Ok(());
+        }
+        if let Some(name) = label {
+            self.pending.push((name.to_owned(), loc.clone(), at.span(name)));
+        }
 
-            // Return an error if the program is too long
-            if stmts.len() == u16::max_value() as usize {
-                return Err(ErrorKind::ProgramTooLong.into());
+        match instr {
+            "macro" => {
+                let (name, params) = split_word(require_directive_arg(instr, arg, &at)?);
+                if is_reserved(name) {
+                    let msg = format!("cannot define a macro named '{}', which is already an \
+                                       instruction or directive",
+                                      name);
+                    return Err(at.error(msg, name));
+                }
+                if self.macros.contains_key(name) {
+                    return Err(at.error(format!("found duplicate macro: '{}'", name), name));
+                }
+                let params = match params.trim() {
+                    "" => Vec::new(),
+                    params => {
+                        split_values(params, &at)?.into_iter().map(|p| p.to_owned()).collect()
+                    }
+                };
+                self.definition = Some((name.to_owned(),
+                                        loc.clone(),
+                                        Macro {
+                                            params,
+                                            body: Vec::new(),
+                                        }));
+            }
+            "include" => {
+                let name = match require_directive_arg(instr, arg, &at)? {
+                    arg if arg.len() >= 2 && arg.starts_with('"') && arg.ends_with('"') => {
+                        &arg[1..arg.len() - 1]
+                    }
+                    arg => {
+                        let msg = "expected a file name in quotes after 'include'";
+                        return Err(at.error(msg, arg));
+                    }
+                };
+                self.include(name, &at, includes)?;
+            }
+            "endm" => {
+                return Err(at.error("found 'endm' outside of a macro", instr));
+            }
+            "org" => {
+                let here = self.here();
+                let arg = require_directive_arg(instr, arg, &at)?;
+                let addr = self.resolve_operand(arg, here, &at)?;
+                if addr < here {
+                    let msg = format!("'org' cannot move backwards (from {:03x} to {:03x})",
+                                      here,
+                                      addr);
+                    return Err(at.error(msg, arg));
+                }
+                self.reserve(addr - here, "0000", &loc);
+            }
+            "space" => {
+                let count = self.resolve_count(require_directive_arg(instr, arg, &at)?, &at)?;
+                self.flush_pending();
+                self.reserve(count, "0000", &loc);
+            }
+            "fill" => {
+                let arg = require_directive_arg(instr, arg, &at)?;
+                let values = split_values(arg, &at)?;
+                if values.len() != 2 {
+                    let msg = "expected a count and a value after 'fill' (e.g. 'fill 10, 0')";
+                    return Err(at.error(msg, arg));
+                }
+                let count = self.resolve_count(values[0], &at)?;
+                self.flush_pending();
+                self.reserve(count, values[1], &at.loc_of(values[1]));
             }
+            "dw" => {
+                let values = split_values(require_directive_arg(instr, arg, &at)?, &at)?;
+                self.flush_pending();
+                for value in values {
+                    self.push(at.loc_of(value), Stmt::Data(value.to_owned()));
+                }
+            }
+            "string" => {
+                let chars = parse_string(require_directive_arg(instr, arg, &at)?, &at)?;
+                self.flush_pending();
+                for c in chars {
+                    self.push(loc.clone(), Stmt::Data(format!("{:04x}", c)));
+                }
+            }
+            _ => {
+                if let Some(def) = self.macros.get(instr).cloned() {
+                    return self.expand(instr, &def, arg, &at, includes);
+                }
+                self.flush_pending();
+                let stmt = parse_stmt(instr, arg, &at)?;
+                self.push(at.loc_of(arg.unwrap_or("")), stmt);
+            }
+        }
+        Ok(())
+    }
+
+    /// Expands an invocation of the given macro.
+    fn expand(&mut self,
+              name: &str,
+              def: &Macro,
+              arg: Option<&str>,
+              at: &At,
+              includes: &mut Includes)
+              -> AsmResult<()> {
+        let args = match arg {
+            Some(arg) => split_values(arg, at)?,
+            None => Vec::new(),
+        };
+        if args.len() != def.params.len() {
+            let msg = format!("macro '{}' expects {} argument(s), but {} were given",
+                              name,
+                              def.params.len(),
+                              args.len());
+            return Err(at.error(msg, name));
+        }
+        if at.loc.macros.len() == MAX_MACRO_DEPTH {
+            let msg = format!("macro expansion is nested too deeply (the limit is {}); \
+                               is '{}' recursive?",
+                              MAX_MACRO_DEPTH,
+                              name);
+            return Err(at.error(msg, name));
+        }
 
-            // Get the instruction and any arguments (there should only be one argument)
-            let instr = part;
-            let arg = iter.next();
-            if let Some(s) = iter.next() {
-                return Err(ErrorKind::Asm(format!("unexpected argument {}", s), n).into());
+        // Each expansion gets its own copy of the local labels
+        self.expansions += 1;
+        let suffix = format!(".{}", self.expansions);
+        for &(body_line, ref text) in &def.body {
+            let text = substitute(text, &def.params, &args, &suffix);
+            let mut macros = vec![(name.to_owned(), body_line)];
+            macros.extend(at.loc.macros.iter().cloned());
+            if let Err(d) = self.line(at.loc.line, &text, &Rc::new(macros), includes) {
+                self.diagnostics.push(d);
             }
+        }
+        Ok(())
+    }
 
-            // Get the statement and add it to the list
-            stmts.push((n, get_stmt(instr, arg, n)?));
+    /// Processes the file with the given name, included from the line at `at`.
+    fn include(&mut self, name: &str, at: &At, includes: &mut Includes) -> AsmResult<()> {
+        let path = match includes.current() {
+            Some(file) => normalize(&file.parent().unwrap_or_else(|| Path::new("")).join(name)),
+            None => normalize(Path::new(name)),
+        };
+        if includes.stack.iter().any(|file| **file == path) {
+            let cycle = includes.stack
+                .iter()
+                .skip_while(|file| ***file != path)
+                .map(|file| file.display().to_string())
+                .collect::<Vec<_>>();
+            let msg = format!("'{}' includes itself (through {} -> {})",
+                              name,
+                              cycle.join(" -> "),
+                              path.display());
+            return Err(at.error(msg, name));
         }
 
-        Ok(Assembler {
-            stmts: stmts,
-            labels: labels,
-        })
+        let input = (includes.read)(&path).map_err(|e| {
+                let msg = format!("could not read included file '{}': {}", path.display(), e);
+                at.error(msg, name)
+            })?;
+        includes.stack.push(Rc::new(path));
+        self.read(input.as_bytes(), includes);
+        includes.stack.pop();
+        Ok(())
     }
 
-    /// Second pass: replace address labels with their corresponding locations.
-    fn second_pass(self) -> Result<Program> {
+    /// Second pass: replace address labels with their corresponding locations, and check the
+    /// result for likely mistakes (`main` is the file containing the main input).
+    fn second_pass(mut self, main: &Option<Rc<PathBuf>>) -> (Program, Vec<Diagnostic>) {
         let mut code = Vec::new();
 
+        // Symbols defined using `set` take their values in order, starting from the first
+        // definition
+        for (_, name, _) in &self.sets {
+            self.labels.remove(name);
+        }
+        let sets = mem::take(&mut self.sets);
+        let mut sets = sets.into_iter().peekable();
+
         // Replace address labels
-        for &(n, ref stmt) in &self.stmts {
-            match *stmt {
-                Stmt::Data(ref s) => code.push(self.assemble_data(n, s)?),
-                Stmt::Instr { instr, ref addr } => code.push(self.assemble_instr(n, instr, addr)?),
+        let stmts = mem::take(&mut self.stmts);
+        for (here, (loc, stmt)) in stmts.iter().enumerate() {
+            while let Some((_, name, value)) = sets.next_if(|&(addr, _, _)| addr <= here) {
+                self.labels.insert(name, value);
             }
+
+            let here = here as u16;
+            let word = match *stmt {
+                Stmt::Data(ref s) => {
+                    let at = At {
+                        loc,
+                        text: s,
+                        start: loc.arg,
+                    };
+                    self.assemble_data(here, &at)
+                }
+                Stmt::Instr { instr, ref addr } => self.assemble_instr(here, instr, addr, loc),
+            };
+            // Words which can't be assembled are left as zero, so the rest can still be checked
+            code.push(word.unwrap_or_else(|d| {
+                self.diagnostics.push(d);
+                0
+            }));
+        }
+        for (_, name, value) in sets {
+            self.labels.insert(name, value);
         }
+        self.warn(main, &stmts, &code);
 
-        Ok(Program {
+        let program = Program {
             data: code,
             labels: self.labels,
-        })
+            constants: self.constants,
+            kinds: stmts.iter()
+                .map(|(_, stmt)| match *stmt {
+                    Stmt::Instr { .. } => WordKind::Code,
+                    Stmt::Data(_) => WordKind::Data,
+                })
+                .collect(),
+            locs: stmts.into_iter().map(|(loc, _)| loc).collect(),
+        };
+        (program, self.diagnostics)
     }
 
-    /// Assemble a data declaration.
-    fn assemble_data(&self, linum: usize, s: &str) -> Result<u16> {
-        u16::from_str_radix(s, 16).chain_err(|| ErrorKind::Asm("invalid data declaration (must be a hexadecimal word)".into(), linum))
+    /// Adds warnings about the given statements, which have been assembled into `code` (see
+    /// `assemble_recovering`).
+    fn warn(&mut self, main: &Option<Rc<PathBuf>>, stmts: &[(Loc, Stmt)], code: &[u16]) {
+        // Symbols from included files and macros often belong to libraries which are only partly
+        // used, so they are left out, as are internal labels
+        for (name, loc, span) in &self.symbols {
+            if !self.used.contains(name) && loc.file == *main && loc.macros.is_empty() &&
+               !is_internal(name) {
+                let kind = if self.constants.contains(name) { "constant" } else { "label" };
+                let msg = format!("{} '{}' is never used", kind, name);
+                self.diagnostics.push(Diagnostic::new(Severity::Warning, msg, loc, *span));
+            }
+        }
+
+        // Execution starts at address 0, and continues past every instruction except `jmp` and
+        // `halt` (`brl` returns to the next word)
+        if let Some((loc, Stmt::Data(_))) = stmts.first() {
+            let msg = "the program starts with data, which will be executed as an instruction";
+            self.diagnostics.push(Diagnostic::new(Severity::Warning, msg.into(), loc, None));
+        }
+        // A `nop` which is stored into is a placeholder for an instruction built at runtime
+        let stored = stmts.iter()
+            .zip(code)
+            .filter_map(|((_, stmt), &word)| match *stmt {
+                Stmt::Instr { instr: Instruction::Store(_), .. } => {
+                    Instruction::from_u16(word).address()
+                }
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        for (addr, pair) in stmts.windows(2).enumerate() {
+            if let ((loc, Stmt::Instr { instr, .. }), (_, Stmt::Data(_))) = (&pair[0], &pair[1]) {
+                let placeholder = *instr == Instruction::Nop && stored.contains(&(addr as u16));
+                if !matches!(*instr, Instruction::Jmp(_) | Instruction::Halt) && !placeholder {
+                    let msg = format!("execution can continue past this instruction into the \
+                                       data at {:03x}",
+                                      addr + 1);
+                    self.diagnostics.push(Diagnostic::new(Severity::Warning, msg, loc, None));
+                }
+            }
+        }
+
+        let halts = stmts.iter()
+            .any(|(_, stmt)| matches!(*stmt, Stmt::Instr { instr: Instruction::Halt, .. }));
+        if let (false, Some((loc, _))) = (halts, stmts.last()) {
+            let msg = "the program has no 'halt' instruction";
+            self.diagnostics.push(Diagnostic::new(Severity::Warning, msg.into(), loc, None));
+        }
+    }
+
+    /// Returns the address of the next word of the program.
+    fn here(&self) -> u16 {
+        self.stmts.len() as u16
+    }
+
+    /// Adds a statement to the program.
+    ///
+    /// Statements which don't fit in memory are dropped, and only the first of them is reported.
+    fn push(&mut self, loc: Loc, stmt: Stmt) {
+        if self.stmts.len() == MEMORY_WORDS {
+            if !self.overflowed {
+                self.overflowed = true;
+                let msg = format!("the program does not fit in memory (which has {} words)",
+                                  MEMORY_WORDS);
+                self.diagnostics.push(Diagnostic::new(Severity::Error, msg, &loc, None));
+            }
+            return;
+        }
+        self.stmts.push((loc, stmt));
+    }
+
+    /// Adds `count` data words with the given value to the program.
+    fn reserve(&mut self, count: u16, value: &str, loc: &Loc) {
+        for _ in 0..count {
+            self.push(loc.clone(), Stmt::Data(value.to_owned()));
+        }
+    }
+
+    /// Defines the labels waiting for the next word of the program.
+    fn flush_pending(&mut self) {
+        let here = self.here();
+        for (label, loc, span) in mem::take(&mut self.pending) {
+            if let Err(d) = self.define(&label, here, false, &loc, span) {
+                self.diagnostics.push(d);
+            }
+        }
+    }
+
+    /// Defines a label or constant with the given value, at the given location.
+    ///
+    /// Constants defined using `set` may be redefined, as long as every definition uses `set`.
+    fn define(&mut self,
+              name: &str,
+              value: u16,
+              set: bool,
+              loc: &Loc,
+              span: Option<Span>)
+              -> AsmResult<()> {
+        let redefinable = set && self.sets.iter().any(|(_, s, _)| s == name);
+        if self.labels.contains_key(name) && !redefinable {
+            let msg = format!("found duplicate label: '{}'", name);
+            return Err(Diagnostic::new(Severity::Error, msg, loc, span));
+        }
+        if !redefinable {
+            self.symbols.push((name.to_owned(), loc.clone(), span));
+        }
+        if set {
+            let here = self.stmts.len();
+            self.sets.push((here, name.to_owned(), value));
+        }
+        self.labels.insert(name.to_owned(), value);
+        Ok(())
     }
 
-    /// Assemble instruction from the base instruction and an optional address.
-    fn assemble_instr(&self, linum: usize, instr: Instruction, addr: &Option<String>) -> Result<u16> {
+    /// Returns the value of a label or constant, marking it as used.
+    fn lookup(&mut self, name: &str) -> Option<u16> {
+        let value = self.labels.get(name).cloned();
+        if value.is_some() && !self.used.contains(name) {
+            self.used.insert(name.to_owned());
+        }
+        value
+    }
+
+    /// Evaluates the number of words given to `space` or `fill`, using only the labels defined so
+    /// far.
+    fn resolve_count(&mut self, arg: &str, at: &At) -> AsmResult<u16> {
+        let here = self.here();
+        let count = self.evaluate(arg, here, at)?;
+        if count < 0 {
+            let msg = format!("invalid number of words '{}' (evaluates to {})", arg, count);
+            return Err(at.error(msg, arg));
+        }
+        if count as usize + self.stmts.len() > MEMORY_WORDS {
+            let msg = format!("{} words starting at address {:03x} do not fit in memory (which \
+                               has {} words)",
+                              count,
+                              here,
+                              MEMORY_WORDS);
+            return Err(at.error(msg, arg));
+        }
+        Ok(count as u16)
+    }
+
+    /// Assemble the data word at `at` (at address `here`).
+    ///
+    /// See the documentation of `Assembler` for the syntax of data words.
+    fn assemble_data(&mut self, here: u16, at: &At) -> AsmResult<u16> {
+        let s = at.text.trim();
+        if s.starts_with('\'') {
+            return parse_char(s, at);
+        }
+        // For compatibility, a plain hexadecimal word is always read as such
+        if let Ok(word) = u16::from_str_radix(s, 16) {
+            if !s.starts_with('+') {
+                if self.labels.contains_key(s) {
+                    let kind = if self.constants.contains(s) { "constant" } else { "label" };
+                    let msg = format!("'{}' is read as a hexadecimal word, not as the {} '{}' \
+                                       (use '{} + 0' for the {})",
+                                      s,
+                                      kind,
+                                      s,
+                                      s,
+                                      kind);
+                    let warning = Diagnostic::new(Severity::Warning, msg, at.loc, at.span(s));
+                    self.diagnostics.push(warning);
+                }
+                return Ok(word);
+            }
+        }
+        let value = self.evaluate(s, here, at)?;
+        check_word(s, value, at)
+    }
+
+    /// Evaluates the value of a constant defined with `equ` or `set`, using only the labels
+    /// defined so far.
+    fn resolve_word(&mut self, s: &str, at: &At) -> AsmResult<u16> {
+        if s.starts_with('\'') {
+            return parse_char(s, at);
+        }
+        let here = self.here();
+        let value = self.evaluate(s, here, at)?;
+        check_word(s, value, at)
+    }
+
+    /// Assemble instruction from the base instruction and an optional address, given the
+    /// address of the instruction itself and its location.
+    fn assemble_instr(&mut self,
+                      here: u16,
+                      instr: Instruction,
+                      addr: &Option<String>,
+                      loc: &Loc)
+                      -> AsmResult<u16> {
+        let at = At {
+            loc,
+            text: addr.as_ref().map_or("", |s| s),
+            start: loc.arg,
+        };
+        let mut resolve = || self.resolve_operand(require_arg(instr, addr, &at)?, here, &at);
+
         // Match instruction and use or reject the address as necessary
         // This is pretty ugly
         let new_instr = match instr {
             Instruction::Halt | Instruction::Io(_) | Instruction::Not | Instruction::Nop => {
-                refuse_arg(instr, addr, linum)?;
+                refuse_arg(instr, addr, &at)?;
                 instr
             }
             Instruction::Shift(_, _) => instr,
-            Instruction::Load(_) => Instruction::Load(self.resolve_label(require_arg(instr, addr, linum)?, linum)?),
-            Instruction::Store(_) => Instruction::Store(self.resolve_label(require_arg(instr, addr, linum)?, linum)?),
-            Instruction::Add(_) => Instruction::Add(self.resolve_label(require_arg(instr, addr, linum)?, linum)?),
-            Instruction::Sub(_) => Instruction::Sub(self.resolve_label(require_arg(instr, addr, linum)?, linum)?),
-            Instruction::And(_) => Instruction::And(self.resolve_label(require_arg(instr, addr, linum)?, linum)?),
-            Instruction::Or(_) => Instruction::Or(self.resolve_label(require_arg(instr, addr, linum)?, linum)?),
-            Instruction::Xor(_) => Instruction::Xor(self.resolve_label(require_arg(instr, addr, linum)?, linum)?),
-            Instruction::Jmp(_) => Instruction::Jmp(self.resolve_label(require_arg(instr, addr, linum)?, linum)?),
-            Instruction::Jmpe(_) => Instruction::Jmpe(self.resolve_label(require_arg(instr, addr, linum)?, linum)?),
-            Instruction::Jmpl(_) => Instruction::Jmpl(self.resolve_label(require_arg(instr, addr, linum)?, linum)?),
-            Instruction::Brl(_) => Instruction::Brl(self.resolve_label(require_arg(instr, addr, linum)?, linum)?),
+            Instruction::Load(_) => Instruction::Load(resolve()?),
+            Instruction::Store(_) => Instruction::Store(resolve()?),
+            Instruction::Add(_) => Instruction::Add(resolve()?),
+            Instruction::Sub(_) => Instruction::Sub(resolve()?),
+            Instruction::And(_) => Instruction::And(resolve()?),
+            Instruction::Or(_) => Instruction::Or(resolve()?),
+            Instruction::Xor(_) => Instruction::Xor(resolve()?),
+            Instruction::Jmp(_) => Instruction::Jmp(resolve()?),
+            Instruction::Jmpe(_) => Instruction::Jmpe(resolve()?),
+            Instruction::Jmpl(_) => Instruction::Jmpl(resolve()?),
+            Instruction::Brl(_) => Instruction::Brl(resolve()?),
         };
 
         Ok(new_instr.to_u16())
     }
 
-    /// Attempts to resolve the label with the given name to an address.
-    fn resolve_label(&self, label: &str, linum: usize) -> Result<u16> {
-        if let Some(&addr) = self.labels.get(label) {
-            Ok(addr)
-        } else {
-            Err(ErrorKind::Asm(format!("label '{}' is undefined", label), linum).into())
+    /// Evaluates the address argument of an instruction at address `here`, checking that it fits
+    /// in the 12-bit address field.
+    ///
+    /// See the documentation of `Assembler` for the syntax of arguments.
+    fn resolve_operand(&mut self, operand: &str, here: u16, at: &At) -> AsmResult<u16> {
+        let value = self.evaluate(operand, here, at)?;
+        if !(0..=0xfff).contains(&value) {
+            let msg = format!("address '{}' evaluates to {}, which does not fit in 12 bits \
+                               (it must be between 0x000 and 0xfff)",
+                              operand,
+                              hex(value));
+            return Err(at.error(msg, operand));
+        }
+        Ok(value as u16)
+    }
+
+    /// Evaluates an expression at address `here`.
+    fn evaluate(&mut self, expr: &str, here: u16, at: &At) -> AsmResult<i64> {
+        // Labels may contain `+`, `-` and `$`, so an exact match always wins
+        if let Some(addr) = self.lookup(expr) {
+            return Ok(addr as i64);
+        }
+
+        let mut value = 0i64;
+        // The sign of the next term, or `None` if an operator is expected
+        let mut sign = Some(1);
+        let mut rest = expr.trim_start();
+        while let Some(c) = rest.chars().next() {
+            match (sign, c) {
+                (Some(s), '-') => sign = Some(-s),
+                (Some(_), '+') => {}
+                (Some(s), _) => {
+                    let end = rest.find(|c: char| c.is_whitespace() || c == '+' || c == '-')
+                        .unwrap_or(rest.len());
+                    value += s * self.resolve_term(&rest[..end], here, at)?;
+                    sign = None;
+                    rest = rest[end..].trim_start();
+                    continue;
+                }
+                (None, '+') => sign = Some(1),
+                (None, '-') => sign = Some(-1),
+                (None, c) => {
+                    let msg = format!("expected '+' or '-' before '{}' in '{}'", c, expr);
+                    return Err(at.error(msg, &rest[..c.len_utf8()]));
+                }
+            }
+            rest = rest[1..].trim_start();
+        }
+        if sign.is_some() {
+            let msg = format!("expected a label or number at the end of '{}'", expr);
+            return Err(at.error(msg, expr));
+        }
+        Ok(value)
+    }
+
+    /// Evaluates a single term of an address argument: a label, `$` (the address `here`), or a
+    /// decimal or hexadecimal number.
+    fn resolve_term(&mut self, term: &str, here: u16, at: &At) -> AsmResult<i64> {
+        if let Some(addr) = self.lookup(term) {
+            return Ok(addr as i64);
+        }
+        if term == "$" {
+            return Ok(here as i64);
+        }
+
+        // Numbers which are too large are parsed anyway, so that their range is checked along
+        // with the rest of the expression
+        let number = match term.strip_prefix("0x").or_else(|| term.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => term.parse::<u32>(),
+        };
+        match number {
+            Ok(n) => Ok(n as i64),
+            // Anything which isn't a number is taken to be a label
+            Err(_) if !term.starts_with(|c: char| c.is_ascii_digit()) => {
+                Err(at.error(format!("label '{}' is undefined", term), term))
+            }
+            Err(_) => Err(at.error(format!("invalid number '{}'", term), term)),
         }
     }
 }
 
 /// A helper function to get a `Stmt` from an instruction and an optional argument.
-fn get_stmt(instr: &str, arg: Option<&str>, linum: usize) -> Result<Stmt> {
+pub fn get_stmt(instr: &str, arg: Option<&str>, linum: usize) -> Result<Stmt> {
+    let loc = Loc {
+        file: None,
+        line: linum,
+        text: "".into(),
+        macros: Rc::new(Vec::new()),
+        arg: 0,
+    };
+    let at = At {
+        loc: &loc,
+        text: "",
+        start: 0,
+    };
+    parse_stmt(instr, arg, &at).map_err(Diagnostic::into_error)
+}
+
+/// Gets a `Stmt` from an instruction and an optional argument, which are part of the line at
+/// `at`.
+fn parse_stmt(instr: &str, arg: Option<&str>, at: &At) -> AsmResult<Stmt> {
     // See if we have a data declaration (`dw`)
     if instr == "dw" {
         return Ok(Stmt::Data(match arg {
             Some(s) => s.into(),
-            None => return Err(ErrorKind::Asm("expected data declaration after 'dw'".into(), linum).into()),
+            None => return Err(at.error("expected data declaration after 'dw'", instr)),
         }));
     }
 
@@ -338,10 +1567,10 @@ fn get_stmt(instr: &str, arg: Option<&str>, linum: usize) -> Result<Stmt> {
         "readC" => Instruction::Io(IoOp::ReadChar),
         "printH" => Instruction::Io(IoOp::WriteHex),
         "printC" => Instruction::Io(IoOp::WriteChar),
-        "shiftL" => Instruction::Shift(ShiftOp::ShiftLeft, get_shift_amt(arg, linum)?),
-        "shiftR" => Instruction::Shift(ShiftOp::ShiftRight, get_shift_amt(arg, linum)?),
-        "rotL" => Instruction::Shift(ShiftOp::RotateLeft, get_shift_amt(arg, linum)?),
-        "rotR" => Instruction::Shift(ShiftOp::RotateRight, get_shift_amt(arg, linum)?),
+        "shiftL" => Instruction::Shift(ShiftOp::ShiftLeft, get_shift_amt(instr, arg, at)?),
+        "shiftR" => Instruction::Shift(ShiftOp::ShiftRight, get_shift_amt(instr, arg, at)?),
+        "rotL" => Instruction::Shift(ShiftOp::RotateLeft, get_shift_amt(instr, arg, at)?),
+        "rotR" => Instruction::Shift(ShiftOp::RotateRight, get_shift_amt(instr, arg, at)?),
         "load" => Instruction::Load(0),
         "store" => Instruction::Store(0),
         "add" => Instruction::Add(0),
@@ -355,7 +1584,7 @@ fn get_stmt(instr: &str, arg: Option<&str>, linum: usize) -> Result<Stmt> {
         "jmpe" => Instruction::Jmpe(0),
         "jmpl" => Instruction::Jmpl(0),
         "brl" => Instruction::Brl(0),
-        s @ _ => return Err(ErrorKind::Asm(format!("unknown instruction '{}'", s), linum).into()),
+        s => return Err(at.error(format!("unknown instruction '{}'", s), s)),
     };
 
     Ok(Stmt::Instr {
@@ -364,35 +1593,280 @@ fn get_stmt(instr: &str, arg: Option<&str>, linum: usize) -> Result<Stmt> {
     })
 }
 
-/// Helper method to parse a shift amount from an optional argument.
-fn get_shift_amt(arg: Option<&str>, linum: usize) -> Result<u16> {
+/// Returns whether the given label is internal (see `Assembler::assemble_recovering`).
+fn is_internal(label: &str) -> bool {
+    label.split('.').any(|part| part.starts_with('_'))
+}
+
+/// Splits the first whitespace-delimited word off the given string, returning the word (which is
+/// empty if there are no words) and the rest of the string.
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    match s.find(char::is_whitespace) {
+        Some(idx) => (&s[..idx], &s[idx..]),
+        None => (s, ""),
+    }
+}
+
+/// Returns the position of the first occurrence of `pat` in `s` which isn't inside a character or
+/// string literal.
+///
+/// Quotes only start a literal at the beginning of a word (or after a comma), so that labels
+/// containing quotes are left alone.
+fn find_unquoted(s: &str, pat: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut prev = ' ';
+    for (idx, c) in s.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if (c == '\'' || c == '"') && (prev.is_whitespace() || prev == ',') => {
+                quote = Some(c)
+            }
+            None if s[idx..].starts_with(pat) => return Some(idx),
+            None => {}
+        }
+        prev = c;
+    }
+    None
+}
+
+/// Splits a comma-separated list of values (which may contain character literals).
+fn split_values<'a>(s: &'a str, at: &At) -> AsmResult<Vec<&'a str>> {
+    let mut values = Vec::new();
+    let mut rest = s;
+    loop {
+        let end = find_unquoted(rest, ",").unwrap_or(rest.len());
+        let value = rest[..end].trim();
+        if value.is_empty() {
+            return Err(at.error(format!("missing value in list '{}'", s), &rest[..end]));
+        }
+        values.push(value);
+        if end == rest.len() {
+            return Ok(values);
+        }
+        rest = &rest[end + 1..];
+    }
+}
+
+/// Parses the next (possibly escaped) character of a character or string literal.
+///
+/// The supported escapes are `\n`, `\t`, `\r`, `\0`, `\\`, `\'`, `\"` and `\xHH`, as in IBCMC.
+/// Errors point to the whole literal `lit`.
+fn next_char<I: Iterator<Item = char>>(chars: &mut I, lit: &str, at: &At) -> AsmResult<u16> {
+    let c = match chars.next() {
+        Some('\\') => {
+            match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some(c @ '\\') | Some(c @ '\'') | Some(c @ '"') => c,
+                Some('x') => {
+                    let mut digit = || chars.next().and_then(|c| c.to_digit(16));
+                    match digit().zip(digit()) {
+                        Some((hi, lo)) => return Ok((16 * hi + lo) as u16),
+                        None => {
+                            let msg = "expected two hexadecimal digits after '\\x'";
+                            return Err(at.error(msg, lit));
+                        }
+                    }
+                }
+                Some(c) => {
+                    let msg = format!("unknown escape sequence '\\{}'", c);
+                    return Err(at.error(msg, lit));
+                }
+                None => {
+                    return Err(at.error("unterminated escape sequence", lit));
+                }
+            }
+        }
+        Some(c) => c,
+        None => return Err(at.error("unterminated literal", lit)),
+    };
+    if !c.is_ascii() {
+        let msg = format!("character '{}' is not an ASCII character", c);
+        return Err(at.error(msg, lit));
+    }
+    Ok(c as u16)
+}
+
+/// Parses a character literal (e.g. `'a'` or `'\n'`).
+fn parse_char(s: &str, at: &At) -> AsmResult<u16> {
+    let mut chars = s.chars();
+    chars.next();
+    if chars.clone().next() == Some('\'') {
+        return Err(at.error("empty character literal", s));
+    }
+    let c = next_char(&mut chars, s, at)?;
+    if chars.as_str() != "'" {
+        return Err(at.error(format!("invalid character literal {}", s), s));
+    }
+    Ok(c)
+}
+
+/// Parses the string literal given to `string`, returning its characters.
+fn parse_string(s: &str, at: &At) -> AsmResult<Vec<u16>> {
+    if !s.starts_with('"') {
+        return Err(at.error("expected a string literal after 'string'", s));
+    }
+    let mut chars = s[1..].chars();
+    let mut string = Vec::new();
+    loop {
+        if chars.as_str().starts_with('"') {
+            break;
+        }
+        if chars.as_str().is_empty() {
+            return Err(at.error("unterminated string literal", s));
+        }
+        string.push(next_char(&mut chars, s, at)?);
+    }
+    if chars.as_str() != "\"" {
+        let msg = format!("unexpected text after string literal {}", s);
+        return Err(at.error(msg, s));
+    }
+    Ok(string)
+}
+
+/// Formats a number in hexadecimal, with a `0x` prefix (and at least three digits, like an
+/// address).
+fn hex(value: i64) -> String {
+    if value < 0 {
+        format!("-0x{:03x}", -value)
+    } else {
+        format!("0x{:03x}", value)
+    }
+}
+
+/// Checks that the value of a data word or constant fits in 16 bits, as either a signed or an
+/// unsigned number.
+fn check_word(s: &str, value: i64, at: &At) -> AsmResult<u16> {
+    if !(i16::MIN as i64..=u16::MAX as i64).contains(&value) {
+        let msg = format!("value '{}' evaluates to {}, which does not fit in a word", s, value);
+        return Err(at.error(msg, s));
+    }
+    Ok(value as u16)
+}
+
+/// Helper method to extract the required argument of a directive.
+fn require_directive_arg<'a>(directive: &str, arg: Option<&'a str>, at: &At) -> AsmResult<&'a str> {
+    match arg {
+        Some(s) => Ok(s),
+        None => Err(at.error(format!("expected argument to '{}'", directive), directive)),
+    }
+}
+
+/// Returns whether the given name is an instruction or directive.
+fn is_reserved(name: &str) -> bool {
+    // The opcode and the two bits after it determine the name of an instruction
+    DIRECTIVES.contains(&name) ||
+    (0..0x40).any(|op: u16| Instruction::from_u16(op << 10).name() == name)
+}
+
+/// Substitutes the arguments of a macro invocation for its parameters in a line of its body, and
+/// adds the given suffix to local labels (those starting with `%%`).
+///
+/// Only whole words are replaced, where words are separated by whitespace, commas, colons and
+/// `+` and `-`; character and string literals are left alone.
+fn substitute(line: &str, params: &[String], args: &[&str], suffix: &str) -> String {
+    let is_separator = |c: char| c.is_whitespace() || c == ',' || c == ':' || c == '+' || c == '-';
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        if is_separator(c) {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        if c == '\'' || c == '"' {
+            // Copy the literal up to (and including) the closing quote
+            let mut escaped = false;
+            let end = rest.char_indices()
+                .skip(1)
+                .find(|&(_, d)| {
+                    let end = !escaped && d == c;
+                    escaped = !escaped && d == '\\';
+                    end
+                })
+                .map_or(rest.len(), |(idx, _)| idx + 1);
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+
+        let end = rest.find(is_separator).unwrap_or(rest.len());
+        let word = &rest[..end];
+        match params.iter().position(|p| p == word) {
+            Some(i) => out.push_str(args[i]),
+            None if word.starts_with("%%") => {
+                out.push_str(word);
+                out.push_str(suffix);
+            }
+            None => out.push_str(word),
+        }
+        rest = &rest[end..];
+    }
+    out
+}
+
+/// Normalizes a path by removing `.` components and any `..` components which follow a normal
+/// component, without accessing the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if matches!(normalized.components().next_back(),
+                                             Some(Component::Normal(_))) => {
+                normalized.pop();
+            }
+            component => normalized.push(component.as_os_str()),
+        }
+    }
+    normalized
+}
+
+/// Helper method to parse the shift amount of the given instruction from an optional argument.
+fn get_shift_amt(instr: &str, arg: Option<&str>, at: &At) -> AsmResult<u16> {
     let amt = match arg {
         Some(s) => s,
-        None => return Err(ErrorKind::Asm("must specify amount to shift".into(), linum).into()),
+        None => return Err(at.error("must specify amount to shift", instr)),
     };
-    let amt = amt.parse::<u16>().chain_err(|| ErrorKind::Asm("invalid shift amount".into(), linum))?;
-    if amt >= 16 {
-        return Err(ErrorKind::Asm("invalid shift amount (must be between 0 and 15, inclusive)".into(), linum).into());
+    match amt.parse::<u16>() {
+        Ok(amt) if amt < 16 => Ok(amt),
+        Ok(_) => Err(at.error("invalid shift amount (must be between 0 and 15, inclusive)", amt)),
+        Err(_) => Err(at.error("invalid shift amount", amt)),
     }
-
-    Ok(amt)
 }
 
 /// Helper method to return an error if an argument was given.
 ///
 /// Accepts as an argument the instruction, for better error messages.
-fn refuse_arg(instr: Instruction, arg: &Option<String>, linum: usize) -> Result<()> {
-    if let &Some(_) = arg {
-        Err(ErrorKind::Asm(format!("unexpected argument to '{}'", instr.name()), linum).into())
-    } else {
-        Ok(())
+fn refuse_arg(instr: Instruction, arg: &Option<String>, at: &At) -> AsmResult<()> {
+    match *arg {
+        Some(ref s) => Err(at.error(format!("unexpected argument to '{}'", instr.name()), s)),
+        None => Ok(()),
     }
 }
 
 /// Helper method to extract a required argument from an option.
-fn require_arg(instr: Instruction, arg: &Option<String>, linum: usize) -> Result<&str> {
+fn require_arg<'a>(instr: Instruction, arg: &'a Option<String>, at: &At) -> AsmResult<&'a str> {
     match *arg {
         Some(ref s) => Ok(s),
-        None => Err(ErrorKind::Asm(format!("expected argument to '{}'", instr.name()), linum).into()),
+        None => {
+            let msg = format!("expected argument to '{}'", instr.name());
+            Err(Diagnostic::new(Severity::Error, msg, at.loc, None))
+        }
+    }
+}
+
+/// Returns the assembled program, or the first error found in it.
+fn first_error((program, diagnostics): (Program, Vec<Diagnostic>)) -> Result<Program> {
+    match diagnostics.into_iter().find(|d| d.severity == Severity::Error) {
+        Some(d) => Err(d.into_error()),
+        None => Ok(program),
     }
 }
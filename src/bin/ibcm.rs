@@ -6,15 +6,17 @@ extern crate ibcm;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-use std::fs::File;
+use std::collections::HashMap;
+use std::fs::{self, File};
 use std::io::{self, Read, Write};
 
 use clap::{Arg, App, ArgMatches, SubCommand};
 
 use ibcm::errors::*;
-use ibcm::{Assembler, Debugger, Simulator};
-use ibcm::ibcmc::lexer::Lexer;
-use ibcm::ibcmc::parser::Parser;
+use ibcm::{Assembler, DebugInfo, Debugger, Disassembly, Program, Severity, Simulator};
+use ibcm::ibcmc;
+use ibcm::ibcmc::alloc::MemoryUsage;
+use ibcm::ibcmc::codegen::Line;
 
 quick_main!(run);
 
@@ -31,10 +33,25 @@ fn run() -> Result<()> {
                                  .short("b")
                                  .long("binary")
                                  .help("Outputs a binary file instead of a hexadecimal listing"))
+                        .arg(Arg::with_name("debug-info")
+                                 .conflicts_with("hex")
+                                 .short("g")
+                                 .long("debug-info")
+                                 .help("Also writes the debug information of the program next to \
+                                        the output file (with `.dbg` added to its name), for use \
+                                        by the debugger"))
                         .arg(Arg::with_name("hex")
                                  .short("x")
                                  .long("hex")
                                  .help("Processes the input as a hexadecimal listing"))
+                        .arg(Arg::with_name("listing")
+                                 .conflicts_with("hex")
+                                 .short("l")
+                                 .long("listing")
+                                 .value_name("FILE")
+                                 .help("Also writes a listing of the assembled program, with the \
+                                        address, encoding and source line of each word")
+                                 .takes_value(true))
                         .arg(Arg::with_name("output")
                                  .short("o")
                                  .long("output")
@@ -47,23 +64,50 @@ fn run() -> Result<()> {
                                  .help("The program to debug")
                                  .required(true))
                         .arg(Arg::with_name("asm")
-                                 .conflicts_with("binary")
+                                 .conflicts_with_all(&["binary", "ibcmc"])
                                  .short("s")
                                  .long("asm")
                                  .help("Processes the input as an ICBM assembly file"))
+                        .arg(Arg::with_name("ibcmc")
+                                 .conflicts_with("binary")
+                                 .short("c")
+                                 .long("ibcmc")
+                                 .help("Processes the input as an IBCMC source file (errors will \
+                                        then refer to its source lines)"))
                         .arg(Arg::with_name("binary")
                                  .short("b")
                                  .long("binary")
                                  .help("Processes the input as a binary file")))
+        .subcommand(SubCommand::with_name("disasm")
+                        .arg(Arg::with_name("INPUT")
+                                 .help("The program to disassemble")
+                                 .required(true))
+                        .arg(Arg::with_name("binary")
+                                 .short("b")
+                                 .long("binary")
+                                 .help("Processes the input as a binary file"))
+                        .arg(Arg::with_name("output")
+                                 .short("o")
+                                 .long("output")
+                                 .value_name("FILE")
+                                 .help("Sets the output file name (the assembly is printed to \
+                                        stdout if it isn't given)")
+                                 .takes_value(true)))
         .subcommand(SubCommand::with_name("execute")
                         .arg(Arg::with_name("INPUT")
                                  .help("The program data file to load")
                                  .required(true))
                         .arg(Arg::with_name("asm")
-                                 .conflicts_with("binary")
+                                 .conflicts_with_all(&["binary", "ibcmc"])
                                  .short("s")
                                  .long("asm")
                                  .help("Processes the input as an ICBM assembly file"))
+                        .arg(Arg::with_name("ibcmc")
+                                 .conflicts_with("binary")
+                                 .short("c")
+                                 .long("ibcmc")
+                                 .help("Processes the input as an IBCMC source file (errors will \
+                                        then refer to its source lines)"))
                         .arg(Arg::with_name("binary")
                                  .short("b")
                                  .long("binary")
@@ -72,17 +116,53 @@ fn run() -> Result<()> {
                         .arg(Arg::with_name("INPUT")
                                  .help("The IBCMC source file to compile")
                                  .required(true))
+                        .arg(Arg::with_name("asm")
+                                 .conflicts_with("binary")
+                                 .short("s")
+                                 .long("asm")
+                                 .help("Outputs IBCM assembly (the default if the output file \
+                                        name ends in `.ibcmasm`)"))
+                        .arg(Arg::with_name("binary")
+                                 .short("b")
+                                 .long("binary")
+                                 .help("Outputs a binary file instead of a hexadecimal listing"))
+                        .arg(Arg::with_name("memory")
+                                 .short("m")
+                                 .long("memory")
+                                 .help("Prints the amount of memory used by the program"))
+                        .arg(Arg::with_name("fmt")
+                                 .long("fmt")
+                                 .conflicts_with_all(&["asm", "binary", "memory"])
+                                 .help("Prints the source file formatted in the canonical style \
+                                        instead of compiling it, keeping its comments"))
+                        .arg(Arg::with_name("check")
+                                 .long("check")
+                                 .requires("fmt")
+                                 .help("With `--fmt`, checks that the source file is already \
+                                        formatted instead of printing it"))
+                        .arg(Arg::with_name("opt-level")
+                                 .short("O")
+                                 .long("opt-level")
+                                 .value_name("LEVEL")
+                                 .default_value("0")
+                                 .possible_values(&["0", "1", "2"])
+                                 .help("Sets the optimization level")
+                                 .takes_value(true))
                         .arg(Arg::with_name("output")
                                  .short("o")
                                  .long("output")
-                                 .value_name("OUTPUT")
-                                 .help("Sets the output file name")
+                                 .value_name("FILE")
+                                 .default_value("ibcm.out")
+                                 .help("Sets the output file name (the debug information of a \
+                                        hexadecimal or binary file is written next to it, with \
+                                        `.dbg` added to its name)")
                                  .takes_value(true)))
         .get_matches();
 
     match matches.subcommand() {
         ("compile", Some(sub_m)) => compile(sub_m),
         ("debug", Some(sub_m)) => debug(sub_m),
+        ("disasm", Some(sub_m)) => disasm(sub_m),
         ("execute", Some(sub_m)) => execute(sub_m),
         ("ibcmc", Some(sub_m)) => ibcmc(sub_m),
         _ => {
@@ -97,11 +177,25 @@ fn compile(m: &ArgMatches) -> Result<()> {
     let input = m.value_of("INPUT").unwrap();
     let f = File::open(input)
         .chain_err(|| ErrorKind::Io(format!("could not open input file `{}`", input)))?;
+    // A debug info file from an earlier compilation would no longer match the program
+    let sidecar = sidecar(m.value_of("output").unwrap());
     // Read input file into a simulator (only needed for memory)
     let sim = if m.is_present("hex") {
+        remove_sidecar(&sidecar)?;
         Simulator::from_hex(f)
     } else {
-        Simulator::from_instructions(Assembler::assemble(f)?.data())
+        let program = assemble(input)?;
+        if let Some(listing) = m.value_of("listing") {
+            let lf = File::create(listing)
+                .chain_err(|| ErrorKind::Io(format!("could not create listing file `{}`", listing)))?;
+            program.to_listing(lf)?;
+        }
+        if m.is_present("debug-info") {
+            write_sidecar(&sidecar, &program.debug_info())?;
+        } else {
+            remove_sidecar(&sidecar)?;
+        }
+        Simulator::from_instructions(program.data())
     }?;
 
     // Safe because we provided a default value
@@ -122,17 +216,11 @@ fn compile(m: &ArgMatches) -> Result<()> {
 fn debug(m: &ArgMatches) -> Result<()> {
     // We can unwrap here since INPUT is a required argument
     let input = m.value_of("INPUT").unwrap();
-    let f = File::open(input)
-        .chain_err(|| ErrorKind::Io(format!("could not open input file `{}`", input)))?;
-    // Read the input file into a simulator
-    let sim = if m.is_present("binary") {
-        Simulator::from_binary(f)
-    } else if m.is_present("asm") {
-        Simulator::from_instructions(Assembler::assemble(f)?.data())
-    } else {
-        Simulator::from_hex(f)
-    }?;
+    let (sim, debug_info) = load(m, input)?;
     let mut debug = Debugger::new(sim);
+    if let Some(debug_info) = debug_info {
+        debug.set_debug_info(debug_info);
+    }
 
     // Debug console
     loop {
@@ -143,6 +231,7 @@ fn debug(m: &ArgMatches) -> Result<()> {
         io::stdin()
             .read_line(&mut input)
             .chain_err(|| ErrorKind::Io("could not read from stdin".into()))?;
+        #[allow(clippy::trim_split_whitespace)]
         let input_parts = input.trim().split_whitespace().collect::<Vec<_>>();
         if input_parts.is_empty() {
             continue;
@@ -168,33 +257,261 @@ fn debug(m: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
+/// The `disasm` subcommand.
+fn disasm(m: &ArgMatches) -> Result<()> {
+    let input = m.value_of("INPUT").unwrap();
+    let f = File::open(input)
+        .chain_err(|| ErrorKind::Io(format!("could not open input file `{}`", input)))?;
+    let sim = if m.is_present("binary") {
+        Simulator::from_binary(f)
+    } else {
+        Simulator::from_hex(f)
+    }?;
+    let asm = Disassembly::new(sim.program()).to_string();
+
+    match m.value_of("output") {
+        Some(output) => {
+            fs::write(output, asm)
+                .chain_err(|| ErrorKind::Io(format!("could not write to output file `{}`", output)))
+        }
+        None => {
+            print!("{}", asm);
+            Ok(())
+        }
+    }
+}
+
 /// The `execute` subcommand.
 fn execute(m: &ArgMatches) -> Result<()> {
     // We can unwrap here since INPUT is a required argument
     let input = m.value_of("INPUT").unwrap();
+    let (mut sim, debug_info) = load(m, input)?;
+    // Errors report their source location if it is known
+    if let Some(debug_info) = debug_info {
+        sim.set_source_map(debug_info.source_map());
+    }
+
+    // Run the simulator program (there are no breakpoints, so it only stops when it halts)
+    sim.run().map(|_| ())
+}
+
+/// Reads the input file of the `debug` or `execute` subcommand into a simulator, along with the
+/// debug information of the program, if it is available.
+///
+/// The debug information of a hexadecimal or binary file is read from its sidecar file (see
+/// `sidecar`), if there is one and it matches the program.
+fn load<'a, 'b>(m: &ArgMatches, input: &str) -> Result<(Simulator<'a, 'b>, Option<DebugInfo>)> {
+    if m.is_present("ibcmc") {
+        return Ok((ibcmc_simulator(input)?, None));
+    }
+    if m.is_present("asm") {
+        let program = assemble(input)?;
+        return Ok((Simulator::from_instructions(program.data())?, Some(program.debug_info())));
+    }
+
     let f = File::open(input)
         .chain_err(|| ErrorKind::Io(format!("could not open input file `{}`", input)))?;
-    // Read the input file into a simulator
-    let mut sim = if m.is_present("binary") {
+    let sim = if m.is_present("binary") {
         Simulator::from_binary(f)
-    } else if m.is_present("asm") {
-        Simulator::from_instructions(Assembler::assemble(f)?.data())
     } else {
         Simulator::from_hex(f)
     }?;
+    let sidecar = sidecar(input);
+    let debug_info = match File::open(&sidecar) {
+        Ok(df) => {
+            let info = DebugInfo::from_reader(df)
+                .chain_err(|| format!("could not read debug info file `{}`", sidecar))?;
+            // A sidecar left over from another version of the program would give wrong locations
+            if info.matches(sim.program()) {
+                Some(info)
+            } else {
+                eprintln!("warning: ignoring debug info file `{}`, which doesn't match `{}`",
+                          sidecar,
+                          input);
+                None
+            }
+        }
+        Err(_) => None,
+    };
+    Ok((sim, debug_info))
+}
+
+/// Writes the given debug information to a sidecar file.
+fn write_sidecar(sidecar: &str, debug_info: &DebugInfo) -> Result<()> {
+    let df = File::create(sidecar)
+        .chain_err(|| ErrorKind::Io(format!("could not create debug info file `{}`", sidecar)))?;
+    debug_info.to_writer(df)
+}
+
+/// Removes the given sidecar file, if it exists, so that it isn't used with a program compiled
+/// without debug information.
+fn remove_sidecar(sidecar: &str) -> Result<()> {
+    match fs::remove_file(sidecar) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => {
+            res.chain_err(|| {
+                          ErrorKind::Io(format!("could not remove debug info file `{}`", sidecar))
+                      })
+        }
+    }
+}
+
+/// Returns the name of the sidecar file containing the debug information of the given
+/// hexadecimal or binary file.
+fn sidecar(file: &str) -> String {
+    format!("{}.dbg", file)
+}
+
+/// Assembles an IBCM assembly file.
+///
+/// All the errors and warnings in the file are printed to stderr along with the offending source
+/// lines, and the program is only returned if there are no errors.
+fn assemble(input: &str) -> Result<Program> {
+    let (program, diagnostics) = Assembler::assemble_file_recovering(input)?;
+
+    // Each file is only read once, however many diagnostics it has
+    let mut sources = HashMap::new();
+    for d in &diagnostics {
+        let source = match d.file {
+            Some(ref file) => {
+                sources.entry(file)
+                    .or_insert_with(|| fs::read_to_string(file).unwrap_or_default())
+                    .as_str()
+            }
+            None => "",
+        };
+        eprintln!("{}", d.format(source));
+    }
+
+    let errors = diagnostics.iter().filter(|d| d.severity == Severity::Error).count();
+    if errors > 0 {
+        return Err(failure(input, errors, "assemble"));
+    }
+    Ok(program)
+}
+
+/// Compiles an IBCMC source file at the given optimization level, returning the lines of
+/// assembly along with the source of the program.
+///
+/// If the program can't be compiled, all the errors are printed to stderr along with the
+/// offending source lines.
+fn compile_ibcmc(input: &str, level: u8) -> Result<(Vec<Line>, String)> {
+    let source = read_source(input)?;
+
+    // Syntax errors are collected first, so that they can all be reported at once
+    let (_, mut errors) = ibcmc::parse(source.as_bytes());
+    if errors.is_empty() {
+        match ibcmc::compile_to_lines(source.as_bytes(), level) {
+            Ok(code) => return Ok((code, source)),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    Err(report_errors(input, &source, &errors, "compile"))
+}
+
+/// Formats an IBCMC source file in the canonical style, printing it to stdout or (if `check` is
+/// true) checking that the file is already formatted.
+fn format_ibcmc(input: &str, check: bool) -> Result<()> {
+    let source = read_source(input)?;
+    let formatted = match ibcmc::format(source.as_bytes()) {
+        Ok(formatted) => formatted,
+        // Report all the syntax errors, not just the first one
+        Err(_) => {
+            let (_, errors) = ibcmc::parse(source.as_bytes());
+            return Err(report_errors(input, &source, &errors, "format"));
+        }
+    };
+    if !check {
+        print!("{}", formatted);
+    } else if formatted != source {
+        bail!("`{}` is not formatted (run `ibcm ibcmc --fmt` to see the formatted source)",
+              input);
+    }
+    Ok(())
+}
+
+/// Reads an IBCMC source file.
+fn read_source(input: &str) -> Result<String> {
+    let mut source = String::new();
+    File::open(input)
+        .and_then(|mut f| f.read_to_string(&mut source))
+        .chain_err(|| ErrorKind::Io(format!("could not read input file `{}`", input)))?;
+    Ok(source)
+}
+
+/// Prints the given errors in an IBCMC source file to stderr along with the offending source
+/// lines, returning an error saying that the file could not be processed (e.g. compiled).
+fn report_errors(input: &str, source: &str, errors: &[ibcmc::errors::Error], action: &str) -> Error {
+    for e in errors {
+        eprintln!("{}", ibcmc::format_error(e, input, source));
+    }
+    failure(input, errors.len(), action)
+}
+
+/// Returns an error saying that a file could not be processed (e.g. compiled) due to the given
+/// number of errors.
+fn failure(input: &str, errors: usize, action: &str) -> Error {
+    format!("could not {} `{}` due to {} error{}",
+            action,
+            input,
+            errors,
+            if errors == 1 { "" } else { "s" })
+        .into()
+}
 
-    // Run the simulator program
-    sim.run()
+/// Returns the text of the given lines of assembly.
+fn asm_text(code: &[Line]) -> String {
+    code.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// Compiles an IBCMC source file and loads it into a simulator, along with its source map.
+fn ibcmc_simulator<'a, 'b>(input: &str) -> Result<Simulator<'a, 'b>> {
+    let (code, _) = compile_ibcmc(input, 0)?;
+    let mut sim = Simulator::from_instructions(Assembler::assemble(asm_text(&code).as_bytes())?
+                                                   .data())?;
+    let mut source_map = ibcmc::source_map(&code);
+    source_map.set_file(input);
+    sim.set_source_map(source_map);
+
+    Ok(sim)
 }
 
 /// The `ibcmc` subcommand.
 fn ibcmc(m: &ArgMatches) -> Result<()> {
     let input = m.value_of("INPUT").unwrap();
-    let f = File::open(input)
-        .chain_err(|| ErrorKind::Io(format!("could not open input file `{}`", input)))?;
+    if m.is_present("fmt") {
+        return format_ibcmc(input, m.is_present("check"));
+    }
+    // Safe because we provided a default value and the possible values are all numbers
+    let level = m.value_of("opt-level").unwrap().parse().unwrap();
+    let (code, source) = compile_ibcmc(input, level)?;
+    if m.is_present("memory") {
+        println!("memory usage: {}", MemoryUsage::of(&code));
+    }
+
+    // Safe because we provided a default value
+    let output = m.value_of("output").unwrap();
+    let sidecar = sidecar(output);
+    let asm = asm_text(&code);
+    let mut of =
+        File::create(output)
+            .chain_err(|| ErrorKind::Io(format!("could not create output file `{}`", output)))?;
+    if m.is_present("asm") || (!m.is_present("binary") && output.ends_with(".ibcmasm")) {
+        remove_sidecar(&sidecar)?;
+        return of.write_all(asm.as_bytes())
+            .chain_err(|| ErrorKind::Io(format!("could not write to output file `{}`", output)));
+    }
 
-    let ast = Parser::parse_from_lexer(Lexer::new(f.bytes()))?;
-    println!("{:#?}", ast);
+    let program = Assembler::assemble(asm.as_bytes())?;
+    let sim = Simulator::from_instructions(program.data())?;
+    if m.is_present("binary") {
+        sim.to_binary(of)?;
+    } else {
+        sim.to_hex(of)?;
+    }
+    // Errors in the compiled program can then refer to its source lines
+    write_sidecar(&sidecar, &ibcmc::debug_info(&code, program.data(), input, &source))?;
 
     Ok(())
 }
@@ -1,16 +1,18 @@
 //! The debugger.
 use errors::*;
 use simulator::Simulator;
+use source_map::{DebugInfo, WordKind};
 
 /// The help string for the debugger
-const HELP: &'static str = "The following commands are recognized:
+const HELP: &str = "The following commands are recognized:
 quit            Exit the debugger.
 help            Print this message.
 dump <amt>      Display the contents of the first <amt>
                 memory locations.
 run             Run the program until it halts.
 status          Output the content of all registers and print
-                the current instruction.
+                the current instruction (and its source line,
+                if known).
 step <n>        Execute the next <n> instructions.";
 
 /// A debugger, which is a wrapper around a `Simulator` that
@@ -18,16 +20,27 @@ step <n>        Execute the next <n> instructions.";
 pub struct Debugger<'a, 'b> {
     /// The underlying `Simulator`.
     sim: Simulator<'a, 'b>,
+    /// The debug information of the program, if any.
+    debug_info: Option<DebugInfo>,
 }
 
 impl<'a, 'b> Debugger<'a, 'b> {
     /// Construct a new `Debugger` from the given `Simulator`.
     pub fn new(sim: Simulator<'a, 'b>) -> Self {
         Debugger {
-            sim: sim,
+            sim,
+            debug_info: None,
         }
     }
 
+    /// Sets the debug information of the program, which is used to show the source of the
+    /// current instruction (it also becomes the source map of the simulator, so that errors
+    /// report their source location).
+    pub fn set_debug_info(&mut self, debug_info: DebugInfo) {
+        self.sim.set_source_map(debug_info.source_map());
+        self.debug_info = Some(debug_info);
+    }
+
     /// Executes the specified command with the given arguments.
     ///
     /// Returns `true` if the debugger should quit.
@@ -73,7 +86,7 @@ impl<'a, 'b> Debugger<'a, 'b> {
         while !self.sim.step()? {
             steps += 1;
         }
-        println!("machine halted after {} step(s)", steps);
+        println!("machine halted after {} step(s){}", steps, self.location());
         Ok(false)
     }
 
@@ -89,6 +102,17 @@ impl<'a, 'b> Debugger<'a, 'b> {
         println!("ir:     {}", ir);
         println!("pc:     {}", pc);
         println!("halted? {}", self.sim.is_halted());
+        if let Some(loc) = self.sim.source_map().and_then(|map| map.location(pc)) {
+            println!("source: {}", loc);
+        }
+        if let Some(word) = self.debug_info.as_ref().and_then(|info| info.word(pc)) {
+            if word.line.is_some() {
+                println!("        {}", word.text.trim());
+            }
+            if word.kind == WordKind::Data {
+                println!("warning: the current word is data, not an instruction");
+            }
+        }
         // Print out the current instruction with a backtrace
         let mut ins = self.sim.current_instruction()?;
         println!("current instruction: {}", ins);
@@ -121,11 +145,21 @@ impl<'a, 'b> Debugger<'a, 'b> {
         // Execute the steps
         for i in 0..n {
             if self.sim.step()? {
-                println!("halted after {} step(s)", i + 1);
+                println!("halted after {} step(s){}", i + 1, self.location());
                 return Ok(false);
             }
         }
-        println!("executed {} step(s)", n);
+        println!("executed {} step(s){}", n, self.location());
         Ok(false)
     }
+
+    /// Returns a description of the source location of the last instruction executed
+    /// (e.g. ` (in foo.ibcmc line 12)`), or an empty string if it isn't known.
+    fn location(&self) -> String {
+        let (_, _, pc) = self.sim.regs();
+        match self.sim.source_map().and_then(|map| map.location(pc.wrapping_sub(1))) {
+            Some(loc) => format!(" (in {})", loc),
+            None => String::new(),
+        }
+    }
 }
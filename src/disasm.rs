@@ -0,0 +1,350 @@
+//! The disassembler.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use instruction::Instruction;
+use source_map::WordKind;
+
+/// A disassembled program, which can be displayed as IBCM assembly.
+///
+/// Words of the program are found to be instructions by following the control flow of the
+/// program from address 0: every word which can be executed is taken to be an instruction, and
+/// all the others are data. Each word which is the target of a jump or branch, or which is the
+/// address argument of another instruction, is given a label (`code_NNN` for instructions and
+/// `data_NNN` for data, where `NNN` is its address), which is used in place of the address.
+///
+/// Data words, as well as instructions whose unused bits aren't all zero (which would be lost if
+/// they were written using an opcode), are written using `dw`, so the assembly always assembles
+/// back to exactly the same words.
+///
+/// Since the IBCM only supports direct addressing, programs access computed addresses (e.g. the
+/// elements of an array) by building an instruction at runtime, usually by adding an address to
+/// an *instruction template* such as `3000` (`load 000`), storing the result in place of an
+/// instruction and executing it. A `store` into an instruction which is directly preceded by a
+/// `load` and any number of `add`s and `sub`s is taken to be such a construction: the data words
+/// used in it which contain an instruction taking an address (possibly minus a small offset,
+/// e.g. `2ffd` for `load` with an offset of -3) are labelled as templates (`tmpl_NNN`), and
+/// comments describe the templates and the instructions built from them.
+///
+/// # Examples
+///
+/// ```
+/// use ibcm::{Assembler, Disassembly, WordKind};
+///
+/// let program = &[0xc002, 0x0001, 0x1000, 0x5001, 0x1800, 0x0000];
+/// let disassembly = Disassembly::new(program);
+///
+/// assert_eq!(WordKind::Data, disassembly.kinds()[1]);
+/// assert_eq!("          jmp     code_002
+/// data_001: dw      0001
+/// code_002: readH
+///           add     data_001
+///           printH
+///           halt
+/// ",
+///            disassembly.to_string());
+///
+/// let reassembled = Assembler::assemble(disassembly.to_string().as_bytes()).unwrap();
+/// assert_eq!(program, reassembled.data());
+/// ```
+///
+/// A program reading the element of an array given by its input:
+///
+/// ```
+/// use ibcm::Disassembly;
+///
+/// let program = &[0x1000, 0x4008, 0x3009, 0x5008, 0x4005, 0xb000, 0x1800, 0x0000, 0x0000,
+///                 0x300a, 0x000a, 0x000b];
+///
+/// assert_eq!("          readH
+///           store   data_008
+///           load    tmpl_009
+///           add     data_008
+///           store   code_005
+/// code_005: nop               // load built at runtime from tmpl_009 + data_008
+///           printH
+///           halt
+/// data_008: dw      0000
+/// tmpl_009: dw      300a      // template for load 0x00a
+///           dw      000a
+///           dw      000b
+/// ",
+///            Disassembly::new(program).to_string());
+/// ```
+#[derive(Debug)]
+pub struct Disassembly {
+    data: Vec<u16>,
+    /// Whether each word is an instruction or data.
+    kinds: Vec<WordKind>,
+    /// The labels given to the words of the program, by address.
+    labels: HashMap<u16, String>,
+    /// The comments describing instruction templates and the instructions built from them, by
+    /// address.
+    comments: HashMap<u16, String>,
+}
+
+impl Disassembly {
+    /// Disassembles the given program.
+    pub fn new(data: &[u16]) -> Self {
+        let kinds = trace(data);
+        let builds = builds(data, &kinds);
+        // Only words which are added can be templates
+        let templates = builds.iter()
+            .flat_map(|build| &build.terms)
+            .filter(|&&(sign, addr)| sign == 1 && kinds[addr as usize] == WordKind::Data)
+            .filter_map(|&(_, addr)| template(data, addr).map(|template| (addr, template)))
+            .collect::<HashMap<_, _>>();
+
+        let mut labels = HashMap::new();
+        for (&word, &kind) in data.iter().zip(&kinds) {
+            if kind == WordKind::Data {
+                continue;
+            }
+            let target = match Instruction::from_u16(word).address() {
+                Some(addr) if (addr as usize) < data.len() => addr,
+                _ => continue,
+            };
+            labels.entry(target).or_insert_with(|| {
+                let prefix = match kinds[target as usize] {
+                    WordKind::Code => "code",
+                    WordKind::Data if templates.contains_key(&target) => "tmpl",
+                    WordKind::Data => "data",
+                };
+                format!("{}_{:03x}", prefix, target)
+            });
+        }
+
+        let mut comments = HashMap::new();
+        for (&addr, &(instr, offset)) in &templates {
+            let instr = Instruction::from_u16(instr);
+            let target = match offset {
+                0 => String::new(),
+                n if n < 0 => format!(" - {}", -n),
+                n => {
+                    labels.get(&(n as u16))
+                        .map_or_else(|| format!(" 0x{:03x}", n), |label| format!(" {}", label))
+                }
+            };
+            comments.insert(addr, format!("template for {}{}", instr.name(), target));
+        }
+        for build in &builds {
+            if !build.terms.iter().any(|&(sign, addr)| sign == 1 && templates.contains_key(&addr)) {
+                continue;
+            }
+            let mut expr = String::new();
+            for (i, &(sign, addr)) in build.terms.iter().enumerate() {
+                match (i, sign) {
+                    (0, _) => {}
+                    (_, 1) => expr.push_str(" + "),
+                    _ => expr.push_str(" - "),
+                }
+                expr.push_str(&labels[&addr]);
+            }
+            // The instruction is assumed to be the one built from the initial values of the terms
+            let instr = Instruction::from_u16(build.value);
+            let name = if instr.address().is_some() { instr.name() } else { "instruction" };
+            let comment = format!("{} built at runtime from {}", name, expr);
+            comments.entry(build.target)
+                .and_modify(|comments: &mut String| {
+                    if !comments.contains(&comment) {
+                        comments.push_str("; ");
+                        comments.push_str(&comment);
+                    }
+                })
+                .or_insert(comment);
+        }
+
+        Disassembly {
+            data: data.to_vec(),
+            kinds,
+            labels,
+            comments,
+        }
+    }
+
+    /// Returns the program instructions.
+    pub fn data(&self) -> &[u16] {
+        &self.data
+    }
+
+    /// Returns whether each word of the program is an instruction or data.
+    pub fn kinds(&self) -> &[WordKind] {
+        &self.kinds
+    }
+
+    /// Returns the labels given to the words of the program, by address.
+    pub fn labels(&self) -> &HashMap<u16, String> {
+        &self.labels
+    }
+
+    /// Returns the assembly for the word at the given address (without its label).
+    fn statement(&self, addr: usize) -> String {
+        let word = self.data[addr];
+        let instr = Instruction::from_u16(word);
+        if self.kinds[addr] == WordKind::Data || instr.to_u16() != word {
+            return format!("{:<8}{:04x}", "dw", word);
+        }
+        match instr {
+            Instruction::Shift(_, n) => format!("{:<8}{}", instr.name(), n),
+            _ => {
+                match instr.address() {
+                    Some(target) => {
+                        let operand = self.labels
+                            .get(&target)
+                            .cloned()
+                            .unwrap_or_else(|| format!("0x{:03x}", target));
+                        format!("{:<8}{}", instr.name(), operand)
+                    }
+                    None => instr.name().to_string(),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Labels go in a column of their own, which is at least as wide as an indent
+        let width = self.labels.values().map(|label| label.len() + 2).max().unwrap_or(0).max(8);
+        for addr in 0..self.data.len() {
+            let label = self.labels
+                .get(&(addr as u16))
+                .map_or(String::new(), |label| format!("{}:", label));
+            let statement = match self.comments.get(&(addr as u16)) {
+                Some(comment) => format!("{:<16}  // {}", self.statement(addr), comment),
+                None => self.statement(addr),
+            };
+            writeln!(f, "{:<width$}{}", label, statement, width = width)?;
+        }
+        Ok(())
+    }
+}
+
+/// An instruction built at runtime, from the sum of some words of the program.
+struct Build {
+    /// The address where the instruction is stored.
+    target: u16,
+    /// The addresses of the words making up the instruction, along with their signs (`1` for the
+    /// first word and those added, and `-1` for those subtracted).
+    terms: Vec<(i32, u16)>,
+    /// The instruction built from the initial values of the words.
+    value: u16,
+}
+
+/// Finds the instructions built at runtime by the given program (see the documentation of
+/// `Disassembly`).
+fn builds(data: &[u16], kinds: &[WordKind]) -> Vec<Build> {
+    let is_code = |addr: usize| kinds.get(addr) == Some(&WordKind::Code);
+    let mut builds = Vec::new();
+    'stores: for (addr, &word) in data.iter().enumerate() {
+        let target = match Instruction::from_u16(word) {
+            Instruction::Store(target) if is_code(addr) && is_code(target as usize) => target,
+            _ => continue,
+        };
+
+        // Look back for the `load` starting the construction
+        let mut terms = Vec::new();
+        for prev in (0..addr).rev() {
+            if !is_code(prev) {
+                continue 'stores;
+            }
+            match Instruction::from_u16(data[prev]) {
+                Instruction::Load(n) => {
+                    terms.push((1, n));
+                    break;
+                }
+                Instruction::Add(n) => terms.push((1, n)),
+                Instruction::Sub(n) => terms.push((-1, n)),
+                _ => continue 'stores,
+            }
+        }
+        terms.reverse();
+        // Every term needs a label to be shown
+        if terms.first().is_none_or(|&(sign, _)| sign != 1) ||
+           terms.iter().any(|&(_, n)| n as usize >= data.len()) {
+            continue;
+        }
+
+        let value = terms.iter()
+            .fold(0u16, |value, &(sign, n)| if sign == 1 {
+                value.wrapping_add(data[n as usize])
+            } else {
+                value.wrapping_sub(data[n as usize])
+            });
+        builds.push(Build {
+                        target,
+                        terms,
+                        value,
+                    });
+    }
+    builds
+}
+
+/// Returns the instruction which the given word is a template for (without its address) and the
+/// offset added to it, if the word is a template.
+///
+/// A word whose address field is near the end of memory and doesn't refer to the program is taken
+/// to be the next instruction with a negative offset (e.g. `2ffd` is `load` with an offset of -3).
+fn template(data: &[u16], addr: u16) -> Option<(u16, i32)> {
+    let word = data[addr as usize];
+    let (opcode, offset) = match (word >> 12, (word & 0xfff) as i32) {
+        (op, n) if n >= 0x800 && n as usize >= data.len() && op < 0xf => (op + 1, n - 0x1000),
+        (op, n) => (op, n),
+    };
+    let instr = opcode << 12;
+    Instruction::from_u16(instr).address().map(|_| (instr, offset))
+}
+
+/// Determines which words of the program are instructions, by following every path of execution
+/// from address 0.
+///
+/// Instructions which are overwritten by the program (e.g. placeholders for instructions built at
+/// runtime) can't be relied on to continue to the next word, so execution isn't followed from them
+/// into a word which the program uses as data.
+fn trace(data: &[u16]) -> Vec<WordKind> {
+    let kinds = follow(data, |_| false);
+    let mut stored = HashSet::new();
+    let mut used = HashSet::new();
+    for (&word, &kind) in data.iter().zip(&kinds) {
+        let instr = Instruction::from_u16(word);
+        match instr.address() {
+            Some(addr) if kind == WordKind::Code && !instr.is_jmp() => {
+                used.insert(addr);
+                if let Instruction::Store(_) = instr {
+                    stored.insert(addr);
+                }
+            }
+            _ => {}
+        }
+    }
+    follow(data, |addr| stored.contains(&addr) && used.contains(&addr.wrapping_add(1)))
+}
+
+/// Marks the words which can be executed starting from address 0, without following execution
+/// from the words for which `stop` returns `true` to the next word.
+fn follow<F: Fn(u16) -> bool>(data: &[u16], stop: F) -> Vec<WordKind> {
+    let mut kinds = vec![WordKind::Data; data.len()];
+    let mut todo = vec![0u16];
+    while let Some(addr) = todo.pop() {
+        // Execution may run past the end of the program, but there is nothing to mark there
+        if addr as usize >= data.len() || kinds[addr as usize] == WordKind::Code {
+            continue;
+        }
+        kinds[addr as usize] = WordKind::Code;
+
+        let next = addr.wrapping_add(1);
+        match Instruction::from_u16(data[addr as usize]) {
+            Instruction::Halt => {}
+            Instruction::Jmp(target) => todo.push(target),
+            // A branch returns to the next instruction by jumping to the address it leaves in
+            // the accumulator
+            Instruction::Jmpe(target) |
+            Instruction::Jmpl(target) |
+            Instruction::Brl(target) => todo.extend(&[next, target]),
+            _ if stop(addr) => {}
+            _ => todo.push(next),
+        }
+    }
+    kinds
+}
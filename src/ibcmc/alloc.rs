@@ -0,0 +1,288 @@
+//! Storage allocation for IBCMC programs.
+//!
+//! Every variable is given a fixed memory cell (or, for an array, a run of consecutive cells)
+//! when the program is compiled. Global variables are allocated in the global data area, and
+//! the parameters and local variables of each function in the function's own statically
+//! allocated frame. Variables are only live while they are in scope, so the cells of a block
+//! are released at the end of the block and reused by any later sibling blocks; a cell shared
+//! in this way simply carries the labels of all the variables stored in it.
+//!
+//! A static frame is only enough for a function as long as it is never active more than once
+//! at the same time. When a function makes a call which may lead back to itself (as determined
+//! by the `CallGraph`), its frame is saved on the stack for the duration of the call, giving
+//! each activation of a recursive function its own copy of the frame. Calls which can never
+//! lead back to the caller don't need to save anything.
+//!
+//! Once the program has been generated, its total memory usage is checked against the 4096
+//! words of memory available on the IBCM (see `MemoryUsage`).
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use ibcmc::ast::{visit_exprs, visit_subexprs};
+use ibcmc::ast::{Block, Expr, Stmt};
+use ibcmc::codegen::{Line, Op};
+use ibcmc::errors::*;
+
+/// The number of words of memory on the IBCM.
+pub const MEMORY_SIZE: usize = 4096;
+
+/// An area of memory in which variables are allocated (the global data area, or the frame of a
+/// function).
+///
+/// The cells of the variables declared in a scope are released when the scope is exited, so that
+/// they can be reused by the variables of the next scope.
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct Area {
+    /// The labels of the variables stored in each cell.
+    cells: Vec<Vec<String>>,
+    /// The number of cells in use by the variables currently in scope.
+    used: usize,
+    /// The number of cells which were in use when each enclosing scope was entered.
+    scopes: Vec<usize>,
+}
+
+impl Area {
+    /// Creates a new, empty area.
+    pub fn new() -> Self {
+        Area::default()
+    }
+
+    /// Enters a new scope.
+    pub fn enter(&mut self) {
+        self.scopes.push(self.used);
+    }
+
+    /// Exits the current scope, releasing the cells of the variables declared in it.
+    pub fn exit(&mut self) {
+        self.used = self.scopes.pop().expect("no scope to exit");
+    }
+
+    /// Allocates consecutive cells for a variable in the current scope, labelling them with the
+    /// given labels (one for each cell).
+    pub fn alloc<I: IntoIterator<Item = String>>(&mut self, labels: I) {
+        for label in labels {
+            if self.used == self.cells.len() {
+                self.cells.push(Vec::new());
+            }
+            self.cells[self.used].push(label);
+            self.used += 1;
+        }
+    }
+
+    /// Adds a cell with the given label after all the others, which is never reused by any
+    /// variable (e.g. for a temporary).
+    ///
+    /// This must only be done once all the variables in the area have been allocated.
+    pub fn push(&mut self, label: String) {
+        self.cells.push(vec![label]);
+        self.used = self.cells.len();
+    }
+
+    /// Returns the labels of each cell in the area.
+    pub fn cells(&self) -> &[Vec<String>] {
+        &self.cells
+    }
+
+    /// Returns the number of cells in the area.
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Returns whether the area has no cells.
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+}
+
+/// The functions which may be called, directly or indirectly, by each function of a program.
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct CallGraph {
+    /// The functions reachable through the calls made by each function.
+    reachable: HashMap<String, HashSet<String>>,
+}
+
+impl CallGraph {
+    /// Builds the call graph of the given program.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ibcm::ibcmc;
+    /// use ibcm::ibcmc::alloc::CallGraph;
+    ///
+    /// let program = "int f(int n) { return g(n); }
+    /// int g(int n) { if (n) return f(n - 1); return h(); }
+    /// int h() { return 0; }";
+    ///
+    /// let (ast, _) = ibcmc::parse(program.as_bytes());
+    /// let calls = CallGraph::new(&ast);
+    /// assert!(calls.is_recursive("f") && calls.is_recursive("g"));
+    /// assert!(!calls.is_recursive("h"));
+    /// // `f` has to be saved while calling `g`, but `g` doesn't have to be saved while calling `h`
+    /// assert!(calls.may_call("g", "f"));
+    /// assert!(!calls.may_call("h", "g"));
+    /// ```
+    pub fn new(program: &Block) -> Self {
+        let mut calls = HashMap::new();
+        for stmt in &program.0 {
+            if let Stmt::Function(ref decl, _, ref body) = *stmt.stmt() {
+                let mut callees = HashSet::new();
+                for stmt in &body.0 {
+                    visit_exprs(stmt.stmt(), &mut |expr| {
+                        visit_subexprs(expr, &mut |expr| if let Expr::Call(ref ident, _) = *expr {
+                            callees.insert(ident.0.clone());
+                        })
+                    });
+                }
+                calls.insert(decl.name.0.clone(), callees);
+            }
+        }
+
+        let reachable = calls.keys()
+            .map(|name| {
+                let mut reachable = HashSet::new();
+                let mut stack = calls[name].iter().collect::<Vec<_>>();
+                while let Some(callee) = stack.pop() {
+                    if reachable.insert(callee.clone()) {
+                        stack.extend(calls.get(callee).into_iter().flatten());
+                    }
+                }
+                (name.clone(), reachable)
+            })
+            .collect();
+
+        CallGraph { reachable }
+    }
+
+    /// Returns whether a call to the function `from` may lead to a call to the function `to`
+    /// (which is always the case if they are the same function).
+    pub fn may_call(&self, from: &str, to: &str) -> bool {
+        from == to || self.reachable.get(from).is_some_and(|reachable| reachable.contains(to))
+    }
+
+    /// Returns whether the given function may call itself (directly or indirectly).
+    pub fn is_recursive(&self, name: &str) -> bool {
+        self.reachable.get(name).is_some_and(|reachable| reachable.contains(name))
+    }
+}
+
+/// The amount of memory used by a compiled program.
+///
+/// The rest of memory is left for the stack, whose size depends on how deeply recursive
+/// functions are nested at runtime, so it can't be checked when the program is compiled (the
+/// generated code checks it instead, halting on overflow).
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct MemoryUsage {
+    /// The number of words of code (including the runtime library).
+    pub code: usize,
+    /// The number of words of data (variables, temporaries, constants and address cells).
+    pub data: usize,
+}
+
+impl MemoryUsage {
+    /// Computes the memory used by the given generated code.
+    pub fn of(code: &[Line]) -> Self {
+        let data = code.iter()
+            .filter(|line| match line.op {
+                        Op::Data(_) => true,
+                        // Address cells hold instructions, but are never executed
+                        Op::Instr(..) => line.labels.iter().any(|label| label.starts_with('&')),
+                    })
+            .count();
+
+        MemoryUsage {
+            code: code.len() - data,
+            data,
+        }
+    }
+
+    /// Returns the total number of words used.
+    pub fn total(&self) -> usize {
+        self.code + self.data
+    }
+
+    /// Returns the number of words left over (for the stack).
+    pub fn free(&self) -> usize {
+        MEMORY_SIZE.saturating_sub(self.total())
+    }
+
+    /// Checks that the program fits in memory, returning an error if it doesn't.
+    ///
+    /// The error is reported on the line of the largest variable declared in the given program,
+    /// since large arrays are the most likely culprit.
+    pub fn check(&self, program: &Block) -> Result<()> {
+        if self.total() <= MEMORY_SIZE {
+            return Ok(());
+        }
+
+        let mut largest = None;
+        for stmt in &program.0 {
+            largest_decl(stmt.stmt(), stmt.line(), &mut largest);
+        }
+        let (line, detail) = match largest {
+            Some((size, line, name)) => {
+                (line, format!(" (the largest variable is `{}`, with {} word{})", name, size, plural(size)))
+            }
+            None => (program.0.last().map_or(1, |stmt| stmt.line()), String::new()),
+        };
+        Err(ErrorKind::Codegen(format!("the program needs {} words of memory, but the IBCM only has {}{}",
+                                       self.total(),
+                                       MEMORY_SIZE,
+                                       detail),
+                               line)
+                    .into())
+    }
+}
+
+impl Display for MemoryUsage {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f,
+               "{} word{} of code and {} word{} of data ({} of {} words, leaving {} for the stack)",
+               self.code,
+               plural(self.code),
+               self.data,
+               plural(self.data),
+               self.total(),
+               MEMORY_SIZE,
+               self.free())
+    }
+}
+
+/// Records the size, line and name of the largest variable declared by the given statement on
+/// the given line (or any statement nested inside it) in `largest`, if it is larger than the one
+/// already there.
+fn largest_decl(stmt: &Stmt, line: usize, largest: &mut Option<(usize, usize, String)>) {
+    let (decl, size) = match *stmt {
+        Stmt::Decl(ref decl) | Stmt::Init(ref decl, _) => (decl, 1),
+        Stmt::Array(ref decl, size) => (decl, size as usize),
+        Stmt::Function(_, _, ref block) | Stmt::Block(ref block) => {
+            for stmt in &block.0 {
+                largest_decl(stmt.stmt(), stmt.line(), largest);
+            }
+            return;
+        }
+        Stmt::If(_, ref body, ref else_body) => {
+            largest_decl(body.stmt(), body.line(), largest);
+            if let Some(ref else_body) = *else_body {
+                largest_decl(else_body.stmt(), else_body.line(), largest);
+            }
+            return;
+        }
+        Stmt::While(_, ref body) => return largest_decl(body.stmt(), body.line(), largest),
+        Stmt::For(ref init, _, _, ref body) => {
+            largest_decl(init, line, largest);
+            return largest_decl(body.stmt(), body.line(), largest);
+        }
+        _ => return,
+    };
+
+    if largest.as_ref().is_none_or(|&(max, _, _)| size > max) {
+        *largest = Some((size, line, decl.name.0.clone()));
+    }
+}
+
+/// Returns the suffix for the plural of a noun describing `n` things.
+fn plural(n: usize) -> &'static str {
+    if n == 1 { "" } else { "s" }
+}
@@ -1,5 +1,7 @@
 //! The abstract syntax tree produced by the parser.
 
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
 use ibcmc::lexer::{Ident, Literal};
 
 /// Represents a single block (e.g. the definition of a function, or a block delimited by `{}`).
@@ -33,17 +35,42 @@ pub enum Stmt {
     /// A block (e.g. delimited by `{}`).
     Block(Block),
     /// An assignment (e.g. `i = 3`).
-    Assign(Ident, Expr),
+    ///
+    /// The target is always an lvalue (see `Expr::is_lvalue`).
+    Assign(Expr, Expr),
     /// A compound assignment (e.g. `i += 3`).
-    CompoundAssign(Ident, BinOp, Expr),
+    CompoundAssign(Expr, BinOp, Expr),
     /// A declaration (e.g. `int i`).
     ///
     /// The first member specifies whether a constant is being declared.
     Decl(Decl),
     /// An initialization (e.g. `int i = 2`).
     Init(Decl, Expr),
+    /// An array declaration (e.g. `int a[10]`).
+    ///
+    /// The members are: declaration (element type and name) and the number of elements.
+    Array(Decl, u16),
     /// An expression.
     Expr(Expr),
+    /// A return statement, with an optional return value.
+    Return(Option<Expr>),
+    /// An `if` statement.
+    ///
+    /// The members are: condition, body, and the optional `else` body.
+    If(Expr, Box<StmtLine>, Option<Box<StmtLine>>),
+    /// A `while` loop.
+    ///
+    /// The members are: condition and body.
+    While(Expr, Box<StmtLine>),
+    /// A `for` loop.
+    ///
+    /// The members are: initialization statement, optional condition, step statement, and body.
+    /// The initialization and step are `Stmt::Empty` if they are omitted.
+    For(Box<Stmt>, Option<Expr>, Box<Stmt>, Box<StmtLine>),
+    /// A `break` statement.
+    Break,
+    /// A `continue` statement.
+    Continue,
     /// The empty statement.
     Empty,
 }
@@ -60,10 +87,33 @@ impl Stmt {
 pub enum Expr {
     /// A binary operation (e.g. `i + 3`).
     BinOp(BinOp, Box<Expr>, Box<Expr>),
+    /// A unary operation (e.g. `-i`).
+    UnOp(UnOp, Box<Expr>),
+    /// A function call (e.g. `f(i, 3)`).
+    Call(Ident, Vec<Expr>),
+    /// An indexing operation (e.g. `a[i]`), which is equivalent to `*(a + i)`.
+    Index(Box<Expr>, Box<Expr>),
+    /// A dereference (e.g. `*p`), which gives the value stored at the given address.
+    Deref(Box<Expr>),
+    /// An address-of operation (e.g. `&i`), whose operand is always an lvalue.
+    AddrOf(Box<Expr>),
     /// An identifier.
     Ident(Ident),
     /// A literal.
     Literal(Literal),
+    /// A conversion of a value to a smaller type (`char` or `bool`).
+    ///
+    /// The parser never produces conversions: they are inserted by the checker wherever a value
+    /// is implicitly converted.
+    Cast(Type, Box<Expr>),
+}
+
+impl Expr {
+    /// Returns whether the expression is an lvalue (i.e. it refers to a memory cell, so it can be
+    /// assigned to or have its address taken).
+    pub fn is_lvalue(&self) -> bool {
+        matches!(*self, Expr::Ident(_) | Expr::Index(..) | Expr::Deref(_))
+    }
 }
 
 /// Represents a variable declaration or function parameter.
@@ -78,10 +128,54 @@ pub struct Decl {
 }
 
 /// An enumeration of all possible types.
+///
+/// Every value occupies a single word. Values of type `char` and `bool` are promoted to `int`
+/// when they are used in arithmetic, and converted back (by keeping the low 8 bits of a `char`,
+/// or by comparing with 0 for a `bool`) when they are stored. If either operand of an arithmetic
+/// operation or comparison is `unsigned`, the operation is done on unsigned values.
 #[derive(Clone,Debug,PartialEq,Eq)]
 pub enum Type {
-    /// An integer (`u16`).
+    /// A signed integer (`i16`).
     Int,
+    /// An unsigned integer (`u16`).
+    Unsigned,
+    /// A character, between 0 and 255.
+    Char,
+    /// A truth value, which is either 0 or 1.
+    Bool,
+    /// No value (only valid as the return type of a function).
+    Void,
+}
+
+impl Type {
+    /// Returns the type to which values of this type are promoted when used in arithmetic.
+    pub fn promoted(&self) -> Type {
+        match *self {
+            Type::Char | Type::Bool => Type::Int,
+            ref ty => ty.clone(),
+        }
+    }
+
+    /// Converts a constant to this type, giving the same result as an `Expr::Cast`.
+    pub fn cast(&self, n: u16) -> u16 {
+        match *self {
+            Type::Char => n & 0xff,
+            Type::Bool => (n != 0) as u16,
+            _ => n,
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            Type::Int => write!(f, "int"),
+            Type::Unsigned => write!(f, "unsigned"),
+            Type::Char => write!(f, "char"),
+            Type::Bool => write!(f, "bool"),
+            Type::Void => write!(f, "void"),
+        }
+    }
 }
 
 /// All the binary operations which can be performed on a variable.
@@ -91,5 +185,156 @@ pub enum BinOp {
     Add,
     /// Subtraction.
     Sub,
+    /// Multiplication.
+    Mul,
+    /// Division (rounding towards zero).
+    Div,
+    /// Remainder (with the sign of the dividend).
+    Mod,
+    /// Equality (`==`).
+    Eq,
+    /// Inequality (`!=`).
+    Ne,
+    /// Less than (`<`).
+    Lt,
+    /// Less than or equal to (`<=`).
+    Le,
+    /// Greater than (`>`).
+    Gt,
+    /// Greater than or equal to (`>=`).
+    Ge,
+    /// Bitwise and (`&`).
+    And,
+    /// Bitwise or (`|`).
+    Or,
+    /// Bitwise exclusive or (`^`).
+    Xor,
+    /// Left shift (`<<`).
+    Shl,
+    /// Logical (unsigned) right shift (`>>`).
+    Shr,
+    /// Unsigned division.
+    UDiv,
+    /// Unsigned remainder.
+    UMod,
+    /// Unsigned less than.
+    ULt,
+    /// Unsigned less than or equal to.
+    ULe,
+    /// Unsigned greater than.
+    UGt,
+    /// Unsigned greater than or equal to.
+    UGe,
 }
 
+impl BinOp {
+    /// Returns whether the operation is a comparison.
+    pub fn is_comparison(&self) -> bool {
+        matches!(*self,
+                 BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge | BinOp::ULt |
+                 BinOp::ULe | BinOp::UGt | BinOp::UGe)
+    }
+
+    /// Returns the precedence of the operation (higher numbers bind more tightly).
+    ///
+    /// The precedence levels are the same as in C, and all the operations are left-associative.
+    pub fn precedence(&self) -> u8 {
+        match *self {
+            BinOp::Or => 0,
+            BinOp::Xor => 1,
+            BinOp::And => 2,
+            BinOp::Eq | BinOp::Ne => 3,
+            BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge | BinOp::ULt | BinOp::ULe | BinOp::UGt |
+            BinOp::UGe => 4,
+            BinOp::Shl | BinOp::Shr => 5,
+            BinOp::Add | BinOp::Sub => 6,
+            BinOp::Mul | BinOp::Div | BinOp::Mod | BinOp::UDiv | BinOp::UMod => 7,
+        }
+    }
+
+    /// Returns the unsigned version of the operation, if it is different from the signed one
+    /// (the unsigned operations are never produced by the parser, but only by the checker).
+    pub fn unsigned(&self) -> Option<BinOp> {
+        Some(match *self {
+            BinOp::Div => BinOp::UDiv,
+            BinOp::Mod => BinOp::UMod,
+            BinOp::Lt => BinOp::ULt,
+            BinOp::Le => BinOp::ULe,
+            BinOp::Gt => BinOp::UGt,
+            BinOp::Ge => BinOp::UGe,
+            _ => return None,
+        })
+    }
+}
+
+/// All the unary operations which can be performed on a variable.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum UnOp {
+    /// Negation (`-`).
+    Neg,
+    /// Bitwise not (`~`).
+    Not,
+    /// Logical not (`!`), which gives 1 if its operand is 0 and 0 otherwise.
+    LogicalNot,
+}
+
+/// Calls `f` on the given statement and all the statements nested inside it.
+pub fn visit_stmts<F: FnMut(&Stmt)>(stmt: &Stmt, f: &mut F) {
+    f(stmt);
+    match *stmt {
+        Stmt::Function(_, _, ref block) |
+        Stmt::Block(ref block) => {
+            for stmt in &block.0 {
+                visit_stmts(stmt.stmt(), f);
+            }
+        }
+        Stmt::If(_, ref body, ref else_body) => {
+            visit_stmts(body.stmt(), f);
+            if let Some(ref else_body) = *else_body {
+                visit_stmts(else_body.stmt(), f);
+            }
+        }
+        Stmt::While(_, ref body) => visit_stmts(body.stmt(), f),
+        Stmt::For(ref init, _, ref step, ref body) => {
+            visit_stmts(init, f);
+            visit_stmts(step, f);
+            visit_stmts(body.stmt(), f);
+        }
+        _ => {}
+    }
+}
+
+/// Calls `f` on each top-level expression in the given statement and the statements nested
+/// inside it (including assignment targets).
+pub fn visit_exprs<F: FnMut(&Expr)>(stmt: &Stmt, f: &mut F) {
+    visit_stmts(stmt, &mut |stmt| match *stmt {
+        Stmt::Assign(ref target, ref expr) |
+        Stmt::CompoundAssign(ref target, _, ref expr) => {
+            f(target);
+            f(expr);
+        }
+        Stmt::Init(_, ref expr) | Stmt::Expr(ref expr) | Stmt::Return(Some(ref expr)) |
+        Stmt::If(ref expr, _, _) | Stmt::While(ref expr, _) | Stmt::For(_, Some(ref expr), _, _) => f(expr),
+        _ => {}
+    });
+}
+
+/// Calls `f` on the given expression and all its subexpressions.
+pub fn visit_subexprs<F: FnMut(&Expr)>(expr: &Expr, f: &mut F) {
+    f(expr);
+    match *expr {
+        Expr::BinOp(_, ref lhs, ref rhs) |
+        Expr::Index(ref lhs, ref rhs) => {
+            visit_subexprs(lhs, f);
+            visit_subexprs(rhs, f);
+        }
+        Expr::UnOp(_, ref expr) | Expr::Deref(ref expr) | Expr::AddrOf(ref expr) |
+        Expr::Cast(_, ref expr) => visit_subexprs(expr, f),
+        Expr::Call(_, ref args) => {
+            for arg in args {
+                visit_subexprs(arg, f);
+            }
+        }
+        Expr::Ident(_) | Expr::Literal(_) => {}
+    }
+}
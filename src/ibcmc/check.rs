@@ -0,0 +1,543 @@
+//! Semantic analysis for IBCMC.
+//!
+//! The checker runs between the parser and the code generator. It resolves every name
+//! used in the program (taking into account nested scopes and shadowing), checks that
+//! constants are never assigned to, and checks the types of expressions and function calls,
+//! so that the code generator can assume that the program it is given is valid.
+//!
+//! # Types and conversions
+//!
+//! Along the way, the checker works out the type of every expression and makes the conversions
+//! between types explicit, returning a lowered copy of the program:
+//!
+//! * Values of type `char` and `bool` are promoted to `int` in arithmetic. If either operand of
+//!   an arithmetic operation is `unsigned`, the result is `unsigned`, and division, remainder
+//!   and the ordering comparisons are replaced by their unsigned versions (e.g. `BinOp::ULt`).
+//!   Shifts have the (promoted) type of their left operand, and comparisons and `!` give a
+//!   `bool`.
+//! * Wherever a value is stored in a `char` or `bool` (by an assignment, an initialization, a
+//!   function argument or a return value), it is converted using an `Expr::Cast`, which keeps
+//!   the low 8 bits for a `char` and compares with 0 for a `bool`. Conversions of literals are
+//!   done immediately, and conversions which can't change the value (e.g. from a `bool` to a
+//!   `char`) are left out.
+//! * Compound assignments to `char` and `bool` variables are expanded into plain assignments,
+//!   so that the result can be converted before it is stored.
+//!
+//! Every type occupies a single word, and `int` and `unsigned` only differ in how they are
+//! compared and divided, so no conversions are needed between them.
+//!
+//! # Scoping rules
+//!
+//! Every block, as well as the body of each `if`, `else`, `while` and `for`, gets its own
+//! scope, in which variables from enclosing scopes may be shadowed. Variables declared in
+//! the initialization of a `for` loop are only visible inside the loop. The parameters of a
+//! function share a scope with the top level of its body, so they cannot be redeclared there.
+//!
+//! Functions must be defined at the top level, but may be called before they are defined.
+//! Their bodies are checked after all the top-level statements, so they can refer to any
+//! global variable.
+
+use std::collections::HashMap;
+
+use ibcmc::ast::visit_subexprs;
+use ibcmc::ast::{BinOp, Block, Decl, Expr, Stmt, StmtLine, Type, UnOp};
+use ibcmc::errors::*;
+use ibcmc::lexer::{Ident, IntFormat, Literal};
+
+macro_rules! esemantic {
+    ($self:ident, $($arg:tt)*) => {
+        ErrorKind::Semantic(format!($($arg)*), $self.line).into()
+    }
+}
+
+/// The built-in I/O functions, along with their signatures (`prints`, which takes a string
+/// literal, is handled specially).
+const BUILTINS: &[(&str, Type, &[Type])] = &[("readh", Type::Int, &[]),
+                                             ("readc", Type::Int, &[]),
+                                             ("printh", Type::Void, &[Type::Int]),
+                                             ("printc", Type::Void, &[Type::Int]),
+                                             ("prints", Type::Void, &[])];
+
+/// The signature of a function.
+struct Signature {
+    /// The return type.
+    ret: Type,
+    /// The types of the parameters.
+    params: Vec<Type>,
+}
+
+/// Information about a variable.
+struct Var {
+    /// Whether the variable is a constant.
+    is_const: bool,
+    /// Whether the variable is an array.
+    is_array: bool,
+    /// The type of the variable (or of the elements of an array).
+    ty: Type,
+}
+
+/// Represents the state of the checker.
+pub struct Checker {
+    /// The variables visible in each nested scope.
+    scopes: Vec<HashMap<String, Var>>,
+    /// The signatures of all the functions in the program.
+    functions: HashMap<String, Signature>,
+    /// The name and return type of the function currently being checked, if any.
+    function: Option<(String, Type)>,
+    /// The number of loops enclosing the current statement.
+    loops: usize,
+    /// The line number of the statement currently being checked.
+    line: usize,
+}
+
+impl Checker {
+    /// Checks that the given program is semantically valid, returning the program with all of
+    /// its conversions made explicit (see the module documentation).
+    pub fn check(program: &Block) -> Result<Block> {
+        let mut checker = Checker {
+            scopes: vec![HashMap::new()],
+            functions: HashMap::new(),
+            function: None,
+            loops: 0,
+            line: 0,
+        };
+
+        // Functions may be called before they are defined, so we need all the signatures first
+        for stmt in &program.0 {
+            if let Stmt::Function(ref decl, ref params, _) = *stmt.stmt() {
+                checker.line = stmt.line();
+                checker.signature(decl, params)?;
+            }
+        }
+
+        let mut checked = program.0.clone();
+        for (stmt, checked) in program.0.iter().zip(&mut checked) {
+            checker.line = stmt.line();
+            match *stmt.stmt() {
+                Stmt::Function(..) => {}
+                ref inner => *checked = checker.stmt(inner)?.with_line(stmt.line()),
+            }
+        }
+        for (stmt, checked) in program.0.iter().zip(&mut checked) {
+            if let Stmt::Function(ref decl, ref params, ref body) = *stmt.stmt() {
+                checker.line = stmt.line();
+                let body = checker.function(decl, params, body)?;
+                *checked = Stmt::Function(decl.clone(), params.to_vec(), body).with_line(stmt.line());
+            }
+        }
+
+        Ok(Block(checked))
+    }
+
+    /// Records the signature of a function.
+    fn signature(&mut self, decl: &Decl, params: &[Decl]) -> Result<()> {
+        let name = &decl.name.0;
+        if BUILTINS.iter().any(|&(builtin, _, _)| builtin == name) {
+            return Err(esemantic!(self, "`{}` is a built-in function", name));
+        }
+        if self.functions.contains_key(name) {
+            return Err(esemantic!(self, "function `{}` is already defined", name));
+        }
+        if decl.is_const {
+            return Err(esemantic!(self, "function `{}` cannot be declared const", name));
+        }
+        for param in params {
+            if param.ty == Type::Void {
+                return Err(esemantic!(self, "parameter `{}` declared void", param.name.0));
+            }
+        }
+
+        self.functions.insert(name.clone(),
+                              Signature {
+                                  ret: decl.ty.clone(),
+                                  params: params.iter().map(|param| param.ty.clone()).collect(),
+                              });
+        Ok(())
+    }
+
+    /// Checks the body of a function.
+    fn function(&mut self, decl: &Decl, params: &[Decl], body: &Block) -> Result<Block> {
+        self.scopes.push(HashMap::new());
+        for param in params {
+            if self.scopes.last().unwrap().contains_key(&param.name.0) {
+                return Err(esemantic!(self, "duplicate parameter `{}`", param.name.0));
+            }
+            self.scopes
+                .last_mut()
+                .unwrap()
+                .insert(param.name.0.clone(),
+                        Var {
+                            is_const: param.is_const,
+                            is_array: false,
+                            ty: param.ty.clone(),
+                        });
+        }
+
+        self.function = Some((decl.name.0.clone(), decl.ty.clone()));
+        let body = self.stmts(body)?;
+        self.function = None;
+        self.scopes.pop();
+        Ok(body)
+    }
+
+    /// Checks the statements of a block (without giving them a scope of their own).
+    fn stmts(&mut self, block: &Block) -> Result<Block> {
+        let mut stmts = Vec::with_capacity(block.0.len());
+        for stmt in &block.0 {
+            self.line = stmt.line();
+            stmts.push(self.stmt(stmt.stmt())?.with_line(stmt.line()));
+        }
+        Ok(Block(stmts))
+    }
+
+    /// Checks a single statement.
+    fn stmt(&mut self, stmt: &Stmt) -> Result<Stmt> {
+        match *stmt {
+            Stmt::Function(ref decl, _, _) => {
+                Err(esemantic!(self, "function `{}` must be defined at the top level", decl.name.0))
+            }
+            Stmt::Block(ref block) => {
+                self.scopes.push(HashMap::new());
+                let block = self.stmts(block)?;
+                self.scopes.pop();
+                Ok(Stmt::Block(block))
+            }
+            Stmt::Assign(ref target, ref expr) |
+            Stmt::CompoundAssign(ref target, _, ref expr) => {
+                let (expr, ty) = self.value(expr)?;
+                let (target, target_ty) = match *target {
+                    Expr::Ident(ref ident) => {
+                        let var = self.lookup(ident)?;
+                        if var.is_const {
+                            return Err(esemantic!(self, "cannot assign to constant `{}`", ident.0));
+                        }
+                        if var.is_array {
+                            return Err(esemantic!(self, "cannot assign to array `{}`", ident.0));
+                        }
+                        (target.clone(), var.ty.clone())
+                    }
+                    _ => self.value(target)?,
+                };
+
+                match *stmt {
+                    Stmt::CompoundAssign(_, ref op, _) if target_ty == Type::Char || target_ty == Type::Bool => {
+                        Ok(self.compound_assign(target, target_ty, op, expr, ty))
+                    }
+                    Stmt::CompoundAssign(_, ref op, _) => Ok(Stmt::CompoundAssign(target, op.clone(), expr)),
+                    _ => Ok(Stmt::Assign(target, convert(expr, &ty, &target_ty))),
+                }
+            }
+            Stmt::Decl(ref decl) => {
+                if decl.is_const {
+                    return Err(esemantic!(self, "constant `{}` must be initialized", decl.name.0));
+                }
+                self.declare(decl, false)?;
+                Ok(stmt.clone())
+            }
+            Stmt::Init(ref decl, ref expr) => {
+                // The initializer is checked before the variable comes into scope
+                let (expr, ty) = self.value(expr)?;
+                self.declare(decl, false)?;
+                Ok(Stmt::Init(decl.clone(), convert(expr, &ty, &decl.ty)))
+            }
+            Stmt::Array(ref decl, size) => {
+                if decl.is_const {
+                    return Err(esemantic!(self, "array `{}` cannot be declared const", decl.name.0));
+                }
+                if size == 0 {
+                    return Err(esemantic!(self, "array `{}` must have at least one element", decl.name.0));
+                }
+                self.declare(decl, true)?;
+                Ok(stmt.clone())
+            }
+            Stmt::Expr(ref expr) => self.expr(expr).map(|(expr, _)| Stmt::Expr(expr)),
+            Stmt::Return(ref expr) => {
+                let (name, ret) = match self.function {
+                    Some(ref function) => function.clone(),
+                    None => return Err(esemantic!(self, "`return` outside of a function")),
+                };
+                match (&ret, expr.as_ref()) {
+                    (&Type::Void, Some(_)) => {
+                        Err(esemantic!(self, "`return` with a value in void function `{}`", name))
+                    }
+                    (&Type::Void, None) => Ok(Stmt::Return(None)),
+                    (_, None) => {
+                        Err(esemantic!(self, "`return` without a value in function `{}`, which returns {}", name, ret))
+                    }
+                    (_, Some(expr)) => {
+                        let (expr, ty) = self.value(expr)?;
+                        Ok(Stmt::Return(Some(convert(expr, &ty, &ret))))
+                    }
+                }
+            }
+            Stmt::If(ref cond, ref body, ref else_body) => {
+                let (cond, _) = self.value(cond)?;
+                let body = self.nested_stmt(body)?;
+                let else_body = match *else_body {
+                    Some(ref else_body) => Some(Box::new(self.nested_stmt(else_body)?)),
+                    None => None,
+                };
+                Ok(Stmt::If(cond, Box::new(body), else_body))
+            }
+            Stmt::While(ref cond, ref body) => {
+                let (cond, _) = self.value(cond)?;
+                let body = self.loop_body(body)?;
+                Ok(Stmt::While(cond, Box::new(body)))
+            }
+            Stmt::For(ref init, ref cond, ref step, ref body) => {
+                // Variables declared in the initialization are only visible in the loop
+                self.scopes.push(HashMap::new());
+                let init = self.stmt(init)?;
+                let cond = match *cond {
+                    Some(ref cond) => Some(self.value(cond)?.0),
+                    None => None,
+                };
+                let line = self.line;
+                let body = self.loop_body(body)?;
+                self.line = line;
+                let step = self.stmt(step)?;
+                self.scopes.pop();
+                Ok(Stmt::For(Box::new(init), cond, Box::new(step), Box::new(body)))
+            }
+            Stmt::Break => {
+                if self.loops == 0 {
+                    return Err(esemantic!(self, "`break` outside of a loop"));
+                }
+                Ok(Stmt::Break)
+            }
+            Stmt::Continue => {
+                if self.loops == 0 {
+                    return Err(esemantic!(self, "`continue` outside of a loop"));
+                }
+                Ok(Stmt::Continue)
+            }
+            Stmt::Empty => Ok(Stmt::Empty),
+        }
+    }
+
+    /// Expands a compound assignment to a `char` or `bool` into a plain assignment, so that the
+    /// result can be converted before it is stored.
+    ///
+    /// If evaluating the target twice could have side effects, its address is computed once
+    /// and kept in a local variable instead.
+    fn compound_assign(&self, target: Expr, target_ty: Type, op: &BinOp, expr: Expr, ty: Type) -> Stmt {
+        let update = |target: Expr| {
+            let (value, value_ty) = arithmetic(op, target.clone(), &target_ty, expr, &ty);
+            Stmt::Assign(target, convert(value, &value_ty, &target_ty))
+        };
+        let mut has_calls = false;
+        visit_subexprs(&target, &mut |expr| has_calls |= matches!(*expr, Expr::Call(..)));
+        if !has_calls {
+            return update(target);
+        }
+
+        // Internal names begin with `_`, so this can't shadow anything in the program
+        let addr = Ident("_addr".into());
+        let init = Stmt::Init(Decl {
+                                  is_const: false,
+                                  ty: Type::Int,
+                                  name: addr.clone(),
+                              },
+                              Expr::AddrOf(Box::new(target)));
+        let assign = update(Expr::Deref(Box::new(Expr::Ident(addr))));
+        Stmt::Block(Block(vec![init.with_line(self.line), assign.with_line(self.line)]))
+    }
+
+    /// Checks a statement nested inside another (e.g. the body of a loop), which gets its
+    /// own scope.
+    fn nested_stmt(&mut self, stmt: &StmtLine) -> Result<StmtLine> {
+        self.scopes.push(HashMap::new());
+        self.line = stmt.line();
+        let checked = self.stmt(stmt.stmt())?;
+        self.scopes.pop();
+        Ok(checked.with_line(stmt.line()))
+    }
+
+    /// Checks the body of a loop.
+    fn loop_body(&mut self, body: &StmtLine) -> Result<StmtLine> {
+        self.loops += 1;
+        let body = self.nested_stmt(body)?;
+        self.loops -= 1;
+        Ok(body)
+    }
+
+    /// Checks an expression, returning it (with any conversions made explicit) along with
+    /// its type.
+    fn expr(&mut self, expr: &Expr) -> Result<(Expr, Type)> {
+        match *expr {
+            Expr::BinOp(ref op, ref lhs, ref rhs) => {
+                let (lhs, lhs_ty) = self.value(lhs)?;
+                let (rhs, rhs_ty) = self.value(rhs)?;
+                Ok(arithmetic(op, lhs, &lhs_ty, rhs, &rhs_ty))
+            }
+            Expr::UnOp(ref op, ref operand) => {
+                let (operand, ty) = self.value(operand)?;
+                let ty = match *op {
+                    UnOp::LogicalNot => Type::Bool,
+                    UnOp::Neg | UnOp::Not => ty.promoted(),
+                };
+                Ok((Expr::UnOp(op.clone(), Box::new(operand)), ty))
+            }
+            Expr::Call(ref ident, ref args) => self.call(ident, args),
+            Expr::Index(ref base, ref index) => {
+                let (base_expr, _) = self.value(base)?;
+                let (index, _) = self.value(index)?;
+                // Only arrays know the type of their elements: pointers are just `int`s
+                let ty = match **base {
+                    Expr::Ident(ref ident) => {
+                        let var = self.lookup(ident)?;
+                        if var.is_array { var.ty.clone() } else { Type::Int }
+                    }
+                    _ => Type::Int,
+                };
+                Ok((Expr::Index(Box::new(base_expr), Box::new(index)), ty))
+            }
+            Expr::Deref(ref operand) => {
+                let (operand, _) = self.value(operand)?;
+                Ok((Expr::Deref(Box::new(operand)), Type::Int))
+            }
+            Expr::AddrOf(ref operand) => {
+                let (operand, _) = self.value(operand)?;
+                Ok((Expr::AddrOf(Box::new(operand)), Type::Int))
+            }
+            // Arrays are converted to the address of their first element
+            Expr::Ident(ref ident) => {
+                let var = self.lookup(ident)?;
+                let ty = if var.is_array { Type::Int } else { var.ty.clone() };
+                Ok((expr.clone(), ty))
+            }
+            Expr::Literal(Literal::Int(..)) => Ok((expr.clone(), Type::Int)),
+            Expr::Literal(Literal::Str(_)) => {
+                Err(esemantic!(self, "string literals can only be passed to `prints`"))
+            }
+            Expr::Cast(ref ty, ref operand) => {
+                let (operand, from) = self.value(operand)?;
+                Ok((convert(operand, &from, ty), ty.clone()))
+            }
+        }
+    }
+
+    /// Checks an expression whose value is used, which must therefore not be void.
+    fn value(&mut self, expr: &Expr) -> Result<(Expr, Type)> {
+        let (checked, ty) = self.expr(expr)?;
+        if ty == Type::Void {
+            return Err(match *expr {
+                           Expr::Call(ref ident, _) => {
+                               esemantic!(self, "void function `{}` used as a value", ident.0)
+                           }
+                           _ => esemantic!(self, "void expression used as a value"),
+                       });
+        }
+        Ok((checked, ty))
+    }
+
+    /// Checks a function call, returning the call (with its arguments converted to the types of
+    /// the parameters) along with the return type of the function.
+    fn call(&mut self, ident: &Ident, args: &[Expr]) -> Result<(Expr, Type)> {
+        let name = &ident.0;
+        if name == "prints" {
+            return match args {
+                [Expr::Literal(Literal::Str(_))] => Ok((Expr::Call(ident.clone(), args.to_vec()), Type::Void)),
+                _ => Err(esemantic!(self, "`prints` takes a single string literal")),
+            };
+        }
+
+        let (ret, params) = match BUILTINS.iter().find(|&&(builtin, _, _)| builtin == name) {
+            Some(&(_, ref ret, params)) => (ret.clone(), params.to_vec()),
+            None => {
+                match self.functions.get(name) {
+                    Some(sig) => (sig.ret.clone(), sig.params.clone()),
+                    None => return Err(esemantic!(self, "call to undefined function `{}`", name)),
+                }
+            }
+        };
+        if args.len() != params.len() {
+            return Err(esemantic!(self,
+                                  "function `{}` takes {} argument(s), but {} were given",
+                                  name,
+                                  params.len(),
+                                  args.len()));
+        }
+        let mut checked = Vec::with_capacity(args.len());
+        for (arg, param) in args.iter().zip(&params) {
+            let (arg, ty) = self.value(arg)?;
+            checked.push(convert(arg, &ty, param));
+        }
+
+        Ok((Expr::Call(ident.clone(), checked), ret))
+    }
+
+    /// Declares a new variable (or array) in the current scope.
+    fn declare(&mut self, decl: &Decl, is_array: bool) -> Result<()> {
+        let name = &decl.name.0;
+        if decl.ty == Type::Void {
+            return Err(esemantic!(self, "variable `{}` declared void", name));
+        }
+        if self.scopes.last().unwrap().contains_key(name) {
+            return Err(esemantic!(self, "variable `{}` is already declared in this scope", name));
+        }
+        if self.function.is_none() && self.functions.contains_key(name) {
+            // Global variables and functions share labels in the generated code
+            return Err(esemantic!(self, "`{}` is already declared as a function", name));
+        }
+
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(name.clone(),
+                    Var {
+                        is_const: decl.is_const,
+                        is_array,
+                        ty: decl.ty.clone(),
+                    });
+        Ok(())
+    }
+
+    /// Returns the variable with the given name.
+    fn lookup(&self, ident: &Ident) -> Result<&Var> {
+        for scope in self.scopes.iter().rev() {
+            if let Some(var) = scope.get(&ident.0) {
+                return Ok(var);
+            }
+        }
+        Err(esemantic!(self, "use of undeclared variable `{}`", ident.0))
+    }
+}
+
+/// Returns a binary operation on operands of the given types, along with the type of its
+/// result, replacing the operation with its unsigned version if either operand is unsigned.
+fn arithmetic(op: &BinOp, lhs: Expr, lhs_ty: &Type, rhs: Expr, rhs_ty: &Type) -> (Expr, Type) {
+    let unsigned = lhs_ty.promoted() == Type::Unsigned || rhs_ty.promoted() == Type::Unsigned;
+    let ty = match *op {
+        _ if op.is_comparison() => Type::Bool,
+        BinOp::Shl | BinOp::Shr => lhs_ty.promoted(),
+        _ if unsigned => Type::Unsigned,
+        _ => Type::Int,
+    };
+    let op = match op.unsigned() {
+        Some(op) if unsigned => op,
+        _ => op.clone(),
+    };
+    (Expr::BinOp(op, Box::new(lhs), Box::new(rhs)), ty)
+}
+
+/// Converts a value of type `from` to type `to`, inserting a cast if the conversion could
+/// change the value.
+fn convert(expr: Expr, from: &Type, to: &Type) -> Expr {
+    let needed = match *to {
+        Type::Char => *from != Type::Char && *from != Type::Bool,
+        Type::Bool => *from != Type::Bool,
+        _ => false,
+    };
+    if !needed {
+        return expr;
+    }
+    match expr {
+        Expr::Literal(Literal::Int(n, format)) => {
+            // The literal can only be printed back the same way if its value is unchanged
+            let value = to.cast(n);
+            Expr::Literal(Literal::Int(value, if value == n { format } else { IntFormat::Dec }))
+        }
+        expr => Expr::Cast(to.clone(), Box::new(expr)),
+    }
+}
@@ -0,0 +1,1205 @@
+//! The IBCMC code generator.
+//!
+//! The code generator walks the AST produced by the parser and emits IBCM assembly
+//! (as a list of `Line`s), which can be rendered to text and passed to the `Assembler`.
+//!
+//! # Memory layout
+//!
+//! The generated program begins with the code for the top-level statements, which
+//! are executed in order and followed by a `halt`. After this comes the code for each
+//! function, followed by any routines from the runtime library (see the `runtime` module)
+//! used by the program, and then the data: the memory cells for global variables (declared using
+//! `dw`), any temporary cells needed to evaluate complex expressions, the frame of each
+//! function, the constant pool, which contains one cell for each distinct constant value
+//! used in the program, and finally the address cells described below.
+//!
+//! Each variable is stored in a fixed cell, but variables whose scopes don't overlap (e.g. those
+//! of two sibling blocks) share cells, so a cell may have several labels (see the `alloc`
+//! module).
+//!
+//! Labels derived from the names in the program always begin with a letter: global
+//! variables and functions are labelled with their own names, the variables of a function
+//! `f` are prefixed with `f.`, and shadowed variables get a numeric suffix (e.g. `x.1`).
+//! Labels used internally by the compiler begin with `_` (e.g. the temporaries `_t0`
+//! and `f._t0`), `#` (constants, which are named after their value in hex, e.g. `#000a`)
+//! or `&` (address cells), so they can never clash with user names.
+//!
+//! # Arrays and pointers
+//!
+//! The elements of an array `a` are stored in consecutive cells, labelled `a`, `a._1`,
+//! `a._2` and so on. A pointer is just the address of a cell, and the value of an array is
+//! the address of its first element, so `a[i]` is equivalent to `*(a + i)`.
+//!
+//! Since the IBCM only supports direct addressing, any access to a computed address is
+//! done using self-modifying code: a `load` instruction for the address is built in the
+//! accumulator (by wrapping the address to 12 bits and adding the `load` opcode, `3000`),
+//! stored in place and executed. Stores work the same way, after adding `1000` to turn the
+//! `load` into a `store`. To find the address of a cell `x` in the first place, a `load x`
+//! instruction is placed in the address cell `&x`, from which the address can be computed or
+//! indexed.
+//!
+//! The address of a local variable of a function refers to the variable in the function's
+//! (static) frame, so it is only valid until the function returns or calls itself again.
+//!
+//! # Calling convention
+//!
+//! Every function `f` has a statically allocated frame, consisting of the return address
+//! (`f._ret`), its parameters and local variables, and its temporaries. Functions are called
+//! using `brl`, which leaves the return address in the accumulator for the callee to store
+//! in `f._ret`. To return, the callee stores its return value in `_rv`, then builds a `jmp`
+//! to the return address by adding the `jmp` opcode to `f._ret` and executes it.
+//!
+//! To make recursion possible, the frame of a function is saved on a software stack while
+//! it makes a call which may lead back to the function itself: the stack grows downwards from
+//! the end of memory, and `_sp` points to the next free cell. A call `g(a, b)` made from a
+//! recursive function `f` proceeds as follows:
+//!
+//! 1. The arguments are evaluated (in order) into temporaries of `f`.
+//! 2. `f`'s frame is pushed onto the stack by `brl f._save`.
+//! 3. The temporaries are copied into the parameters of `g`, and `brl g` is executed.
+//! 4. `f`'s frame is popped off the stack by `brl f._restore`, and the return value is
+//!    loaded from `_rv`.
+//!
+//! Calls made from the top-level statements skip steps 2 and 4, since they are not part
+//! of any frame, as do calls which can never lead back to the caller. The save and restore
+//! helpers are only generated for functions whose frames need to be saved, and return (using
+//! the same trick as functions) through the return address stored in `_ra`.
+//!
+//! Before pushing a frame, the save helper checks that it fits between the stack pointer and
+//! the end of the program (the last word of which is labelled `_end`). If it doesn't, it jumps
+//! to `_overflow`, which prints `stack overflow` and halts, instead of overwriting the program.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use instruction::Instruction;
+use instruction::{IoOp, ShiftOp};
+use ibcmc::alloc::{Area, CallGraph};
+use ibcmc::ast::{BinOp, Block, Decl, Expr, Stmt, StmtLine, Type, UnOp};
+use ibcmc::errors::*;
+use ibcmc::lexer::{Ident, Literal};
+use ibcmc::runtime::Routine;
+
+/// The names of the built-in I/O functions, which are lowered directly to I/O instructions.
+const BUILTINS: &[&str] = &["readh", "readc", "printh", "printc", "prints"];
+
+/// The initial value of the stack pointer (the last cell in memory).
+const STACK_START: u16 = 0x0fff;
+
+/// The message printed when a frame doesn't fit on the stack.
+const STACK_OVERFLOW: &str = "stack overflow";
+
+/// A single line of generated assembly.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Line {
+    /// The labels referring to this line.
+    pub labels: Vec<String>,
+    /// The contents of the line.
+    pub op: Op,
+    /// The line of the IBCMC source from which this line was generated, if any.
+    pub line: Option<usize>,
+}
+
+/// The contents of a line of generated assembly.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Op {
+    /// An instruction, along with its (symbolic) address argument, if any.
+    ///
+    /// The address stored in the `Instruction` itself is ignored.
+    Instr(Instruction, Option<String>),
+    /// A data word (`dw`).
+    Data(u16),
+}
+
+impl Display for Line {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        // Labels which are too long to fit in the first column go on their own line
+        let (last, rest) = match self.labels.split_last() {
+            Some((last, rest)) if last.len() < 7 => (format!("{}:", last), rest),
+            _ => (String::new(), self.labels.as_slice()),
+        };
+        for label in rest {
+            writeln!(f, "{}:", label)?;
+        }
+
+        match self.op {
+            Op::Instr(Instruction::Shift(_, n), _) => {
+                write!(f, "{:<8}{:<8}{}", last, self.op.name(), n)
+            }
+            Op::Instr(_, Some(ref addr)) => write!(f, "{:<8}{:<8}{}", last, self.op.name(), addr),
+            Op::Instr(_, None) => write!(f, "{:<8}{}", last, self.op.name()),
+            Op::Data(w) => write!(f, "{:<8}{:<8}{:04x}", last, self.op.name(), w),
+        }
+    }
+}
+
+impl Op {
+    /// Returns the mnemonic of the operation.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Op::Instr(instr, _) => instr.name(),
+            Op::Data(_) => "dw",
+        }
+    }
+}
+
+/// The signature of a function.
+struct Signature {
+    /// The return type.
+    ret: Type,
+    /// The labels of the parameters.
+    params: Vec<String>,
+}
+
+/// The state of the function currently being generated.
+struct Frame {
+    /// The name of the function.
+    name: String,
+    /// The cells in the frame (not including temporaries, until the end of the function).
+    cells: Area,
+    /// Whether the frame has to be saved on the stack during any of the calls made by the
+    /// function (i.e. whether the function is recursive).
+    saved: bool,
+}
+
+/// Represents the state of the code generator.
+pub struct Codegen {
+    /// The generated code.
+    code: Vec<Line>,
+    /// Labels which will be attached to the next line of code.
+    pending: Vec<String>,
+    /// The global variable cells.
+    globals: Area,
+    /// The labels of all the function frame cells, in order.
+    frames: Vec<Vec<String>>,
+    /// The call graph of the program.
+    calls: CallGraph,
+    /// Whether any function frame is saved on the stack.
+    stack: bool,
+    /// The values in the constant pool.
+    consts: BTreeSet<u16>,
+    /// The labels of the cells whose addresses are needed (see `address_label`).
+    addrs: BTreeSet<String>,
+    /// The labels of all the arrays.
+    arrays: HashSet<String>,
+    /// The runtime library routines used by the program.
+    runtime: BTreeSet<Routine>,
+    /// The variables visible in each nested scope, mapping names to labels.
+    scopes: Vec<HashMap<String, String>>,
+    /// The number of times each variable label has been used (for generating unique labels).
+    decl_counts: HashMap<String, usize>,
+    /// The signatures of all the functions in the program.
+    functions: HashMap<String, Signature>,
+    /// The function currently being generated, if any.
+    frame: Option<Frame>,
+    /// The labels to jump to for `continue` and `break` in each enclosing loop.
+    loops: Vec<(String, String)>,
+    /// The number of labels generated so far.
+    labels: usize,
+    /// The number of temporaries currently in use.
+    temps: usize,
+    /// The maximum number of temporaries in use at any time.
+    max_temps: usize,
+    /// The line number of the statement currently being processed (or 0 if the code being
+    /// generated doesn't belong to any statement).
+    line: usize,
+}
+
+impl Codegen {
+    /// Generates IBCM assembly for the given program.
+    ///
+    /// The program must already have been checked using `Checker::check`.
+    pub fn generate(program: &Block) -> Result<Vec<Line>> {
+        let mut gen = Codegen {
+            code: Vec::new(),
+            pending: Vec::new(),
+            globals: Area::new(),
+            frames: Vec::new(),
+            calls: CallGraph::new(program),
+            stack: false,
+            consts: BTreeSet::new(),
+            addrs: BTreeSet::new(),
+            arrays: HashSet::new(),
+            runtime: BTreeSet::new(),
+            scopes: vec![HashMap::new()],
+            decl_counts: HashMap::new(),
+            functions: HashMap::new(),
+            frame: None,
+            loops: Vec::new(),
+            labels: 0,
+            temps: 0,
+            max_temps: 0,
+            line: 0,
+        };
+
+        // Functions may be called before they are defined, so we need all the signatures first
+        for stmt in &program.0 {
+            if let Stmt::Function(ref decl, ref params, _) = *stmt.stmt() {
+                gen.signature(decl, params);
+            }
+        }
+
+        // Top-level statements
+        for stmt in &program.0 {
+            gen.line = stmt.line();
+            match *stmt.stmt() {
+                Stmt::Function(..) => {}
+                ref stmt => gen.stmt(stmt)?,
+            }
+        }
+        gen.line = 0;
+        gen.emit(Instruction::Halt, None);
+        for i in 0..gen.max_temps {
+            let temp = gen.temp_label(i);
+            gen.globals.push(temp);
+        }
+        gen.temps = 0;
+        gen.max_temps = 0;
+
+        // Functions
+        for stmt in &program.0 {
+            if let Stmt::Function(ref decl, ref params, ref body) = *stmt.stmt() {
+                gen.line = stmt.line();
+                gen.function(decl, params, body)?;
+            }
+        }
+        gen.line = 0;
+        if gen.stack {
+            gen.overflow_stub();
+        }
+
+        // Runtime library
+        for routine in gen.runtime.clone() {
+            for line in routine.lines() {
+                if let Op::Instr(_, Some(ref arg)) = line.op {
+                    if let Some(value) = arg.strip_prefix('#') {
+                        gen.constant(u16::from_str_radix(value, 16).expect("invalid constant label"));
+                    }
+                }
+                gen.code.push(line);
+            }
+        }
+
+        // Data section
+        for labels in gen.globals.cells().to_vec().into_iter().chain(gen.frames.clone()) {
+            gen.pending.extend(labels);
+            gen.data(0);
+        }
+        if !gen.functions.is_empty() {
+            gen.label("_rv".into());
+            gen.data(0);
+        }
+        if gen.stack {
+            for &(label, init) in &[("_sp", STACK_START), ("_ra", 0)] {
+                gen.label(label.into());
+                gen.data(init);
+            }
+            gen.addrs.insert("_end".into());
+        }
+        for c in gen.consts.clone() {
+            gen.label(const_label(c));
+            gen.data(c);
+        }
+        for label in gen.addrs.clone() {
+            gen.label(address_label(&label));
+            gen.emit(Instruction::Load(0), Some(label));
+        }
+        if gen.stack {
+            // The stack may grow down to (but not including) the last word of the program
+            gen.code.last_mut().expect("no data section").labels.push("_end".into());
+        }
+
+        Ok(gen.code)
+    }
+
+    /// Records the signature of a function.
+    fn signature(&mut self, decl: &Decl, params: &[Decl]) {
+        let name = &decl.name.0;
+        self.functions.insert(name.clone(),
+                              Signature {
+                                  ret: decl.ty.clone(),
+                                  params: params.iter().map(|param| format!("{}.{}", name, param.name.0)).collect(),
+                              });
+    }
+
+    /// Generates code for a function.
+    fn function(&mut self, decl: &Decl, params: &[Decl], body: &Block) -> Result<()> {
+        let name = decl.name.0.clone();
+        let ret = format!("{}._ret", name);
+        let mut scope = HashMap::new();
+        let mut cells = Area::new();
+        cells.alloc(Some(ret.clone()));
+        for (param, label) in params.iter().zip(&self.functions[&name].params) {
+            scope.insert(param.name.0.clone(), label.clone());
+            self.decl_counts.insert(label.clone(), 1);
+            cells.alloc(Some(label.clone()));
+        }
+        self.frame = Some(Frame {
+                              name: name.clone(),
+                              cells,
+                              saved: false,
+                          });
+        self.scopes.push(scope);
+
+        // Entry: save the return address left by `brl`
+        let line = self.line;
+        self.label(name.clone());
+        self.emit(Instruction::Store(0), Some(ret.clone()));
+        for stmt in &body.0 {
+            self.line = stmt.line();
+            self.stmt(stmt.stmt())?;
+        }
+
+        // Exit: store the return value and jump back to the caller
+        self.line = line;
+        self.label(format!("{}._exit", name));
+        self.emit(Instruction::Store(0), Some("_rv".into()));
+        self.return_through(&ret, &format!("{}._jmp", name));
+
+        for i in 0..self.max_temps {
+            let temp = self.temp_label(i);
+            self.frame.as_mut().unwrap().cells.push(temp);
+        }
+        self.scopes.pop();
+        let frame = self.frame.take().unwrap();
+        if frame.saved {
+            self.save_restore(&frame);
+        }
+        self.frames.extend(frame.cells.cells().iter().cloned());
+        self.temps = 0;
+        self.max_temps = 0;
+
+        Ok(())
+    }
+
+    /// Generates the helpers to save and restore the given function frame on the stack.
+    fn save_restore(&mut self, frame: &Frame) {
+        let n = frame.cells.len() as u16;
+
+        // Check that the frame fits above the program: `_sp - n - _end` is computed as
+        // `_sp + (3000 - n) - &_end`, since `&_end` holds `load _end`
+        self.label(format!("{}._save", frame.name));
+        self.emit(Instruction::Store(0), Some("_ra".into()));
+        self.emit(Instruction::Load(0), Some("_sp".into()));
+        let offset = self.constant(Instruction::Load(0).to_u16().wrapping_sub(n));
+        self.emit(Instruction::Add(0), Some(offset));
+        self.emit(Instruction::Sub(0), Some(address_label("_end")));
+        self.emit(Instruction::Jmpl(0), Some("_overflow".into()));
+
+        // Push each cell, then adjust the stack pointer
+        for (i, cell) in frame.cells.cells().iter().enumerate() {
+            let store = format!("{}._s{}", frame.name, i);
+            self.emit(Instruction::Load(0), Some("_sp".into()));
+            let offset = self.constant(Instruction::Store(0).to_u16().wrapping_sub(i as u16));
+            self.emit(Instruction::Add(0), Some(offset));
+            self.emit(Instruction::Store(0), Some(store.clone()));
+            self.emit(Instruction::Load(0), Some(cell[0].clone()));
+            self.label(store);
+            self.emit(Instruction::Nop, None);
+        }
+        self.adjust_sp(Instruction::Sub(0), n);
+        self.return_through("_ra", &format!("{}._sjmp", frame.name));
+
+        // Adjust the stack pointer, then pop each cell
+        self.label(format!("{}._restore", frame.name));
+        self.emit(Instruction::Store(0), Some("_ra".into()));
+        self.adjust_sp(Instruction::Add(0), n);
+        for (i, cell) in frame.cells.cells().iter().enumerate() {
+            let load = format!("{}._r{}", frame.name, i);
+            self.emit(Instruction::Load(0), Some("_sp".into()));
+            let offset = self.constant(Instruction::Load(0).to_u16().wrapping_sub(i as u16));
+            self.emit(Instruction::Add(0), Some(offset));
+            self.emit(Instruction::Store(0), Some(load.clone()));
+            self.label(load);
+            self.emit(Instruction::Nop, None);
+            self.emit(Instruction::Store(0), Some(cell[0].clone()));
+        }
+        self.return_through("_ra", &format!("{}._rjmp", frame.name));
+    }
+
+    /// Generates the code which reports a stack overflow and halts the program.
+    fn overflow_stub(&mut self) {
+        self.label("_overflow".into());
+        for b in STACK_OVERFLOW.bytes() {
+            let c = self.constant(b as u16);
+            self.emit(Instruction::Load(0), Some(c));
+            self.emit(Instruction::Io(IoOp::WriteChar), None);
+        }
+        self.emit(Instruction::Halt, None);
+    }
+
+    /// Generates code to add or subtract the given amount from the stack pointer.
+    fn adjust_sp(&mut self, instr: Instruction, n: u16) {
+        let amt = self.constant(n);
+        self.emit(Instruction::Load(0), Some("_sp".into()));
+        self.emit(instr, Some(amt));
+        self.emit(Instruction::Store(0), Some("_sp".into()));
+    }
+
+    /// Generates code to jump to the address stored in the given cell, by constructing
+    /// a `jmp` instruction in place (the `jmp` itself is given the label `jmp`).
+    fn return_through(&mut self, addr: &str, jmp: &str) {
+        let opcode = self.constant(Instruction::Jmp(0).to_u16());
+        self.emit(Instruction::Load(0), Some(addr.into()));
+        self.emit(Instruction::Add(0), Some(opcode));
+        self.emit(Instruction::Store(0), Some(jmp.into()));
+        self.label(jmp.into());
+        self.emit(Instruction::Nop, None);
+    }
+
+    /// Generates code for a single statement.
+    fn stmt(&mut self, stmt: &Stmt) -> Result<()> {
+        match *stmt {
+            Stmt::Function(..) => unreachable!("nested function definition"),
+            Stmt::Block(ref block) => {
+                self.enter_scope();
+                for stmt in &block.0 {
+                    self.line = stmt.line();
+                    self.stmt(stmt.stmt())?;
+                }
+                self.exit_scope();
+                Ok(())
+            }
+            Stmt::Assign(Expr::Ident(ref ident), ref expr) => {
+                self.expr(expr)?;
+                let var = self.lookup(ident);
+                self.emit(Instruction::Store(0), Some(var));
+                Ok(())
+            }
+            Stmt::Assign(ref target, ref expr) => {
+                let (value, temps) = self.cell(expr)?;
+                self.load_word(target)?;
+                self.indirect(Some(value));
+                for _ in 0..temps {
+                    self.free_temp();
+                }
+                Ok(())
+            }
+            Stmt::CompoundAssign(Expr::Ident(ref ident), ref op, ref expr) => {
+                self.binop(op, &Expr::Ident(ident.clone()), expr)?;
+                let var = self.lookup(ident);
+                self.emit(Instruction::Store(0), Some(var));
+                Ok(())
+            }
+            Stmt::CompoundAssign(ref target, ref op, ref expr) => {
+                // The target is only evaluated once: its `load` word is kept in a temporary
+                // while the new value is computed
+                let (rhs, temps) = self.cell(expr)?;
+                let word = self.alloc_temp();
+                let value = self.alloc_temp();
+                self.load_word(target)?;
+                self.emit(Instruction::Store(0), Some(word.clone()));
+                self.indirect(None);
+                self.emit(instruction(op), Some(rhs));
+                self.emit(Instruction::Store(0), Some(value.clone()));
+                self.emit(Instruction::Load(0), Some(word));
+                self.indirect(Some(value));
+                for _ in 0..temps + 2 {
+                    self.free_temp();
+                }
+                Ok(())
+            }
+            Stmt::Decl(ref decl) => {
+                self.declare(decl, None);
+                Ok(())
+            }
+            Stmt::Init(ref decl, ref expr) => {
+                // The initializer is evaluated before the variable comes into scope
+                self.expr(expr)?;
+                let var = self.declare(decl, None);
+                self.emit(Instruction::Store(0), Some(var));
+                Ok(())
+            }
+            Stmt::Array(ref decl, size) => {
+                self.declare(decl, Some(size));
+                Ok(())
+            }
+            Stmt::Expr(ref expr) => self.expr(expr),
+            Stmt::Return(ref expr) => {
+                let exit = format!("{}._exit", self.frame.as_ref().expect("`return` outside of a function").name);
+                if let Some(ref expr) = *expr {
+                    self.expr(expr)?;
+                }
+                self.emit(Instruction::Jmp(0), Some(exit));
+                Ok(())
+            }
+            Stmt::If(ref cond, ref body, ref else_body) => {
+                let else_label = self.new_label();
+                self.branch(cond, false, &else_label)?;
+                self.nested_stmt(body)?;
+                if let Some(ref else_body) = *else_body {
+                    let end = self.new_label();
+                    self.emit(Instruction::Jmp(0), Some(end.clone()));
+                    self.label(else_label);
+                    self.nested_stmt(else_body)?;
+                    self.label(end);
+                } else {
+                    self.label(else_label);
+                }
+                Ok(())
+            }
+            Stmt::While(ref cond, ref body) => {
+                let top = self.new_label();
+                let end = self.new_label();
+                self.label(top.clone());
+                self.branch(cond, false, &end)?;
+                self.loop_body(body, &top, &end)?;
+                self.emit(Instruction::Jmp(0), Some(top));
+                self.label(end);
+                Ok(())
+            }
+            Stmt::For(ref init, ref cond, ref step, ref body) => {
+                // Variables declared in the initialization are only visible in the loop
+                self.enter_scope();
+                self.stmt(init)?;
+                let top = self.new_label();
+                let cont = self.new_label();
+                let end = self.new_label();
+                self.label(top.clone());
+                if let Some(ref cond) = *cond {
+                    self.branch(cond, false, &end)?;
+                }
+                let line = self.line;
+                self.loop_body(body, &cont, &end)?;
+                self.line = line;
+                self.label(cont);
+                self.stmt(step)?;
+                self.emit(Instruction::Jmp(0), Some(top));
+                self.label(end);
+                self.exit_scope();
+                Ok(())
+            }
+            Stmt::Break => {
+                let target = self.loops.last().expect("`break` outside of a loop").1.clone();
+                self.emit(Instruction::Jmp(0), Some(target));
+                Ok(())
+            }
+            Stmt::Continue => {
+                let target = self.loops.last().expect("`continue` outside of a loop").0.clone();
+                self.emit(Instruction::Jmp(0), Some(target));
+                Ok(())
+            }
+            Stmt::Empty => Ok(()),
+        }
+    }
+
+    /// Generates code for a statement nested inside another (e.g. the body of a loop),
+    /// which gets its own scope.
+    fn nested_stmt(&mut self, stmt: &StmtLine) -> Result<()> {
+        self.enter_scope();
+        self.line = stmt.line();
+        self.stmt(stmt.stmt())?;
+        self.exit_scope();
+        Ok(())
+    }
+
+    /// Enters a new (nested) scope.
+    fn enter_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+        self.area().enter();
+    }
+
+    /// Exits the current scope, so that the cells of its variables can be reused.
+    fn exit_scope(&mut self) {
+        self.scopes.pop();
+        self.area().exit();
+    }
+
+    /// Returns the area in which variables are currently allocated (the frame of the current
+    /// function, or the global data area).
+    fn area(&mut self) -> &mut Area {
+        match self.frame {
+            Some(ref mut frame) => &mut frame.cells,
+            None => &mut self.globals,
+        }
+    }
+
+    /// Generates code for the body of a loop, with the given `continue` and `break` targets.
+    fn loop_body(&mut self, body: &StmtLine, cont: &str, end: &str) -> Result<()> {
+        self.loops.push((cont.into(), end.into()));
+        self.nested_stmt(body)?;
+        self.loops.pop();
+        Ok(())
+    }
+
+    /// Generates code to jump to `target` if the truth value of the given expression is `when`
+    /// (i.e. if it is non-zero and `when` is `true`, or if it is zero and `when` is `false`).
+    ///
+    /// Comparisons and logical negations are handled specially, since their values don't have
+    /// to be computed.
+    fn branch(&mut self, expr: &Expr, when: bool, target: &str) -> Result<()> {
+        match *expr {
+            Expr::UnOp(UnOp::LogicalNot, ref expr) => return self.branch(expr, !when, target),
+            // Converting to `bool` doesn't change the truth value
+            Expr::Cast(Type::Bool, ref expr) => return self.branch(expr, when, target),
+            _ => {}
+        }
+
+        let skip = self.new_label();
+        let skipped = match *expr {
+            Expr::BinOp(ref op, ref lhs, ref rhs) if op.is_comparison() => {
+                let (a, b, temps) = self.cells(lhs, rhs)?;
+                // Everything can be expressed using `==` and `<`
+                let (eq, a, b, when) = match *op {
+                    BinOp::Eq => (true, a, b, when),
+                    BinOp::Ne => (true, a, b, !when),
+                    BinOp::Lt | BinOp::ULt => (false, a, b, when),
+                    BinOp::Ge | BinOp::UGe => (false, a, b, !when),
+                    BinOp::Gt | BinOp::UGt => (false, b, a, when),
+                    BinOp::Le | BinOp::ULe => (false, b, a, !when),
+                    _ => unreachable!(),
+                };
+                let unsigned = matches!(*op, BinOp::ULt | BinOp::ULe | BinOp::UGt | BinOp::UGe);
+                for _ in 0..temps {
+                    self.free_temp();
+                }
+
+                if eq {
+                    self.emit(Instruction::Load(0), Some(a));
+                    self.emit(Instruction::Sub(0), Some(b));
+                    self.jmpe(when, target, &skip)
+                } else {
+                    // `a - b` may overflow if the signs of `a` and `b` differ, but in that case
+                    // `a < b` exactly when `a` is negative (or, if they are unsigned, when the
+                    // top bit of `b` is set)
+                    let (t, f) = if when {
+                        (target, skip.as_str())
+                    } else {
+                        (skip.as_str(), target)
+                    };
+                    let diff = self.new_label();
+                    self.emit(Instruction::Load(0), Some(a.clone()));
+                    self.emit(Instruction::Xor(0), Some(b.clone()));
+                    self.emit(Instruction::Jmpl(0), Some(diff.clone()));
+                    self.emit(Instruction::Load(0), Some(a.clone()));
+                    self.emit(Instruction::Sub(0), Some(b.clone()));
+                    self.emit(Instruction::Jmpl(0), Some(t.into()));
+                    self.emit(Instruction::Jmp(0), Some(f.into()));
+                    self.label(diff);
+                    self.emit(Instruction::Load(0), Some(if unsigned { b } else { a }));
+                    self.emit(Instruction::Jmpl(0), Some(t.into()));
+                    if f != skip {
+                        self.emit(Instruction::Jmp(0), Some(f.into()));
+                    }
+                    true
+                }
+            }
+            _ => {
+                self.expr(expr)?;
+                self.jmpe(!when, target, &skip)
+            }
+        };
+        if skipped {
+            self.label(skip);
+        }
+        Ok(())
+    }
+
+    /// Generates code to jump to `target` if the accumulator is zero (if `when` is `true`)
+    /// or non-zero (if `when` is `false`), using the given label to skip over the jump.
+    ///
+    /// Returns whether the skip label was used.
+    fn jmpe(&mut self, when: bool, target: &str, skip: &str) -> bool {
+        if when {
+            self.emit(Instruction::Jmpe(0), Some(target.into()));
+            false
+        } else {
+            self.emit(Instruction::Jmpe(0), Some(skip.into()));
+            self.emit(Instruction::Jmp(0), Some(target.into()));
+            true
+        }
+    }
+
+    /// Generates code to evaluate the given expression into the accumulator.
+    fn expr(&mut self, expr: &Expr) -> Result<()> {
+        match *expr {
+            Expr::BinOp(ref op, _, _) if op.is_comparison() => self.truth_value(expr),
+            Expr::UnOp(UnOp::LogicalNot, _) => self.truth_value(expr),
+            Expr::BinOp(BinOp::Shl, ref lhs, ref rhs) => self.shift(ShiftOp::ShiftLeft, lhs, rhs),
+            Expr::BinOp(BinOp::Shr, ref lhs, ref rhs) => self.shift(ShiftOp::ShiftRight, lhs, rhs),
+            Expr::BinOp(BinOp::Mul, ref lhs, ref rhs) => self.runtime_call(Routine::Mul, lhs, rhs),
+            Expr::BinOp(BinOp::Div, ref lhs, ref rhs) => self.runtime_call(Routine::Div, lhs, rhs),
+            Expr::BinOp(BinOp::Mod, ref lhs, ref rhs) => {
+                self.runtime_call(Routine::Div, lhs, rhs)?;
+                self.emit(Instruction::Load(0), Some("_div.r".into()));
+                Ok(())
+            }
+            Expr::BinOp(BinOp::UDiv, ref lhs, ref rhs) => self.runtime_call(Routine::UDiv, lhs, rhs),
+            Expr::BinOp(BinOp::UMod, ref lhs, ref rhs) => {
+                self.runtime_call(Routine::UDiv, lhs, rhs)?;
+                self.emit(Instruction::Load(0), Some("_udiv.r".into()));
+                Ok(())
+            }
+            Expr::BinOp(ref op, ref lhs, ref rhs) => self.binop(op, lhs, rhs),
+            Expr::UnOp(UnOp::Neg, ref expr) => {
+                // Two's complement negation
+                self.expr(expr)?;
+                let one = self.constant(1);
+                self.emit(Instruction::Not, None);
+                self.emit(Instruction::Add(0), Some(one));
+                Ok(())
+            }
+            Expr::UnOp(UnOp::Not, ref expr) => {
+                self.expr(expr)?;
+                self.emit(Instruction::Not, None);
+                Ok(())
+            }
+            Expr::Cast(Type::Char, ref expr) => {
+                self.expr(expr)?;
+                let mask = self.constant(0x00ff);
+                self.emit(Instruction::And(0), Some(mask));
+                Ok(())
+            }
+            Expr::Cast(Type::Bool, ref expr) => {
+                // Any non-zero value becomes 1
+                self.expr(expr)?;
+                let zero = self.new_label();
+                let one = self.constant(1);
+                self.emit(Instruction::Jmpe(0), Some(zero.clone()));
+                self.emit(Instruction::Load(0), Some(one));
+                self.label(zero);
+                Ok(())
+            }
+            Expr::Cast(_, ref expr) => self.expr(expr),
+            Expr::Call(ref ident, ref args) => self.call(ident, args),
+            Expr::Index(..) | Expr::Deref(_) => {
+                self.load_word(expr)?;
+                self.indirect(None);
+                Ok(())
+            }
+            Expr::AddrOf(ref target) => {
+                if let Expr::Deref(ref addr) = **target {
+                    return self.expr(addr);
+                }
+                self.load_word(target)?;
+                let opcode = self.constant(Instruction::Load(0).to_u16());
+                self.emit(Instruction::Sub(0), Some(opcode));
+                Ok(())
+            }
+            Expr::Ident(ref ident) if self.arrays.contains(&self.lookup(ident)) => {
+                // Arrays are converted to the address of their first element
+                self.load_word(expr)?;
+                let opcode = self.constant(Instruction::Load(0).to_u16());
+                self.emit(Instruction::Sub(0), Some(opcode));
+                Ok(())
+            }
+            _ => {
+                let cell = self.operand(expr)?.expect("simple expression must have an operand");
+                self.emit(Instruction::Load(0), Some(cell));
+                Ok(())
+            }
+        }
+    }
+
+    /// Generates code to compute a `load` instruction for the memory cell referred to by the
+    /// given lvalue (or array), leaving it in the accumulator.
+    ///
+    /// The IBCM only supports direct addressing, so accessing a computed address is done by
+    /// building the instruction to execute at runtime (see `indirect`). Since the opcode of
+    /// `load` is 3, the `load` instruction for an address is just the address plus `3000`
+    /// (computed addresses are wrapped to 12 bits first; see `load_instruction`).
+    fn load_word(&mut self, target: &Expr) -> Result<()> {
+        match *target {
+            Expr::Ident(ref ident) => {
+                let label = self.lookup(ident);
+                self.addrs.insert(label.clone());
+                self.emit(Instruction::Load(0), Some(address_label(&label)));
+            }
+            Expr::Index(ref base, ref index) => {
+                if let Expr::Ident(ref ident) = **base {
+                    let label = self.lookup(ident);
+                    if self.arrays.contains(&label) {
+                        // Index directly from the `load` instruction for the array
+                        let (cell, temps) = self.cell(index)?;
+                        self.addrs.insert(label.clone());
+                        self.emit(Instruction::Load(0), Some(address_label(&label)));
+                        self.emit(Instruction::Add(0), Some(cell));
+                        for _ in 0..temps {
+                            self.free_temp();
+                        }
+                        self.load_instruction();
+                        return Ok(());
+                    }
+                }
+                self.binop(&BinOp::Add, base, index)?;
+                self.load_instruction();
+            }
+            Expr::Deref(ref addr) => {
+                self.expr(addr)?;
+                self.load_instruction();
+            }
+            _ => unreachable!("expression is not an lvalue"),
+        }
+        Ok(())
+    }
+
+    /// Generates code to turn the address in the accumulator into a `load` instruction for it.
+    ///
+    /// The address is first wrapped to 12 bits, like the address field of an instruction, so
+    /// that an address outside of memory can't turn the `load` into a different instruction.
+    fn load_instruction(&mut self) {
+        let mask = self.constant(0x0fff);
+        let opcode = self.constant(Instruction::Load(0).to_u16());
+        self.emit(Instruction::And(0), Some(mask));
+        self.emit(Instruction::Add(0), Some(opcode));
+    }
+
+    /// Generates code to execute the `load` instruction in the accumulator (built by `load_word`)
+    /// by storing it in place, so that the value of the cell is loaded into the accumulator.
+    ///
+    /// If `value` is given, the instruction is instead turned into a `store`, which stores the
+    /// value of the cell with the given label.
+    fn indirect(&mut self, value: Option<String>) {
+        let cell = self.new_label();
+        if let Some(value) = value {
+            let offset = self.constant(Instruction::Store(0).to_u16() - Instruction::Load(0).to_u16());
+            self.emit(Instruction::Add(0), Some(offset));
+            self.emit(Instruction::Store(0), Some(cell.clone()));
+            self.emit(Instruction::Load(0), Some(value));
+        } else {
+            self.emit(Instruction::Store(0), Some(cell.clone()));
+        }
+        self.label(cell);
+        self.emit(Instruction::Nop, None);
+    }
+
+    /// Generates code to evaluate a logical expression (e.g. a comparison) into the accumulator,
+    /// giving 1 if it is true and 0 if it is false.
+    fn truth_value(&mut self, expr: &Expr) -> Result<()> {
+        let zero = self.new_label();
+        let end = self.new_label();
+        let (c0, c1) = (self.constant(0), self.constant(1));
+        self.branch(expr, false, &zero)?;
+        self.emit(Instruction::Load(0), Some(c1));
+        self.emit(Instruction::Jmp(0), Some(end.clone()));
+        self.label(zero);
+        self.emit(Instruction::Load(0), Some(c0));
+        self.label(end);
+        Ok(())
+    }
+
+    /// Generates code to evaluate a binary operation into the accumulator.
+    fn binop(&mut self, op: &BinOp, lhs: &Expr, rhs: &Expr) -> Result<()> {
+        let instr = instruction(op);
+
+        if let Some(cell) = self.operand(rhs)? {
+            self.expr(lhs)?;
+            self.emit(instr, Some(cell));
+        } else {
+            // The right-hand side has to be stored in a temporary first
+            self.expr(rhs)?;
+            let temp = self.alloc_temp();
+            self.emit(Instruction::Store(0), Some(temp.clone()));
+            self.expr(lhs)?;
+            self.emit(instr, Some(temp));
+            self.free_temp();
+        }
+        Ok(())
+    }
+
+    /// Generates code to evaluate a shift into the accumulator.
+    ///
+    /// The IBCM shift instructions only take a constant amount, so shifts by any other amount
+    /// are done one bit at a time in a loop.
+    fn shift(&mut self, op: ShiftOp, lhs: &Expr, rhs: &Expr) -> Result<()> {
+        if let Expr::Literal(Literal::Int(n, _)) = *rhs {
+            self.expr(lhs)?;
+            if n < 16 {
+                self.emit(Instruction::Shift(op, n), None);
+            } else {
+                // Everything gets shifted out
+                let zero = self.constant(0);
+                self.emit(Instruction::Load(0), Some(zero));
+            }
+            return Ok(());
+        }
+
+        let (a, b, temps) = self.cells(lhs, rhs)?;
+        let value = self.alloc_temp();
+        let count = self.alloc_temp();
+        let top = self.new_label();
+        let end = self.new_label();
+        let one = self.constant(1);
+        self.emit(Instruction::Load(0), Some(b));
+        self.emit(Instruction::Store(0), Some(count.clone()));
+        self.emit(Instruction::Load(0), Some(a));
+        self.emit(Instruction::Store(0), Some(value.clone()));
+        // Shift until the count runs out or there is nothing left to shift
+        self.label(top.clone());
+        self.emit(Instruction::Load(0), Some(count.clone()));
+        self.emit(Instruction::Jmpe(0), Some(end.clone()));
+        self.emit(Instruction::Sub(0), Some(one));
+        self.emit(Instruction::Store(0), Some(count));
+        self.emit(Instruction::Load(0), Some(value.clone()));
+        self.emit(Instruction::Shift(op, 1), None);
+        self.emit(Instruction::Store(0), Some(value.clone()));
+        self.emit(Instruction::Jmpe(0), Some(end.clone()));
+        self.emit(Instruction::Jmp(0), Some(top));
+        self.label(end);
+        self.emit(Instruction::Load(0), Some(value));
+        for _ in 0..temps + 2 {
+            self.free_temp();
+        }
+        Ok(())
+    }
+
+    /// Generates code to call a runtime library routine with the given arguments, leaving the
+    /// result in the accumulator.
+    fn runtime_call(&mut self, routine: Routine, lhs: &Expr, rhs: &Expr) -> Result<()> {
+        let (a, b, temps) = self.cells(lhs, rhs)?;
+        let (x, y) = routine.args();
+        self.emit(Instruction::Load(0), Some(a));
+        self.emit(Instruction::Store(0), Some(x.into()));
+        self.emit(Instruction::Load(0), Some(b));
+        self.emit(Instruction::Store(0), Some(y.into()));
+        self.emit(Instruction::Brl(0), Some(routine.entry().into()));
+        for _ in 0..temps {
+            self.free_temp();
+        }
+        self.runtime.insert(routine);
+        Ok(())
+    }
+
+    /// Generates code to call a function, leaving the return value in the accumulator.
+    fn call(&mut self, ident: &Ident, args: &[Expr]) -> Result<()> {
+        if BUILTINS.contains(&ident.0.as_str()) {
+            return self.builtin(&ident.0, args);
+        }
+        let (ret, params) = {
+            let sig = &self.functions[&ident.0];
+            (sig.ret.clone(), sig.params.clone())
+        };
+
+        // Evaluate the arguments into temporaries
+        let mut temps = Vec::new();
+        for arg in args {
+            self.expr(arg)?;
+            let temp = self.alloc_temp();
+            self.emit(Instruction::Store(0), Some(temp.clone()));
+            temps.push(temp);
+        }
+
+        // The frame of the caller only has to be saved if the call may lead back to it
+        let calls = &self.calls;
+        let frame = self.frame
+            .as_mut()
+            .filter(|frame| calls.may_call(&ident.0, &frame.name))
+            .map(|frame| {
+                     frame.saved = true;
+                     frame.name.clone()
+                 });
+        self.stack |= frame.is_some();
+        if let Some(ref frame) = frame {
+            self.emit(Instruction::Brl(0), Some(format!("{}._save", frame)));
+        }
+        for (temp, param) in temps.into_iter().zip(params) {
+            self.emit(Instruction::Load(0), Some(temp));
+            self.emit(Instruction::Store(0), Some(param));
+        }
+        for _ in args {
+            self.free_temp();
+        }
+        self.emit(Instruction::Brl(0), Some(ident.0.clone()));
+        if let Some(ref frame) = frame {
+            self.emit(Instruction::Brl(0), Some(format!("{}._restore", frame)));
+        }
+        if ret != Type::Void {
+            self.emit(Instruction::Load(0), Some("_rv".into()));
+        }
+
+        Ok(())
+    }
+
+    /// Generates code for a call to a built-in I/O function, leaving the value read (if any)
+    /// in the accumulator.
+    fn builtin(&mut self, name: &str, args: &[Expr]) -> Result<()> {
+        match name {
+            "readh" => self.emit(Instruction::Io(IoOp::ReadHex), None),
+            "readc" => self.emit(Instruction::Io(IoOp::ReadChar), None),
+            "printh" => {
+                self.expr(&args[0])?;
+                self.emit(Instruction::Io(IoOp::WriteHex), None);
+            }
+            "printc" => {
+                self.expr(&args[0])?;
+                self.emit(Instruction::Io(IoOp::WriteChar), None);
+            }
+            "prints" => {
+                let s = match args[0] {
+                    Expr::Literal(Literal::Str(ref s)) => s,
+                    _ => unreachable!("`prints` takes a string literal"),
+                };
+                // Each character of a string literal is a single byte
+                for b in s.chars() {
+                    let c = self.constant(b as u16);
+                    self.emit(Instruction::Load(0), Some(c));
+                    self.emit(Instruction::Io(IoOp::WriteChar), None);
+                }
+            }
+            _ => unreachable!("unknown built-in function `{}`", name),
+        }
+        Ok(())
+    }
+
+    /// Returns the labels of memory cells holding the values of the given expressions (which
+    /// are evaluated in order), along with the number of temporaries which were allocated to
+    /// hold them (and which must be freed by the caller).
+    fn cells(&mut self, lhs: &Expr, rhs: &Expr) -> Result<(String, String, usize)> {
+        let (a, lhs_temps) = self.cell(lhs)?;
+        let (b, rhs_temps) = self.cell(rhs)?;
+
+        Ok((a, b, lhs_temps + rhs_temps))
+    }
+
+    /// Returns the label of a memory cell holding the value of the given expression, along
+    /// with the number of temporaries which were allocated to hold it (0 or 1, which must be
+    /// freed by the caller).
+    fn cell(&mut self, expr: &Expr) -> Result<(String, usize)> {
+        Ok(match self.operand(expr)? {
+               Some(cell) => (cell, 0),
+               None => {
+                   self.expr(expr)?;
+                   let temp = self.alloc_temp();
+                   self.emit(Instruction::Store(0), Some(temp.clone()));
+                   (temp, 1)
+               }
+           })
+    }
+
+    /// Returns the label of a memory cell holding the value of the given expression,
+    /// if there is one (i.e. if the expression is a variable or literal).
+    fn operand(&mut self, expr: &Expr) -> Result<Option<String>> {
+        Ok(match *expr {
+            Expr::Ident(ref ident) => {
+                let label = self.lookup(ident);
+                if self.arrays.contains(&label) {
+                    // The value of an array is its address, which has to be computed
+                    None
+                } else {
+                    Some(label)
+                }
+            }
+            Expr::Literal(Literal::Int(n, _)) => Some(self.constant(n)),
+            Expr::Literal(Literal::Str(_)) => unreachable!("string literal used as a value"),
+            _ => None,
+        })
+    }
+
+    /// Declares a new variable (or array, if `size` is given) in the current scope, returning
+    /// its label.
+    ///
+    /// The elements of an array after the first are given labels with numeric suffixes
+    /// (e.g. `a._1`), so they can be saved and restored along with the rest of a frame.
+    fn declare(&mut self, decl: &Decl, size: Option<u16>) -> String {
+        let name = &decl.name.0;
+        let base = match self.frame {
+            Some(ref frame) => format!("{}.{}", frame.name, name),
+            None => name.clone(),
+        };
+        let count = self.decl_counts.entry(base.clone()).or_insert(0);
+        let label = if *count == 0 {
+            base
+        } else {
+            format!("{}.{}", base, count)
+        };
+        *count += 1;
+
+        self.scopes.last_mut().unwrap().insert(name.clone(), label.clone());
+        let cells = Some(label.clone()).into_iter().chain((1..size.unwrap_or(1)).map(|i| format!("{}._{}", label, i)));
+        self.area().alloc(cells);
+        if size.is_some() {
+            self.arrays.insert(label.clone());
+        }
+        label
+    }
+
+    /// Returns the label of the variable with the given name.
+    fn lookup(&self, ident: &Ident) -> String {
+        self.scopes
+            .iter()
+            .rev()
+            .filter_map(|scope| scope.get(&ident.0))
+            .next()
+            .unwrap_or_else(|| panic!("use of undeclared variable `{}`", ident.0))
+            .clone()
+    }
+
+    /// Returns the label of the constant pool cell containing the given value.
+    fn constant(&mut self, value: u16) -> String {
+        self.consts.insert(value);
+        const_label(value)
+    }
+
+    /// Returns the label of the temporary cell with the given index.
+    fn temp_label(&self, i: usize) -> String {
+        match self.frame {
+            Some(ref frame) => format!("{}._t{}", frame.name, i),
+            None => format!("_t{}", i),
+        }
+    }
+
+    /// Allocates a new temporary cell, returning its label.
+    fn alloc_temp(&mut self) -> String {
+        let label = self.temp_label(self.temps);
+        self.temps += 1;
+        if self.temps > self.max_temps {
+            self.max_temps = self.temps;
+        }
+        label
+    }
+
+    /// Frees the most recently allocated temporary cell.
+    fn free_temp(&mut self) {
+        self.temps -= 1;
+    }
+
+    /// Returns a new, unique label for use in control flow.
+    fn new_label(&mut self) -> String {
+        self.labels += 1;
+        format!("_L{}", self.labels - 1)
+    }
+
+    /// Attaches a label to the next line of code.
+    fn label(&mut self, label: String) {
+        self.pending.push(label);
+    }
+
+    /// Emits a single instruction.
+    fn emit(&mut self, instr: Instruction, addr: Option<String>) {
+        self.push(Op::Instr(instr, addr));
+    }
+
+    /// Emits a data word.
+    fn data(&mut self, word: u16) {
+        self.push(Op::Data(word));
+    }
+
+    /// Adds a line of code, attaching any pending labels and the current source line (if any).
+    fn push(&mut self, op: Op) {
+        let labels = self.pending.drain(..).collect();
+        let line = Some(self.line).filter(|&line| line > 0);
+        self.code.push(Line { labels, op, line });
+    }
+}
+
+/// Returns the label of the constant pool cell with the given value.
+fn const_label(value: u16) -> String {
+    format!("#{:04x}", value)
+}
+
+/// Returns the label of the cell holding a `load` instruction for the cell with the given label.
+fn address_label(label: &str) -> String {
+    format!("&{}", label)
+}
+
+/// Returns the instruction corresponding to the given binary operation.
+fn instruction(op: &BinOp) -> Instruction {
+    match *op {
+        BinOp::Add => Instruction::Add(0),
+        BinOp::Sub => Instruction::Sub(0),
+        BinOp::And => Instruction::And(0),
+        BinOp::Or => Instruction::Or(0),
+        BinOp::Xor => Instruction::Xor(0),
+        _ => unreachable!("operation `{:?}` does not correspond to an instruction", op),
+    }
+}
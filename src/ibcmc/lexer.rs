@@ -2,6 +2,7 @@
 
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::io::Result as IoResult;
+use std::str;
 
 use itertools::{self, PutBack};
 
@@ -14,12 +15,48 @@ pub enum Token {
     Add,
     /// `-`
     Sub,
+    /// `*`
+    Mul,
+    /// `/`
+    Div,
+    /// `%`
+    Mod,
+    /// `&`
+    And,
+    /// `|`
+    Or,
+    /// `^`
+    Xor,
+    /// `~`
+    Not,
+    /// `!`
+    LogicalNot,
+    /// `&&` (not supported, but lexed so that the parser can report it)
+    LogicalAnd,
+    /// `||` (not supported, but lexed so that the parser can report it)
+    LogicalOr,
+    /// `<<`
+    Shl,
+    /// `>>`
+    Shr,
     /// `=`
     Assign,
     /// `+=`
     AddAssign,
     /// `-=`
     SubAssign,
+    /// `==`
+    Eq,
+    /// `!=`
+    Ne,
+    /// `<`
+    Lt,
+    /// `<=`
+    Le,
+    /// `>`
+    Gt,
+    /// `>=`
+    Ge,
     /// `;`
     Semi,
     /// `,`
@@ -28,6 +65,10 @@ pub enum Token {
     LParen,
     /// `)`
     RParen,
+    /// `[`
+    LBracket,
+    /// `]`
+    RBracket,
     /// `{`
     LBrace,
     /// `}`
@@ -44,20 +85,40 @@ pub enum Token {
 impl Display for Token {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         match self {
-            &Token::Add => write!(f, "+"),
-            &Token::Sub => write!(f, "-"),
-            &Token::Assign => write!(f, "="),
-            &Token::AddAssign => write!(f, "+="),
-            &Token::SubAssign => write!(f, "-="),
-            &Token::Semi => write!(f, ";"),
-            &Token::Comma => write!(f, ","),
-            &Token::LParen => write!(f, "("),
-            &Token::RParen => write!(f, ")"),
-            &Token::LBrace => write!(f, "{{"),
-            &Token::RBrace => write!(f, "}}"),
-            &Token::Ident(ref ident) => write!(f, "{}", ident),
-            &Token::Keyword(ref keyword) => write!(f, "{}", keyword),
-            &Token::Literal(ref literal) => write!(f, "{}", literal),
+            Token::Add => write!(f, "+"),
+            Token::Sub => write!(f, "-"),
+            Token::Mul => write!(f, "*"),
+            Token::Div => write!(f, "/"),
+            Token::Mod => write!(f, "%"),
+            Token::And => write!(f, "&"),
+            Token::Or => write!(f, "|"),
+            Token::Xor => write!(f, "^"),
+            Token::Not => write!(f, "~"),
+            Token::LogicalNot => write!(f, "!"),
+            Token::LogicalAnd => write!(f, "&&"),
+            Token::LogicalOr => write!(f, "||"),
+            Token::Shl => write!(f, "<<"),
+            Token::Shr => write!(f, ">>"),
+            Token::Assign => write!(f, "="),
+            Token::AddAssign => write!(f, "+="),
+            Token::SubAssign => write!(f, "-="),
+            Token::Eq => write!(f, "=="),
+            Token::Ne => write!(f, "!="),
+            Token::Lt => write!(f, "<"),
+            Token::Le => write!(f, "<="),
+            Token::Gt => write!(f, ">"),
+            Token::Ge => write!(f, ">="),
+            Token::Semi => write!(f, ";"),
+            Token::Comma => write!(f, ","),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::LBracket => write!(f, "["),
+            Token::RBracket => write!(f, "]"),
+            Token::LBrace => write!(f, "{{"),
+            Token::RBrace => write!(f, "}}"),
+            Token::Ident(ident) => write!(f, "{}", ident),
+            Token::Keyword(keyword) => write!(f, "{}", keyword),
+            Token::Literal(literal) => write!(f, "{}", literal),
         }
     }
 }
@@ -75,20 +136,50 @@ impl Display for Ident {
 /// A keyword.
 #[derive(Clone,Debug,PartialEq,Eq)]
 pub enum Keyword {
+    /// `bool`
+    Bool,
+    /// `break`
+    Break,
+    /// `char`
+    Char,
     /// `const`
     Const,
+    /// `continue`
+    Continue,
+    /// `else`
+    Else,
+    /// `for`
+    For,
+    /// `if`
+    If,
     /// `int`
     Int,
+    /// `return`
+    Return,
+    /// `unsigned`
+    Unsigned,
     /// `void`
     Void,
+    /// `while`
+    While,
 }
 
 impl Display for Keyword {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         match *self {
+            Keyword::Bool => write!(f, "bool"),
+            Keyword::Break => write!(f, "break"),
+            Keyword::Char => write!(f, "char"),
             Keyword::Const => write!(f, "const"),
+            Keyword::Continue => write!(f, "continue"),
+            Keyword::Else => write!(f, "else"),
+            Keyword::For => write!(f, "for"),
+            Keyword::If => write!(f, "if"),
             Keyword::Int => write!(f, "int"),
+            Keyword::Return => write!(f, "return"),
+            Keyword::Unsigned => write!(f, "unsigned"),
             Keyword::Void => write!(f, "void"),
+            Keyword::While => write!(f, "while"),
         }
     }
 }
@@ -96,26 +187,98 @@ impl Display for Keyword {
 /// A literal.
 #[derive(Clone,Debug,PartialEq,Eq)]
 pub enum Literal {
-    /// An integer literal.
-    Int(u16),
+    /// An integer literal, along with the way it was written.
+    Int(u16, IntFormat),
+    /// A string literal.
+    ///
+    /// Each character stands for a single byte (so it is at most `'\u{ff}'`): ASCII characters
+    /// are written as themselves, and higher bytes can only be written using `\xHH`.
+    Str(String),
+}
+
+/// The way an integer literal was written in the source code (so that it can be printed back in
+/// the same way).
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum IntFormat {
+    /// A decimal literal (also used for integers which don't come from the source code).
+    Dec,
+    /// A hexadecimal literal, with the given number of digits (including any leading zeros).
+    Hex(usize),
+    /// A character literal.
+    Char,
 }
 
 impl Display for Literal {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         match *self {
-            Literal::Int(n) => write!(f, "int_lit({})", n),
+            Literal::Int(n, _) => write!(f, "int_lit({})", n),
+            Literal::Str(ref s) => write!(f, "str_lit({:?})", s),
         }
     }
 }
 
+/// A position in the source code.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq,PartialOrd,Ord)]
+pub struct Pos {
+    /// The line number (starting from 1).
+    pub line: usize,
+    /// The column number (starting from 1, and counted in bytes).
+    pub col: usize,
+}
+
+/// The span of a token in the source code.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct Span {
+    /// The position of the first character of the token.
+    pub start: Pos,
+    /// The position just after the last character of the token.
+    pub end: Pos,
+}
+
+/// A comment, which the lexer skips but keeps so that it can be printed back (see the `printer`
+/// module).
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Comment {
+    /// The text of the comment, including the `//` or `/*` and `*/` (but not the newline ending
+    /// a `//` comment).
+    pub text: String,
+    /// The position of the start of the comment.
+    pub pos: Pos,
+    /// Whether the comment follows a token on the same line.
+    pub after_code: bool,
+}
+
+/// The comments in a program, in order, along with the positions of the code around them (which
+/// the printer needs in order to put each comment back in the same place).
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct Comments {
+    /// The comments.
+    pub comments: Vec<Comment>,
+    /// The position of each statement, `else` and `}`, in order (these are the places where the
+    /// printer may start a new line).
+    ///
+    /// The lexer only knows about `else`s and `}`s; the positions of statements are added by the
+    /// parser.
+    pub breaks: Vec<Pos>,
+}
+
 /// Represents the state of the lexer.
 pub struct Lexer<I>
     where I: Iterator<Item = IoResult<u8>>
 {
     input: PutBack<I>,
-    line: usize,
+    /// The position of the next character of input.
+    pos: Pos,
+    /// The span of the last token returned.
+    span: Span,
+    /// The span of the token returned before the last one (for use with the `put_back` method).
+    prev_span: Span,
     /// Buffer of tokens (for use with the `put_back` method).
-    buf: Vec<Token>,
+    buf: Vec<(Token, Span)>,
+    /// The line on which the last token lexed ended (or 0 if there is none yet).
+    last_line: usize,
+    /// The comments skipped so far.
+    comments: Comments,
 }
 
 impl<I> Lexer<I>
@@ -127,19 +290,36 @@ impl<I> Lexer<I>
     {
         Lexer {
             input: itertools::put_back(input),
-            line: 1,
+            pos: Pos { line: 1, col: 1 },
+            span: Span::default(),
+            prev_span: Span::default(),
             buf: Vec::new(),
+            last_line: 0,
+            comments: Comments::default(),
         }
     }
 
-    /// Returns the current line number.
+    /// Returns the line number of the last token returned.
     pub fn line(&self) -> usize {
-        self.line
+        self.span.start.line
+    }
+
+    /// Returns the span of the last token returned.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// Returns the comments skipped so far.
+    pub fn comments(&self) -> &Comments {
+        &self.comments
     }
 
     /// Puts the given token back into the lexer for later access.
+    ///
+    /// The token must be the last one returned by the lexer.
     pub fn put_back(&mut self, tok: Token) {
-        self.buf.push(tok);
+        self.buf.push((tok, self.span));
+        self.span = self.prev_span;
     }
 
     /// Returns the next token in the stream without consuming it.
@@ -154,51 +334,387 @@ impl<I> Lexer<I>
         }
     }
 
-    /// Helper method for parsing an integer literal.
-    fn parse_int_lit(&mut self) -> Result<Token> {
-        let mut int = 0;
-
-        while let Some(res) = self.input.next() {
-            match res.chain_err(|| {
-                                    ErrorKind::Lexer("could not read lexer input".into(), self.line)
-                                })? {
-                digit @ b'0'...b'9' => int = 10 * int + (digit - b'0') as u16,
-                other => {
-                    self.input.put_back(Ok(other));
+    /// Returns a lexer error with the given message, at the start of the current token.
+    fn error<S: Into<String>>(&self, msg: S) -> ErrorKind {
+        ErrorKind::Lexer(msg.into(), self.span.start.line, self.span.start.col)
+    }
+
+    /// Returns the next character of input without consuming it.
+    fn peek_byte(&mut self) -> Result<Option<u8>> {
+        match self.input.next() {
+            Some(Ok(b)) => {
+                self.input.put_back(Ok(b));
+                Ok(Some(b))
+            }
+            Some(Err(e)) => Err(Error::with_chain(e, self.error("could not read lexer input"))),
+            None => Ok(None),
+        }
+    }
+
+    /// Consumes the next character of input, updating the current position.
+    fn bump(&mut self) -> Result<Option<u8>> {
+        let b = self.peek_byte()?;
+        if b.is_some() {
+            self.input.next();
+        }
+        match b {
+            Some(b'\n') => {
+                self.pos.line += 1;
+                self.pos.col = 1;
+            }
+            Some(_) => self.pos.col += 1,
+            None => {}
+        }
+        Ok(b)
+    }
+
+    /// Consumes the next character of input if it is `b`, returning whether it was consumed.
+    fn eat(&mut self, b: u8) -> Result<bool> {
+        if self.peek_byte()? == Some(b) {
+            self.bump()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Returns the error for an unknown token starting with the given byte (which has already been
+    /// consumed), consuming the rest of the character first if it takes up several bytes.
+    fn unknown_token(&mut self, b: u8) -> Result<ErrorKind> {
+        let len = match b {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 1,
+        };
+        let mut bytes = vec![b];
+        while bytes.len() < len {
+            match self.peek_byte()? {
+                Some(b @ 0x80..=0xbf) => {
+                    self.bump()?;
+                    bytes.push(b);
+                }
+                _ => break,
+            }
+        }
+        Ok(match str::from_utf8(&bytes) {
+               Ok(c) => self.error(format!("unknown token `{}`", c)),
+               Err(_) => self.error("input is not valid UTF-8"),
+           })
+    }
+
+    /// Skips whitespace.
+    fn skip_whitespace(&mut self) -> Result<()> {
+        while let Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') = self.peek_byte()? {
+            self.bump()?;
+        }
+        Ok(())
+    }
+
+    /// Skips the rest of a comment (the opening `//` or `/*` has already been consumed, and
+    /// `block` specifies which kind of comment it is), recording it in `comments`.
+    fn skip_comment(&mut self, block: bool) -> Result<()> {
+        let mut text = if block { b"/*".to_vec() } else { b"//".to_vec() };
+        loop {
+            match self.bump()? {
+                Some(b'\n') if !block => break,
+                Some(b'*') if block && self.eat(b'/')? => {
+                    text.extend(b"*/");
                     break;
                 }
+                Some(b) => text.push(b),
+                None if block => return Err(self.error("unterminated comment").into()),
+                None => break,
             }
         }
 
-        Ok(Token::Literal(Literal::Int(int)))
+        let pos = self.span.start;
+        self.comments.comments.push(Comment {
+                                        text: String::from_utf8_lossy(&text).trim_end().to_owned(),
+                                        pos,
+                                        after_code: self.last_line == pos.line,
+                                    });
+        Ok(())
+    }
+
+    /// Helper method for parsing an integer literal (decimal, or hexadecimal with a `0x` prefix).
+    fn parse_int_lit(&mut self) -> Result<Token> {
+        let radix = if self.eat(b'0')? {
+            if self.eat(b'x')? || self.eat(b'X')? {
+                match self.peek_byte()? {
+                    Some(b) if b.is_ascii_hexdigit() => 16,
+                    _ => return Err(self.error("expected hexadecimal digits after `0x`").into()),
+                }
+            } else {
+                10
+            }
+        } else {
+            10
+        };
+
+        // The rest of the literal is consumed even if it overflows, so that the parser can
+        // recover from the error cleanly
+        let mut int = Some(0u16);
+        let mut digits = 0;
+        while let Some(b) = self.peek_byte()? {
+            let digit = match (b as char).to_digit(radix) {
+                Some(digit) => digit,
+                None => break,
+            };
+            self.bump()?;
+            digits += 1;
+            int = int.and_then(|int| int.checked_mul(radix as u16))
+                .and_then(|int| int.checked_add(digit as u16));
+        }
+        let int = match int {
+            Some(int) => int,
+            None => return Err(self.error("integer literal is too large (the maximum is 65535)").into()),
+        };
+        let format = if radix == 16 { IntFormat::Hex(digits) } else { IntFormat::Dec };
+
+        Ok(Token::Literal(Literal::Int(int, format)))
+    }
+
+    /// Helper method for parsing an escape sequence in a character or string literal (the `\`
+    /// has already been consumed).
+    ///
+    /// The supported escapes are `\n`, `\t`, `\r`, `\0`, `\\`, `\'`, `\"` and `\xHH`.
+    fn parse_escape(&mut self) -> Result<u8> {
+        Ok(match self.bump()? {
+               Some(b'n') => b'\n',
+               Some(b't') => b'\t',
+               Some(b'r') => b'\r',
+               Some(b'0') => 0,
+               Some(b'\\') => b'\\',
+               Some(b'\'') => b'\'',
+               Some(b'"') => b'"',
+               Some(b'x') => {
+                   let mut value = 0;
+                   for _ in 0..2 {
+                       // The character after the digits is left alone, since it may end the literal
+                       match self.peek_byte()?.and_then(|b| (b as char).to_digit(16)) {
+                           Some(digit) => {
+                               self.bump()?;
+                               value = 16 * value + digit as u8;
+                           }
+                           None => return Err(self.error("expected two hexadecimal digits after `\\x`").into()),
+                       }
+                   }
+                   value
+               }
+               Some(b) => return Err(self.error(format!("unknown escape sequence `\\{}`", b as char)).into()),
+               None => return Err(self.error("unterminated escape sequence").into()),
+           })
+    }
+
+    /// Helper method for parsing a character literal (the opening `'` has already been consumed),
+    /// which is just an integer literal with the value of the character.
+    ///
+    /// If the literal is malformed, the rest of it (up to the closing `'` or the end of the line)
+    /// is skipped, so that lexing can resume after it.
+    fn parse_char_lit(&mut self) -> Result<Token> {
+        let c = match self.bump()? {
+            Some(b'\\') => self.parse_escape(),
+            Some(b'\'') => return Err(self.error("empty character literal").into()),
+            Some(b'\n') | None => return Err(self.error("unterminated character literal").into()),
+            Some(b) if b.is_ascii() => Ok(b),
+            Some(_) => Err(self.error("character literal must be an ASCII character").into()),
+        };
+        match c {
+            Ok(c) if self.eat(b'\'')? => {
+                Ok(Token::Literal(Literal::Int(c as u16, IntFormat::Char)))
+            }
+            Ok(_) => {
+                self.skip_char_lit()?;
+                Err(self.error("expected `'` to end character literal").into())
+            }
+            Err(e) => {
+                self.skip_char_lit()?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Skips the rest of a malformed character literal, up to and including the closing `'`, or
+    /// up to the end of the line if there isn't one.
+    fn skip_char_lit(&mut self) -> Result<()> {
+        loop {
+            match self.peek_byte()? {
+                Some(b'\n') | None => return Ok(()),
+                Some(b) => {
+                    self.bump()?;
+                    if b == b'\'' {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Helper method for parsing a string literal (the opening `"` has already been consumed).
+    ///
+    /// If the literal is malformed, the rest of it (up to the closing `"` or the end of the line)
+    /// is skipped, so that lexing can resume after it.
+    fn parse_str_lit(&mut self) -> Result<Token> {
+        let mut bytes = Vec::new();
+
+        loop {
+            match self.bump()? {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    match self.parse_escape() {
+                        Ok(b) => bytes.push(b),
+                        Err(e) => {
+                            self.skip_str_lit()?;
+                            return Err(e);
+                        }
+                    }
+                }
+                Some(b'\n') | None => return Err(self.error("unterminated string literal").into()),
+                Some(b) if b.is_ascii() => bytes.push(b),
+                Some(_) => {
+                    self.skip_str_lit()?;
+                    return Err(self.error("string literal must only contain ASCII characters").into());
+                }
+            }
+        }
+
+        Ok(Token::Literal(Literal::Str(bytes.into_iter().map(|b| b as char).collect())))
+    }
+
+    /// Skips the rest of a malformed string literal, up to and including the closing `"`, or up to
+    /// the end of the line if there isn't one.
+    ///
+    /// Escaped quotes (`\"`) don't end the literal.
+    fn skip_str_lit(&mut self) -> Result<()> {
+        loop {
+            match self.peek_byte()? {
+                Some(b'\n') | None => return Ok(()),
+                Some(b) => {
+                    self.bump()?;
+                    match b {
+                        b'"' => return Ok(()),
+                        b'\\' if self.peek_byte()? != Some(b'\n') => {
+                            self.bump()?;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Helper method for parsing a token which may be followed by `=` (e.g. `<` or `<=`).
+    ///
+    /// Returns `with_eq` if the next character is `=` (consuming it), and `without_eq` otherwise.
+    fn maybe_followed_by_eq(&mut self, without_eq: Token, with_eq: Token) -> Result<Token> {
+        Ok(if self.eat(b'=')? { with_eq } else { without_eq })
     }
 
     /// Helper method for parsing a word (identfier or keyword).
     fn parse_word(&mut self) -> Result<Token> {
         let mut word = String::new();
 
-        while let Some(res) = self.input.next() {
-            match res.chain_err(|| {
-                                    ErrorKind::Lexer("could not read lexer input".into(), self.line)
-                                })? {
-                letter @ b'A'...b'Z' |
-                letter @ b'a'...b'z' |
-                letter @ b'0'...b'9' => word.push(letter as char),
-                other => {
-                    self.input.put_back(Ok(other));
-                    break;
+        while let Some(b) = self.peek_byte()? {
+            match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => {
+                    self.bump()?;
+                    word.push(b as char);
                 }
+                _ => break,
             }
         }
 
         // Check to see if we have a keyword
         Ok(match word.as_str() {
+               "bool" => Token::Keyword(Keyword::Bool),
+               "break" => Token::Keyword(Keyword::Break),
+               "char" => Token::Keyword(Keyword::Char),
                "const" => Token::Keyword(Keyword::Const),
+               "continue" => Token::Keyword(Keyword::Continue),
+               "else" => Token::Keyword(Keyword::Else),
+               "for" => Token::Keyword(Keyword::For),
+               "if" => Token::Keyword(Keyword::If),
                "int" => Token::Keyword(Keyword::Int),
+               "return" => Token::Keyword(Keyword::Return),
+               "unsigned" => Token::Keyword(Keyword::Unsigned),
                "void" => Token::Keyword(Keyword::Void),
+               "while" => Token::Keyword(Keyword::While),
                _ => Token::Ident(Ident(word)),
            })
     }
+
+    /// Lexes the next token, returning `None` at the end of the input.
+    fn token(&mut self) -> Result<Option<Token>> {
+        // Skip any whitespace and comments before the token
+        let b = loop {
+            self.skip_whitespace()?;
+            self.prev_span = self.span;
+            self.span = Span {
+                start: self.pos,
+                end: self.pos,
+            };
+            match self.peek_byte()? {
+                Some(b'/') => {
+                    self.bump()?;
+                    if self.eat(b'/')? {
+                        self.skip_comment(false)?;
+                    } else if self.eat(b'*')? {
+                        self.skip_comment(true)?;
+                    } else {
+                        self.span.end = self.pos;
+                        return Ok(Some(Token::Div));
+                    }
+                    self.span = self.prev_span;
+                }
+                Some(b) => break b,
+                None => return Ok(None),
+            }
+        };
+        let tok = match b {
+            b'0'..=b'9' => self.parse_int_lit()?,
+            b'A'..=b'Z' | b'a'..=b'z' => self.parse_word()?,
+            _ => {
+                self.bump()?;
+                match b {
+                    b'+' => self.maybe_followed_by_eq(Token::Add, Token::AddAssign)?,
+                    b'-' => self.maybe_followed_by_eq(Token::Sub, Token::SubAssign)?,
+                    b'=' => self.maybe_followed_by_eq(Token::Assign, Token::Eq)?,
+                    b'<' if self.eat(b'<')? => Token::Shl,
+                    b'<' => self.maybe_followed_by_eq(Token::Lt, Token::Le)?,
+                    b'>' if self.eat(b'>')? => Token::Shr,
+                    b'>' => self.maybe_followed_by_eq(Token::Gt, Token::Ge)?,
+                    b'!' => self.maybe_followed_by_eq(Token::LogicalNot, Token::Ne)?,
+                    b'*' => Token::Mul,
+                    b'%' => Token::Mod,
+                    b'&' if self.eat(b'&')? => Token::LogicalAnd,
+                    b'&' => Token::And,
+                    b'|' if self.eat(b'|')? => Token::LogicalOr,
+                    b'|' => Token::Or,
+                    b'^' => Token::Xor,
+                    b'~' => Token::Not,
+                    b';' => Token::Semi,
+                    b',' => Token::Comma,
+                    b'(' => Token::LParen,
+                    b')' => Token::RParen,
+                    b'[' => Token::LBracket,
+                    b']' => Token::RBracket,
+                    b'{' => Token::LBrace,
+                    b'}' => Token::RBrace,
+                    b'\'' => self.parse_char_lit()?,
+                    b'"' => self.parse_str_lit()?,
+                    _ => return Err(self.unknown_token(b)?.into()),
+                }
+            }
+        };
+        self.span.end = self.pos;
+        self.last_line = self.pos.line;
+        if let Token::Keyword(Keyword::Else) | Token::RBrace = tok {
+            self.comments.breaks.push(self.span.start);
+        }
+
+        Ok(Some(tok))
+    }
 }
 
 impl<I> Iterator for Lexer<I>
@@ -207,68 +723,16 @@ impl<I> Iterator for Lexer<I>
     type Item = Result<Token>;
 
     fn next(&mut self) -> Option<Result<Token>> {
-        if let Some(tok) = self.buf.pop() {
+        if let Some((tok, span)) = self.buf.pop() {
+            self.prev_span = self.span;
+            self.span = span;
             return Some(Ok(tok));
         }
 
-        match self.input.next() {
-            Some(Ok(b)) => {
-                Some(Ok(match b {
-                            b'+' => {
-                                match self.input.next() {
-                                    Some(Ok(b'=')) => Token::AddAssign,
-                                    Some(res) => {
-                                        self.input.put_back(res);
-                                        Token::Add
-                                    }
-                                    None => Token::Add,
-                                }
-                            }
-                            b'-' => {
-                                match self.input.next() {
-                                    Some(Ok(b'=')) => Token::SubAssign,
-                                    Some(res) => {
-                                        self.input.put_back(res);
-                                        Token::Sub
-                                    }
-                                    None => Token::Sub,
-                                }
-                            }
-                            b'=' => Token::Assign,
-                            b';' => Token::Semi,
-                            b',' => Token::Comma,
-                            b'(' => Token::LParen,
-                            b')' => Token::RParen,
-                            b'{' => Token::LBrace,
-                            b'}' => Token::RBrace,
-                            b'0'...b'9' => {
-                                self.input.put_back(Ok(b));
-                                return Some(self.parse_int_lit());
-                            }
-                            b'\n' => {
-                                self.line += 1;
-                                return self.next();
-                            }
-                            b' ' | b'\t' | b'\r' => return self.next(),
-                            b'A'...b'Z' | b'a'...b'z' => {
-                                self.input.put_back(Ok(b));
-                                return Some(self.parse_word());
-                            }
-                            _ => {
-                                return Some(Err(ErrorKind::Lexer(format!("unknown token `{}`",
-                                                                         b as char),
-                                                                 self.line)
-                                                    .into()))
-                            }
-                        }))
-            }
-            Some(Err(e)) => {
-                Some(Err(Error::with_chain(e,
-                                           ErrorKind::Lexer("could not read lexer input".into(),
-                                                            self.line))))
-            }
-            None => None,
+        match self.token() {
+            Ok(Some(tok)) => Some(Ok(tok)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
         }
     }
 }
-
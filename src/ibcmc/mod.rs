@@ -5,35 +5,302 @@ pub mod errors {
 
     error_chain!{
         errors {
-            /// A lexer error.
-            Lexer(s: String, n: usize) {
+            /// A lexer error (with the line and column of the token where it occurred).
+            Lexer(s: String, n: usize, col: usize) {
                 description("lexer error")
-                display("lexer error on line {}: {}", n, s)
+                display("lexer error on line {}, column {}: {}", n, col, s)
             }
 
-            /// A parser error.
-            Parser(s: String, n: usize) {
+            /// A parser error (with the line and column of the token where it occurred).
+            Parser(s: String, n: usize, col: usize) {
                 description("parser error")
-                display("parser error on line {}: {}", n, s)
+                display("parser error on line {}, column {}: {}", n, col, s)
+            }
+
+            /// A semantic error (e.g. use of an undeclared variable).
+            Semantic(s: String, n: usize) {
+                description("semantic error")
+                display("semantic error on line {}: {}", n, s)
+            }
+
+            /// A code generation error.
+            Codegen(s: String, n: usize) {
+                description("code generation error")
+                display("code generation error on line {}: {}", n, s)
             }
         }
     }
 }
 
+pub mod alloc;
 pub mod ast;
+pub mod check;
+pub mod codegen;
 pub mod lexer;
+pub mod optimize;
 pub mod parser;
+pub mod printer;
+pub mod runtime;
+
+use std::io::{BufReader, Read};
+
+use {DebugInfo, SourceMap, WordInfo, WordKind};
 
+pub use self::check::Checker;
+pub use self::codegen::Codegen;
 pub use self::lexer::Lexer;
 pub use self::parser::Parser;
 
+/// Parses the IBCMC program from the given reader, recovering from syntax errors so that all of
+/// them can be reported at once.
+///
+/// Returns the syntax tree, which contains every statement that could be parsed (so it can
+/// still be used by tooling when the program has errors), along with all the lexer and parser
+/// errors, in the order in which they occur.
+///
+/// # Examples
+///
+/// ```
+/// use ibcm::ibcmc;
+///
+/// let program = "int a = ;
+/// int b = 2;
+/// b = (b + 1;";
+///
+/// let (ast, errors) = ibcmc::parse(program.as_bytes());
+/// assert_eq!(2, errors.len());
+/// // Only `int b = 2;` could be parsed
+/// assert_eq!(1, ast.0.len());
+/// ```
+pub fn parse<R: Read>(input: R) -> (ast::Block, Vec<errors::Error>) {
+    Parser::parse_recovering(Lexer::new(BufReader::new(input).bytes()))
+}
+
+/// Formats the IBCMC program from the given reader in the canonical style (see the `printer`
+/// module), returning the first syntax error if it can't be parsed.
+///
+/// Comments and the way integer literals are written are preserved.
+///
+/// # Examples
+///
+/// ```
+/// use ibcm::ibcmc;
+///
+/// let program = "int a=(1+2)*0x03;while(a>0){a-=1;} // count down";
+///
+/// assert_eq!("int a = (1 + 2) * 0x03;\nwhile (a > 0) {\n    a -= 1;\n} // count down\n",
+///            ibcmc::format(program.as_bytes()).unwrap());
+/// ```
+pub fn format<R: Read>(input: R) -> errors::Result<String> {
+    let (ast, comments) = Parser::parse_with_comments(Lexer::new(BufReader::new(input).bytes()))?;
+    Ok(printer::print_with_comments(&ast, &comments))
+}
+
+/// Formats an error from compiling the given source code for display, showing the offending
+/// source line with a caret under the location of the error.
+///
+/// Semantic and code generation errors only have a line number, so the caret points at the start
+/// of the statement on that line. Errors without a source location are formatted as usual.
+///
+/// # Examples
+///
+/// ```
+/// use ibcm::ibcmc;
+///
+/// let program = "int a = 1;
+/// a = (a + 1;";
+///
+/// let e = ibcmc::compile(program.as_bytes()).unwrap_err();
+/// assert_eq!("parser error: expected `)`, got `;`
+///  --> foo.ibcmc:2:11
+///   |
+/// 2 | a = (a + 1;
+///   |           ^
+/// ",
+///            ibcmc::format_error(&e, "foo.ibcmc", program));
+/// ```
+pub fn format_error(error: &errors::Error, file: &str, source: &str) -> String {
+    use self::errors::ErrorKind;
+
+    let (kind, msg, line, col) = match *error.kind() {
+        ErrorKind::Lexer(ref s, n, col) => ("lexer error", s, n, Some(col)),
+        ErrorKind::Parser(ref s, n, col) => ("parser error", s, n, Some(col)),
+        ErrorKind::Semantic(ref s, n) => ("semantic error", s, n, None),
+        ErrorKind::Codegen(ref s, n) => ("code generation error", s, n, None),
+        _ => return format!("error: {}\n", error),
+    };
+    ::format_snippet(kind, msg, file, line, col.map(|col| (col, col + 1)), source)
+}
+
+/// Compiles the IBCMC program from the given reader into IBCM assembly.
+///
+/// The output can be assembled using the `Assembler`.
+///
+/// # Examples
+///
+/// ```
+/// use ibcm::{Assembler, Simulator};
+/// use ibcm::ibcmc;
+///
+/// let program = "int a = 2;
+/// int b = a + 3;";
+///
+/// let asm = ibcmc::compile(program.as_bytes()).unwrap();
+/// let assembled = Assembler::assemble(asm.as_bytes()).unwrap();
+/// let mut sim = Simulator::from_instructions(assembled.data()).unwrap();
+/// sim.run().unwrap();
+///
+/// assert_eq!(5, sim.memory()[assembled.labels()["b"] as usize]);
+/// ```
+pub fn compile<R: Read>(input: R) -> errors::Result<String> {
+    compile_optimized(input, 0)
+}
+
+/// Compiles the IBCMC program from the given reader into IBCM assembly, optimizing it at the
+/// given level (see the `optimize` module for a description of the levels).
+///
+/// # Examples
+///
+/// ```
+/// use ibcm::{Assembler, Simulator};
+/// use ibcm::ibcmc;
+///
+/// let program = "int a = 2 * 3 + 1;
+/// int b = a * 4;";
+///
+/// let asm = ibcmc::compile_optimized(program.as_bytes(), 2).unwrap();
+/// // Both multiplications are optimized away, so the runtime library isn't needed
+/// assert!(!asm.contains("_mul"));
+///
+/// let assembled = Assembler::assemble(asm.as_bytes()).unwrap();
+/// let mut sim = Simulator::from_instructions(assembled.data()).unwrap();
+/// sim.run().unwrap();
+///
+/// assert_eq!(28, sim.memory()[assembled.labels()["b"] as usize]);
+/// ```
+pub fn compile_optimized<R: Read>(input: R, level: u8) -> errors::Result<String> {
+    compile_with_source_map(input, level).map(|(asm, _)| asm)
+}
+
+/// Compiles the IBCMC program from the given reader into IBCM assembly, optimizing it at the
+/// given level and returning a source map which gives the source line of each address in the
+/// assembled program.
+///
+/// # Examples
+///
+/// ```
+/// use ibcm::{Assembler, ErrorKind, Simulator};
+/// use ibcm::ibcmc;
+///
+/// let program = "int a = 2;
+/// int b = readh();";
+///
+/// let (asm, mut source_map) = ibcmc::compile_with_source_map(program.as_bytes(), 0).unwrap();
+/// source_map.set_file("foo.ibcmc");
+/// let assembled = Assembler::assemble(asm.as_bytes()).unwrap();
+/// let mut sim = Simulator::from_instructions(assembled.data()).unwrap();
+/// sim.set_input("oops".as_bytes());
+/// sim.set_source_map(source_map);
+///
+/// match *sim.run().unwrap_err().kind() {
+///     ErrorKind::Runtime(ref loc) => assert_eq!("foo.ibcmc line 2", loc),
+///     ref e => panic!("unexpected error: {}", e),
+/// }
+/// ```
+pub fn compile_with_source_map<R: Read>(input: R, level: u8) -> errors::Result<(String, SourceMap)> {
+    let code = compile_to_lines(input, level)?;
+    Ok((code.iter().map(|line| format!("{}\n", line)).collect(), source_map(&code)))
+}
+
+/// Returns a source map giving the IBCMC source line of each word of a compiled program.
+pub fn source_map(code: &[codegen::Line]) -> SourceMap {
+    // Each line is assembled into exactly one word
+    SourceMap::new(code.iter().map(|line| line.line).collect())
+}
+
+/// Returns the debug information of a compiled program, given the words it was assembled into
+/// and the name and contents of its source file.
+///
+/// Words generated without a source line (such as those of the runtime library) have no line
+/// or text.
+///
+/// # Examples
+///
+/// ```
+/// use ibcm::{Assembler, WordKind};
+/// use ibcm::ibcmc;
+///
+/// let source = "int a = 2;\nprinth(a);";
+/// let code = ibcmc::compile_to_lines(source.as_bytes(), 0).unwrap();
+/// let asm = code.iter().map(|line| format!("{}\n", line)).collect::<String>();
+/// let program = Assembler::assemble(asm.as_bytes()).unwrap();
+///
+/// let info = ibcmc::debug_info(&code, program.data(), "foo.ibcmc", source);
+/// let printh = info.words().iter().find(|word| word.line == Some(2)).unwrap();
+/// assert_eq!((Some("foo.ibcmc"), WordKind::Code, "printh(a);"),
+///            (printh.file.as_deref(), printh.kind, printh.text.as_str()));
+/// ```
+pub fn debug_info(code: &[codegen::Line], words: &[u16], file: &str, source: &str) -> DebugInfo {
+    let lines = source.lines().collect::<Vec<_>>();
+    let words = code.iter()
+        .zip(words)
+        .map(|(line, &value)| {
+            WordInfo {
+                value,
+                file: Some(file.to_owned()),
+                line: line.line,
+                kind: match line.op {
+                    codegen::Op::Data(_) => WordKind::Data,
+                    codegen::Op::Instr(..) => WordKind::Code,
+                },
+                text: line.line
+                    .and_then(|n| lines.get(n - 1))
+                    .map_or_else(String::new, |text| text.to_string()),
+            }
+        })
+        .collect();
+    DebugInfo::new(words)
+}
+
+/// Compiles the IBCMC program from the given reader into lines of IBCM assembly, optimizing it at
+/// the given level and checking that it fits in memory.
+///
+/// # Examples
+///
+/// ```
+/// use ibcm::ibcmc;
+/// use ibcm::ibcmc::alloc::MemoryUsage;
+///
+/// let code = ibcmc::compile_to_lines("int a[4000];".as_bytes(), 0).unwrap();
+/// assert_eq!(4000, MemoryUsage::of(&code).data);
+///
+/// let e = ibcmc::compile_to_lines("int a[1100];\nint b[3000];".as_bytes(), 0).unwrap_err();
+/// assert_eq!("code generation error on line 2: the program needs 4101 words of memory, but the \
+///             IBCM only has 4096 (the largest variable is `b`, with 3000 words)",
+///            e.to_string());
+/// ```
+pub fn compile_to_lines<R: Read>(input: R, level: u8) -> errors::Result<Vec<codegen::Line>> {
+    let ast = Parser::parse_from_lexer(Lexer::new(BufReader::new(input).bytes()))?;
+    let ast = Checker::check(&ast)?;
+    let ast = optimize::optimize(&ast, level);
+    let mut code = Codegen::generate(&ast)?;
+    if level > 0 {
+        code = optimize::peephole(code);
+    }
+    alloc::MemoryUsage::of(&code).check(&ast)?;
+
+    Ok(code)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use super::ast::*;
     use super::errors::*;
     use super::lexer::*;
+    use std::collections::{HashMap, HashSet};
     use std::io::{Read, Cursor};
+    use {Assembler, Simulator, StopReason};
 
     fn lex(input: &[u8]) -> Vec<Token> {
         Lexer::new(Cursor::new(input).bytes())
@@ -45,6 +312,17 @@ mod tests {
         Parser::parse_from_lexer(Lexer::new(Cursor::new(input).bytes())).unwrap()
     }
 
+    /// Compiles and runs the given program, returning the final values of the given variables.
+    fn run(input: &[u8], vars: &[&str]) -> Vec<u16> {
+        let asm = compile(input).unwrap();
+        let program = Assembler::assemble(asm.as_bytes()).unwrap();
+        let mut sim = Simulator::from_instructions(program.data()).unwrap();
+        sim.run().unwrap();
+
+        let labels: &HashMap<String, u16> = program.labels();
+        vars.iter().map(|&v| sim.memory()[labels[v] as usize]).collect()
+    }
+
     #[test]
     fn tokens() {
         // Check to make sure the lexer can parse tokens correctly
@@ -60,7 +338,7 @@ mod tests {
                     Token::Keyword(Keyword::Int),
                     Token::Ident(Ident("i".into())),
                     Token::Assign,
-                    Token::Literal(Literal::Int(3)),
+                    Token::Literal(Literal::Int(3, IntFormat::Dec)),
                     Token::Semi,
                     Token::Keyword(Keyword::Void),
                     Token::Ident(Ident("function".into())),
@@ -74,15 +352,15 @@ mod tests {
                     Token::LBrace,
                     Token::Ident(Ident("local".into())),
                     Token::AddAssign,
-                    Token::Literal(Literal::Int(5)),
+                    Token::Literal(Literal::Int(5, IntFormat::Dec)),
                     Token::Add,
-                    Token::Literal(Literal::Int(2)),
+                    Token::Literal(Literal::Int(2, IntFormat::Dec)),
                     Token::Semi,
                     Token::Ident(Ident("k".into())),
                     Token::SubAssign,
-                    Token::Literal(Literal::Int(6)),
+                    Token::Literal(Literal::Int(6, IntFormat::Dec)),
                     Token::Sub,
-                    Token::Literal(Literal::Int(1)),
+                    Token::Literal(Literal::Int(1, IntFormat::Dec)),
                     Token::Semi,
                     Token::RBrace]);
     }
@@ -114,16 +392,1620 @@ mod tests {
         let parsed = parse(prog);
 
         assert_eq!(parsed,
-                   Block(vec![Stmt::Assign(Ident("i".into()), Expr::Literal(Literal::Int(2)))
+                   Block(vec![Stmt::Assign(Expr::Ident(Ident("i".into())),
+                                           Expr::Literal(Literal::Int(2, IntFormat::Dec)))
                                   .with_line(1),
-                              Stmt::CompoundAssign(Ident("j".into()),
+                              Stmt::CompoundAssign(Expr::Ident(Ident("j".into())),
                                                    BinOp::Add,
-                                                   Expr::Literal(Literal::Int(3)))
+                                                   Expr::Literal(Literal::Int(3, IntFormat::Dec)))
                                   .with_line(2),
-                              Stmt::CompoundAssign(Ident("k".into()),
+                              Stmt::CompoundAssign(Expr::Ident(Ident("k".into())),
                                                    BinOp::Sub,
-                                                   Expr::Literal(Literal::Int(4)))
+                                                   Expr::Literal(Literal::Int(4, IntFormat::Dec)))
                                   .with_line(3)]));
     }
+
+    #[test]
+    fn codegen_arithmetic() {
+        // Check that simple arithmetic is compiled correctly.
+        let prog = b"int a = 5;
+        int b = a + 3;
+        int c;
+        c = 7 + b - a;
+        a += b + c;
+        b -= 20;";
+
+        assert_eq!(run(prog, &["a", "b", "c"]), [23, 0u16.wrapping_sub(12), 10]);
+    }
+
+    #[test]
+    fn codegen_scopes() {
+        // Check that shadowed variables get their own memory cells.
+        let prog = b"int x = 1;
+        int y;
+        {
+            int x = x + 1;
+            y = x;
+            {
+                x += 10;
+                int x = 100;
+            }
+            y += x;
+        }";
+
+        assert_eq!(run(prog, &["x", "y", "x.1", "x.2"]), [1, 14, 12, 100]);
+    }
+
+    #[test]
+    fn codegen_constant_pool() {
+        // Each literal value should get exactly one cell in the constant pool.
+        let asm = compile(&b"int a = 3 + 3; int b = a + 3 - 4;"[..]).unwrap();
+
+        assert_eq!(1, asm.matches("#0003:").count());
+        assert_eq!(1, asm.matches("#0004:").count());
+    }
+
+    #[test]
+    fn codegen_errors() {
+        // Undeclared and redeclared variables should be rejected.
+        match compile(&b"int a;\nb = 2;"[..]) {
+            Err(Error(ErrorKind::Semantic(_, 2), _)) => {}
+            res => panic!("expected semantic error on line 2, got {:?}", res),
+        }
+        match compile(&b"int a;\n{ int a; }\nint a;"[..]) {
+            Err(Error(ErrorKind::Semantic(_, 3), _)) => {}
+            res => panic!("expected semantic error on line 3, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn codegen_functions() {
+        // Check function calls, including calls before definition and nested calls.
+        let prog = b"int a = add(1, 2);
+        int b;
+        int c = add(add(a, 4), add(5, a)) - 1;
+        setb(c);
+
+        int add(int x, int y) {
+            int sum = x + y;
+            return sum;
+        }
+
+        void setb(int value) {
+            b = add(value, value);
+            return;
+        }";
+
+        assert_eq!(run(prog, &["a", "b", "c"]), [3, 28, 14]);
+    }
+
+    #[test]
+    fn codegen_function_errors() {
+        let progs: &[(&[u8], usize)] = &[(b"int f(int a) { return a; }\nint x = f(1, 2);", 2),
+                                         (b"int x = g();", 1),
+                                         (b"int x;\nreturn x;", 2),
+                                         (b"void f() {\n  int g() { return 1; }\n}", 2),
+                                         (b"int f;\nvoid f() {}", 1),
+                                         (b"void f() {}\nvoid f() {}", 2)];
+        for &(prog, line) in progs {
+            match compile(prog) {
+                Err(Error(ErrorKind::Semantic(_, n), _)) if n == line => {}
+                res => panic!("expected semantic error on line {}, got {:?}", line, res),
+            }
+        }
+    }
+
+    #[test]
+    fn control_flow_ast() {
+        // Check parsing of control flow statements.
+        let prog = b"if (a == 1) b = 2; else {}
+        while (a < b)
+            break;
+        for (int i = 0; i != 10; i += 1) continue;";
+        let parsed = parse(prog);
+        let ident = |s: &str| Expr::Ident(Ident(s.into()));
+        let int = |n| Expr::Literal(Literal::Int(n, IntFormat::Dec));
+
+        assert_eq!(parsed,
+                   Block(vec![Stmt::If(Expr::BinOp(BinOp::Eq, Box::new(ident("a")), Box::new(int(1))),
+                                       Box::new(Stmt::Assign(ident("b"), int(2)).with_line(1)),
+                                       Some(Box::new(Stmt::Block(Block(vec![])).with_line(1))))
+                                  .with_line(1),
+                              Stmt::While(Expr::BinOp(BinOp::Lt,
+                                                      Box::new(ident("a")),
+                                                      Box::new(ident("b"))),
+                                          Box::new(Stmt::Break.with_line(3)))
+                                  .with_line(2),
+                              Stmt::For(Box::new(Stmt::Init(Decl {
+                                                                is_const: false,
+                                                                ty: Type::Int,
+                                                                name: Ident("i".into()),
+                                                            },
+                                                            int(0))),
+                                        Some(Expr::BinOp(BinOp::Ne,
+                                                         Box::new(ident("i")),
+                                                         Box::new(int(10)))),
+                                        Box::new(Stmt::CompoundAssign(ident("i"), BinOp::Add, int(1))),
+                                        Box::new(Stmt::Continue.with_line(4)))
+                                  .with_line(4)]));
+    }
+
+    #[test]
+    fn codegen_loops() {
+        // Sum the odd numbers below 20, skipping 7 and stopping at 15.
+        let prog = b"int sum = 0;
+        int iters = 0;
+        for (int i = 1; i < 20; i += 2) {
+            if (i == 7) continue;
+            if (i >= 15) break;
+            sum += i;
+        }
+        int n = 10;
+        while (n > 0) {
+            n -= 1;
+            iters += 1;
+        }";
+
+        assert_eq!(run(prog, &["sum", "iters", "n"]), [1 + 3 + 5 + 9 + 11 + 13, 10, 0]);
+    }
+
+    #[test]
+    fn codegen_comparisons() {
+        // Check every comparison in both value and branch context, including operands whose
+        // difference overflows.
+        let cases: &[(u16, u16)] = &[(1, 2), (2, 1), (5, 5), (0, 65535), (32767, 32768),
+                                     (32768, 32767), (32768, 32768), (65535, 1)];
+        for &(a, b) in cases {
+            let prog = format!("int a = {}; int b = {};
+            int eq = a == b; int ne = a != b; int lt = a < b;
+            int le = a <= b; int gt = a > b; int ge = a >= b;
+            int branches = 0;
+            if (a == b) branches += 1;
+            if (a != b) branches += 2;
+            if (a < b) branches += 4;
+            if (a <= b) branches += 8;
+            if (a > b) branches += 16;
+            if (a >= b) branches += 32;",
+                               a,
+                               b);
+            let (a, b) = (a as i16, b as i16);
+            let expected = [a == b, a != b, a < b, a <= b, a > b, a >= b];
+            let mut results = run(prog.as_bytes(), &["eq", "ne", "lt", "le", "gt", "ge", "branches"]);
+            let branches = results.pop().unwrap();
+
+            assert_eq!(results, expected.iter().map(|&b| b as u16).collect::<Vec<_>>(), "{} {}", a, b);
+            for (i, &b) in expected.iter().enumerate() {
+                assert_eq!(branches & (1 << i) != 0, b, "{} {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn codegen_recursion() {
+        // The recursive multiplication routine from the IBCM documentation,
+        // along with some other recursive functions.
+        let prog = b"int prod = mult(12, 11);
+        int fib10 = fib(10);
+        int even = isEven(7);
+
+        int mult(int a, int b) {
+            if (b == 0) return 0;
+            return a + mult(a, b - 1);
+        }
+
+        int fib(int n) {
+            if (n < 2) return n;
+            return fib(n - 1) + fib(n - 2);
+        }
+
+        int isEven(int n) {
+            if (n == 0) return 1;
+            return isOdd(n - 1);
+        }
+
+        int isOdd(int n) {
+            if (n == 0) return 0;
+            return isEven(n - 1);
+        }";
+
+        assert_eq!(run(prog, &["prod", "fib10", "even"]), [132, 55, 0]);
+    }
+
+    #[test]
+    fn codegen_loop_errors() {
+        for &prog in &[&b"break;"[..], &b"if (1) continue;"[..]] {
+            match compile(prog) {
+                Err(Error(ErrorKind::Semantic(_, 1), _)) => {}
+                res => panic!("expected semantic error on line 1, got {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn expr_precedence() {
+        // Check operator precedence, associativity and parentheses.
+        fn bin(op: BinOp, lhs: Expr, rhs: Expr) -> Expr {
+            Expr::BinOp(op, Box::new(lhs), Box::new(rhs))
+        }
+        fn un(op: UnOp, expr: Expr) -> Expr {
+            Expr::UnOp(op, Box::new(expr))
+        }
+        let v = |s: &str| Expr::Ident(Ident(s.into()));
+        let cases = vec![("a - b - c", bin(BinOp::Sub, bin(BinOp::Sub, v("a"), v("b")), v("c"))),
+                         ("a - (b - c)", bin(BinOp::Sub, v("a"), bin(BinOp::Sub, v("b"), v("c")))),
+                         ("a + b << c - d",
+                          bin(BinOp::Shl,
+                              bin(BinOp::Add, v("a"), v("b")),
+                              bin(BinOp::Sub, v("c"), v("d")))),
+                         ("a >> b >> c", bin(BinOp::Shr, bin(BinOp::Shr, v("a"), v("b")), v("c"))),
+                         ("a | b ^ c & d",
+                          bin(BinOp::Or, v("a"), bin(BinOp::Xor, v("b"), bin(BinOp::And, v("c"), v("d"))))),
+                         ("a & b == c < d << e",
+                          bin(BinOp::And,
+                              v("a"),
+                              bin(BinOp::Eq,
+                                  v("b"),
+                                  bin(BinOp::Lt, v("c"), bin(BinOp::Shl, v("d"), v("e")))))),
+                         ("-a + ~b", bin(BinOp::Add, un(UnOp::Neg, v("a")), un(UnOp::Not, v("b")))),
+                         ("!(a + b) & --c",
+                          bin(BinOp::And,
+                              un(UnOp::LogicalNot, bin(BinOp::Add, v("a"), v("b"))),
+                              un(UnOp::Neg, un(UnOp::Neg, v("c"))))),
+                         ("f((a), b | c)",
+                          Expr::Call(Ident("f".into()), vec![v("a"), bin(BinOp::Or, v("b"), v("c"))]))];
+
+        for (src, expected) in cases {
+            let parsed = parse(format!("x = {};", src).as_bytes());
+            assert_eq!(parsed,
+                       Block(vec![Stmt::Assign(Expr::Ident(Ident("x".into())), expected).with_line(1)]),
+                       "{}",
+                       src);
+        }
+    }
+
+    #[test]
+    fn codegen_operators() {
+        // Check each operator against the equivalent Rust computation.
+        let values: &[(i16, i16)] = &[(0, 0), (1, 3), (-7, 12), (0x1234, 0x7f0f), (-32768, 5), (12, -1)];
+        for &(a, b) in values {
+            let prog = format!("int a = {}; int b = {}; int n = 3;
+            int add = a + b; int sub = a - b - n; int and = a & b; int or = a | b;
+            int xor = a ^ b; int neg = -a; int not = ~a; int lnot = !a; int lnot2 = !!b;
+            int shl = a << 5; int shr = a >> 3; int shln = a << n; int shrn = a >> (n + 1);
+            int shl16 = b << 16; int shlb = a << b; int mixed = (a + 1) & ~(b - 2) | n << 1;",
+                               a as u16,
+                               b as u16);
+            let (au, bu) = (a as u16, b as u16);
+            let expected = [a.wrapping_add(b),
+                            a.wrapping_sub(b).wrapping_sub(3),
+                            a & b,
+                            a | b,
+                            a ^ b,
+                            a.wrapping_neg(),
+                            !a,
+                            (a == 0) as i16,
+                            (b != 0) as i16,
+                            a << 5,
+                            (au >> 3) as i16,
+                            a << 3,
+                            (au >> 4) as i16,
+                            0,
+                            au.checked_shl(bu as u32).unwrap_or(0) as i16,
+                            (a.wrapping_add(1) & !(b.wrapping_sub(2))) | (3 << 1)];
+            let results = run(prog.as_bytes(),
+                              &["add", "sub", "and", "or", "xor", "neg", "not", "lnot", "lnot2", "shl",
+                                "shr", "shln", "shrn", "shl16", "shlb", "mixed"]);
+
+            assert_eq!(results, expected.iter().map(|&n| n as u16).collect::<Vec<_>>(), "{} {}", a, b);
+        }
+    }
+
+    #[test]
+    fn codegen_mul_div() {
+        // Compile once, then check the runtime routines against Rust on random operands
+        // by patching the values of `a` and `b` into the program.
+        let prog = b"int a; int b; int mul = a * b; int div = a / b; int rem = a % b;
+        int expr = a - a / b * b - a % b;";
+        let asm = compile(&prog[..]).unwrap();
+        let program = Assembler::assemble(asm.as_bytes()).unwrap();
+        let labels = program.labels();
+        let cell = |v: &str| labels[v] as usize;
+
+        // A simple xorshift generator, with some edge cases thrown in first
+        let mut state = 0x2545_f491u32;
+        let mut values: Vec<(i16, i16)> = vec![(0, 1), (7, 2), (-7, 2), (7, -2), (-7, -2), (-32768, -1),
+                                               (-32768, 1), (-32768, -32768), (32767, -32768), (1, 0),
+                                               (-5, 0), (255, 255)];
+        for _ in 0..500 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            values.push(((state >> 16) as i16, state as i16 >> (state % 16)));
+        }
+
+        for (a, b) in values {
+            let mut data = program.data().to_vec();
+            data[cell("a")] = a as u16;
+            data[cell("b")] = b as u16;
+            let mut sim = Simulator::from_instructions(&data).unwrap();
+            sim.run().unwrap();
+            let results = ["mul", "div", "rem", "expr"].iter().map(|&v| sim.memory()[cell(v)]).collect::<Vec<_>>();
+
+            // Division by zero gives a quotient of 0 and a remainder equal to the dividend
+            let (div, rem) = if b == 0 {
+                (0, a)
+            } else {
+                (a.wrapping_div(b), a.wrapping_rem(b))
+            };
+            assert_eq!(results,
+                       [a.wrapping_mul(b) as u16, div as u16, rem as u16, 0],
+                       "{} {}",
+                       a,
+                       b);
+        }
+    }
+
+    #[test]
+    fn codegen_unsigned() {
+        // Like `codegen_mul_div`, but for the unsigned operations, which differ from the signed
+        // ones when either operand has its top bit set
+        let prog = b"unsigned int a; unsigned b; int sa; int sb;
+        unsigned div = a / b; unsigned rem = a % b; unsigned expr = a - a / b * b - a % b;
+        bool lt = a < b; bool le = a <= b; bool gt = a > b; bool ge = a >= b;
+        bool slt = sa < sb; bool mixed = sa < b;";
+        let asm = compile(&prog[..]).unwrap();
+        let program = Assembler::assemble(asm.as_bytes()).unwrap();
+        let labels = program.labels();
+        let cell = |v: &str| labels[v] as usize;
+
+        let edges = [0u16, 1, 2, 0x7ffe, 0x7fff, 0x8000, 0x8001, 0xfffe, 0xffff];
+        let mut values = edges.iter().flat_map(|&a| edges.iter().map(move |&b| (a, b))).collect::<Vec<_>>();
+        let mut state = 0x0bad_5eedu32;
+        for _ in 0..300 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            values.push(((state >> 16) as u16, state as u16 >> (state % 16)));
+        }
+
+        for (a, b) in values {
+            let mut data = program.data().to_vec();
+            for &(var, value) in &[("a", a), ("b", b), ("sa", a), ("sb", b)] {
+                data[cell(var)] = value;
+            }
+            let mut sim = Simulator::from_instructions(&data).unwrap();
+            sim.run().unwrap();
+            let results = ["div", "rem", "expr", "lt", "le", "gt", "ge", "slt", "mixed"]
+                .iter()
+                .map(|&v| sim.memory()[cell(v)])
+                .collect::<Vec<_>>();
+
+            let (div, rem) = (a.checked_div(b).unwrap_or(0), a.checked_rem(b).unwrap_or(a));
+            assert_eq!(results,
+                       [div, rem, 0, (a < b) as u16, (a <= b) as u16, (a > b) as u16, (a >= b) as u16,
+                        ((a as i16) < (b as i16)) as u16, (a < b) as u16],
+                       "{:04x} {:04x}",
+                       a,
+                       b);
+        }
+    }
+
+    #[test]
+    fn codegen_types() {
+        // `char` and `bool` values are converted whenever they are stored, but promoted to `int`
+        // in arithmetic
+        let prog = b"char c = 300;
+        char d = c + 250;
+        int e = c + 250;
+        bool b = 0x8000;
+        bool f = 256 & 0xff00;
+        int g = b + b;
+        char s[3];
+        s[1] = 'a' + 256;
+        s[1] += 200;
+        bool t[1];
+        t[0] = 7;
+        int h = s[1] + t[0];
+        char k = 0x7fff;
+        k -= 1;
+        bool m = 2;
+        m -= 1;
+        int n = 0;
+        char next() {
+            n += 1;
+            return 0x102;
+        }
+        s[next()] += 0xff;
+        bool nonzero(int x) {
+            return x;
+        }
+        unsigned big = 0x8000;
+        int q = nonzero(big) + nonzero(0) + nonzero(-1) + next();
+        char r = readc();
+        char cmp = 0xff;
+        int lt = cmp > 0;";
+
+        assert_eq!(run_optimized(prog,
+                                 b"A",
+                                 &["c", "d", "e", "b", "f", "g", "s._1", "h", "k", "m", "n", "s._2", "q",
+                                   "r", "lt"]),
+                   [44, 38, 294, 1, 1, 2, 41, 42, 0xfe, 0, 2, 0xff, 4, 0x41, 1]);
+    }
+
+    #[test]
+    fn runtime_linking() {
+        // Runtime routines should only be included if they are needed
+        let asm = compile(&b"int a = 3; int b = a + 4;"[..]).unwrap();
+        assert!(!asm.contains("_mul") && !asm.contains("_div"));
+        let asm = compile(&b"int a = 3; int b = a * 4;"[..]).unwrap();
+        assert!(asm.contains("_mul") && !asm.contains("_div"));
+        let asm = compile(&b"int a = 3; int b = a % 4 + a / 2;"[..]).unwrap();
+        assert!(!asm.contains("_mul") && asm.contains("_div"));
+
+        // Multiplication binds more tightly than addition, and is left-associative
+        assert_eq!(run(b"int a = 2 + 3 * 4; int b = 100 / 5 / 2; int c = 17 % 5 * 3;
+                       int f(int n) { if (n == 0) return 1; return n * f(n - 1); } int d = f(7);",
+                       &["a", "b", "c", "d"]),
+                   [14, 10, 6, 5040]);
+    }
+
+    #[test]
+    fn codegen_io() {
+        // Echo characters until a `.`, then print the sum of two hex numbers
+        let prog = b"int c = readc();
+        while (c != 46) {
+            printc(c);
+            c = readc();
+        }
+        prints(\"=>\");
+        printh(readh() + readh());";
+        let asm = compile(&prog[..]).unwrap();
+        let program = Assembler::assemble(asm.as_bytes()).unwrap();
+        let mut output = Vec::<u8>::new();
+        {
+            let mut sim = Simulator::from_instructions(program.data()).unwrap();
+            sim.set_input(&b"a\nb\n.\n00ff\n0002\n"[..]);
+            sim.set_output(&mut output, false);
+            sim.run().unwrap();
+        }
+
+        // Each character is printed on its own line
+        assert_eq!(String::from_utf8(output).unwrap(), "a\nb\n=\n>\n0101\n");
+    }
+
+    #[test]
+    fn codegen_io_errors() {
+        let progs: &[(&[u8], usize)] = &[(b"int x = readh(1);", 1),
+                                         (b"printh();", 1),
+                                         (b"int x;\nprints(x);", 2),
+                                         (b"int x = \"abc\";", 1),
+                                         (b"\nvoid printc(int c) {}", 2)];
+        for &(prog, line) in progs {
+            match compile(prog) {
+                Err(Error(ErrorKind::Semantic(_, n), _)) if n == line => {}
+                res => panic!("expected semantic error on line {}, got {:?}", line, res),
+            }
+        }
+
+        match compile(&b"prints(\"abc);"[..]) {
+            Err(Error(ErrorKind::Lexer(_, 1, _), _)) => {}
+            res => panic!("expected lexer error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn check_errors() {
+        let progs: &[(&[u8], usize)] = &[// Constants
+                                         (b"const int a = 1;\na = 2;", 2),
+                                         (b"const int a = 1;\n{\n  a += 2;\n}", 3),
+                                         (b"int f(const int x) {\n  x = 1;\n  return x;\n}", 2),
+                                         (b"const int a;", 1),
+                                         // Scopes
+                                         (b"{ int a = 1; }\nint b = a;", 2),
+                                         (b"for (int i = 0; i < 3; i += 1) {}\ni = 1;", 2),
+                                         (b"int f(int x) {\n  int x = 2;\n  return x;\n}", 2),
+                                         (b"int f(int x, int x) { return x; }", 1),
+                                         (b"int a = a;", 1),
+                                         // Types and calls
+                                         (b"void f() {}\nint a = f();", 2),
+                                         (b"void f() {}\nint a = 1 + f();", 2),
+                                         (b"void f() {}\nif (f()) {}", 2),
+                                         (b"void f() {}\nprinth(f());", 2),
+                                         (b"void f() {\n  return 1;\n}", 2),
+                                         (b"int f() {\n  return;\n}", 2),
+                                         (b"char f() {\n  return;\n}", 2),
+                                         (b"void f() {}\nbool b = f();", 2),
+                                         (b"unsigned a = 1;\nchar c[2];\nc = a;", 3),
+                                         (b"int f(void x) { return 1; }", 1),
+                                         (b"void a;", 1),
+                                         (b"int f(int x) { return x; }\nint a = f();", 2),
+                                         (b"int a = printc(1, 2);", 1),
+                                         (b"const int f() { return 1; }", 1)];
+        for &(prog, line) in progs {
+            match compile(prog) {
+                Err(Error(ErrorKind::Semantic(_, n), _)) if n == line => {}
+                res => {
+                    panic!("expected semantic error on line {} in {:?}, got {:?}",
+                           line,
+                           String::from_utf8_lossy(prog),
+                           res)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn check_valid() {
+        // Shadowing, constants and void calls used as statements are all fine.
+        let prog = b"const int a = 5;
+        int b = a;
+        {
+            int a = 2;
+            a += 1;
+            b += a;
+        }
+        int f(const int x) {
+            {
+                int x = 4;
+                x -= 1;
+            }
+            for (int b = 0; b < x; b += 1) {
+                int x = b;
+            }
+            return x * 2;
+        }
+        void g() {
+            return;
+        }
+        g();
+        int c = f(a) + b;";
+
+        assert_eq!(run(prog, &["a", "b", "c"]), [5, 8, 18]);
+    }
+
+    #[test]
+    fn array_ast() {
+        // Check parsing of arrays, dereferences and address-of.
+        let parsed = parse(b"int a[4];\na[i + 1] = *p + &b[2];\n*&x -= a[0][1];");
+        let ident = |s: &str| Box::new(Expr::Ident(Ident(s.into())));
+        let int = |n| Box::new(Expr::Literal(Literal::Int(n, IntFormat::Dec)));
+
+        assert_eq!(parsed,
+                   Block(vec![Stmt::Array(Decl {
+                                              is_const: false,
+                                              ty: Type::Int,
+                                              name: Ident("a".into()),
+                                          },
+                                          4)
+                                  .with_line(1),
+                              Stmt::Assign(Expr::Index(ident("a"),
+                                                       Box::new(Expr::BinOp(BinOp::Add, ident("i"), int(1)))),
+                                           Expr::BinOp(BinOp::Add,
+                                                       Box::new(Expr::Deref(ident("p"))),
+                                                       Box::new(Expr::AddrOf(Box::new(Expr::Index(ident("b"),
+                                                                                                  int(2)))))))
+                                  .with_line(2),
+                              Stmt::CompoundAssign(Expr::Deref(Box::new(Expr::AddrOf(ident("x")))),
+                                                   BinOp::Sub,
+                                                   Expr::Index(Box::new(Expr::Index(ident("a"), int(0))), int(1)))
+                                  .with_line(3)]));
+
+        // Only lvalues may be assigned to or have their address taken
+        for &prog in &[&b"a + 1 = 2;"[..], &b"int p = &3;"[..], &b"f() += 1;"[..]] {
+            match compile(prog) {
+                Err(Error(ErrorKind::Parser(_, 1, _), _)) => {}
+                res => panic!("expected parser error on line 1, got {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn codegen_arrays() {
+        let prog = b"int a[10];
+        for (int i = 0; i < 10; i += 1)
+            a[i] = i * i;
+        a[3] += 100;
+        int x = 5;
+        int y = 9;
+        swap(&x, &y);
+        int p = &a[2];
+        *p = *p - 1;
+        p[2] -= 1;
+        int total = sum(a, 10);
+        int last = *(a + 9);
+        int same = &*p == p;
+
+        void swap(int p, int q) {
+            int t = *p;
+            *p = *q;
+            *q = t;
+        }
+
+        int sum(int p, int n) {
+            int s = 0;
+            while (n > 0) {
+                n -= 1;
+                s += p[n];
+            }
+            return s;
+        }";
+
+        assert_eq!(run(prog, &["a", "a._2", "a._3", "a._4", "x", "y", "total", "last", "same"]),
+                   [0, 3, 109, 15, 9, 5, 383, 81, 1]);
+    }
+
+    #[test]
+    fn codegen_address_wrapping() {
+        // Computed addresses wrap around memory instead of changing the built instruction
+        let prog = b"int x = 7;
+        int p = &x;
+        int q = p + 0x1000;
+        int y = *q;
+        *(q - 0x3000) = 8;
+        int a[2];
+        a[1] = 5;
+        int z = a[0x1001];
+        int end = 4095;
+        int w = *(end + 1);";
+        let asm = compile(&prog[..]).unwrap();
+        let program = Assembler::assemble(asm.as_bytes()).unwrap();
+        let mut sim = Simulator::from_instructions(program.data()).unwrap();
+        sim.run().unwrap();
+
+        let labels = program.labels();
+        let vars = ["x", "y", "z", "w"]
+            .iter()
+            .map(|&v| sim.memory()[labels[v] as usize])
+            .collect::<Vec<_>>();
+        // `*(end + 1)` reads the first word of the program, and leaves it alone
+        assert_eq!(vars, [8, 7, 5, program.data()[0]]);
+        assert_eq!(sim.memory()[0], program.data()[0]);
+    }
+
+    #[test]
+    fn codegen_array_recursion() {
+        // Local arrays are saved and restored along with the rest of the frame.
+        let prog = b"int f(int n) {
+            int a[3];
+            a[0] = n;
+            a[1] = n * 2;
+            a[2] = n * 3;
+            if (n > 0)
+                f(n - 1);
+            return a[0] + a[1] + a[2];
+        }
+        int r = f(3);";
+
+        assert_eq!(run(prog, &["r"]), [18]);
+    }
+
+    #[test]
+    fn no_warnings() {
+        // The generated labels and placeholder instructions don't look like mistakes
+        let prog = b"int a[3];
+        int f(int x) {
+            if (x)
+                return a[x] + f(x - 1);
+            return 1;
+        }
+        for (int i = 0; i < 3; i += 1)
+            a[i] = i * 2;
+        printh(f(2));";
+        for level in 0..3 {
+            let asm = compile_optimized(&prog[..], level).unwrap();
+            let (_, diagnostics) = Assembler::assemble_recovering(asm.as_bytes());
+            assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        }
+    }
+
+    #[test]
+    fn stack_overflow() {
+        // Recursing too deeply stops the program before the stack reaches its code or data
+        let prog = b"int d = depth(readh());
+        int depth(int n) {
+            if (n == 0)
+                return 0;
+            return depth(n - 1) + 1;
+        }";
+        let asm = compile(&prog[..]).unwrap();
+        let program = Assembler::assemble(asm.as_bytes()).unwrap();
+        for &(input, depth, message) in &[("0005\n", 5, ""), ("07d0\n", 0, "stack overflow")] {
+            let mut output = Vec::<u8>::new();
+            {
+                let mut sim = Simulator::from_instructions(program.data()).unwrap();
+                sim.set_input(input.as_bytes());
+                sim.set_output(&mut output, false);
+                assert_eq!(sim.run().unwrap(), StopReason::Halted);
+                let labels = program.labels();
+                assert_eq!(sim.memory()[labels["d"] as usize], depth);
+                // The stack never went past the last word of the program
+                let end = labels["_end"] as usize;
+                assert!(sim.memory()[labels["_sp"] as usize] as usize >= end);
+                assert_eq!(sim.memory()[end], program.data()[end]);
+            }
+            let expected = message.chars().map(|c| format!("{}\n", c)).collect::<String>();
+            assert_eq!(String::from_utf8(output).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn array_errors() {
+        let progs: &[(&[u8], usize)] = &[(b"int a[3];\na = 2;", 2),
+                                         (b"int a[3];\na += 2;", 2),
+                                         (b"int a[0];", 1),
+                                         (b"const int a[2];", 1),
+                                         (b"void a[2];", 1),
+                                         (b"int a[2];\nint a;", 2),
+                                         (b"int x = b[1];", 1)];
+        for &(prog, line) in progs {
+            match compile(prog) {
+                Err(Error(ErrorKind::Semantic(_, n), _)) if n == line => {}
+                res => panic!("expected semantic error on line {}, got {:?}", line, res),
+            }
+        }
+    }
+
+    /// Runs the program (with the given input) at every optimization level, checking that the
+    /// values of the given variables and the output are always the same, and that optimization
+    /// never makes the program longer.
+    fn run_optimized(input: &[u8], stdin: &[u8], vars: &[&str]) -> Vec<u16> {
+        let mut results = Vec::new();
+        for level in 0..3 {
+            let asm = compile_optimized(input, level).unwrap();
+            let program = Assembler::assemble(asm.as_bytes()).unwrap();
+            let mut output = Vec::<u8>::new();
+            let values = {
+                let mut sim = Simulator::from_instructions(program.data()).unwrap();
+                sim.set_input(stdin);
+                sim.set_output(&mut output, false);
+                sim.run().unwrap();
+                vars.iter().map(|&v| sim.memory()[program.labels()[v] as usize]).collect::<Vec<_>>()
+            };
+            results.push((values, output, program.data().len()));
+        }
+
+        for (level, result) in results.iter().enumerate().skip(1) {
+            assert_eq!((&result.0, &result.1), (&results[0].0, &results[0].1), "level {}", level);
+            assert!(result.2 <= results[level - 1].2, "level {} is longer", level);
+        }
+        results.swap_remove(0).0
+    }
+
+    #[test]
+    fn optimize_folding() {
+        let fold = |input: &[u8]| optimize::optimize(&parse(input), 1);
+        let int = |n| Expr::Literal(Literal::Int(n, IntFormat::Dec));
+        let ident = |s: &str| Expr::Ident(Ident(s.into()));
+        let init = |s: &str, expr| Stmt::Init(Decl { is_const: false, ty: Type::Int, name: Ident(s.into()) }, expr);
+
+        assert_eq!(fold(b"int a = 2 + 3 * 4 - (1 << 3);\nint b = -1 / 0 + -7 % 2 + (5 > -3);"),
+                   Block(vec![init("a", int(6)).with_line(1), init("b", int(0)).with_line(2)]));
+        // Constants are propagated, but not through shadowing variables
+        assert_eq!(fold(b"const int n = 4 * 4; int a = n - 1; { int n = 2; a = n + 0; } a = &n;"),
+                   Block(vec![Stmt::Init(Decl { is_const: true, ty: Type::Int, name: Ident("n".into()) },
+                                         int(16))
+                                      .with_line(1),
+                              init("a", int(15)).with_line(1),
+                              Stmt::Block(Block(vec![init("n", int(2)).with_line(1),
+                                                     Stmt::Assign(ident("a"), ident("n")).with_line(1)]))
+                                      .with_line(1),
+                              Stmt::Assign(ident("a"), Expr::AddrOf(Box::new(ident("n")))).with_line(1)]));
+        // Multiplication by a power of 2 becomes a shift
+        assert_eq!(fold(b"int a; int b = 8 * a * 1;"),
+                   Block(vec![Stmt::Decl(Decl { is_const: false, ty: Type::Int, name: Ident("a".into()) })
+                                  .with_line(1),
+                              init("b", Expr::BinOp(BinOp::Shl, Box::new(ident("a")), Box::new(int(3))))
+                                  .with_line(1)]));
+    }
+
+    #[test]
+    fn optimize_dead_code() {
+        let optimize = |input: &[u8]| optimize::optimize(&parse(input), 2);
+        let call = |s: &str| Stmt::Expr(Expr::Call(Ident(s.into()), vec![]));
+
+        assert_eq!(optimize(b"if (1 - 1) f(); else { g(); }\nwhile (0) f(); for (;0;) f();
+                            void f() {} void g() { return; h(); } void h() {}"),
+                   Block(vec![Stmt::Block(Block(vec![call("g").with_line(1)])).with_line(1),
+                              Stmt::Function(Decl { is_const: false, ty: Type::Void, name: Ident("g".into()) },
+                                             vec![],
+                                             Block(vec![Stmt::Return(None).with_line(3)]))
+                                      .with_line(3)]));
+        // Unused local variables are removed, but calls in their initializers are kept
+        assert_eq!(optimize(b"int x = f();\nint f() { int a = readh(); int b = 2; int c = 3; b += 1; return c; }"),
+                   optimize(b"int x = f();\nint f() { readh(); int c = 3; return c; }"));
+        // Variables with the same name as a global are kept
+        let prog = b"int a; f(); void f() { { int a = 1; } a = 2; }";
+        assert_eq!(optimize(prog), parse(prog));
+    }
+
+    #[test]
+    fn optimize_peephole() {
+        use self::codegen::{Line, Op};
+        use instruction::Instruction;
+
+        let line = |labels: &[&str], instr, arg: Option<&str>| {
+            Line {
+                labels: labels.iter().map(|&s| s.to_owned()).collect(),
+                op: Op::Instr(instr, arg.map(|s| s.to_owned())),
+                line: None,
+            }
+        };
+
+        let code = vec![line(&[], Instruction::Load(0), Some("a")),
+                        line(&[], Instruction::Add(0), Some("#0000")),
+                        line(&[], Instruction::Store(0), Some("b")),
+                        line(&[], Instruction::Load(0), Some("b")),
+                        line(&[], Instruction::Jmpe(0), Some("_L0")),
+                        line(&[], Instruction::Jmp(0), Some("_L1")),
+                        line(&[], Instruction::Halt, None),
+                        line(&["_L1"], Instruction::Jmp(0), Some("_L0")),
+                        line(&["_L0"], Instruction::Not, None),
+                        line(&[], Instruction::Store(0), Some("_L2")),
+                        line(&["_L2"], Instruction::Nop, None),
+                        line(&["_L3"], Instruction::Halt, None),
+                        Line { labels: vec!["#0000".into()], op: Op::Data(0), line: None },
+                        Line { labels: vec!["a".into()], op: Op::Data(0), line: None },
+                        Line { labels: vec!["b".into()], op: Op::Data(0), line: None }];
+        assert_eq!(optimize::peephole(code),
+                   vec![line(&[], Instruction::Load(0), Some("a")),
+                        line(&[], Instruction::Store(0), Some("b")),
+                        line(&[], Instruction::Not, None),
+                        line(&[], Instruction::Store(0), Some("_L2")),
+                        line(&["_L2"], Instruction::Nop, None),
+                        line(&[], Instruction::Halt, None),
+                        Line { labels: vec!["a".into()], op: Op::Data(0), line: None },
+                        Line { labels: vec!["b".into()], op: Op::Data(0), line: None }]);
+    }
+
+    #[test]
+    fn optimize_semantics() {
+        assert_eq!(run_optimized(b"const int n = 10; int a = 3 * n; int b; int c;
+                                 for (int i = 0; i < n; i += 1) { if (i % 2 == 0) continue; b += i * 4; }
+                                 while (1) { c += 1; if (c >= n / 3) break; }
+                                 if (n > 5) a -= 1; else a += 1;",
+                                 b"",
+                                 &["a", "b", "c"]),
+                   [29, 100, 3]);
+        assert_eq!(run_optimized(b"int a = fib(10); int b = fact(7); int c = unused(3);
+                                 int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+                                 int fact(int n) { int r = 1; int dead = n * 7; while (n > 1) { r = r * n; n -= 1; } return r; }
+                                 int unused(int n) { int x = n; x += 1; return 2; never(); }
+                                 void never() { printh(1); }",
+                                 b"",
+                                 &["a", "b", "c"]),
+                   [55, 5040, 2]);
+        // Arrays and pointers, including writes through pointers
+        assert_eq!(run_optimized(b"int a[6]; int sum; int p;
+                                 for (int i = 0; i < 6; i += 1) a[i] = (6 - i) * 3;
+                                 sort(a, 6);
+                                 int x = 1; p = &x; *p += 4; a[0] = *p;
+                                 for (int i = 0; i < 6; i += 1) sum = sum * 2 + a[i];
+                                 void sort(int v, int n) {
+                                     for (int i = 0; i < n; i += 1) for (int j = i + 1; j < n; j += 1)
+                                         if (v[j] < v[i]) { int t = v[i]; v[i] = v[j]; v[j] = t; }
+                                 }",
+                                 b"",
+                                 &["a", "x", "sum"]),
+                   [5, 5, 424]);
+        // I/O is preserved
+        assert_eq!(run_optimized(b"int c = readc(); int unused = readh();
+                                 while (c != 46) { printc(c + 0); c = readc(); }
+                                 prints(\"ok\"); printh(readh() * 2);",
+                                 b"a\n0001\nb\n.\n0010\n",
+                                 &["c"]),
+                   [46]);
+    }
+
+    #[test]
+    fn optimize_folding_matches_runtime() {
+        // Fold random constant operands with every operator, and check the results against
+        // the unoptimized program
+        let ops = ["+", "-", "*", "/", "%", "==", "!=", "<", "<=", ">", ">=", "&", "|", "^", "<<", ">>"];
+        let mut state = 0x1234_5678u32;
+        let mut prog = String::new();
+        let mut vars = Vec::new();
+        for (i, op) in ops.iter().enumerate() {
+            for j in 0..8 {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                let (a, b) = ((state >> 16) as u16, match j {
+                    0 => 0,
+                    1 => 0xffff,
+                    2 => 0x8000,
+                    _ => state as u16 >> (state % 16),
+                });
+                prog.push_str(&format!("int r{}x{} = {} {} {};\n", i, j, a, op, b));
+                prog.push_str(&format!("int u{}x{} = -{} {} ~{};\n", i, j, b, op, a));
+                vars.push(format!("r{}x{}", i, j));
+                vars.push(format!("u{}x{}", i, j));
+            }
+        }
+        let vars = vars.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        run_optimized(prog.as_bytes(), b"", &vars);
+    }
+
+    #[test]
+    fn optimize_folding_unsigned() {
+        // The same for the operations which have unsigned versions, using constants (which are
+        // only folded when optimizing) to get unsigned operands
+        let ops = ["/", "%", "<", "<=", ">", ">="];
+        let mut state = 0x8765_4321u32;
+        let mut prog = String::new();
+        let mut vars = Vec::new();
+        for (i, op) in ops.iter().enumerate() {
+            for j in 0..8 {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                let (a, b) = ((state >> 16) as u16, match j {
+                    0 => 0,
+                    1 => 0x7fff,
+                    2 => 0x8000,
+                    _ => state as u16 >> (state % 16),
+                });
+                prog.push_str(&format!("const unsigned a{0}x{1} = {2};\nconst unsigned b{0}x{1} = {3};\n", i, j, a, b));
+                prog.push_str(&format!("unsigned r{0}x{1} = a{0}x{1} {2} b{0}x{1};\n", i, j, op));
+                prog.push_str(&format!("unsigned u{0}x{1} = b{0}x{1} {2} a{0}x{1};\n", i, j, op));
+                vars.push(format!("r{}x{}", i, j));
+                vars.push(format!("u{}x{}", i, j));
+            }
+        }
+        let vars = vars.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        run_optimized(prog.as_bytes(), b"", &vars);
+    }
+
+    #[test]
+    fn source_map() {
+        let prog = b"int a = 1;
+        int b = f(a);
+        int f(int x) {
+            x += 1;
+            return x - a;
+        }";
+        for level in 0..3 {
+            let (asm, source_map) = compile_with_source_map(&prog[..], level).unwrap();
+            let program = Assembler::assemble(asm.as_bytes()).unwrap();
+            let addr = |label: &str| program.labels()[label];
+
+            assert_eq!(source_map.line(0), Some(1));
+            assert_eq!(source_map.line(addr("f")), Some(3));
+            assert_eq!(source_map.line(addr("a")), None);
+            // The body of the function is mapped to its lines
+            let body = (addr("f")..addr("f._exit")).filter_map(|a| source_map.line(a)).collect::<Vec<_>>();
+            assert!(body.contains(&4) && body.contains(&5), "{:?}", body);
+            assert!(body.iter().all(|line| (3..=5).contains(line)), "{:?}", body);
+        }
+    }
+
+    #[test]
+    fn lexer_literals() {
+        let int = |n, format| Token::Literal(Literal::Int(n, format));
+        assert_eq!(lex(b"// comment\nint a = 0x1F + 'a' - '\\n' / 65535; /* multi\nline */
+                       prints(\"a\\tb\\\"\\x41\\xe9\"); // no newline at the end"),
+                   [Token::Keyword(Keyword::Int),
+                    Token::Ident(Ident("a".into())),
+                    Token::Assign,
+                    int(31, IntFormat::Hex(2)),
+                    Token::Add,
+                    int(97, IntFormat::Char),
+                    Token::Sub,
+                    int(10, IntFormat::Char),
+                    Token::Div,
+                    int(65535, IntFormat::Dec),
+                    Token::Semi,
+                    Token::Ident(Ident("prints".into())),
+                    Token::LParen,
+                    Token::Literal(Literal::Str("a\tb\"A\u{e9}".into())),
+                    Token::RParen,
+                    Token::Semi]);
+        assert_eq!(lex(b"0x00ff '\\'' '\\\\' '\\0'"),
+                   [int(0xff, IntFormat::Hex(4)),
+                    int(39, IntFormat::Char),
+                    int(92, IntFormat::Char),
+                    int(0, IntFormat::Char)]);
+    }
+
+    #[test]
+    fn lexer_comments() {
+        let source = "// a\nint x; /* b\n */ {\n  /* c */ x = 1; // d \r\n}";
+        let mut lexer = Lexer::new(Cursor::new(source).bytes());
+        for tok in lexer.by_ref() {
+            tok.unwrap();
+        }
+        let comment = |text: &str, line, col, after_code| {
+            Comment {
+                text: text.into(),
+                pos: Pos { line, col },
+                after_code,
+            }
+        };
+        assert_eq!(lexer.comments().comments,
+                   [comment("// a", 1, 1, false),
+                    comment("/* b\n */", 2, 8, true),
+                    comment("/* c */", 4, 3, false),
+                    comment("// d", 4, 18, true)]);
+        // Only the `}` is known to the lexer; statements are added by the parser
+        assert_eq!(lexer.comments().breaks, [Pos { line: 5, col: 1 }]);
+    }
+
+    #[test]
+    fn lexer_errors() {
+        let progs: &[(&[u8], usize, usize)] = &[(b"int a = 65536;", 1, 9),
+                                                (b"int a = 0x10000;", 1, 9),
+                                                (b"int a = 0x;", 1, 9),
+                                                (b"int a;\n  /* unterminated", 2, 3),
+                                                (b"int c = '';", 1, 9),
+                                                (b"int c = 'ab';", 1, 9),
+                                                (b"prints(\"\\q\");", 1, 8),
+                                                (b"prints(\"abc);", 1, 8),
+                                                ("prints(\"caf\u{e9}\");".as_bytes(), 1, 8),
+                                                (b"int a = 1;\n  int b = $;", 2, 11)];
+        for &(prog, line, col) in progs {
+            match compile(prog) {
+                Err(Error(ErrorKind::Lexer(_, n, c), _)) if (n, c) == (line, col) => {}
+                res => panic!("expected lexer error at {}:{}, got {:?}", line, col, res),
+            }
+        }
+    }
+
+    #[test]
+    fn token_spans() {
+        let mut lexer = Lexer::new(Cursor::new(&b"int a =\n  0x10; /* c */ b\n\tc"[..]).bytes());
+        let mut spans = Vec::new();
+        while let Some(tok) = lexer.next() {
+            tok.unwrap();
+            let span = lexer.span();
+            spans.push(((span.start.line, span.start.col), (span.end.line, span.end.col)));
+        }
+        assert_eq!(spans,
+                   [((1, 1), (1, 4)),
+                    ((1, 5), (1, 6)),
+                    ((1, 7), (1, 8)),
+                    ((2, 3), (2, 7)),
+                    ((2, 7), (2, 8)),
+                    ((2, 17), (2, 18)),
+                    ((3, 2), (3, 3))]);
+
+        // Parser errors point to the offending token
+        let progs: &[(&[u8], usize, usize)] = &[(b"int a = 1 +;", 1, 12),
+                                                (b"int a = (1;", 1, 11),
+                                                (b"int a;\nif (a) {\n  a = 2\n}", 4, 1)];
+        for &(prog, line, col) in progs {
+            match compile(prog) {
+                Err(Error(ErrorKind::Parser(_, n, c), _)) if (n, c) == (line, col) => {}
+                res => panic!("expected parser error at {}:{}, got {:?}", line, col, res),
+            }
+        }
+    }
+
+    #[test]
+    fn logical_operators() {
+        assert_eq!(lex(b"a && b || c & &d | e"),
+                   [Token::Ident(Ident("a".into())),
+                    Token::LogicalAnd,
+                    Token::Ident(Ident("b".into())),
+                    Token::LogicalOr,
+                    Token::Ident(Ident("c".into())),
+                    Token::And,
+                    Token::And,
+                    Token::Ident(Ident("d".into())),
+                    Token::Or,
+                    Token::Ident(Ident("e".into()))]);
+
+        let prog = "bool a = 1;\nbool b = a && a;\nbool c = a || b;\nint d = &&a;";
+        let errors = super::parse(prog.as_bytes())
+            .1
+            .iter()
+            .map(|e| match *e.kind() {
+                     ErrorKind::Parser(ref s, n, c) => (s.clone(), n, c),
+                     ref e => panic!("unexpected error: {}", e),
+                 })
+            .collect::<Vec<_>>();
+        assert_eq!(errors,
+                   [("logical `&&` is not supported; use `&` on bool values".into(), 2, 12),
+                    ("logical `||` is not supported; use `|` on bool values".into(), 3, 12),
+                    ("expected expression term, got `&&`".into(), 4, 9)]);
+    }
+
+    #[test]
+    fn char_lit_recovery() {
+        let prog = "char c = 'ab';\nchar d = '\\q';\nchar e = '\\x4';\nchar f = 'g;\nchar h = 'i';";
+        let errors = super::parse(prog.as_bytes())
+            .1
+            .iter()
+            .map(|e| match *e.kind() {
+                     ErrorKind::Lexer(ref s, n, c) => (s.clone(), n, c),
+                     ref e => panic!("unexpected error: {}", e),
+                 })
+            .collect::<Vec<_>>();
+        assert_eq!(errors,
+                   [("expected `'` to end character literal".into(), 1, 10),
+                    ("unknown escape sequence `\\q`".into(), 2, 10),
+                    ("expected two hexadecimal digits after `\\x`".into(), 3, 10),
+                    ("expected `'` to end character literal".into(), 4, 10)]);
+    }
+
+    #[test]
+    fn str_lit_recovery() {
+        let prog = "prints(\"bad \\q escape; \\\"\");\nprints(\"\\x4 \\n\");\nprints(\"\\q);\nprints(\"ok\");";
+        let errors = super::parse(prog.as_bytes())
+            .1
+            .iter()
+            .map(|e| match *e.kind() {
+                     ErrorKind::Lexer(ref s, n, c) => (s.clone(), n, c),
+                     ref e => panic!("unexpected error: {}", e),
+                 })
+            .collect::<Vec<_>>();
+        assert_eq!(errors,
+                   [("unknown escape sequence `\\q`".into(), 1, 8),
+                    ("expected two hexadecimal digits after `\\x`".into(), 2, 8),
+                    ("unknown escape sequence `\\q`".into(), 3, 8)]);
+    }
+
+    #[test]
+    fn unknown_chars() {
+        let mut prog = "int a = 1 é;\nint b = 2 €;\nint c = 3 ".as_bytes().to_vec();
+        prog.extend(b"\xff;\nint d = 4 \xe2\x82;");
+        let errors = super::parse(&prog[..])
+            .1
+            .iter()
+            .map(|e| match *e.kind() {
+                     ErrorKind::Lexer(ref s, n, c) => (s.clone(), n, c),
+                     ref e => panic!("unexpected error: {}", e),
+                 })
+            .collect::<Vec<_>>();
+        assert_eq!(errors,
+                   [("unknown token `é`".into(), 1, 11),
+                    ("unknown token `€`".into(), 2, 11),
+                    ("input is not valid UTF-8".into(), 3, 11),
+                    ("input is not valid UTF-8".into(), 4, 11)]);
+    }
+
+    #[test]
+    fn parse_recovery() {
+        let prog = "int a = 1 +;
+int b = 65536 + 1;
+int f(int x, 5) {
+    return x;
+}
+int c = 2;
+if (c) {
+    c = $;
+    c += 1;
+}
+}
+while (c { c = 3; }
+int d = c;";
+        let (ast, errors) = super::parse(prog.as_bytes());
+        let errors = errors.iter()
+            .map(|e| match *e.kind() {
+                     ErrorKind::Lexer(_, n, c) => ("lexer", n, c),
+                     ErrorKind::Parser(_, n, c) => ("parser", n, c),
+                     ref e => panic!("unexpected error: {}", e),
+                 })
+            .collect::<Vec<_>>();
+        assert_eq!(errors,
+                   [("parser", 1, 12),
+                    ("lexer", 2, 9),
+                    ("parser", 3, 14),
+                    ("lexer", 8, 9),
+                    ("parser", 11, 1),
+                    ("parser", 12, 10)]);
+
+        // The statements which could be parsed are kept
+        assert_eq!(ast.0.iter().map(|stmt| stmt.line()).collect::<Vec<_>>(), [6, 7, 13]);
+        match *ast.0[1].stmt() {
+            Stmt::If(_, ref body, None) => {
+                match *body.stmt() {
+                    Stmt::Block(ref block) => assert_eq!(1, block.0.len()),
+                    ref stmt => panic!("unexpected statement: {:?}", stmt),
+                }
+            }
+            ref stmt => panic!("unexpected statement: {:?}", stmt),
+        }
+
+        // A valid program has no errors, and the first error is the one returned by `compile`
+        assert!(super::parse(&b"int a = 1; { a += 2; }"[..]).1.is_empty());
+        match compile(prog.as_bytes()) {
+            Err(Error(ErrorKind::Parser(_, 1, 12), _)) => {}
+            res => panic!("expected parser error at 1:12, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn error_snippets() {
+        let prog = "int a = 1;\n\tif (a) {\n\t\tb = 2;\n\t}";
+        let e = compile(prog.as_bytes()).unwrap_err();
+        assert_eq!(format_error(&e, "foo.ibcmc", prog),
+                   "semantic error: use of undeclared variable `b`\n \
+                    --> foo.ibcmc:3:3\n  |\n3 | \t\tb = 2;\n  | \t\t^\n");
+
+        let prog = "int a;\n".repeat(9) + "int b = 0x;";
+        let e = compile(prog.as_bytes()).unwrap_err();
+        assert_eq!(format_error(&e, "foo.ibcmc", &prog),
+                   "lexer error: expected hexadecimal digits after `0x`\n  \
+                    --> foo.ibcmc:10:9\n   |\n10 | int b = 0x;\n   |         ^\n");
+    }
+
+    /// Returns a copy of the given block with all the line numbers set to 0.
+    fn strip_lines(block: &Block) -> Block {
+        Block(block.0.iter().map(|stmt| strip_stmt(stmt.stmt()).with_line(0)).collect())
+    }
+
+    fn strip_stmt(stmt: &Stmt) -> Stmt {
+        let nested = |stmt: &StmtLine| Box::new(strip_stmt(stmt.stmt()).with_line(0));
+        match *stmt {
+            Stmt::Function(ref decl, ref params, ref body) => {
+                Stmt::Function(decl.clone(), params.clone(), strip_lines(body))
+            }
+            Stmt::Block(ref block) => Stmt::Block(strip_lines(block)),
+            Stmt::If(ref cond, ref body, ref else_body) => {
+                Stmt::If(cond.clone(), nested(body), else_body.as_ref().map(|stmt| nested(stmt)))
+            }
+            Stmt::While(ref cond, ref body) => Stmt::While(cond.clone(), nested(body)),
+            Stmt::For(ref init, ref cond, ref step, ref body) => {
+                Stmt::For(init.clone(), cond.clone(), step.clone(), nested(body))
+            }
+            ref stmt => stmt.clone(),
+        }
+    }
+
+    /// A generator of random syntax trees, of the kind which the parser produces.
+    struct AstGen(u32);
+
+    impl AstGen {
+        /// Returns a random number less than `n`.
+        fn below(&mut self, n: u32) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 % n
+        }
+
+        fn pick<T: Clone>(&mut self, items: &[T]) -> T {
+            items[self.below(items.len() as u32) as usize].clone()
+        }
+
+        fn ident(&mut self) -> Ident {
+            Ident(self.pick(&["a", "b", "x", "foo", "i2"]).into())
+        }
+
+        fn decl(&mut self, ty: &[Type]) -> Decl {
+            Decl {
+                is_const: self.below(4) == 0,
+                ty: self.pick(ty),
+                name: self.ident(),
+            }
+        }
+
+        fn expr(&mut self, depth: u32) -> Expr {
+            let ops = [BinOp::Add, BinOp::Sub, BinOp::Mul, BinOp::Div, BinOp::Mod, BinOp::Eq, BinOp::Ne,
+                       BinOp::Lt, BinOp::Le, BinOp::Gt, BinOp::Ge, BinOp::And, BinOp::Or, BinOp::Xor,
+                       BinOp::Shl, BinOp::Shr];
+            match if depth == 0 { 7 } else { self.below(10) } {
+                0 | 1 => {
+                    let op = self.pick(&ops);
+                    Expr::BinOp(op, Box::new(self.expr(depth - 1)), Box::new(self.expr(depth - 1)))
+                }
+                2 => {
+                    let op = self.pick(&[UnOp::Neg, UnOp::Not, UnOp::LogicalNot]);
+                    let expr = self.expr(depth - 1);
+                    // Nested minus signs are printed apart
+                    let expr = match self.below(4) {
+                        0 => Expr::UnOp(UnOp::Neg, Box::new(expr)),
+                        _ => expr,
+                    };
+                    Expr::UnOp(op, Box::new(expr))
+                }
+                3 => Expr::Deref(Box::new(self.expr(depth - 1))),
+                4 => Expr::AddrOf(Box::new(self.lvalue(depth - 1))),
+                5 => Expr::Index(Box::new(self.expr(depth - 1)), Box::new(self.expr(depth - 1))),
+                6 => {
+                    let args = (0..self.below(3)).map(|_| self.expr(depth - 1)).collect();
+                    Expr::Call(self.ident(), args)
+                }
+                _ => {
+                    match self.below(8) {
+                        0 => Expr::Literal(Literal::Str(self.pick(&["", "hi\n", "\"q\" \\ \t\x01\0", "é"]).into())),
+                        1..=3 => {
+                            let n = self.below(0x10000) as u16;
+                            Expr::Literal(match self.below(3) {
+                                              0 => Literal::Int(n, IntFormat::Dec),
+                                              1 => {
+                                                  let digits = format!("{:x}", n).len();
+                                                  let width = digits.max(self.below(5) as usize);
+                                                  Literal::Int(n, IntFormat::Hex(width))
+                                              }
+                                              _ => Literal::Int(n & 0xff, IntFormat::Char),
+                                          })
+                        }
+                        _ => Expr::Ident(self.ident()),
+                    }
+                }
+            }
+        }
+
+        fn lvalue(&mut self, depth: u32) -> Expr {
+            match self.below(3) {
+                0 if depth > 0 => Expr::Index(Box::new(self.expr(depth - 1)), Box::new(self.expr(depth - 1))),
+                1 if depth > 0 => Expr::Deref(Box::new(self.expr(depth - 1))),
+                _ => Expr::Ident(self.ident()),
+            }
+        }
+
+        /// Generates an assignment or expression statement.
+        fn simple(&mut self, depth: u32) -> Stmt {
+            match self.below(3) {
+                0 => Stmt::Assign(self.lvalue(depth), self.expr(depth)),
+                1 => {
+                    let op = self.pick(&[BinOp::Add, BinOp::Sub]);
+                    Stmt::CompoundAssign(self.lvalue(depth), op, self.expr(depth))
+                }
+                _ => Stmt::Expr(self.expr(depth)),
+            }
+        }
+
+        fn stmt(&mut self, depth: u32) -> Stmt {
+            let vars = [Type::Int, Type::Unsigned, Type::Char, Type::Bool];
+            match if depth == 0 { self.below(6) } else { self.below(12) } {
+                0 => Stmt::Decl(self.decl(&vars)),
+                1 => Stmt::Init(self.decl(&vars), self.expr(2)),
+                2 => Stmt::Array(self.decl(&vars), self.below(0x10000) as u16),
+                3 => Stmt::Return(if self.below(2) == 0 { None } else { Some(self.expr(2)) }),
+                4 => self.pick(&[Stmt::Break, Stmt::Continue, Stmt::Empty]),
+                5 => self.simple(2),
+                6 | 7 => Stmt::Block(self.block(depth - 1)),
+                8 => {
+                    let cond = self.expr(2);
+                    if self.below(2) == 0 {
+                        Stmt::If(cond, Box::new(self.stmt(depth - 1).with_line(0)), None)
+                    } else {
+                        // The printer would have to add braces around an `if` without an `else`
+                        // here, which the parser never produces
+                        let body = match self.stmt(depth - 1) {
+                            Stmt::If(..) | Stmt::While(..) | Stmt::For(..) => Stmt::Empty,
+                            body => body,
+                        };
+                        Stmt::If(cond, Box::new(body.with_line(0)), Some(Box::new(self.stmt(depth - 1).with_line(0))))
+                    }
+                }
+                9 => Stmt::While(self.expr(2), Box::new(self.stmt(depth - 1).with_line(0))),
+                10 => {
+                    let init = match self.below(3) {
+                        0 => Stmt::Empty,
+                        1 => Stmt::Init(self.decl(&vars), self.expr(1)),
+                        _ => self.simple(1),
+                    };
+                    let cond = if self.below(3) == 0 { None } else { Some(self.expr(2)) };
+                    let step = if self.below(3) == 0 { Stmt::Empty } else { self.simple(1) };
+                    Stmt::For(Box::new(init), cond, Box::new(step), Box::new(self.stmt(depth - 1).with_line(0)))
+                }
+                _ => {
+                    let params = (0..self.below(3)).map(|_| self.decl(&vars)).collect();
+                    let mut types = vars.to_vec();
+                    types.push(Type::Void);
+                    Stmt::Function(self.decl(&types), params, self.block(depth - 1))
+                }
+            }
+        }
+
+        fn block(&mut self, depth: u32) -> Block {
+            Block((0..self.below(4)).map(|_| self.stmt(depth).with_line(0)).collect())
+        }
+    }
+
+    #[test]
+    fn printer_round_trip() {
+        // Printing any syntax tree and parsing the result gives the same tree, and printing that
+        // again gives the same text
+        let mut gen = AstGen(0x5eed_1e55);
+        for _ in 0..500 {
+            let ast = gen.block(3);
+            let printed = ast.to_string();
+            let parsed = Parser::parse_from_lexer(Lexer::new(Cursor::new(printed.as_bytes()).bytes()))
+                .unwrap_or_else(|e| panic!("{}\n{}", e, printed));
+            assert_eq!(strip_lines(&parsed), ast, "{}", printed);
+            assert_eq!(parsed.to_string(), printed);
+            assert!(!printed.contains("--"), "{}", printed);
+        }
+    }
+
+    #[test]
+    fn printer_canonical() {
+        let prog = b"int a=1;const char c='A';int s[0x3];
+        a = (a - (a - 1)) - a - 1 + (a << 2 >> 1) * -(a + 1) / ~*s;
+        a = *(s + 1) + (s + 1)[1] + -(-a) + &s[2] - !(a == 1 < 2);
+        if (a) if (a < 2) { a = 2; } else a = 3;
+        for (;;) { break; }
+        for (a = 0; a < 3; ) while (a) a -= 1;
+        prints(\"tab\\there\\n\\x7f\");
+        void f(unsigned int x, bool y) {}
+        int g() { return 0; }";
+        let formatted = "int a = 1;
+const char c = 'A';
+int s[3];
+a = a - (a - 1) - a - 1 + (a << 2 >> 1) * -(a + 1) / ~*s;
+a = *(s + 1) + (s + 1)[1] + - -a + &s[2] - !(a == 1 < 2);
+if (a)
+    if (a < 2) {
+        a = 2;
+    } else
+        a = 3;
+for (;;) {
+    break;
+}
+for (a = 0; a < 3;)
+    while (a)
+        a -= 1;
+prints(\"tab\\there\\n\\x7f\");
+
+void f(unsigned x, bool y) {}
+
+int g() {
+    return 0;
+}
+";
+        assert_eq!(format(&prog[..]).unwrap(), formatted);
+        assert_eq!(format(formatted.as_bytes()).unwrap(), formatted);
+
+        // An `if` without an `else` nested in one with an `else` needs braces
+        let inner = Stmt::If(Expr::Ident(Ident("b".into())), Box::new(Stmt::Break.with_line(0)), None);
+        let outer = Stmt::If(Expr::Ident(Ident("a".into())),
+                             Box::new(Stmt::While(Expr::Ident(Ident("c".into())), Box::new(inner.with_line(0)))
+                                 .with_line(0)),
+                             Some(Box::new(Stmt::Continue.with_line(0))));
+        assert_eq!(outer.to_string(), "if (a) {\n    while (c)\n        if (b)\n            break;\n} else\n    continue;");
+
+        // The output of the checker prints as an equivalent program, with conversions left implicit
+        let prog = b"unsigned u = 1;\nchar c = u / 2;\nc += 1;\n";
+        assert_eq!(Checker::check(&parse(prog)).unwrap().to_string(),
+                   "unsigned u = 1;\nchar c = u / 2;\nc = c + 1;\n");
+    }
+
+    #[test]
+    fn printer_comments() {
+        // Comments following code stay at the end of its line, and others go on their own lines
+        let prog = b"// a
+        int a = 0x0A; /* b */ // c
+        if (a) // d
+        { /* e */ a = '\\x7f'; // f
+          // g
+        } else /* h */ { } // i
+        while (a) a -= 1;
+        /* j */ void f() { /* k */ }
+        int g(int x) {
+            return f(x, // l
+                     2);
+        } // m
+        // n";
+        let formatted = "// a
+int a = 0x0a; /* b */ // c
+if (a) { // d
+    /* e */
+    a = '\\x7f'; // f
+    // g
+} else {
+    /* h */
+} // i
+while (a)
+    a -= 1;
+
+/* j */
+void f() {
+    /* k */
 }
 
+int g(int x) {
+    return f(x, 2); // l
+} // m
+// n
+";
+        assert_eq!(format(&prog[..]).unwrap(), formatted);
+        assert_eq!(format(formatted.as_bytes()).unwrap(), formatted);
+
+        // Comments stay with the statement they follow, even if another one starts on the same
+        // line or the statement opens a block
+        let prog = b"int a = 1; int b = 2; // two
+        while (a) { a -= 1; // dec
+        } if (b) { /* x */ b = 0; } else { b = 1; /* y */ }
+        for (a = 0; a < 2; a += 1) b += a; /* sum */ b -= 1; // last";
+        let formatted = "int a = 1;
+int b = 2; // two
+while (a) {
+    a -= 1; // dec
+}
+if (b) { /* x */
+    b = 0;
+} else {
+    b = 1; /* y */
+}
+for (a = 0; a < 2; a += 1)
+    b += a; /* sum */
+b -= 1; // last
+";
+        assert_eq!(format(&prog[..]).unwrap(), formatted);
+        assert_eq!(format(formatted.as_bytes()).unwrap(), formatted);
+
+        // Character literals which need escapes, and hexadecimal literals which are already
+        // formatted
+        let formatted = "int a = '\\'' + '\\\\' + '\\n' + '\\x80' + 0x1 + 0xffff + 0x0000;\n";
+        assert_eq!(format(formatted.as_bytes()).unwrap(), formatted);
+    }
+
+    #[test]
+    fn storage_reuse() {
+        // Sibling blocks share cells (as do variables declared after a block ends), but enclosing
+        // variables don't
+        let prog = b"int a = 1;
+        int b;
+        {
+            int c = 2;
+            int d[2];
+            d[1] = c + a;
+            b = d[1];
+        }
+        {
+            int e = 5;
+            b += e;
+        }
+        for (int i = 0; i < 2; i += 1) { b += i; }
+        int f = g(4);
+        int g(int n) {
+            { int x = n + 1; n = x; }
+            { int y = n * 2; n = y; }
+            return n;
+        }";
+        let asm = compile(&prog[..]).unwrap();
+        let program = Assembler::assemble(asm.as_bytes()).unwrap();
+        let addr = |label: &str| program.labels()[label];
+        assert_eq!(addr("c"), addr("e"));
+        assert_eq!(addr("c"), addr("i"));
+        assert_eq!(addr("g.x"), addr("g.y"));
+        assert_eq!(addr("c"), addr("f"));
+        let distinct = ["a", "b", "c", "d", "d._1"].iter().map(|&l| addr(l)).collect::<HashSet<_>>();
+        assert_eq!(5, distinct.len());
+
+        assert_eq!(run(prog, &["a", "b", "f"]), [1, 9, 10]);
+    }
+
+    #[test]
+    fn static_frames() {
+        // Frames are only saved on the stack when a call may lead back to the caller
+        let prog = b"int r = f(3);
+        int f(int n) { return g(n) + h(n); }
+        int g(int n) { return h(n) * 2; }
+        int h(int n) { return n + 1; }";
+        let asm = compile(&prog[..]).unwrap();
+        assert!(!asm.contains("_save") && !asm.contains("_sp"), "{}", asm);
+        assert_eq!(run(prog, &["r"]), [12]);
+
+        let prog = b"int r = f(5);
+        int f(int n) { if (n == 0) return 0; return h(n) + g(n - 1); }
+        int g(int n) { return f(n); }
+        int h(int n) { return n; }";
+        let asm = compile(&prog[..]).unwrap();
+        assert!(asm.contains("f._save") && asm.contains("g._save") && !asm.contains("h._save"), "{}", asm);
+        assert_eq!(run(prog, &["r"]), [15]);
+    }
+
+    #[test]
+    fn memory_limit() {
+        // The `halt` and the array fill memory exactly
+        let code = compile_to_lines(&b"int a[4095];"[..], 0).unwrap();
+        assert_eq!(alloc::MemoryUsage::of(&code), alloc::MemoryUsage { code: 1, data: 4095 });
+        assert_eq!(0, alloc::MemoryUsage::of(&code).free());
+
+        let progs: &[(&[u8], usize)] = &[(b"int a[4096];", 1),
+                                         (b"int a;\nint f(int x) {\n  int b[4090];\n  return x;\n}", 3),
+                                         (b"int a[2000];\n{ int b[1000]; }\n{ int c[3000]; }", 3)];
+        for &(prog, line) in progs {
+            match compile(prog) {
+                Err(Error(ErrorKind::Codegen(_, n), _)) if n == line => {}
+                res => panic!("expected codegen error on line {}, got {:?}", line, res),
+            }
+        }
+        // Cells are reused, so these fit
+        compile(&b"int a[1000];\n{ int b[3000]; }\n{ int c[3000]; }"[..]).unwrap();
+    }
+}
@@ -0,0 +1,679 @@
+//! The IBCMC optimizer.
+//!
+//! Optimization happens in two stages. Before code generation, the AST is simplified by
+//! `optimize`, and after code generation, redundant instructions are removed from the
+//! generated code by `peephole`. The optimization level determines what is done:
+//!
+//! * Level 0 does no optimization at all.
+//! * Level 1 folds constant expressions (including uses of `const` variables whose
+//!   initializers are constant) and runs the peephole optimizer.
+//! * Level 2 also removes dead code: statements which can never be executed, `if` and loop
+//!   statements whose conditions are constant, functions which are never called, and local
+//!   variables of functions which are never read. Global variables are never removed, since
+//!   their values are observable (e.g. in the debugger).
+//!
+//! Optimization never changes the behaviour of a valid program, so it must only be done after
+//! the program has been checked using `Checker::check`.
+
+use std::collections::{HashMap, HashSet};
+
+use instruction::Instruction;
+use ibcmc::ast::{BinOp, Block, Decl, Expr, Stmt, StmtLine, UnOp};
+use ibcmc::ast::{visit_exprs, visit_stmts, visit_subexprs};
+use ibcmc::codegen::{Line, Op};
+use ibcmc::lexer::{Ident, IntFormat, Literal};
+
+/// Optimizes the given program at the given level, returning the optimized program.
+pub fn optimize(program: &Block, level: u8) -> Block {
+    if level == 0 {
+        return program.clone();
+    }
+
+    let mut folder = Folder {
+        scopes: vec![HashMap::new()],
+        dead_code: level >= 2,
+    };
+    let program = folder.program(program);
+    if level >= 2 {
+        remove_unused_locals(&remove_uncalled_functions(&program))
+    } else {
+        program
+    }
+}
+
+/// Folds constant expressions (and optionally removes dead code) in a program.
+struct Folder {
+    /// The variables visible in each nested scope, along with their values if they are
+    /// constants with a known value.
+    scopes: Vec<HashMap<String, Option<u16>>>,
+    /// Whether to remove dead code.
+    dead_code: bool,
+}
+
+impl Folder {
+    /// Folds a complete program.
+    ///
+    /// As in the code generator, functions are processed after all the top-level statements,
+    /// so that they see every global variable.
+    fn program(&mut self, program: &Block) -> Block {
+        let mut stmts = program.0
+            .iter()
+            .map(|stmt| match *stmt.stmt() {
+                     Stmt::Function(..) => None,
+                     ref s => Some(self.stmt(s).with_line(stmt.line())),
+                 })
+            .collect::<Vec<_>>();
+
+        for (i, stmt) in program.0.iter().enumerate() {
+            if let Stmt::Function(ref decl, ref params, ref body) = *stmt.stmt() {
+                self.scopes.push(params.iter().map(|param| (param.name.0.clone(), None)).collect());
+                let body = self.stmts(body);
+                self.scopes.pop();
+                stmts[i] = Some(Stmt::Function(decl.clone(), params.clone(), body).with_line(stmt.line()));
+            }
+        }
+
+        Block(stmts.into_iter()
+                  .map(Option::unwrap)
+                  .filter(|stmt| !self.dead_code || *stmt.stmt() != Stmt::Empty)
+                  .collect())
+    }
+
+    /// Folds the statements of a block, without giving it a new scope.
+    fn stmts(&mut self, block: &Block) -> Block {
+        let mut stmts = Vec::new();
+        for stmt in &block.0 {
+            let folded = self.stmt(stmt.stmt());
+            if self.dead_code && folded == Stmt::Empty {
+                continue;
+            }
+            let jumps = matches!(folded, Stmt::Return(_) | Stmt::Break | Stmt::Continue);
+            stmts.push(folded.with_line(stmt.line()));
+            if self.dead_code && jumps {
+                // Nothing after this in the block can be executed
+                break;
+            }
+        }
+        Block(stmts)
+    }
+
+    /// Folds a single statement.
+    fn stmt(&mut self, stmt: &Stmt) -> Stmt {
+        match *stmt {
+            Stmt::Function(..) => unreachable!("nested function definition"),
+            Stmt::Block(ref block) => {
+                self.scopes.push(HashMap::new());
+                let block = self.stmts(block);
+                self.scopes.pop();
+                if self.dead_code && block.0.is_empty() {
+                    Stmt::Empty
+                } else {
+                    Stmt::Block(block)
+                }
+            }
+            Stmt::Assign(ref target, ref expr) => Stmt::Assign(self.lvalue(target), self.expr(expr)),
+            Stmt::CompoundAssign(ref target, ref op, ref expr) => {
+                Stmt::CompoundAssign(self.lvalue(target), op.clone(), self.expr(expr))
+            }
+            Stmt::Decl(ref decl) => {
+                self.declare(decl, None);
+                stmt.clone()
+            }
+            Stmt::Init(ref decl, ref expr) => {
+                // The initializer is evaluated before the variable comes into scope
+                let expr = self.expr(expr);
+                let value = match expr {
+                    Expr::Literal(Literal::Int(n, _)) if decl.is_const => Some(n),
+                    _ => None,
+                };
+                self.declare(decl, value);
+                Stmt::Init(decl.clone(), expr)
+            }
+            Stmt::Array(ref decl, _) => {
+                self.declare(decl, None);
+                stmt.clone()
+            }
+            Stmt::Expr(ref expr) => Stmt::Expr(self.expr(expr)),
+            Stmt::Return(ref expr) => Stmt::Return(expr.as_ref().map(|expr| self.expr(expr))),
+            Stmt::If(ref cond, ref body, ref else_body) => {
+                let cond = self.expr(cond);
+                let body = self.nested_stmt(body);
+                let else_body = else_body.as_ref().map(|stmt| self.nested_stmt(stmt));
+                if !self.dead_code {
+                    return Stmt::If(cond, Box::new(body), else_body.map(Box::new));
+                }
+                match cond {
+                    Expr::Literal(Literal::Int(0, _)) => else_body.map_or(Stmt::Empty, scoped),
+                    Expr::Literal(Literal::Int(..)) => scoped(body),
+                    _ => Stmt::If(cond, Box::new(body), else_body.map(Box::new)),
+                }
+            }
+            Stmt::While(ref cond, ref body) => {
+                let cond = self.expr(cond);
+                let body = self.nested_stmt(body);
+                if self.dead_code && matches!(cond, Expr::Literal(Literal::Int(0, _))) {
+                    Stmt::Empty
+                } else {
+                    Stmt::While(cond, Box::new(body))
+                }
+            }
+            Stmt::For(ref init, ref cond, ref step, ref body) => {
+                // Variables declared in the initialization are only visible in the loop
+                self.scopes.push(HashMap::new());
+                let init = self.stmt(init);
+                let cond = cond.as_ref().map(|cond| self.expr(cond));
+                let body = self.nested_stmt(body);
+                let step = self.stmt(step);
+                self.scopes.pop();
+                if self.dead_code && matches!(cond, Some(Expr::Literal(Literal::Int(0, _)))) {
+                    // Only the initialization is ever executed
+                    match init {
+                        Stmt::Empty => Stmt::Empty,
+                        init => Stmt::Block(Block(vec![init.with_line(body.line())])),
+                    }
+                } else {
+                    Stmt::For(Box::new(init), cond, Box::new(step), Box::new(body))
+                }
+            }
+            Stmt::Break | Stmt::Continue | Stmt::Empty => stmt.clone(),
+        }
+    }
+
+    /// Folds a statement nested inside another, which gets its own scope.
+    fn nested_stmt(&mut self, stmt: &StmtLine) -> StmtLine {
+        self.scopes.push(HashMap::new());
+        let folded = self.stmt(stmt.stmt());
+        self.scopes.pop();
+        folded.with_line(stmt.line())
+    }
+
+    /// Declares a variable, along with its value if it is a constant with a known value.
+    fn declare(&mut self, decl: &Decl, value: Option<u16>) {
+        self.scopes.last_mut().unwrap().insert(decl.name.0.clone(), value);
+    }
+
+    /// Returns the value of the given variable, if it is a constant with a known value.
+    fn lookup(&self, ident: &Ident) -> Option<u16> {
+        self.scopes
+            .iter()
+            .rev()
+            .filter_map(|scope| scope.get(&ident.0))
+            .next()
+            .and_then(|&value| value)
+    }
+
+    /// Folds the subexpressions of an lvalue (the lvalue itself is left alone, since it refers
+    /// to a memory cell rather than a value).
+    fn lvalue(&self, expr: &Expr) -> Expr {
+        match *expr {
+            Expr::Index(ref base, ref index) => Expr::Index(Box::new(self.expr(base)), Box::new(self.expr(index))),
+            Expr::Deref(ref addr) => Expr::Deref(Box::new(self.expr(addr))),
+            _ => expr.clone(),
+        }
+    }
+
+    /// Folds an expression.
+    fn expr(&self, expr: &Expr) -> Expr {
+        match *expr {
+            Expr::BinOp(ref op, ref lhs, ref rhs) => {
+                match (self.expr(lhs), self.expr(rhs)) {
+                    (Expr::Literal(Literal::Int(a, _)), Expr::Literal(Literal::Int(b, _))) => {
+                        int(eval_binop(op, a, b))
+                    }
+                    (lhs, rhs) => simplify(op, lhs, rhs),
+                }
+            }
+            Expr::UnOp(ref op, ref expr) => {
+                match self.expr(expr) {
+                    Expr::Literal(Literal::Int(n, _)) => int(eval_unop(op, n)),
+                    expr => Expr::UnOp(op.clone(), Box::new(expr)),
+                }
+            }
+            Expr::Call(ref ident, ref args) => {
+                Expr::Call(ident.clone(), args.iter().map(|arg| self.expr(arg)).collect())
+            }
+            Expr::Index(..) | Expr::Deref(_) => self.lvalue(expr),
+            Expr::AddrOf(ref target) => Expr::AddrOf(Box::new(self.lvalue(target))),
+            Expr::Ident(ref ident) => {
+                match self.lookup(ident) {
+                    Some(n) => int(n),
+                    None => expr.clone(),
+                }
+            }
+            Expr::Literal(_) => expr.clone(),
+            Expr::Cast(ref ty, ref expr) => {
+                match self.expr(expr) {
+                    Expr::Literal(Literal::Int(n, _)) => int(ty.cast(n)),
+                    expr => Expr::Cast(ty.clone(), Box::new(expr)),
+                }
+            }
+        }
+    }
+}
+
+/// Wraps a statement in a block if necessary, so that it keeps its own scope when it is
+/// no longer nested inside another statement.
+fn scoped(stmt: StmtLine) -> Stmt {
+    match *stmt.stmt() {
+        Stmt::Block(_) | Stmt::Empty => stmt.stmt().clone(),
+        _ => Stmt::Block(Block(vec![stmt])),
+    }
+}
+
+/// Evaluates a binary operation on constants, giving the same result as the generated code.
+fn eval_binop(op: &BinOp, a: u16, b: u16) -> u16 {
+    let (sa, sb) = (a as i16, b as i16);
+    match *op {
+        BinOp::Add => a.wrapping_add(b),
+        BinOp::Sub => a.wrapping_sub(b),
+        BinOp::Mul => a.wrapping_mul(b),
+        // See the division routine in the runtime library
+        BinOp::Div => if b == 0 { 0 } else { sa.wrapping_div(sb) as u16 },
+        BinOp::Mod => if b == 0 { a } else { sa.wrapping_rem(sb) as u16 },
+        BinOp::Eq => (a == b) as u16,
+        BinOp::Ne => (a != b) as u16,
+        BinOp::Lt => (sa < sb) as u16,
+        BinOp::Le => (sa <= sb) as u16,
+        BinOp::Gt => (sa > sb) as u16,
+        BinOp::Ge => (sa >= sb) as u16,
+        BinOp::And => a & b,
+        BinOp::Or => a | b,
+        BinOp::Xor => a ^ b,
+        BinOp::Shl => if b < 16 { a << b } else { 0 },
+        BinOp::Shr => if b < 16 { a >> b } else { 0 },
+        // See the unsigned division routine in the runtime library
+        BinOp::UDiv => a.checked_div(b).unwrap_or(0),
+        BinOp::UMod => a.checked_rem(b).unwrap_or(a),
+        BinOp::ULt => (a < b) as u16,
+        BinOp::ULe => (a <= b) as u16,
+        BinOp::UGt => (a > b) as u16,
+        BinOp::UGe => (a >= b) as u16,
+    }
+}
+
+/// Evaluates a unary operation on a constant.
+fn eval_unop(op: &UnOp, n: u16) -> u16 {
+    match *op {
+        UnOp::Neg => n.wrapping_neg(),
+        UnOp::Not => !n,
+        UnOp::LogicalNot => (n == 0) as u16,
+    }
+}
+
+/// Returns an integer literal with the given value.
+fn int(n: u16) -> Expr {
+    Expr::Literal(Literal::Int(n, IntFormat::Dec))
+}
+
+/// Simplifies a binary operation with at most one constant operand, using algebraic identities
+/// which don't require evaluating the other operand any differently.
+fn simplify(op: &BinOp, lhs: Expr, rhs: Expr) -> Expr {
+    let value = |expr: &Expr| match *expr {
+        Expr::Literal(Literal::Int(n, _)) => Some(n),
+        _ => None,
+    };
+
+    match (op, value(&lhs), value(&rhs)) {
+        (&BinOp::Add, Some(0), _) | (&BinOp::Or, Some(0), _) | (&BinOp::Xor, Some(0), _) |
+        (&BinOp::Mul, Some(1), _) | (&BinOp::And, Some(0xffff), _) => rhs,
+        (&BinOp::Add, _, Some(0)) | (&BinOp::Sub, _, Some(0)) | (&BinOp::Or, _, Some(0)) |
+        (&BinOp::Xor, _, Some(0)) | (&BinOp::Shl, _, Some(0)) | (&BinOp::Shr, _, Some(0)) |
+        (&BinOp::Mul, _, Some(1)) | (&BinOp::Div, _, Some(1)) | (&BinOp::UDiv, _, Some(1)) |
+        (&BinOp::And, _, Some(0xffff)) => lhs,
+        // Multiplication by a power of 2 is just a shift
+        (&BinOp::Mul, Some(n), _) if n.is_power_of_two() => {
+            Expr::BinOp(BinOp::Shl, Box::new(rhs), Box::new(int(n.trailing_zeros() as u16)))
+        }
+        (&BinOp::Mul, _, Some(n)) if n.is_power_of_two() => {
+            Expr::BinOp(BinOp::Shl, Box::new(lhs), Box::new(int(n.trailing_zeros() as u16)))
+        }
+        _ => Expr::BinOp(op.clone(), Box::new(lhs), Box::new(rhs)),
+    }
+}
+
+/// Removes the definitions of functions which can never be called.
+fn remove_uncalled_functions(program: &Block) -> Block {
+    let mut functions = HashMap::new();
+    let mut called = HashSet::new();
+    let mut pending = Vec::new();
+    for stmt in &program.0 {
+        match *stmt.stmt() {
+            Stmt::Function(ref decl, _, ref body) => {
+                functions.insert(decl.name.0.clone(), body);
+            }
+            ref stmt => visit_exprs(stmt, &mut |expr| collect_calls(expr, &mut pending)),
+        }
+    }
+
+    while let Some(name) = pending.pop() {
+        if !called.insert(name.clone()) {
+            continue;
+        }
+        if let Some(body) = functions.get(&name) {
+            for stmt in &body.0 {
+                visit_exprs(stmt.stmt(), &mut |expr| collect_calls(expr, &mut pending));
+            }
+        }
+    }
+
+    Block(program.0
+              .iter()
+              .filter(|stmt| match *stmt.stmt() {
+                          Stmt::Function(ref decl, _, _) => called.contains(&decl.name.0),
+                          _ => true,
+                      })
+              .cloned()
+              .collect())
+}
+
+/// Removes local variables of functions which are never read, along with any assignments to
+/// them (keeping any function calls in the assigned values).
+///
+/// To avoid having to resolve names, a variable is only removed if nothing with the same name
+/// is read anywhere in the function and there is no global variable with the same name.
+fn remove_unused_locals(program: &Block) -> Block {
+    let mut globals = HashSet::new();
+    for stmt in &program.0 {
+        if let Stmt::Function(..) = *stmt.stmt() {
+            continue;
+        }
+        visit_stmts(stmt.stmt(), &mut |stmt| match *stmt {
+            Stmt::Decl(ref decl) | Stmt::Init(ref decl, _) | Stmt::Array(ref decl, _) => {
+                globals.insert(decl.name.0.clone());
+            }
+            _ => {}
+        });
+    }
+
+    Block(program.0
+              .iter()
+              .map(|stmt| match *stmt.stmt() {
+                       Stmt::Function(ref decl, ref params, ref body) => {
+                           let mut read = HashSet::new();
+                           for stmt in &body.0 {
+                               visit_reads(stmt.stmt(), &mut read);
+                           }
+                           let unused = |name: &Ident| !read.contains(&name.0) && !globals.contains(&name.0);
+                           let body = remove_assignments(body, &unused);
+                           Stmt::Function(decl.clone(), params.clone(), body).with_line(stmt.line())
+                       }
+                       _ => stmt.clone(),
+                   })
+              .collect())
+}
+
+/// Removes the declarations of (and assignments to) the variables for which `unused` returns
+/// `true` from a block.
+fn remove_assignments<F: Fn(&Ident) -> bool>(block: &Block, unused: &F) -> Block {
+    // Keeps the value of a removed assignment only if it has side effects
+    let keep = |expr: &Expr| if has_calls(expr) {
+        Stmt::Expr(expr.clone())
+    } else {
+        Stmt::Empty
+    };
+    let nested = |stmt: &StmtLine| {
+        remove_assignments(&Block(vec![stmt.clone()]), unused)
+            .0
+            .pop()
+            .unwrap_or_else(|| Stmt::Empty.with_line(stmt.line()))
+    };
+
+    Block(block.0
+              .iter()
+              .map(|stmt| {
+        let new = match *stmt.stmt() {
+            Stmt::Decl(ref decl) |
+            Stmt::Array(ref decl, _) if unused(&decl.name) => Stmt::Empty,
+            Stmt::Init(ref decl, ref expr) if unused(&decl.name) => keep(expr),
+            Stmt::Assign(Expr::Ident(ref ident), ref expr) |
+            Stmt::CompoundAssign(Expr::Ident(ref ident), _, ref expr) if unused(ident) => keep(expr),
+            Stmt::Block(ref block) => Stmt::Block(remove_assignments(block, unused)),
+            Stmt::If(ref cond, ref body, ref else_body) => {
+                Stmt::If(cond.clone(),
+                         Box::new(nested(body)),
+                         else_body.as_ref().map(|stmt| Box::new(nested(stmt))))
+            }
+            Stmt::While(ref cond, ref body) => Stmt::While(cond.clone(), Box::new(nested(body))),
+            Stmt::For(ref init, ref cond, ref step, ref body) => {
+                let line = stmt.line();
+                let simple = |stmt: &Stmt| nested(&stmt.clone().with_line(line)).stmt().clone();
+                Stmt::For(Box::new(simple(init)),
+                          cond.clone(),
+                          Box::new(simple(step)),
+                          Box::new(nested(body)))
+            }
+            ref stmt => stmt.clone(),
+        };
+        new.with_line(stmt.line())
+    })
+              .filter(|stmt| *stmt.stmt() != Stmt::Empty)
+              .collect())
+}
+
+/// Adds the names of all the functions called in the given expression to `calls`.
+fn collect_calls(expr: &Expr, calls: &mut Vec<String>) {
+    visit_subexprs(expr, &mut |expr| if let Expr::Call(ref ident, _) = *expr {
+        calls.push(ident.0.clone());
+    });
+}
+
+/// Adds the names of all the variables read in the given statement to `read`.
+///
+/// A variable which is only assigned to (including by a compound assignment, which only reads
+/// the variable in order to assign to it) is not considered to be read.
+fn visit_reads(stmt: &Stmt, read: &mut HashSet<String>) {
+    let mut record = |expr: &Expr| {
+        visit_subexprs(expr, &mut |expr| if let Expr::Ident(ref ident) = *expr {
+            read.insert(ident.0.clone());
+        })
+    };
+    visit_stmts(stmt, &mut |stmt| match *stmt {
+        Stmt::Assign(Expr::Ident(_), ref expr) |
+        Stmt::CompoundAssign(Expr::Ident(_), _, ref expr) => record(expr),
+        Stmt::Assign(ref target, ref expr) |
+        Stmt::CompoundAssign(ref target, _, ref expr) => {
+            record(target);
+            record(expr);
+        }
+        Stmt::Init(_, ref expr) | Stmt::Expr(ref expr) | Stmt::Return(Some(ref expr)) |
+        Stmt::If(ref expr, _, _) | Stmt::While(ref expr, _) | Stmt::For(_, Some(ref expr), _, _) => record(expr),
+        _ => {}
+    });
+}
+
+/// Returns whether the given expression contains any function calls (which are the only
+/// expressions that can have side effects).
+fn has_calls(expr: &Expr) -> bool {
+    let mut calls = Vec::new();
+    collect_calls(expr, &mut calls);
+    !calls.is_empty()
+}
+
+/// Removes redundant instructions from generated code.
+///
+/// The following optimizations are repeated until none of them apply:
+///
+/// * A `load x` directly after a `store x` (or vice versa) is removed.
+/// * An instruction which only changes the accumulator is removed if it is directly followed by
+///   a `load`, and so is an `add`, `sub`, `or` or `xor` of the constant 0.
+/// * Jumps to the next line are removed, and jumps to a `jmp` are redirected to its target.
+/// * Unlabelled instructions after a `jmp` or `halt` (which can never be executed) are removed.
+/// * Internal labels, constants and address cells which are no longer referenced are removed.
+///
+/// Lines whose labels are used by anything other than a jump (e.g. the cells overwritten by
+/// self-modifying code) are never changed, since their contents at runtime may differ from
+/// what was generated.
+pub fn peephole(mut code: Vec<Line>) -> Vec<Line> {
+    loop {
+        let mut changed = false;
+        changed |= remove_redundant(&mut code);
+        changed |= thread_jumps(&mut code);
+        changed |= remove_unreachable(&mut code);
+        changed |= remove_unreferenced(&mut code);
+        if !changed {
+            return code;
+        }
+    }
+}
+
+/// Returns the labels used as arguments of instructions, and the subset of these which are used
+/// by instructions other than jumps (i.e. referring to cells which are used as data).
+fn references(code: &[Line]) -> (HashSet<String>, HashSet<String>) {
+    let mut referenced = HashSet::new();
+    let mut data = HashSet::new();
+    for line in code {
+        if let Op::Instr(instr, Some(ref label)) = line.op {
+            referenced.insert(label.clone());
+            if !is_jump(instr) {
+                data.insert(label.clone());
+            }
+        }
+    }
+    (referenced, data)
+}
+
+/// Returns whether the instruction is a jump (including `brl`).
+fn is_jump(instr: Instruction) -> bool {
+    matches!(instr, Instruction::Jmp(_) | Instruction::Jmpe(_) | Instruction::Jmpl(_) | Instruction::Brl(_))
+}
+
+/// Returns whether the instruction only changes the accumulator (and does nothing else).
+fn only_changes_acc(instr: Instruction) -> bool {
+    matches!(instr,
+             Instruction::Load(_) | Instruction::Add(_) | Instruction::Sub(_) | Instruction::And(_) |
+             Instruction::Or(_) | Instruction::Xor(_) | Instruction::Not | Instruction::Shift(..))
+}
+
+/// Removes redundant loads, stores and arithmetic, and jumps to the next line.
+fn remove_redundant(code: &mut Vec<Line>) -> bool {
+    let (_, data) = references(code);
+    let fixed = |line: &Line| line.labels.iter().any(|label| data.contains(label));
+    let zero = Some("#0000".to_owned());
+
+    let mut changed = false;
+    let mut i = 0;
+    while i + 1 < code.len() {
+        if fixed(&code[i]) || fixed(&code[i + 1]) {
+            i += 1;
+            continue;
+        }
+        let redundant = match (&code[i].op, &code[i + 1].op) {
+            // The second line can be skipped only if nothing jumps to it
+            (&Op::Instr(Instruction::Store(_), ref a), &Op::Instr(Instruction::Load(_), ref b)) |
+            (&Op::Instr(Instruction::Load(_), ref a), &Op::Instr(Instruction::Store(_), ref b))
+                if a == b && code[i + 1].labels.is_empty() => Some(i + 1),
+            (&Op::Instr(instr, _), &Op::Instr(Instruction::Load(_), _)) if only_changes_acc(instr) => Some(i),
+            (&Op::Instr(Instruction::Add(_), ref a), &Op::Instr(..)) |
+            (&Op::Instr(Instruction::Sub(_), ref a), &Op::Instr(..)) |
+            (&Op::Instr(Instruction::Or(_), ref a), &Op::Instr(..)) |
+            (&Op::Instr(Instruction::Xor(_), ref a), &Op::Instr(..)) if *a == zero => Some(i),
+            (&Op::Instr(Instruction::Jmp(_), Some(ref target)), _) |
+            (&Op::Instr(Instruction::Jmpe(_), Some(ref target)), _) |
+            (&Op::Instr(Instruction::Jmpl(_), Some(ref target)), _)
+                if code[i + 1].labels.contains(target) => Some(i),
+            _ => None,
+        };
+
+        match redundant {
+            Some(n) => {
+                // Anything jumping to the removed line can just go to the next one
+                let mut labels = code.remove(n).labels;
+                if !labels.is_empty() {
+                    labels.append(&mut code[n].labels);
+                    code[n].labels = labels;
+                }
+                changed = true;
+            }
+            None => i += 1,
+        }
+    }
+
+    changed
+}
+
+/// Redirects jumps to a `jmp` instruction to the target of the `jmp`.
+fn thread_jumps(code: &mut [Line]) -> bool {
+    let (_, data) = references(code);
+    let mut targets = HashMap::new();
+    for line in code.iter() {
+        if let Op::Instr(Instruction::Jmp(_), Some(ref target)) = line.op {
+            if line.labels.iter().all(|label| !data.contains(label)) {
+                for label in &line.labels {
+                    targets.insert(label.clone(), target.clone());
+                }
+            }
+        }
+    }
+
+    let mut changed = false;
+    for line in code.iter_mut() {
+        if let Op::Instr(instr, Some(ref mut target)) = line.op {
+            if !is_jump(instr) {
+                continue;
+            }
+            // Follow the chain of jumps, making sure not to get stuck in a loop
+            let mut seen = HashSet::new();
+            let mut dest = target.clone();
+            while let Some(next) = targets.get(&dest) {
+                if !seen.insert(dest.clone()) {
+                    break;
+                }
+                dest = next.clone();
+            }
+            if !seen.contains(&dest) && dest != *target {
+                *target = dest;
+                changed = true;
+            }
+        }
+    }
+
+    changed
+}
+
+/// Removes unlabelled instructions following a `jmp` or `halt`, which can never be executed.
+fn remove_unreachable(code: &mut Vec<Line>) -> bool {
+    let (_, data) = references(code);
+    let mut changed = false;
+    let mut i = 0;
+    while i + 1 < code.len() {
+        let ends = match code[i].op {
+            Op::Instr(Instruction::Jmp(_), _) | Op::Instr(Instruction::Halt, _) => {
+                code[i].labels.iter().all(|label| !data.contains(label))
+            }
+            _ => false,
+        };
+        if ends {
+            while i + 1 < code.len() && code[i + 1].labels.is_empty() {
+                if let Op::Data(_) = code[i + 1].op {
+                    break;
+                }
+                code.remove(i + 1);
+                changed = true;
+            }
+        }
+        i += 1;
+    }
+
+    changed
+}
+
+/// Removes internal labels, constants and address cells which are no longer referenced.
+fn remove_unreferenced(code: &mut Vec<Line>) -> bool {
+    let (referenced, _) = references(code);
+    let len = code.len();
+
+    // Constants and address cells are in the data section, so they can be removed entirely
+    code.retain(|line| {
+        line.labels.is_empty() ||
+        line.labels.iter().any(|label| referenced.contains(label) || !(label.starts_with('#') || label.starts_with('&')))
+    });
+    let mut changed = code.len() != len;
+
+    for line in code.iter_mut() {
+        let len = line.labels.len();
+        line.labels.retain(|label| !label.starts_with("_L") || referenced.contains(label));
+        changed |= line.labels.len() != len;
+    }
+
+    changed
+}
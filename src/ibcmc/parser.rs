@@ -3,12 +3,15 @@
 use std::io::Result as IoResult;
 
 use ibcmc::errors::*;
-use ibcmc::ast::{Type, BinOp, Block, Stmt, Expr, Decl, StmtLine};
-use ibcmc::lexer::{Lexer, Token, Ident, Keyword};
+use ibcmc::ast::{Type, BinOp, UnOp, Block, Stmt, Expr, Decl, StmtLine};
+use ibcmc::lexer::{Comments, Lexer, Token, Ident, Keyword, Literal, Pos};
 
 macro_rules! eparse {
     ($self:ident, $($arg:tt)*) => {
-        ErrorKind::Parser(format!($($arg)*), $self.lexer.line()).into()
+        {
+            let pos = $self.lexer.span().start;
+            ErrorKind::Parser(format!($($arg)*), pos.line, pos.col).into()
+        }
     }
 }
 
@@ -17,32 +20,148 @@ pub struct Parser<I>
     where I: Iterator<Item = IoResult<u8>>
 {
     /// The underlying token stream.
-    lexer: Lexer<I>
+    lexer: Lexer<I>,
+    /// The errors which have been encountered (and recovered from) so far.
+    errors: Vec<Error>,
+    /// Whether an unrecoverable error (such as a failure to read the input) has occurred.
+    fatal: bool,
+    /// The position of each statement parsed so far, in order.
+    stmt_starts: Vec<Pos>,
 }
 
 impl<I> Parser<I>
     where I: Iterator<Item = IoResult<u8>>
 {
-    /// Parses a complete program from the given lexer.
+    /// Parses a complete program from the given lexer, returning the first error if there are
+    /// any.
     pub fn parse_from_lexer(lexer: Lexer<I>) -> Result<Block>
     {
-        let mut parser = Parser { lexer };
-        parser.block()
+        Parser::parse_with_comments(lexer).map(|(block, _)| block)
+    }
+
+    /// Parses a complete program from the given lexer like `parse_from_lexer`, also returning
+    /// the comments skipped by the lexer (so that the program can be printed along with them).
+    pub fn parse_with_comments(lexer: Lexer<I>) -> Result<(Block, Comments)> {
+        let mut parser = Parser::new(lexer);
+        let block = parser.program();
+        if parser.errors.is_empty() {
+            let mut comments = parser.lexer.comments().clone();
+            comments.breaks.extend(parser.stmt_starts);
+            comments.breaks.sort();
+            Ok((block, comments))
+        } else {
+            Err(parser.errors.remove(0))
+        }
+    }
+
+    /// Parses a complete program from the given lexer, recovering from errors so that all of
+    /// them can be reported.
+    ///
+    /// When a statement cannot be parsed, the parser skips ahead to the next `;` or the `}`
+    /// ending the enclosing block, and continues from there. The returned program contains all
+    /// the statements which could be parsed, and the errors are returned in the order in which
+    /// they were encountered.
+    pub fn parse_recovering(lexer: Lexer<I>) -> (Block, Vec<Error>) {
+        let mut parser = Parser::new(lexer);
+        let block = parser.program();
+        (block, parser.errors)
+    }
+
+    /// Creates a parser reading from the given lexer.
+    fn new(lexer: Lexer<I>) -> Self {
+        Parser {
+            lexer,
+            errors: Vec::new(),
+            fatal: false,
+            stmt_starts: Vec::new(),
+        }
     }
 
-    /// Parses a block.
-    fn block(&mut self) -> Result<Block> {
+    /// Parses a complete program, recovering from errors (see `parse_recovering`).
+    fn program(&mut self) -> Block {
         let mut stmts = Vec::new();
 
-        while let Some(tok) = self.lexer.peek() {
-            if tok? == Token::RBrace {
-                // End of the current block
+        loop {
+            stmts.extend(self.block().0);
+            // The top-level block can only end early at a stray `}`
+            match self.lexer.next() {
+                Some(Ok(_)) => {
+                    let e = eparse!(self, "unmatched `}}`");
+                    self.record(e);
+                }
+                Some(Err(e)) => self.record(e),
+                None => break,
+            }
+            if self.fatal {
                 break;
             }
-            stmts.push(self.stmt()?);
         }
 
-        Ok(Block(stmts))
+        Block(stmts)
+    }
+
+    /// Records an error, so that parsing can continue.
+    fn record(&mut self, e: Error) {
+        // Errors which were caused by something else (i.e. a failure to read the input) can't be
+        // recovered from, since reading further would most likely just fail again
+        if e.iter().nth(1).is_some() {
+            self.fatal = true;
+        }
+        self.errors.push(e);
+    }
+
+    /// Recovers from an error in a statement by skipping tokens up to and including the next
+    /// `;`, or up to (but not including) the `}` ending the current block.
+    ///
+    /// Any nested blocks are skipped entirely, and the end of a nested block is also treated as
+    /// the end of the statement (e.g. in a function definition with an invalid parameter list).
+    fn synchronize(&mut self) {
+        let mut depth = 0;
+
+        while !self.fatal {
+            match self.lexer.next() {
+                Some(Ok(Token::Semi)) if depth == 0 => break,
+                Some(Ok(Token::LBrace)) => depth += 1,
+                Some(Ok(Token::RBrace)) => {
+                    if depth == 0 {
+                        self.lexer.put_back(Token::RBrace);
+                        break;
+                    }
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => self.record(e),
+                None => break,
+            }
+        }
+    }
+
+    /// Parses a block, recovering from any errors in its statements.
+    fn block(&mut self) -> Block {
+        let mut stmts = Vec::new();
+
+        while !self.fatal {
+            match self.lexer.peek() {
+                // End of the current block
+                Some(Ok(Token::RBrace)) | None => break,
+                Some(Ok(_)) => {
+                    match self.stmt() {
+                        Ok(stmt) => stmts.push(stmt),
+                        Err(e) => {
+                            self.record(e);
+                            self.synchronize();
+                        }
+                    }
+                }
+                // The lexer has already skipped the invalid input
+                Some(Err(e)) => self.record(e),
+            }
+        }
+
+        Block(stmts)
     }
 
     /// Parses a statement.
@@ -51,48 +170,70 @@ impl<I> Parser<I>
     fn stmt(&mut self) -> Result<StmtLine> {
         if let Some(tok) = self.lexer.next() {
             let line = self.lexer.line();
+            self.stmt_starts.push(self.lexer.span().start);
             let stmt = match tok? {
                 Token::Semi => Stmt::Empty,
-                tok @ Token::Keyword(Keyword::Const) | tok @ Token::Keyword(Keyword::Int) => {
+                tok @ Token::Keyword(Keyword::Const) |
+                tok @ Token::Keyword(Keyword::Int) |
+                tok @ Token::Keyword(Keyword::Unsigned) |
+                tok @ Token::Keyword(Keyword::Char) |
+                tok @ Token::Keyword(Keyword::Bool) |
+                tok @ Token::Keyword(Keyword::Void) => {
                     self.lexer.put_back(tok);
                     self.stmt_after_type()?
                 }
-                Token::Ident(ident) => {
-                    if let Some(tok) = self.lexer.next() {
-                        match tok? {
-                            Token::Assign => {
-                                let expr = self.expr()?;
-                                self.expect(Token::Semi)?;
-                                Stmt::Assign(ident, expr)
-                            }
-                            Token::AddAssign => {
-                                let expr = self.expr()?;
-                                self.expect(Token::Semi)?;
-                                Stmt::CompoundAssign(ident, BinOp::Add, expr)
-                            }
-                            Token::SubAssign => {
-                                let expr = self.expr()?;
-                                self.expect(Token::Semi)?;
-                                Stmt::CompoundAssign(ident, BinOp::Sub, expr)
-                            }
-                            tok => {
-                                self.lexer.put_back(tok);
-                                self.expect(Token::Semi)?;
-                                Stmt::Expr(self.expr()?)
-                            }
+                Token::Keyword(Keyword::Return) => {
+                    match self.lexer.next() {
+                        Some(Ok(Token::Semi)) => Stmt::Return(None),
+                        Some(Ok(tok)) => {
+                            self.lexer.put_back(tok);
+                            let expr = self.expr()?;
+                            self.expect(Token::Semi)?;
+                            Stmt::Return(Some(expr))
                         }
-                    } else {
-                        return Err(eparse!(self, "expected expression or assignment"));
+                        Some(Err(e)) => return Err(e),
+                        None => return Err(eparse!(self, "expected `;` or expression after `return`")),
                     }
                 }
+                Token::Keyword(Keyword::If) => {
+                    self.expect(Token::LParen)?;
+                    let cond = self.expr()?;
+                    self.expect(Token::RParen)?;
+                    let body = self.stmt()?;
+                    let else_body = match self.lexer.next() {
+                        Some(Ok(Token::Keyword(Keyword::Else))) => Some(Box::new(self.stmt()?)),
+                        Some(Ok(tok)) => {
+                            self.lexer.put_back(tok);
+                            None
+                        }
+                        Some(Err(e)) => return Err(e),
+                        None => None,
+                    };
+                    Stmt::If(cond, Box::new(body), else_body)
+                }
+                Token::Keyword(Keyword::While) => {
+                    self.expect(Token::LParen)?;
+                    let cond = self.expr()?;
+                    self.expect(Token::RParen)?;
+                    Stmt::While(cond, Box::new(self.stmt()?))
+                }
+                Token::Keyword(Keyword::For) => self.for_loop()?,
+                Token::Keyword(Keyword::Break) => {
+                    self.expect(Token::Semi)?;
+                    Stmt::Break
+                }
+                Token::Keyword(Keyword::Continue) => {
+                    self.expect(Token::Semi)?;
+                    Stmt::Continue
+                }
                 Token::LBrace => {
-                    let block = self.block()?;
+                    let block = self.block();
                     self.expect(Token::RBrace)?;
                     Stmt::Block(block)
                 }
                 tok => {
                     self.lexer.put_back(tok);
-                    let res = Stmt::Expr(self.expr()?);
+                    let res = self.simple_stmt()?;
                     self.expect(Token::Semi)?;
                     res
                 }
@@ -103,6 +244,72 @@ impl<I> Parser<I>
         }
     }
 
+    /// Parses an assignment or expression statement, without the semicolon at the end.
+    fn simple_stmt(&mut self) -> Result<Stmt> {
+        let expr = self.expr()?;
+        let op = match self.lexer.next() {
+            Some(Ok(Token::Assign)) => None,
+            Some(Ok(Token::AddAssign)) => Some(BinOp::Add),
+            Some(Ok(Token::SubAssign)) => Some(BinOp::Sub),
+            Some(Ok(tok)) => {
+                self.lexer.put_back(tok);
+                return Ok(Stmt::Expr(expr));
+            }
+            Some(Err(e)) => return Err(e),
+            None => return Ok(Stmt::Expr(expr)),
+        };
+
+        if !expr.is_lvalue() {
+            return Err(eparse!(self, "invalid assignment target"));
+        }
+        Ok(match op {
+            Some(op) => Stmt::CompoundAssign(expr, op, self.expr()?),
+            None => Stmt::Assign(expr, self.expr()?),
+        })
+    }
+
+    /// Parses the rest of a `for` loop (after the `for` keyword).
+    fn for_loop(&mut self) -> Result<Stmt> {
+        self.expect(Token::LParen)?;
+        // The initialization may be any statement ending in a semicolon, but is only
+        // allowed to be a declaration, initialization, assignment or expression
+        let starts = self.stmt_starts.len();
+        let init = self.stmt()?.stmt().clone();
+        // The initialization is printed as part of the loop rather than as a statement
+        self.stmt_starts.truncate(starts);
+        match init {
+            Stmt::Decl(_) | Stmt::Init(..) | Stmt::Assign(..) | Stmt::CompoundAssign(..) |
+            Stmt::Expr(_) | Stmt::Empty => {}
+            _ => return Err(eparse!(self, "invalid initialization statement in `for` loop")),
+        }
+
+        let cond = match self.lexer.next() {
+            Some(Ok(Token::Semi)) => None,
+            Some(Ok(tok)) => {
+                self.lexer.put_back(tok);
+                let cond = self.expr()?;
+                self.expect(Token::Semi)?;
+                Some(cond)
+            }
+            Some(Err(e)) => return Err(e),
+            None => return Err(eparse!(self, "expected condition in `for` loop")),
+        };
+
+        let step = match self.lexer.next() {
+            Some(Ok(Token::RParen)) => Stmt::Empty,
+            Some(Ok(tok)) => {
+                self.lexer.put_back(tok);
+                let step = self.simple_stmt()?;
+                self.expect(Token::RParen)?;
+                step
+            }
+            Some(Err(e)) => return Err(e),
+            None => return Err(eparse!(self, "expected step statement in `for` loop")),
+        };
+
+        Ok(Stmt::For(Box::new(init), cond, Box::new(step), Box::new(self.stmt()?)))
+    }
+
     /// Parses a variable declaration, initialization, or function definition.
     fn stmt_after_type(&mut self) -> Result<Stmt> {
         let decl = self.decl()?;
@@ -115,39 +322,141 @@ impl<I> Parser<I>
                     self.expect(Token::Semi)?;
                     Stmt::Init(decl, expr)
                 }
+                Token::LBracket => {
+                    let size = match self.lexer.next() {
+                        Some(Ok(Token::Literal(Literal::Int(n, _)))) => n,
+                        Some(Ok(tok)) => return Err(self.unexpected(format!("expected array size, got `{}`", tok), tok)),
+                        Some(Err(e)) => return Err(e),
+                        None => return Err(eparse!(self, "expected array size")),
+                    };
+                    self.expect(Token::RBracket)?;
+                    self.expect(Token::Semi)?;
+                    Stmt::Array(decl, size)
+                }
                 Token::LParen => {
                     let param_list = self.param_list()?;
                     self.expect(Token::RParen)?;
                     self.expect(Token::LBrace)?;
-                    let body = self.block()?;
+                    let body = self.block();
                     self.expect(Token::RBrace)?;
                     Stmt::Function(decl, param_list, body)
                 }
-                tok => return Err(eparse!(self, "expected `;`, `=`, or `(`, got `{}`", tok)),
+                tok => return Err(self.unexpected(format!("expected `;`, `=`, `[`, or `(`, got `{}`", tok), tok)),
             })
         } else {
-            Err(eparse!(self, "expected `;`, `=`, or `(`"))
+            Err(eparse!(self, "expected `;`, `=`, `[`, or `(`"))
         }
     }
 
     /// Parses an expression.
     fn expr(&mut self) -> Result<Expr> {
-        if let Some(tok) = self.lexer.next() {
-            let lhs = match tok? {
-                Token::Ident(ident) => Expr::Ident(ident),
-                Token::Literal(lit) => Expr::Literal(lit),
-                tok => return Err(eparse!(self, "expected expression term, got `{}`", tok))
+        self.binary_expr(0)
+    }
+
+    /// Parses an expression containing only binary operations with at least the given precedence,
+    /// using precedence climbing.
+    fn binary_expr(&mut self, min_prec: u8) -> Result<Expr> {
+        let mut lhs = self.unary_expr()?;
+
+        loop {
+            let tok = match self.lexer.next() {
+                Some(tok) => tok?,
+                None => return Ok(lhs),
             };
-            Ok(match self.lexer.next() {
-                None => lhs,
-                Some(Err(e)) => return Err(e),
-                Some(Ok(Token::Add)) => Expr::BinOp(BinOp::Add, Box::new(lhs), Box::new(self.expr()?)),
-                Some(Ok(Token::Sub)) => Expr::BinOp(BinOp::Sub, Box::new(lhs), Box::new(self.expr()?)),
-                Some(Ok(tok @ Token::Semi)) => {
+            if let Token::LogicalAnd | Token::LogicalOr = tok {
+                let bitwise = if tok == Token::LogicalAnd { "&" } else { "|" };
+                let msg = format!("logical `{}` is not supported; use `{}` on bool values",
+                                  tok,
+                                  bitwise);
+                return Err(self.unexpected(msg, tok));
+            }
+            match binop(&tok) {
+                Some((op, prec)) if prec >= min_prec => {
+                    // All binary operations are left-associative
+                    let rhs = self.binary_expr(prec + 1)?;
+                    lhs = Expr::BinOp(op, Box::new(lhs), Box::new(rhs));
+                }
+                _ => {
+                    // Whatever comes after the expression is up to the caller
                     self.lexer.put_back(tok);
-                    lhs
+                    return Ok(lhs);
+                }
+            }
+        }
+    }
+
+    /// Parses a unary expression (a postfix expression preceded by any number of unary operators).
+    fn unary_expr(&mut self) -> Result<Expr> {
+        let op = match self.lexer.next() {
+            Some(Ok(Token::Sub)) => UnOp::Neg,
+            Some(Ok(Token::Not)) => UnOp::Not,
+            Some(Ok(Token::LogicalNot)) => UnOp::LogicalNot,
+            Some(Ok(Token::Mul)) => return Ok(Expr::Deref(Box::new(self.unary_expr()?))),
+            Some(Ok(Token::And)) => {
+                let expr = self.unary_expr()?;
+                if !expr.is_lvalue() {
+                    return Err(eparse!(self, "cannot take the address of a value which is not stored in memory"));
                 }
-                Some(Ok(tok)) => return Err(eparse!(self, "expected binary operation, got `{}`", tok)),
+                return Ok(Expr::AddrOf(Box::new(expr)));
+            }
+            Some(Ok(tok)) => {
+                self.lexer.put_back(tok);
+                return self.postfix_expr();
+            }
+            Some(Err(e)) => return Err(e),
+            None => return Err(eparse!(self, "expected expression")),
+        };
+
+        Ok(Expr::UnOp(op, Box::new(self.unary_expr()?)))
+    }
+
+    /// Parses a postfix expression (a term followed by any number of indexing operations).
+    fn postfix_expr(&mut self) -> Result<Expr> {
+        let mut expr = self.term()?;
+
+        loop {
+            match self.lexer.next() {
+                Some(Ok(Token::LBracket)) => {
+                    let index = self.expr()?;
+                    self.expect(Token::RBracket)?;
+                    expr = Expr::Index(Box::new(expr), Box::new(index));
+                }
+                Some(Ok(tok)) => {
+                    self.lexer.put_back(tok);
+                    return Ok(expr);
+                }
+                Some(Err(e)) => return Err(e),
+                None => return Ok(expr),
+            }
+        }
+    }
+
+    /// Parses an expression term (a variable, function call, literal, or parenthesized expression).
+    fn term(&mut self) -> Result<Expr> {
+        if let Some(tok) = self.lexer.next() {
+            Ok(match tok? {
+                Token::Ident(ident) => {
+                    match self.lexer.next() {
+                        Some(Ok(Token::LParen)) => {
+                            let args = self.arg_list()?;
+                            self.expect(Token::RParen)?;
+                            Expr::Call(ident, args)
+                        }
+                        Some(Ok(tok)) => {
+                            self.lexer.put_back(tok);
+                            Expr::Ident(ident)
+                        }
+                        Some(Err(e)) => return Err(e),
+                        None => Expr::Ident(ident),
+                    }
+                }
+                Token::Literal(lit) => Expr::Literal(lit),
+                Token::LParen => {
+                    let expr = self.expr()?;
+                    self.expect(Token::RParen)?;
+                    expr
+                }
+                tok => return Err(self.unexpected(format!("expected expression term, got `{}`", tok), tok))
             })
         } else {
             Err(eparse!(self, "expected expression"))
@@ -180,6 +489,32 @@ impl<I> Parser<I>
         Ok(params)
     }
 
+    /// Parses a list of function call arguments.
+    fn arg_list(&mut self) -> Result<Vec<Expr>> {
+        let mut args = Vec::new();
+
+        while let Some(tok) = self.lexer.peek() {
+            if tok? == Token::RParen {
+                break;
+            }
+            args.push(self.expr()?);
+
+            if let Some(tok) = self.lexer.next() {
+                match tok? {
+                    Token::Comma => continue,
+                    tok => {
+                        self.lexer.put_back(tok);
+                        break;
+                    }
+                }
+            } else {
+                return Err(eparse!(self, "unexpected end of function argument list"));
+            }
+        }
+
+        Ok(args)
+    }
+
     /// Parses a declaration.
     fn decl(&mut self) -> Result<Decl> {
         if let Some(tok) = self.lexer.next() {
@@ -189,15 +524,38 @@ impl<I> Parser<I>
                     res.is_const = true;
                     res
                 }
-                Token::Keyword(Keyword::Int) => {
+                Token::Keyword(Keyword::Unsigned) => {
+                    // `unsigned int` is the same as `unsigned`
+                    match self.lexer.next() {
+                        Some(Ok(Token::Keyword(Keyword::Int))) => {}
+                        Some(Ok(tok)) => self.lexer.put_back(tok),
+                        Some(Err(e)) => return Err(e),
+                        None => {}
+                    }
                     let name = self.ident()?;
                     Decl {
                         is_const: false,
-                        ty: Type::Int,
+                        ty: Type::Unsigned,
                         name
                     }
                 }
-                tok => return Err(eparse!(self, "expected type, found `{}`", tok)),
+                Token::Keyword(keyword @ Keyword::Int) |
+                Token::Keyword(keyword @ Keyword::Char) |
+                Token::Keyword(keyword @ Keyword::Bool) |
+                Token::Keyword(keyword @ Keyword::Void) => {
+                    let name = self.ident()?;
+                    Decl {
+                        is_const: false,
+                        ty: match keyword {
+                            Keyword::Int => Type::Int,
+                            Keyword::Char => Type::Char,
+                            Keyword::Bool => Type::Bool,
+                            _ => Type::Void,
+                        },
+                        name
+                    }
+                }
+                tok => return Err(self.unexpected(format!("expected type, found `{}`", tok), tok)),
             })
         } else {
             Err(eparse!(self, "expected variable declaration"))
@@ -210,7 +568,7 @@ impl<I> Parser<I>
             None => Err(eparse!(self, "expected identifier")),
             Some(Err(e)) => Err(e),
             Some(Ok(Token::Ident(ident))) => Ok(ident),
-            Some(Ok(tok)) => Err(eparse!(self, "expected identifier, got `{}`", tok)),
+            Some(Ok(tok)) => Err(self.unexpected(format!("expected identifier, got `{}`", tok), tok)),
         }
     }
 
@@ -218,14 +576,47 @@ impl<I> Parser<I>
     fn expect(&mut self, tok: Token) -> Result<()> {
         let got = match self.lexer.next() {
             Some(got) => got?,
-            None => return Err(eparse!(self, "expected `{}`", tok)),    
+            None => return Err(eparse!(self, "expected `{}`", tok)),
         };
 
         if got != tok {
-            Err(eparse!(self, "expected `{}`, got `{}`", tok, got))
+            Err(self.unexpected(format!("expected `{}`, got `{}`", tok, got), got))
         } else {
             Ok(())
         }
     }
+
+    /// Returns an error with the given message at the unexpected token `tok` (which must be the
+    /// last token read), putting the token back so that error recovery can take it into account.
+    fn unexpected(&mut self, msg: String, tok: Token) -> Error {
+        let e = eparse!(self, "{}", msg);
+        self.lexer.put_back(tok);
+        e
+    }
 }
 
+/// Returns the binary operation corresponding to the given token, along with its precedence
+/// (see `BinOp::precedence`), if there is one.
+fn binop(tok: &Token) -> Option<(BinOp, u8)> {
+    let op = match *tok {
+        Token::Or => BinOp::Or,
+        Token::Xor => BinOp::Xor,
+        Token::And => BinOp::And,
+        Token::Eq => BinOp::Eq,
+        Token::Ne => BinOp::Ne,
+        Token::Lt => BinOp::Lt,
+        Token::Le => BinOp::Le,
+        Token::Gt => BinOp::Gt,
+        Token::Ge => BinOp::Ge,
+        Token::Shl => BinOp::Shl,
+        Token::Shr => BinOp::Shr,
+        Token::Add => BinOp::Add,
+        Token::Sub => BinOp::Sub,
+        Token::Mul => BinOp::Mul,
+        Token::Div => BinOp::Div,
+        Token::Mod => BinOp::Mod,
+        _ => return None,
+    };
+    let prec = op.precedence();
+    Some((op, prec))
+}
@@ -0,0 +1,519 @@
+//! Printing of IBCMC syntax trees as source code.
+//!
+//! The `Display` implementations in this module print a syntax tree in a canonical format, which
+//! parses back to the same tree (apart from line numbers):
+//!
+//! * Each statement goes on its own line, indented by four spaces for each enclosing block, and
+//!   top-level function definitions are separated from the surrounding statements by a blank
+//!   line.
+//! * Opening braces go on the same line as the `if`, `while`, `for` or function they belong to,
+//!   and `else` follows the closing brace of the `if`. The bodies of statements which aren't
+//!   blocks go on the next line, indented.
+//! * Binary operators are surrounded by spaces, and parentheses are only used where they are
+//!   needed.
+//! * Integer literals are printed the way they were written: in decimal, in hexadecimal (in
+//!   lowercase, with the same number of digits) or as character literals. Array sizes are
+//!   always printed in decimal. Character and string literals use escapes for quotes,
+//!   backslashes and control characters.
+//!
+//! Comments aren't part of the syntax tree, but the lexer keeps them (along with the positions of
+//! the statements, `else`s and `}`s around them), and `print_with_comments` puts them back: a
+//! comment which follows code on the same line stays at the end of the line printed for that
+//! code, and any other comment goes on its own line before the statement, `else` or `}`
+//! following it. Printing a program which is already formatted therefore gives back the same
+//! text.
+//!
+//! Conversions inserted by the checker are left out, and unsigned operations are printed like
+//! their signed versions, since both are implicit in the source; the output of the checker can
+//! therefore also be printed, giving an equivalent program.
+
+use std::fmt::{self, Display, Formatter, Result as FmtResult, Write};
+
+use ibcmc::ast::{BinOp, Block, Decl, Expr, Stmt, StmtLine, UnOp};
+use ibcmc::lexer::{Comment, Comments, IntFormat, Literal, Pos};
+
+/// The number of spaces per level of indentation.
+const INDENT: usize = 4;
+
+/// The precedence of unary operations (higher than any binary operation).
+const UNARY: u8 = 8;
+/// The precedence of indexing.
+const POSTFIX: u8 = 9;
+/// The precedence of terms (which never need parentheses).
+const TERM: u8 = 10;
+
+/// Prints a complete program along with its comments (as kept by the lexer).
+///
+/// # Examples
+///
+/// ```
+/// use ibcm::ibcmc::{Lexer, Parser};
+/// use ibcm::ibcmc::printer;
+/// use std::io::Read;
+///
+/// let program = "// The answer
+/// int a=0x2a; // not 42
+/// if(a){/* nothing */}";
+///
+/// let lexer = Lexer::new(program.as_bytes().bytes());
+/// let (ast, comments) = Parser::parse_with_comments(lexer).unwrap();
+/// assert_eq!("// The answer
+/// int a = 0x2a; // not 42
+/// if (a) {
+///     /* nothing */
+/// }
+/// ",
+///            printer::print_with_comments(&ast, &comments));
+/// ```
+pub fn print_with_comments(program: &Block, comments: &Comments) -> String {
+    WithComments(program, comments).to_string()
+}
+
+/// A program along with its comments, for printing using `print_with_comments`.
+struct WithComments<'a>(&'a Block, &'a Comments);
+
+impl<'a> Display for WithComments<'a> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let mut pending = Pending {
+            comments: &self.1.comments,
+            breaks: &self.1.breaks,
+        };
+        write_program(f, self.0, &mut pending)
+    }
+}
+
+/// The comments which haven't been printed yet, along with the positions of the statements,
+/// `else`s and `}`s which haven't been printed yet.
+///
+/// The code is printed in the same order as the source, so each comment is printed once the code
+/// before it has been, either at the end of the line printed for that code (if it followed code on
+/// its line) or on its own line before the next statement, `else` or `}`.
+struct Pending<'a> {
+    comments: &'a [Comment],
+    breaks: &'a [Pos],
+}
+
+impl<'a> Pending<'a> {
+    /// Returns an empty `Pending`, for printing a syntax tree without comments.
+    fn none() -> Self {
+        Pending {
+            comments: &[],
+            breaks: &[],
+        }
+    }
+
+    /// Returns the next comment if it comes before the next statement, `else` or `}`.
+    fn comment(&self) -> Option<&'a Comment> {
+        self.comments
+            .first()
+            .filter(|comment| self.breaks.first().is_none_or(|&next| comment.pos < next))
+    }
+
+    /// Moves past the next statement, `else` or `}`.
+    fn next_break(&mut self) {
+        if let Some((_, rest)) = self.breaks.split_first() {
+            self.breaks = rest;
+        }
+    }
+}
+
+/// Prints a complete program.
+///
+/// # Examples
+///
+/// ```
+/// use ibcm::ibcmc;
+///
+/// let program = "int f(int x){return (x+1)*-2;}
+/// int a=f(3);if(a<0)a=0;else{prints(\"neg\\n\");}";
+///
+/// let (ast, _) = ibcmc::parse(program.as_bytes());
+/// assert_eq!("int f(int x) {
+///     return (x + 1) * -2;
+/// }
+///
+/// int a = f(3);
+/// if (a < 0)
+///     a = 0;
+/// else {
+///     prints(\"neg\\n\");
+/// }
+/// ",
+///            ast.to_string());
+/// ```
+impl Display for Block {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write_program(f, self, &mut Pending::none())
+    }
+}
+
+impl Display for Stmt {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write_stmt(f, self, 0, &mut Pending::none())
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write_expr(f, self, 0)
+    }
+}
+
+impl Display for Decl {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        if self.is_const {
+            write!(f, "const ")?;
+        }
+        write!(f, "{} {}", self.ty, self.name.0)
+    }
+}
+
+impl Display for BinOp {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let op = match *self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div | BinOp::UDiv => "/",
+            BinOp::Mod | BinOp::UMod => "%",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Lt | BinOp::ULt => "<",
+            BinOp::Le | BinOp::ULe => "<=",
+            BinOp::Gt | BinOp::UGt => ">",
+            BinOp::Ge | BinOp::UGe => ">=",
+            BinOp::And => "&",
+            BinOp::Or => "|",
+            BinOp::Xor => "^",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+impl Display for UnOp {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            UnOp::Neg => write!(f, "-"),
+            UnOp::Not => write!(f, "~"),
+            UnOp::LogicalNot => write!(f, "!"),
+        }
+    }
+}
+
+/// Writes a complete program, followed by any comments after its last statement.
+fn write_program(f: &mut Formatter, program: &Block, pending: &mut Pending) -> FmtResult {
+    let mut prev_function = None;
+    for stmt in &program.0 {
+        let function = matches!(*stmt.stmt(), Stmt::Function(..));
+        if prev_function.is_some_and(|prev| prev || function) {
+            writeln!(f)?;
+        }
+        write_line(f, stmt, 0, pending)?;
+        prev_function = Some(function);
+    }
+    write_comments(f, 0, pending)
+}
+
+/// Writes a statement on its own line(s), at the given level of indentation.
+fn write_line(f: &mut Formatter,
+              stmt: &StmtLine,
+              indent: usize,
+              pending: &mut Pending)
+              -> FmtResult {
+    start_line(f, indent, pending)?;
+    write_stmt(f, stmt.stmt(), indent, pending)?;
+    end_line(f, pending)
+}
+
+/// Starts a line for the next statement, `else` or `}`, after writing any comments before it.
+fn start_line(f: &mut Formatter, indent: usize, pending: &mut Pending) -> FmtResult {
+    write_comments(f, indent, pending)?;
+    pending.next_break();
+    write!(f, "{:1$}", "", indent * INDENT)
+}
+
+/// Ends the current line, after writing any comments which follow the code printed so far.
+fn end_line(f: &mut Formatter, pending: &mut Pending) -> FmtResult {
+    while let Some(comment) = pending.comment() {
+        if !comment.after_code {
+            break;
+        }
+        write!(f, " {}", comment.text)?;
+        pending.comments = &pending.comments[1..];
+        // Anything after a line comment would be part of it
+        if comment.text.starts_with("//") {
+            break;
+        }
+    }
+    writeln!(f)
+}
+
+/// Writes the comments which come before the next statement, `else` or `}`, each on its own line
+/// at the given level of indentation.
+fn write_comments(f: &mut Formatter, indent: usize, pending: &mut Pending) -> FmtResult {
+    while let Some(comment) = pending.comment() {
+        writeln!(f, "{:1$}{2}", "", indent * INDENT, comment.text)?;
+        pending.comments = &pending.comments[1..];
+    }
+    Ok(())
+}
+
+/// Writes a statement, starting at the current position and ending without a newline.
+///
+/// The indentation applies to any lines after the first.
+fn write_stmt(f: &mut Formatter, stmt: &Stmt, indent: usize, pending: &mut Pending) -> FmtResult {
+    match *stmt {
+        Stmt::Function(ref decl, ref params, ref body) => {
+            write!(f, "{}(", decl)?;
+            for (i, param) in params.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", param)?;
+            }
+            write!(f, ") ")?;
+            write_block(f, body, indent, pending)
+        }
+        Stmt::Block(ref block) => write_block(f, block, indent, pending),
+        Stmt::Decl(ref decl) => write!(f, "{};", decl),
+        Stmt::Init(ref decl, ref expr) => write!(f, "{} = {};", decl, expr),
+        Stmt::Array(ref decl, size) => write!(f, "{}[{}];", decl, size),
+        Stmt::Return(None) => write!(f, "return;"),
+        Stmt::Return(Some(ref expr)) => write!(f, "return {};", expr),
+        Stmt::If(ref cond, ref body, ref else_body) => {
+            write!(f, "if ({})", cond)?;
+            // A nested `if` without an `else` would take our `else` for itself
+            let brace = else_body.is_some() && is_open(body.stmt());
+            let braced = write_body(f, body, indent, brace, pending)?;
+            if let Some(ref else_body) = *else_body {
+                if braced {
+                    pending.next_break();
+                    write!(f, " else")?;
+                } else {
+                    end_line(f, pending)?;
+                    start_line(f, indent, pending)?;
+                    write!(f, "else")?;
+                }
+                if let Stmt::If(..) = *else_body.stmt() {
+                    pending.next_break();
+                    write!(f, " ")?;
+                    write_stmt(f, else_body.stmt(), indent, pending)?;
+                } else {
+                    write_body(f, else_body, indent, false, pending)?;
+                }
+            }
+            Ok(())
+        }
+        Stmt::While(ref cond, ref body) => {
+            write!(f, "while ({})", cond)?;
+            write_body(f, body, indent, false, pending).map(|_| ())
+        }
+        Stmt::For(ref init, ref cond, ref step, ref body) => {
+            write!(f, "for (")?;
+            write_stmt(f, init, indent, pending)?;
+            match *cond {
+                Some(ref cond) => write!(f, " {};", cond)?,
+                None => write!(f, ";")?,
+            }
+            if **step != Stmt::Empty {
+                write!(f, " ")?;
+                write_simple(f, step)?;
+            }
+            write!(f, ")")?;
+            write_body(f, body, indent, false, pending).map(|_| ())
+        }
+        Stmt::Break => write!(f, "break;"),
+        Stmt::Continue => write!(f, "continue;"),
+        Stmt::Empty => write!(f, ";"),
+        Stmt::Assign(..) | Stmt::CompoundAssign(..) | Stmt::Expr(_) => {
+            write_simple(f, stmt)?;
+            write!(f, ";")
+        }
+    }
+}
+
+/// Writes an assignment or expression statement, without the semicolon.
+fn write_simple(f: &mut Formatter, stmt: &Stmt) -> FmtResult {
+    match *stmt {
+        Stmt::Assign(ref target, ref expr) => write!(f, "{} = {}", target, expr),
+        Stmt::CompoundAssign(ref target, ref op, ref expr) => write!(f, "{} {}= {}", target, op, expr),
+        Stmt::Expr(ref expr) => write!(f, "{}", expr),
+        _ => unreachable!("not a simple statement"),
+    }
+}
+
+/// Writes a block, from the opening brace to the closing brace.
+fn write_block(f: &mut Formatter,
+               block: &Block,
+               indent: usize,
+               pending: &mut Pending)
+               -> FmtResult {
+    // An empty block contains no statements, so its `}` is the next break
+    if block.0.is_empty() && pending.comment().is_none() {
+        pending.next_break();
+        return write!(f, "{{}}");
+    }
+
+    // Any comments in an empty block go on their own lines
+    if block.0.is_empty() {
+        writeln!(f, "{{")?;
+    } else {
+        write!(f, "{{")?;
+        end_line(f, pending)?;
+    }
+    for stmt in &block.0 {
+        write_line(f, stmt, indent + 1, pending)?;
+    }
+    write_comments(f, indent + 1, pending)?;
+    pending.next_break();
+    write!(f, "{:1$}}}", "", indent * INDENT)
+}
+
+/// Writes the body of an `if`, `while` or `for` (after the closing parenthesis), returning
+/// whether it was written as a block.
+///
+/// If `brace` is true, a body which isn't a block is wrapped in braces.
+fn write_body(f: &mut Formatter,
+              body: &StmtLine,
+              indent: usize,
+              brace: bool,
+              pending: &mut Pending)
+              -> Result<bool, fmt::Error> {
+    match *body.stmt() {
+        Stmt::Block(ref block) => {
+            pending.next_break();
+            write!(f, " ")?;
+            write_block(f, block, indent, pending)?;
+            Ok(true)
+        }
+        _ if brace => {
+            write!(f, " {{")?;
+            end_line(f, pending)?;
+            write_line(f, body, indent + 1, pending)?;
+            write!(f, "{:1$}}}", "", indent * INDENT)?;
+            Ok(true)
+        }
+        ref stmt => {
+            end_line(f, pending)?;
+            start_line(f, indent + 1, pending)?;
+            write_stmt(f, stmt, indent + 1, pending)?;
+            Ok(false)
+        }
+    }
+}
+
+/// Returns whether the given expression is printed starting with a minus sign.
+fn starts_with_neg(expr: &Expr) -> bool {
+    match *expr {
+        Expr::UnOp(UnOp::Neg, _) => true,
+        Expr::Cast(_, ref expr) => starts_with_neg(expr),
+        _ => false,
+    }
+}
+
+/// Returns whether the given statement ends with an `if` without an `else`, which would take any
+/// `else` following the statement for itself.
+fn is_open(stmt: &Stmt) -> bool {
+    match *stmt {
+        Stmt::If(_, _, None) => true,
+        Stmt::If(_, _, Some(ref body)) | Stmt::While(_, ref body) | Stmt::For(_, _, _, ref body) => {
+            is_open(body.stmt())
+        }
+        _ => false,
+    }
+}
+
+/// Writes an expression, in parentheses if its precedence is lower than `min`.
+fn write_expr(f: &mut Formatter, expr: &Expr, min: u8) -> FmtResult {
+    let prec = precedence(expr);
+    if prec < min {
+        write!(f, "(")?;
+        write_expr(f, expr, 0)?;
+        return write!(f, ")");
+    }
+
+    match *expr {
+        Expr::BinOp(ref op, ref lhs, ref rhs) => {
+            // All binary operations are left-associative
+            write_expr(f, lhs, prec)?;
+            write!(f, " {} ", op)?;
+            write_expr(f, rhs, prec + 1)
+        }
+        Expr::UnOp(ref op, ref expr) => {
+            write!(f, "{}", op)?;
+            // Two minus signs in a row would look like a decrement
+            if *op == UnOp::Neg && starts_with_neg(expr) {
+                write!(f, " ")?;
+            }
+            write_expr(f, expr, UNARY)
+        }
+        Expr::Deref(ref expr) => {
+            write!(f, "*")?;
+            write_expr(f, expr, UNARY)
+        }
+        Expr::AddrOf(ref expr) => {
+            write!(f, "&")?;
+            write_expr(f, expr, UNARY)
+        }
+        Expr::Index(ref base, ref index) => {
+            write_expr(f, base, POSTFIX)?;
+            write!(f, "[{}]", index)
+        }
+        Expr::Call(ref ident, ref args) => {
+            write!(f, "{}(", ident.0)?;
+            for (i, arg) in args.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", arg)?;
+            }
+            write!(f, ")")
+        }
+        Expr::Ident(ref ident) => write!(f, "{}", ident.0),
+        Expr::Literal(Literal::Int(n, IntFormat::Hex(digits))) => write!(f, "0x{:01$x}", n, digits),
+        Expr::Literal(Literal::Int(n, IntFormat::Char)) if n < 0x80 => {
+            f.write_char('\'')?;
+            write_escaped(f, n as u8 as char, '\'')?;
+            f.write_char('\'')
+        }
+        Expr::Literal(Literal::Int(n, IntFormat::Char)) if n <= 0xff => write!(f, "'\\x{:02x}'", n),
+        Expr::Literal(Literal::Int(n, _)) => write!(f, "{}", n),
+        Expr::Literal(Literal::Str(ref s)) => {
+            f.write_char('"')?;
+            for c in s.chars() {
+                write_escaped(f, c, '"')?;
+            }
+            f.write_char('"')
+        }
+        // Conversions are implicit in the source
+        Expr::Cast(_, ref expr) => write_expr(f, expr, min),
+    }
+}
+
+/// Writes a character of a character or string literal delimited by the given quote, using an
+/// escape if necessary.
+fn write_escaped(f: &mut Formatter, c: char, quote: char) -> FmtResult {
+    match c {
+        '\n' => f.write_str("\\n"),
+        '\t' => f.write_str("\\t"),
+        '\r' => f.write_str("\\r"),
+        '\0' => f.write_str("\\0"),
+        '\\' => f.write_str("\\\\"),
+        c if c == quote => write!(f, "\\{}", c),
+        c if c.is_ascii_control() || !c.is_ascii() => write!(f, "\\x{:02x}", c as u32),
+        c => f.write_char(c),
+    }
+}
+
+/// Returns the precedence of an expression (higher numbers bind more tightly).
+fn precedence(expr: &Expr) -> u8 {
+    match *expr {
+        Expr::BinOp(ref op, _, _) => op.precedence(),
+        Expr::UnOp(..) | Expr::Deref(_) | Expr::AddrOf(_) => UNARY,
+        Expr::Index(..) => POSTFIX,
+        Expr::Call(..) | Expr::Ident(_) | Expr::Literal(_) => TERM,
+        Expr::Cast(_, ref expr) => precedence(expr),
+    }
+}
@@ -0,0 +1,90 @@
+//! The IBCMC runtime library.
+//!
+//! IBCM has no instructions for multiplication or division, so these operations are
+//! implemented by routines written in IBCM assembly, which are linked into the generated
+//! program only if they are used. Each routine takes its arguments in its own cells, is
+//! called using `brl`, and leaves its result in the accumulator.
+//!
+//! The routines may refer to constants in the constant pool (e.g. `#0001`), which the
+//! code generator adds to the pool when linking them.
+
+use std::mem;
+
+use asm::{self, Stmt};
+use instruction::Instruction;
+use ibcmc::codegen::{Line, Op};
+
+/// A routine in the runtime library.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Routine {
+    /// Multiplication (`_mul.a * _mul.b`).
+    Mul,
+    /// Signed division (`_div.n / _div.d`), which also leaves the remainder in `_div.r`.
+    Div,
+    /// Unsigned division (`_udiv.n / _udiv.d`), which also leaves the remainder in `_udiv.r`.
+    UDiv,
+}
+
+impl Routine {
+    /// Returns the label of the entry point of the routine.
+    pub fn entry(&self) -> &'static str {
+        match *self {
+            Routine::Mul => "_mul",
+            Routine::Div => "_div",
+            Routine::UDiv => "_udiv",
+        }
+    }
+
+    /// Returns the labels of the cells holding the arguments of the routine.
+    pub fn args(&self) -> (&'static str, &'static str) {
+        match *self {
+            Routine::Mul => ("_mul.a", "_mul.b"),
+            Routine::Div => ("_div.n", "_div.d"),
+            Routine::UDiv => ("_udiv.n", "_udiv.d"),
+        }
+    }
+
+    /// Returns the assembly source of the routine.
+    pub fn source(&self) -> &'static str {
+        match *self {
+            Routine::Mul => include_str!("mul.ibcmasm"),
+            Routine::Div => include_str!("div.ibcmasm"),
+            Routine::UDiv => include_str!("udiv.ibcmasm"),
+        }
+    }
+
+    /// Returns the code of the routine as a list of `Line`s.
+    pub fn lines(&self) -> Vec<Line> {
+        let mut lines = Vec::new();
+        let mut labels = Vec::new();
+
+        for (n, l) in self.source().lines().enumerate() {
+            let l = match l.find("//") {
+                Some(idx) => &l[..idx],
+                None => l,
+            };
+            let mut parts = l.split_whitespace().peekable();
+            if let Some(label) = parts.peek().and_then(|part| part.strip_suffix(':')) {
+                labels.push(label.to_owned());
+                parts.next();
+            }
+            let instr = match parts.next() {
+                Some(s) => s,
+                None => continue,
+            };
+
+            let op = match asm::get_stmt(instr, parts.next(), n + 1).expect("invalid runtime routine") {
+                Stmt::Data(s) => Op::Data(u16::from_str_radix(&s, 16).expect("invalid runtime data")),
+                Stmt::Instr { instr: instr @ Instruction::Shift(..), .. } => Op::Instr(instr, None),
+                Stmt::Instr { instr, addr } => Op::Instr(instr, addr),
+            };
+            lines.push(Line {
+                           labels: mem::take(&mut labels),
+                           op,
+                           line: None,
+                       });
+        }
+
+        lines
+    }
+}
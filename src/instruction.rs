@@ -176,13 +176,11 @@ impl Instruction {
     /// Returns whether the current instruction is a control flow construct
     /// (i.e. a jump or a branch).
     pub fn is_jmp(&self) -> bool {
-        match *self {
-            Instruction::Jmp(_) |
-            Instruction::Jmpe(_) |
-            Instruction::Jmpl(_) |
-            Instruction::Brl(_) => true,
-            _ => false,
-        }
+        matches!(*self,
+                 Instruction::Jmp(_) |
+                 Instruction::Jmpe(_) |
+                 Instruction::Jmpl(_) |
+                 Instruction::Brl(_))
     }
 }
 
@@ -9,7 +9,8 @@
 //! as input and output may be handled differently, e.g. with different prompts).
 //! In addition, this library adds an assembler, which aims to mimic the
 //! sample assembly language given in the IBCM documentation, and a debugger,
-//! which fills in for some of the other features in the reference interpreter.
+//! which fills in for some of the other features in the reference interpreter, and a
+//! disassembler, which turns programs back into assembly.
 //!
 //! For examples of use, see the main binary in the `src/bin` folder and the tests
 //! in the `tests` folder.
@@ -43,6 +44,12 @@ pub mod errors {
             OutOfBounds {
                 description("program ran out of bounds")
             }
+            /// An error occurred while running the program, at the given location in its source
+            /// (see `SourceMap`).
+            Runtime(loc: String) {
+                description("runtime error")
+                display("runtime error in {}", loc)
+            }
             /// The given input program is too long.
             ProgramTooLong {
                 description("input program is too long")
@@ -50,10 +57,14 @@ pub mod errors {
 
             /// There was an error when parsing assembly code.
             ///
-            /// Contains error description and line number of error.
-            Asm(s: String, n: usize) {
+            /// Contains error description, the file in which the error occurred (if the
+            /// assembly was read from a file) and line number of error.
+            Asm(s: String, file: Option<String>, n: usize) {
                 description("error parsing assembly")
-                display("error parsing assembly on line {}: {}", n, s)
+                display("error parsing assembly on {}line {}: {}",
+                        file.as_ref().map_or(String::new(), |f| format!("{} ", f)),
+                        n,
+                        s)
             }
 
             /// There was an error in the debugger.
@@ -76,26 +87,89 @@ pub mod errors {
 
 mod asm;
 mod debug;
+mod disasm;
 pub mod ibcmc;
 mod instruction;
 mod simulator;
+mod source_map;
 
 pub use errors::*;
 
-pub use asm::Assembler;
+pub use asm::{Assembler, Diagnostic, Program, Severity};
 pub use debug::Debugger;
+pub use disasm::Disassembly;
 pub use instruction::Instruction;
-pub use simulator::Simulator;
+pub use simulator::{Access, MemoryWrite, Observer, Simulator, StopReason, WatchKind};
+pub use source_map::{DebugInfo, SourceMap, WordInfo, WordKind};
+
+/// Formats a message about a line of source code for display, in the style of `rustc`: the
+/// message is followed by the location and the line itself, with carets under the columns in
+/// `span` (starting from 1 and counted in bytes, with the end excluded), or under the start of
+/// the text on the line if the columns aren't known.
+fn format_snippet(kind: &str,
+                  msg: &str,
+                  file: &str,
+                  line: usize,
+                  span: Option<(usize, usize)>,
+                  source: &str)
+                  -> String {
+    let text = match source.lines().nth(line.wrapping_sub(1)) {
+        Some(text) => text,
+        None => return format!("{}: {}\n --> {}:{}\n", kind, msg, file, line),
+    };
+    let (start, end) = span.unwrap_or_else(|| {
+        let col = text.len() - text.trim_start().len() + 1;
+        (col, col + 1)
+    });
+
+    // Tabs are kept in the padding before the carets so that they line up with the source
+    let padding = text.bytes()
+        .take(start - 1)
+        .map(|b| if b == b'\t' { '\t' } else { ' ' })
+        .collect::<String>();
+    let carets = "^".repeat(end.saturating_sub(start).max(1));
+    let gutter = " ".repeat(line.to_string().len());
+    format!("{}: {}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}\n",
+            kind,
+            msg,
+            gutter,
+            file,
+            line,
+            start,
+            gutter,
+            line,
+            text,
+            gutter,
+            padding,
+            carets)
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::collections::HashMap;
+    use std::io;
+    use std::path::{Path, PathBuf};
+
     /// A helper function to assemble code into a simulator.
-    fn sim_asm(code: &str) -> Simulator {
+    fn sim_asm(code: &str) -> Simulator<'static, 'static> {
         Simulator::from_instructions(Assembler::assemble(code.as_bytes()).unwrap().data()).unwrap()
     }
 
+    /// Test that the instruction register holds the last instruction executed.
+    #[test]
+    fn instruction_register() {
+        let mut sim = sim_asm("load one\nnop\nhalt\none: dw 1");
+        assert_eq!((0, 0, 0), sim.regs());
+        sim.step().unwrap();
+        assert_eq!((1, 0x3003, 1), sim.regs());
+        sim.step().unwrap();
+        assert_eq!((1, 0xb000, 2), sim.regs());
+        assert!(sim.step().unwrap());
+        assert_eq!((1, 0x0000, 3), sim.regs());
+    }
+
     /// Test the `halt` operation.
     #[test]
     fn halt() {
@@ -103,7 +177,7 @@ mod tests {
         let mut sim = sim_asm(program);
 
         // Should halt after a single step
-        assert_eq!(true, sim.step().unwrap());
+        assert!(sim.step().unwrap());
     }
 
     /// Test I/O operations.
@@ -326,4 +400,615 @@ mod tests {
         assert_eq!(7, acc, "wrong accumulator value");
         assert_eq!(7, sim.memory()[3], "did not jump");
     }
+
+    /// Test that errors report their source location.
+    #[test]
+    fn source_map() {
+        // Running off the end of memory is the fault of the last instruction
+        let mut lines = vec![None; 4096];
+        lines[4095] = Some(12);
+        let mut source_map = SourceMap::new(lines);
+        source_map.set_file("foo.ibcmc");
+        let mut sim = Simulator::from_instructions(&[0xb000; 4096]).unwrap();
+        sim.set_source_map(source_map);
+
+        match sim.run() {
+            Err(Error(ErrorKind::Runtime(ref loc), _)) if loc == "foo.ibcmc line 12" => {}
+            res => panic!("expected runtime error, got {:?}", res),
+        }
+
+        // Without a source map, the original error is reported
+        let mut sim = Simulator::from_instructions(&[0xb000; 4096]).unwrap();
+        match sim.run() {
+            Err(Error(ErrorKind::OutOfBounds, _)) => {}
+            res => panic!("expected out of bounds error, got {:?}", res),
+        }
+    }
+
+    /// Test address arguments which are numbers or expressions.
+    #[test]
+    fn operands() {
+        let program = "load    table + 2
+                       add     0x10
+                       store   end - table
+                       jmp     $ + 2
+                       halt
+                       load    16
+                       brl     $
+table:                 dw      0001
+                       dw      0002
+                       dw      0003
+end:                   dw      0005";
+        let code = Assembler::assemble(program.as_bytes()).unwrap();
+        assert_eq!(&[0x3009, 0x5010, 0x4003, 0xc005, 0x0000, 0x3010, 0xf006, 0x0001, 0x0002, 0x0003,
+                     0x0005],
+                   code.data());
+
+        // Each term is looked up as a label first (`1` is the label, `0x1` the number)
+        let program = "load 1 + 0x1
+                       halt
+1:                     dw 0007";
+        let code = Assembler::assemble(program.as_bytes()).unwrap();
+        assert_eq!(0x3003, code.data()[0]);
+
+        // Results outside the 12-bit address field are rejected, however large the numbers are
+        for &(operand, value) in &[("10000", "0x2710"),
+                                   ("70000", "0x11170"),
+                                   ("0x10000 - 1", "0xffff"),
+                                   ("- 1", "-0x001")] {
+            let program = format!("load {}\nhalt", operand);
+            let msg = format!("address '{}' evaluates to {}, which does not fit in 12 bits (it \
+                               must be between 0x000 and 0xfff)",
+                              operand,
+                              value);
+            match Assembler::assemble(program.as_bytes()) {
+                Err(Error(ErrorKind::Asm(ref m, _, 1), _)) if *m == msg => {}
+                res => panic!("expected error for `{}`, got {:?}", operand, res),
+            }
+        }
+        for operand in &["0x1000", "4096", "- 1", "0 - 1", "$ - 1", "table + 0xfff"] {
+            let program = format!("load {}\ntable: dw 0000", operand);
+            match Assembler::assemble(program.as_bytes()) {
+                Err(Error(ErrorKind::Asm(_, _, 1), _)) => {}
+                res => panic!("expected error for `{}`, got {:?}", operand, res),
+            }
+        }
+
+        // Malformed expressions and undefined labels are rejected
+        for operand in &["table +", "table table", "nowhere + 1", "1x"] {
+            let program = format!("load {}\ntable: dw 0000", operand);
+            match Assembler::assemble(program.as_bytes()) {
+                Err(Error(ErrorKind::Asm(_, _, 1), _)) => {}
+                res => panic!("expected error for `{}`, got {:?}", operand, res),
+            }
+        }
+    }
+
+    /// Test the assembler directives.
+    #[test]
+    fn directives() {
+        let program = "size:   equ     3
+                jmp     start
+                org     0x8
+table:          dw      1, -1, +10, 'a', '\\n', table, $
+msg:            string  \"hi, \\\"you\\\"\" // comment
+buf:            space   size
+                fill    2, ffff
+start:          load    table + size
+                halt
+end:";
+        let code = Assembler::assemble(program.as_bytes()).unwrap();
+        let mut expected = vec![0xc01d, 0, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(&[0x0001, 0xffff, 0x000a, 0x0061, 0x000a, 0x0008, 0x000e]);
+        expected.extend("hi, \"you\"".bytes().map(|b| b as u16));
+        expected.extend_from_slice(&[0, 0, 0, 0xffff, 0xffff, 0x300b, 0x0000]);
+        assert_eq!(&expected[..], code.data());
+        let labels = code.labels();
+        assert_eq!((3, 8, 15, 24, 29, 31),
+                   (labels["size"], labels["table"], labels["msg"], labels["buf"], labels["start"], labels["end"]));
+
+        // Constants defined with `set` take effect from their definition onwards
+        let program = "n:      set     1
+                load    n
+n:              set     n + 1
+                load    n
+                string  \"//\"";
+        let code = Assembler::assemble(program.as_bytes()).unwrap();
+        assert_eq!(&[0x3001, 0x3002, 0x002f, 0x002f], code.data());
+
+        // A plain hexadecimal word is read as one even if it is also a label, with a warning
+        let program = "        halt
+beef:   dw      beef, beef + 0, +beef";
+        let (code, diagnostics) = Assembler::assemble_recovering(program.as_bytes());
+        assert_eq!(&[0x0000, 0xbeef, 0x0001, 0x0001], code.data());
+        assert_eq!(diagnostics.into_iter()
+                       .map(|d| (d.severity, d.line, d.span, d.message))
+                       .collect::<Vec<_>>(),
+                   [(Severity::Warning,
+                     2,
+                     Some((17, 21)),
+                     "'beef' is read as a hexadecimal word, not as the label 'beef' \
+                      (use 'beef + 0' for the label)"
+                         .to_owned())]);
+
+        // Programs may only fill the 4096 words of memory
+        let program = "        jmp     last\n        org     0xffe\n        halt\nlast:   halt\n        halt";
+        let (code, diagnostics) = Assembler::assemble_recovering(program.as_bytes());
+        assert_eq!(0x1000, code.data().len());
+        assert_eq!(diagnostics.into_iter()
+                       .map(|d| (d.severity, d.line, d.message))
+                       .collect::<Vec<_>>(),
+                   [(Severity::Error,
+                     5,
+                     "the program does not fit in memory (which has 4096 words)".to_owned())]);
+        let (_, diagnostics) = Assembler::assemble_recovering("halt\nspace 5000".as_bytes());
+        assert_eq!(diagnostics.into_iter()
+                       .map(|d| (d.line, d.span, d.message))
+                       .collect::<Vec<_>>(),
+                   [(2,
+                     Some((7, 11)),
+                     "5000 words starting at address 001 do not fit in memory (which has 4096 \
+                      words)"
+                         .to_owned())]);
+
+        let errors = ["load 0\norg 0",
+                      "equ 3",
+                      "x: equ y\ny: halt",
+                      "x: equ 1\nx: equ 2",
+                      "x: set 1\nx: equ 2",
+                      "x: halt\nx: set 2",
+                      "dw 70000",
+                      "dw 1,,2",
+                      "dw 'ab'",
+                      "string abc",
+                      "string \"abc",
+                      "fill 2",
+                      "space 0 - 1",
+                      "fill 4097, 0",
+                      "org 0xfff\nhalt\nhalt",
+                      "space later\nlater: halt"];
+        for program in &errors {
+            match Assembler::assemble(program.as_bytes()) {
+                Err(Error(ErrorKind::Asm(..), _)) => {}
+                res => panic!("expected error for `{}`, got {:?}", program, res),
+            }
+        }
+    }
+
+    /// Test assembler macros.
+    #[test]
+    fn macros() {
+        let program = "macro inc var
+                load    var
+                add     one
+                store   var
+endm
+// Counts `n` down to zero, using local labels
+macro countdown n
+%%loop:         load    n
+                jmpe    %%done
+                sub     one
+                store   n
+                jmp     %%loop
+%%done:         nop
+endm
+macro inc2 a, b
+                inc     a
+                inc     b
+endm
+
+start:          inc2    x, x
+                countdown y
+                countdown y
+                halt
+one:            dw      1
+x:              dw      0
+y:              dw      3";
+        let code = Assembler::assemble(program.as_bytes()).unwrap();
+        let labels = code.labels().clone();
+        assert_eq!(0, labels["start"]);
+        let mut sim = Simulator::from_instructions(code.data()).unwrap();
+        sim.run().unwrap();
+        assert_eq!(2, sim.memory()[labels["x"] as usize]);
+        assert_eq!(0, sim.memory()[labels["y"] as usize]);
+
+        // Errors point to the invocation and to the line in the macro
+        let error = |program: &str| match Assembler::assemble(program.as_bytes()) {
+            Err(Error(ErrorKind::Asm(msg, _, n), _)) => (msg, n),
+            res => panic!("expected error for `{}`, got {:?}", program, res),
+        };
+        assert_eq!(("unknown instruction 'frob' (at line 5 of macro 'b', expanded from line 2 of macro 'a')"
+                        .to_owned(),
+                    7),
+                   error("macro a\n  b\nendm\nmacro b\n  frob\nendm\n  a"));
+        assert_eq!(("label 'nowhere' is undefined (at line 2 of macro 'bad')".to_owned(), 5),
+                   error("macro bad x\n  load x\nendm\n\n  bad nowhere"));
+        assert!(error("macro r\n  r\nendm\nr").0.contains("nested too deeply"));
+
+        for program in &["macro load\nendm",
+                         "macro dw\nendm",
+                         "macro m\n  halt",
+                         "endm",
+                         "macro m\nmacro n\nendm\nendm",
+                         "macro m\nendm\nmacro m\nendm",
+                         "macro m a, b\nendm\n  m 1"] {
+            error(program);
+        }
+    }
+
+    /// Test included files.
+    #[test]
+    fn include() {
+        let files = |files: Vec<(&'static str, &'static str)>| {
+            let files = files.into_iter()
+                .map(|(path, text)| (PathBuf::from(path), text))
+                .collect::<HashMap<_, _>>();
+            move |path: &Path| {
+                files.get(path)
+                    .map(|text| text.to_string())
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such file"))
+            }
+        };
+
+        // Files are found relative to the file including them, and each one can be included
+        // more than once
+        let read = files(vec![("prog/main.ibcmasm",
+                               "include \"lib/io.ibcmasm\"\nload 0\nhalt\ninclude \"./lib/../data.ibcmasm\""),
+                              ("prog/lib/io.ibcmasm", "include \"../data.ibcmasm\"\nprintH"),
+                              ("prog/data.ibcmasm", "dw 7")]);
+        let code = Assembler::assemble_with("prog/./main.ibcmasm", read).unwrap();
+        assert_eq!(&[0x0007, 0x1800, 0x3000, 0x0000, 0x0007], code.data());
+
+        let error = |main: &str, read| match Assembler::assemble_with(main, read) {
+            Err(Error(ErrorKind::Asm(msg, file, n), _)) => (msg, file, n),
+            res => panic!("expected error, got {:?}", res),
+        };
+
+        // Errors give the file in which they occurred
+        let read = files(vec![("a", "halt\ninclude \"b\""), ("b", "nop\nload nowhere")]);
+        assert_eq!(("label 'nowhere' is undefined".to_owned(), Some("b".to_owned()), 2),
+                   error("a", read));
+        let read = files(vec![("a", "halt\ninclude \"b\""), ("b", "nop\nfrob")]);
+        assert_eq!(("unknown instruction 'frob'".to_owned(), Some("b".to_owned()), 2),
+                   error("a", read));
+        let read = files(vec![("a", "halt\ninclude \"missing\"")]);
+        assert_eq!(("could not read included file 'missing': no such file".to_owned(),
+                    Some("a".to_owned()),
+                    2),
+                   error("a", read));
+
+        // Cycles are detected
+        let read = files(vec![("a", "include \"dir/b\""), ("dir/b", "nop\ninclude \"../a\"")]);
+        assert_eq!(("'../a' includes itself (through a -> dir/b -> a)".to_owned(),
+                    Some("dir/b".to_owned()),
+                    2),
+                   error("a", read));
+    }
+
+    /// Test that the assembler reports every error and warning.
+    #[test]
+    fn diagnostics() {
+        let found = |(_, diagnostics): (Program, Vec<Diagnostic>)| {
+            diagnostics.into_iter()
+                .map(|d| (d.severity, d.file, d.line, d.span, d.message))
+                .collect::<Vec<_>>()
+        };
+        let error = |line, span, msg: &str| (Severity::Error, None, line, span, msg.to_owned());
+        let warning = |line, span, msg: &str| (Severity::Warning, None, line, span, msg.to_owned());
+
+        // Errors from both passes are collected, pointing to the offending text
+        let program = "macro m x
+        load    x
+endm
+start:  jmp     start + 0x1000
+a:      dw      1, 'ab', 2
+a:      halt
+        m       nowhere
+size:   equ     'c
+        nop     3";
+        assert_eq!(vec![error(6, Some((1, 2)), "found duplicate label: 'a'"),
+                        error(8, Some((17, 19)), "invalid character literal 'c"),
+                        error(4,
+                              Some((17, 31)),
+                              "address 'start + 0x1000' evaluates to 0x1000, which does not fit \
+                               in 12 bits (it must be between 0x000 and 0xfff)"),
+                        error(5, Some((20, 24)), "invalid character literal 'ab'"),
+                        error(7, None, "label 'nowhere' is undefined (at line 2 of macro 'm')"),
+                        error(9, Some((17, 18)), "unexpected argument to 'nop'"),
+                        warning(5, Some((1, 2)), "label 'a' is never used")],
+                   found(Assembler::assemble_recovering(program.as_bytes())));
+
+        // Errors in included files name the file, but their unused labels aren't reported
+        let mut files = HashMap::new();
+        files.insert(PathBuf::from("main"), "include \"lib\"\nhalt\nn: equ 1");
+        files.insert(PathBuf::from("lib"), "jmp end\nunused: halt\nend: frob");
+        let expected = vec![(Severity::Error,
+                             Some(PathBuf::from("lib")),
+                             3,
+                             Some((6, 10)),
+                             "unknown instruction 'frob'".to_owned()),
+                            (Severity::Warning,
+                             Some(PathBuf::from("main")),
+                             3,
+                             Some((1, 2)),
+                             "constant 'n' is never used".to_owned())];
+        let read = |path: &Path| Ok(files[path].to_string());
+        assert_eq!(expected,
+                   found(Assembler::assemble_with_recovering("main", read).unwrap()));
+
+        // Execution can fall into data from the start or after any instruction but `jmp` and
+        // `halt`
+        let program = "        dw      0
+        brl     sub
+        dw      0
+sub:    jmp     sub
+        dw      0";
+        assert_eq!(vec![warning(1,
+                                None,
+                                "the program starts with data, which will be executed as an \
+                                 instruction"),
+                        warning(2,
+                                None,
+                                "execution can continue past this instruction into the data at \
+                                 002"),
+                        warning(5, None, "the program has no 'halt' instruction")],
+                   found(Assembler::assemble_recovering(program.as_bytes())));
+
+        let program = "        load    one\n        halt\none:    dw      1";
+        assert_eq!(Vec::<(Severity, Option<PathBuf>, usize, Option<(usize, usize)>, String)>::new(),
+                   found(Assembler::assemble_recovering(program.as_bytes())));
+
+        // Internal labels aren't reported, and neither is a `nop` which an instruction is stored
+        // into, unlike one which isn't
+        let program = "_L1:    load    jump
+        store   f._jmp
+f._jmp: nop
+f._ret: dw      0
+        nop
+jump:   dw      c000";
+        assert_eq!(vec![warning(5,
+                                None,
+                                "execution can continue past this instruction into the data at \
+                                 005"),
+                        warning(6, None, "the program has no 'halt' instruction")],
+                   found(Assembler::assemble_recovering(program.as_bytes())));
+    }
+
+    /// Test the debug information of assembled programs.
+    #[test]
+    fn debug_info() {
+        let mut files = HashMap::new();
+        files.insert(PathBuf::from("main"),
+                     "macro two\n  dw 2\nendm\nhalt\ninclude \"data\"\n  two");
+        files.insert(PathBuf::from("data"), "one: dw 1 // One");
+        let program = Assembler::assemble_with("main", |path| Ok(files[path].to_string())).unwrap();
+        let info = program.debug_info();
+        let word = |value, file: &str, line, kind, text: &str| {
+            WordInfo {
+                value,
+                file: Some(file.to_owned()),
+                line: Some(line),
+                kind,
+                text: text.to_owned(),
+            }
+        };
+        assert_eq!(&[word(0x0000, "main", 4, WordKind::Code, "halt"),
+                     word(0x0001, "data", 1, WordKind::Data, "one: dw 1 // One"),
+                     word(0x0002, "main", 6, WordKind::Data, "  dw 2")],
+                   info.words());
+
+        // The source map gives the file of each word
+        let map = info.source_map();
+        assert_eq!(Some("data line 1".to_owned()), map.location(1));
+        assert_eq!(Some("main line 6".to_owned()), map.location(2));
+        assert_eq!(None, map.location(3));
+
+        let mut sidecar = Vec::new();
+        info.to_writer(&mut sidecar).unwrap();
+        assert_eq!("ibcm debug info
+file main
+file data
+000 0000 code 0 4 halt
+001 0001 data 1 1 one: dw 1 // One
+002 0002 data 0 6   dw 2
+",
+                   String::from_utf8(sidecar.clone()).unwrap());
+        assert_eq!(info, DebugInfo::from_reader(&sidecar[..]).unwrap());
+
+        // Debug information only matches the program it was made from
+        assert!(info.matches(program.data()));
+        assert!(!info.matches(&[0x0000, 0x0001, 0x0003]));
+        assert!(!info.matches(&[0x0000, 0x0001, 0x0002, 0x0000]));
+
+        // Words may have no source line
+        let info = DebugInfo::from_reader(&b"ibcm debug info\n000 0000 code - - \n"[..]).unwrap();
+        assert_eq!(None, info.word(0).unwrap().line);
+        assert_eq!(None, info.source_map().location(0));
+
+        for sidecar in &["debug info\n",
+                         "ibcm debug info\n001 0000 code - 1 halt\n",
+                         "ibcm debug info\n000 code - 1 halt\n",
+                         "ibcm debug info\n000 0000 instr - 1 halt\n",
+                         "ibcm debug info\n000 0000 code 0 1 halt\n",
+                         "ibcm debug info\n000 0000 code - x halt\n"] {
+            match DebugInfo::from_reader(sidecar.as_bytes()) {
+                Err(Error(ErrorKind::UserInput(_), _)) => {}
+                res => panic!("expected error for `{}`, got {:?}", sidecar, res),
+            }
+        }
+    }
+
+    /// Test program listings.
+    #[test]
+    fn listing() {
+        let mut files = HashMap::new();
+        files.insert(PathBuf::from("main"),
+                     "size: equ 2\na:\nb: load table\nstore table\ninclude \"data\"\nhalt");
+        files.insert(PathBuf::from("data"), "table: dw 1, size, ffff // Data\nend:");
+        let program = Assembler::assemble_with("main", |path| Ok(files[path].to_string())).unwrap();
+
+        // Data words are shown as such, even if they would decode to an instruction
+        let mut listing = Vec::new();
+        program.to_listing(&mut listing).unwrap();
+        assert_eq!("addr  word  instr       label  line  source
+; main
+000   3002  load 0002   a b       3  b: load table
+001   4002  store 0002            4  store table
+; data
+002   0001  dw 0001     table     1  table: dw 1, size, ffff // Data
+003   0002  dw 0002
+004   ffff  dw ffff
+; main
+005   0000  halt        end       6  halt
+
+symbols by name:
+a      0000
+b      0000
+end    0005
+size   0002  constant
+table  0002
+
+symbols by value:
+0000  a
+0000  b
+0002  size  constant
+0002  table
+0005  end
+",
+                   String::from_utf8(listing).unwrap());
+    }
+
+    /// Test disassembly.
+    #[test]
+    fn disassembly() {
+        // A routine called with `brl`, a shift, a word with unused bits set and an address outside
+        // of the program
+        let program = &[0xf004, 0x2403, 0x4100, 0x0000, 0x3007, 0xc007, 0x0abc, 0x0000];
+        let disassembly = Disassembly::new(program);
+        assert_eq!("          brl     code_004
+          shiftR  3
+          store   0x100
+          halt
+code_004: load    code_007
+          jmp     code_007
+          dw      0abc
+code_007: halt
+",
+                   disassembly.to_string());
+        assert_eq!(vec![WordKind::Code, WordKind::Code, WordKind::Code, WordKind::Code,
+                        WordKind::Code, WordKind::Code, WordKind::Data, WordKind::Code],
+                   disassembly.kinds());
+
+        // A `store` into a placeholder instruction followed by a variable
+        let program = &[0x3005, 0x5004, 0x4003, 0xb000, 0x0000, 0x0000];
+        let disassembly = Disassembly::new(program);
+        assert_eq!("          load    data_005
+          add     data_004
+          store   code_003
+code_003: nop
+data_004: dw      0000
+data_005: dw      0000
+",
+                   disassembly.to_string());
+
+        // A `load` from a computed address, built in two different ways
+        let program = &[0x3008, 0x5009, 0x4003, 0xb000, 0x300a, 0x5009, 0x4003, 0x0000, 0x0fff,
+                        0x2ffd, 0x0010];
+        let disassembly = Disassembly::new(program);
+        assert_eq!("          load    data_008
+          add     tmpl_009
+          store   code_003
+code_003: nop               // load built at runtime from data_008 + tmpl_009; \
+load built at runtime from data_00a + tmpl_009
+          load    data_00a
+          add     tmpl_009
+          store   code_003
+          halt
+data_008: dw      0fff
+tmpl_009: dw      2ffd      // template for load - 3
+data_00a: dw      0010
+",
+                   disassembly.to_string());
+
+        for program in &[&program[..], &[], &[0x0123, 0x1001, 0xa001]] {
+            let asm = Disassembly::new(program).to_string();
+            assert_eq!(*program, Assembler::assemble(asm.as_bytes()).unwrap().data());
+        }
+    }
+
+    /// Test breakpoints, watchpoints and observers.
+    #[test]
+    fn stop_reasons() {
+        /// Records the address of each instruction and the accumulator after it.
+        struct Trace(Vec<(u16, i16)>);
+
+        impl Observer for Trace {
+            fn before(&mut self, sim: &Simulator, addr: u16, _: Instruction) {
+                assert_eq!((sim.memory()[addr as usize], addr + 1),
+                           (sim.regs().1, sim.regs().2));
+            }
+
+            fn after(&mut self,
+                     sim: &Simulator,
+                     addr: u16,
+                     _: Instruction,
+                     _: Option<MemoryWrite>) {
+                self.0.push((addr, sim.regs().0));
+            }
+        }
+
+        let program = "        load    one
+loop:   add     one
+        store   count
+        jmp     loop
+one:    dw      1
+count:  dw      0";
+        let mut sim = sim_asm(program);
+        sim.add_watchpoint(4, WatchKind::Read);
+        sim.add_breakpoint(3);
+
+        let mut trace = Trace(Vec::new());
+        assert_eq!(StopReason::Watchpoint {
+                       pc: 0,
+                       addr: 4,
+                       access: Access::Read,
+                   },
+                   sim.run_with(&mut trace).unwrap());
+        assert_eq!(vec![(0, 1)], trace.0);
+        assert_eq!(StopReason::Watchpoint {
+                       pc: 1,
+                       addr: 4,
+                       access: Access::Read,
+                   },
+                   sim.run_with(&mut trace).unwrap());
+        // The instruction register holds the last instruction executed
+        assert_eq!((2, 0x5004, 2), sim.regs());
+
+        assert!(sim.remove_watchpoint(4));
+        assert!(!sim.remove_watchpoint(4));
+        assert_eq!(StopReason::Breakpoint(3), sim.run_with(&mut trace).unwrap());
+        assert_eq!(vec![(0, 1), (1, 2), (2, 2)], trace.0);
+        assert_eq!(2, sim.memory()[5]);
+
+        // Write watchpoints ignore reads
+        sim.add_watchpoint(4, WatchKind::Write);
+        sim.add_watchpoint(5, WatchKind::ReadWrite);
+        assert_eq!(StopReason::Watchpoint {
+                       pc: 2,
+                       addr: 5,
+                       access: Access::Write,
+                   },
+                   sim.run().unwrap());
+        assert_eq!(vec![3], sim.breakpoints().iter().cloned().collect::<Vec<_>>());
+
+        sim.remove_breakpoint(3);
+        sim.remove_watchpoint(5);
+        sim.set_step_limit(Some(100));
+        assert_eq!(StopReason::StepLimit, sim.run().unwrap());
+        assert!(!sim.step_with(&mut trace).unwrap());
+
+        let mut sim = sim_asm("nop\nhalt");
+        sim.add_breakpoint(0);
+        assert_eq!(StopReason::Halted, sim.run().unwrap());
+    }
 }
@@ -1,9 +1,124 @@
 //! The IBCM simulation.
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::{self, Read, Write, BufRead, BufReader, BufWriter};
 
 use errors::*;
 use instruction::{Instruction, IoOp, ShiftOp};
+use source_map::SourceMap;
+
+/// A kind of memory access made by an instruction.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Access {
+    /// The word was read (by `load`, `add`, `sub`, `and`, `or` or `xor`).
+    Read,
+    /// The word was written (by `store`).
+    Write,
+}
+
+/// The accesses which trigger a watchpoint.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum WatchKind {
+    /// Only reads.
+    Read,
+    /// Only writes.
+    Write,
+    /// Both reads and writes.
+    ReadWrite,
+}
+
+impl WatchKind {
+    /// Returns whether the given access triggers a watchpoint of this kind.
+    fn matches(self, access: Access) -> bool {
+        matches!((self, access),
+                 (WatchKind::ReadWrite, _) |
+                 (WatchKind::Read, Access::Read) |
+                 (WatchKind::Write, Access::Write))
+    }
+}
+
+/// A change made to a word of memory by an instruction.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct MemoryWrite {
+    /// The address of the word.
+    pub addr: u16,
+    /// The value of the word before the instruction was executed.
+    pub old: u16,
+    /// The value of the word after the instruction was executed.
+    pub new: u16,
+}
+
+/// The reason why `Simulator::run` stopped running the program.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum StopReason {
+    /// The machine halted.
+    Halted,
+    /// The program counter reached a breakpoint at the given address (the instruction there
+    /// hasn't been executed yet).
+    Breakpoint(u16),
+    /// The instruction at `pc` made an access to the address `addr`, which has a watchpoint.
+    Watchpoint {
+        /// The address of the instruction making the access.
+        pc: u16,
+        /// The address which was accessed.
+        addr: u16,
+        /// The kind of access.
+        access: Access,
+    },
+    /// The step limit (see `Simulator::set_step_limit`) was reached.
+    StepLimit,
+}
+
+/// An observer of the execution of a program, which is notified before and after each
+/// instruction is executed (see `Simulator::step_with` and `Simulator::run_with`).
+///
+/// Both methods do nothing by default.
+///
+/// # Examples
+///
+/// Recording every change to memory:
+///
+/// ```
+/// use ibcm::{Instruction, MemoryWrite, Observer, Simulator};
+///
+/// struct Writes(Vec<MemoryWrite>);
+///
+/// impl Observer for Writes {
+///     fn after(&mut self, _: &Simulator, _: u16, _: Instruction, write: Option<MemoryWrite>) {
+///         self.0.extend(write);
+///     }
+/// }
+///
+/// // Copies the word at address 4 to address 5
+/// let mut sim = Simulator::from_instructions(&[0x3004, 0x4005, 0x0000, 0x0000, 0x1234])
+///     .unwrap();
+/// let mut writes = Writes(Vec::new());
+/// sim.run_with(&mut writes).unwrap();
+///
+/// assert_eq!(vec![MemoryWrite { addr: 5, old: 0, new: 0x1234 }], writes.0);
+/// ```
+pub trait Observer {
+    /// Called before the instruction `instr` at address `addr` is executed (the program counter
+    /// has already been incremented, and the instruction register holds the instruction).
+    fn before(&mut self, sim: &Simulator, addr: u16, instr: Instruction) {
+        let _ = (sim, addr, instr);
+    }
+
+    /// Called after the instruction `instr` at address `addr` was executed, along with the change
+    /// it made to memory, if any.
+    fn after(&mut self,
+             sim: &Simulator,
+             addr: u16,
+             instr: Instruction,
+             write: Option<MemoryWrite>) {
+        let _ = (sim, addr, instr, write);
+    }
+}
+
+/// An observer which does nothing, used when stepping without an observer.
+struct Unobserved;
+
+impl Observer for Unobserved {}
 
 /// The IBCM machine simulator.
 ///
@@ -58,11 +173,19 @@ pub struct Simulator<'a, 'b> {
     /// The actual length of the program
     len: usize,
     /// The source of input data
-    input: Box<BufRead + 'a>,
+    input: Box<dyn BufRead + 'a>,
     /// The destination of output data
-    output: Box<Write + 'b>,
+    output: Box<dyn Write + 'b>,
     /// Whether to show a prompt for input
     show_prompt: bool,
+    /// The source map of the program, if any
+    source_map: Option<SourceMap>,
+    /// The addresses of the breakpoints
+    breakpoints: BTreeSet<u16>,
+    /// The addresses of the watchpoints, along with the accesses they watch
+    watchpoints: BTreeMap<u16, WatchKind>,
+    /// The maximum number of steps taken by each call to `run`, if any
+    step_limit: Option<usize>,
 }
 
 impl<'a, 'b> Simulator<'a, 'b> {
@@ -72,15 +195,19 @@ impl<'a, 'b> Simulator<'a, 'b> {
     /// for correct compilation output.
     fn from_memory(memory: [u16; 4096], len: usize) -> Self {
         Simulator {
-            memory: memory,
+            memory,
             acc: 0,
             ir: 0,
             pc: 0,
             halted: false,
-            len: len,
+            len,
             input: Box::new(BufReader::new(io::stdin())),
             output: Box::new(io::stdout()),
             show_prompt: true,
+            source_map: None,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            step_limit: None,
         }
     }
 
@@ -128,6 +255,7 @@ impl<'a, 'b> Simulator<'a, 'b> {
         // implementation.
         let mut upper = false;
 
+        #[allow(clippy::unbuffered_bytes)]
         for b in input.bytes() {
             let b = b.chain_err(|| ErrorKind::Io("could not read from binary input".into()))?;
             if i >= data.len() {
@@ -256,6 +384,11 @@ impl<'a, 'b> Simulator<'a, 'b> {
         &self.memory
     }
 
+    /// Returns a reference to the part of the memory which was loaded with the program.
+    pub fn program(&self) -> &[u16] {
+        &self.memory[..self.len]
+    }
+
     /// Returns the instruction at the given position in memory.
     ///
     /// # Panics
@@ -276,6 +409,9 @@ impl<'a, 'b> Simulator<'a, 'b> {
     }
 
     /// Returns the registers: (acc, ir, pc).
+    ///
+    /// The instruction register `ir` holds the word of the instruction being executed, or the
+    /// last one executed between steps (it is zero before the first step).
     pub fn regs(&self) -> (i16, u16, u16) {
         (self.acc, self.ir, self.pc)
     }
@@ -297,14 +433,63 @@ impl<'a, 'b> Simulator<'a, 'b> {
         self.show_prompt = show_prompt;
     }
 
+    /// Sets the source map of the program, which is used to report the source location of
+    /// any errors that occur while running it.
+    pub fn set_source_map(&mut self, source_map: SourceMap) {
+        self.source_map = Some(source_map);
+    }
+
+    /// Returns the source map of the program, if any.
+    pub fn source_map(&self) -> Option<&SourceMap> {
+        self.source_map.as_ref()
+    }
+
+    /// Adds a breakpoint at the given address, which stops `run` before the instruction there is
+    /// executed.
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    /// Removes the breakpoint at the given address, returning whether there was one.
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    /// Returns the addresses of the breakpoints, in order.
+    pub fn breakpoints(&self) -> &BTreeSet<u16> {
+        &self.breakpoints
+    }
+
+    /// Adds a watchpoint at the given address (replacing any which is already there), which stops
+    /// `run` after an instruction makes the given kind of access to it.
+    pub fn add_watchpoint(&mut self, addr: u16, kind: WatchKind) {
+        self.watchpoints.insert(addr, kind);
+    }
+
+    /// Removes the watchpoint at the given address, returning whether there was one.
+    pub fn remove_watchpoint(&mut self, addr: u16) -> bool {
+        self.watchpoints.remove(&addr).is_some()
+    }
+
+    /// Returns the addresses of the watchpoints, in order, along with the accesses they watch.
+    pub fn watchpoints(&self) -> &BTreeMap<u16, WatchKind> {
+        &self.watchpoints
+    }
+
+    /// Sets the maximum number of instructions executed by each call to `run` (or `None` for no
+    /// limit, which is the default).
+    pub fn set_step_limit(&mut self, limit: Option<usize>) {
+        self.step_limit = limit;
+    }
+
     /// Dumps memory in a nice format to the output.
     pub fn dump(&mut self, amt: usize) -> Result<()> {
-        for (i, chunk) in (&self.memory[..amt]).chunks(8).enumerate() {
+        for (i, chunk) in self.memory[..amt].chunks(8).enumerate() {
             write!(&mut self.output, "{:03x}:", 8 * i).chain_err(|| ErrorKind::Io("could not write to output".into()))?;
             for w in chunk {
                 write!(&mut self.output, " {:04x}", w).chain_err(|| ErrorKind::Io("could not write to output".into()))?;
             }
-            writeln!(&mut self.output, "").chain_err(|| ErrorKind::Io("could not write to output".into()))?;
+            writeln!(&mut self.output).chain_err(|| ErrorKind::Io("could not write to output".into()))?;
         }
 
         Ok(())
@@ -316,21 +501,118 @@ impl<'a, 'b> Simulator<'a, 'b> {
     /// machine was halted. Note that if the machine is already
     /// halted when this method is called, there will be an error.
     pub fn step(&mut self) -> Result<bool> {
+        self.step_with(&mut Unobserved)
+    }
+
+    /// Performs a single step in the code, like `step`, notifying the given observer before and
+    /// after the instruction is executed.
+    pub fn step_with<O: Observer>(&mut self, observer: &mut O) -> Result<bool> {
+        self.step_observed(observer).map(|_| self.halted)
+    }
+
+    /// Performs a single step in the code, returning the memory access made by the instruction
+    /// (if any).
+    fn step_observed<O: Observer>(&mut self, observer: &mut O) -> Result<Option<(u16, Access)>> {
         // Load the instruction and increment the program counter
-        let ins = self.current_instruction()?;
+        let addr = self.pc;
+        let ins = match self.current_instruction() {
+            Ok(ins) => ins,
+            // Running out of bounds is the fault of the last instruction
+            Err(e) => return Err(self.locate(e, addr.wrapping_sub(1))),
+        };
+        self.ir = self.memory[addr as usize];
         self.pc += 1;
 
-        self.execute(ins)?;
-        Ok(self.halted)
+        let access = match ins {
+            Instruction::Load(n) |
+            Instruction::Add(n) |
+            Instruction::Sub(n) |
+            Instruction::And(n) |
+            Instruction::Or(n) |
+            Instruction::Xor(n) => Some((n, Access::Read)),
+            Instruction::Store(n) => Some((n, Access::Write)),
+            _ => None,
+        };
+        let old = access.map(|(n, _)| self.memory[n as usize]);
+
+        observer.before(self, addr, ins);
+        if let Err(e) = self.execute(ins) {
+            return Err(self.locate(e, addr));
+        }
+        let write = match access {
+            Some((n, Access::Write)) => {
+                Some(MemoryWrite {
+                         addr: n,
+                         old: old.unwrap(),
+                         new: self.memory[n as usize],
+                     })
+            }
+            _ => None,
+        };
+        observer.after(self, addr, ins, write);
+        Ok(access)
     }
 
-    /// Runs the loaded program until it halts.
-    pub fn run(&mut self) -> Result<()> {
+    /// Adds the source location of the instruction at the given address to an error,
+    /// if it is known.
+    fn locate(&self, e: Error, addr: u16) -> Error {
+        match self.source_map.as_ref().and_then(|map| map.location(addr)) {
+            Some(loc) => Error::with_chain(e, ErrorKind::Runtime(loc)),
+            None => e,
+        }
+    }
+
+    /// Runs the loaded program until it halts, or until it reaches a breakpoint, triggers a
+    /// watchpoint or reaches the step limit, returning the reason why it stopped.
+    ///
+    /// A breakpoint at the current instruction doesn't stop the program before it has taken a
+    /// step, so running it again continues past the breakpoint.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ibcm::{Access, Simulator, StopReason, WatchKind};
+    ///
+    /// // Stores 0 at address 5, then loops forever
+    /// let mut sim = Simulator::from_instructions(&[0x4005, 0xb000, 0xc001]).unwrap();
+    /// sim.add_watchpoint(5, WatchKind::Write);
+    /// sim.add_breakpoint(2);
+    /// sim.set_step_limit(Some(10));
+    ///
+    /// assert_eq!(StopReason::Watchpoint { pc: 0, addr: 5, access: Access::Write },
+    ///            sim.run().unwrap());
+    /// assert_eq!(StopReason::Breakpoint(2), sim.run().unwrap());
+    /// assert_eq!(StopReason::Breakpoint(2), sim.run().unwrap());
+    ///
+    /// sim.remove_breakpoint(2);
+    /// assert_eq!(StopReason::StepLimit, sim.run().unwrap());
+    /// ```
+    pub fn run(&mut self) -> Result<StopReason> {
+        self.run_with(&mut Unobserved)
+    }
+
+    /// Runs the loaded program, like `run`, notifying the given observer before and after each
+    /// instruction is executed.
+    pub fn run_with<O: Observer>(&mut self, observer: &mut O) -> Result<StopReason> {
+        let mut steps = 0;
         loop {
-            match self.step() {
-                Ok(false) => {}
-                Ok(true) => return Ok(()),
-                Err(e) => return Err(e),
+            if self.step_limit.is_some_and(|limit| steps >= limit) {
+                return Ok(StopReason::StepLimit);
+            }
+            if steps > 0 && self.breakpoints.contains(&self.pc) {
+                return Ok(StopReason::Breakpoint(self.pc));
+            }
+
+            let pc = self.pc;
+            let access = self.step_observed(observer)?;
+            steps += 1;
+            if self.halted {
+                return Ok(StopReason::Halted);
+            }
+            if let Some((addr, access)) = access {
+                if self.watchpoints.get(&addr).is_some_and(|kind| kind.matches(access)) {
+                    return Ok(StopReason::Watchpoint { pc, addr, access });
+                }
             }
         }
     }
@@ -434,7 +716,7 @@ impl<'a, 'b> Simulator<'a, 'b> {
         let hex = input.trim();
 
         // Validate input
-        if hex.len() >= 1 && hex.len() <= 4 {
+        if !hex.is_empty() && hex.len() <= 4 {
             Ok(u16::from_str_radix(hex, 16).chain_err(|| {
                     ErrorKind::UserInput(format!("'{}' is not a valid hexadecimal word", hex))
                 })?)
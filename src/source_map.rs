@@ -0,0 +1,276 @@
+//! Source maps and debug information for compiled programs.
+
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+use errors::*;
+
+/// A map from the addresses of a program to the lines of the source file from which they were
+/// generated (e.g. by the IBCMC compiler).
+///
+/// # Examples
+///
+/// ```
+/// use ibcm::SourceMap;
+///
+/// let mut map = SourceMap::new(vec![Some(1), Some(1), Some(3), None]);
+/// assert_eq!(Some("line 3".into()), map.location(2));
+/// assert_eq!(None, map.location(3));
+///
+/// map.set_file("foo.ibcmc");
+/// assert_eq!(Some("foo.ibcmc line 1".into()), map.location(0));
+/// ```
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct SourceMap {
+    /// The name of the source file, if known.
+    file: Option<String>,
+    /// The source line of each address, if any.
+    lines: Vec<Option<usize>>,
+    /// The source file of each address, for programs made from several files (addresses without
+    /// one use `file`).
+    files: Vec<Option<String>>,
+}
+
+impl SourceMap {
+    /// Creates a new source map from the source line of each address (starting at 0).
+    pub fn new(lines: Vec<Option<usize>>) -> Self {
+        SourceMap {
+            file: None,
+            lines,
+            files: Vec::new(),
+        }
+    }
+
+    /// Sets the name of the source file.
+    pub fn set_file<S: Into<String>>(&mut self, file: S) {
+        self.file = Some(file.into());
+    }
+
+    /// Sets the name of the source file of each address (starting at 0), for programs made from
+    /// several files.
+    ///
+    /// Addresses without a file of their own use the one given to `set_file`.
+    pub fn set_files(&mut self, files: Vec<Option<String>>) {
+        self.files = files;
+    }
+
+    /// Returns the name of the source file, if known.
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// Returns the source line from which the word at the given address was generated, if any.
+    pub fn line(&self, addr: u16) -> Option<usize> {
+        self.lines.get(addr as usize).and_then(|&line| line)
+    }
+
+    /// Returns a description of the source location of the given address (e.g.
+    /// `foo.ibcmc line 12`), if it is known.
+    pub fn location(&self, addr: u16) -> Option<String> {
+        let file = self.files
+            .get(addr as usize)
+            .and_then(|file| file.as_ref())
+            .or(self.file.as_ref());
+        self.line(addr).map(|line| match file {
+                                Some(file) => format!("{} line {}", file, line),
+                                None => format!("line {}", line),
+                            })
+    }
+}
+
+/// Whether a word of a program is an instruction or data.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum WordKind {
+    /// An instruction.
+    Code,
+    /// Data (e.g. from the `dw` directive).
+    Data,
+}
+
+/// The source of a single word of a program.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct WordInfo {
+    /// The value of the word.
+    pub value: u16,
+    /// The file containing the source of the word (`None` if the program wasn't read from a
+    /// file).
+    pub file: Option<String>,
+    /// The line number of the source of the word, if it has one (words generated by a compiler,
+    /// such as those of its runtime routines, may not).
+    pub line: Option<usize>,
+    /// Whether the word is an instruction or data.
+    pub kind: WordKind,
+    /// The text of the source line.
+    pub text: String,
+}
+
+/// Debug information for an assembled program, giving the source of each of its words.
+///
+/// Debug information can be saved in a *sidecar* file next to the hexadecimal or binary version
+/// of a program, so that the debugger can show the source of a program which is no longer
+/// assembled from its source. The file is a text file starting with the line
+/// `ibcm debug info`, followed by a line `file NAME` for each source file (these are numbered
+/// from 0) and then a line `ADDR VALUE KIND FILE LINE TEXT` for each word, in order: the
+/// address and value of the word in hexadecimal, `code` or `data`, the number of the file (or `-`
+/// if it isn't known), the line number (or `-` if the word has no source line) and the text of
+/// the line. The values make it possible to
+/// check that a sidecar file still belongs to its program (see `matches`).
+///
+/// # Examples
+///
+/// ```
+/// use ibcm::{Assembler, DebugInfo, WordKind};
+///
+/// let program = "        load    one
+///         halt
+/// one:    dw      1";
+///
+/// let info = Assembler::assemble(program.as_bytes()).unwrap().debug_info();
+/// assert_eq!(WordKind::Data, info.word(2).unwrap().kind);
+///
+/// let mut sidecar = Vec::new();
+/// info.to_writer(&mut sidecar).unwrap();
+/// assert_eq!("ibcm debug info
+/// 000 3002 code - 1         load    one
+/// 001 0000 code - 2         halt
+/// 002 0001 data - 3 one:    dw      1
+/// ",
+///            String::from_utf8(sidecar.clone()).unwrap());
+/// assert_eq!(info, DebugInfo::from_reader(&sidecar[..]).unwrap());
+/// assert!(info.matches(&[0x3002, 0x0000, 0x0001]));
+/// assert!(!info.matches(&[0x3002, 0x0000]));
+/// ```
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct DebugInfo {
+    /// The source of each word, in order.
+    words: Vec<WordInfo>,
+}
+
+/// The first line of a debug information file.
+const DEBUG_INFO_HEADER: &str = "ibcm debug info";
+
+impl DebugInfo {
+    /// Creates debug information from the source of each word of a program (starting at
+    /// address 0).
+    pub fn new(words: Vec<WordInfo>) -> Self {
+        DebugInfo { words }
+    }
+
+    /// Returns the source of each word of the program.
+    pub fn words(&self) -> &[WordInfo] {
+        &self.words
+    }
+
+    /// Returns the source of the word at the given address, if it is known.
+    pub fn word(&self, addr: u16) -> Option<&WordInfo> {
+        self.words.get(addr as usize)
+    }
+
+    /// Returns whether the debug information belongs to the given program, i.e. whether it has
+    /// the same number of words with the same values.
+    pub fn matches(&self, program: &[u16]) -> bool {
+        self.words.len() == program.len() &&
+        self.words.iter().zip(program).all(|(word, &value)| word.value == value)
+    }
+
+    /// Returns a source map giving the file and line of each word.
+    pub fn source_map(&self) -> SourceMap {
+        let mut map = SourceMap::new(self.words.iter().map(|word| word.line).collect());
+        map.set_files(self.words.iter().map(|word| word.file.clone()).collect());
+        map
+    }
+
+    /// Writes the debug information to the given writer, in the format described above.
+    pub fn to_writer<W: Write>(&self, output: W) -> Result<()> {
+        let mut bw = BufWriter::new(output);
+        let mut files: Vec<&str> = Vec::new();
+        for file in self.words.iter().filter_map(|word| word.file.as_ref()) {
+            if !files.contains(&file.as_str()) {
+                files.push(file);
+            }
+        }
+
+        let mut write = || -> ::std::io::Result<()> {
+            writeln!(bw, "{}", DEBUG_INFO_HEADER)?;
+            for file in &files {
+                writeln!(bw, "file {}", file)?;
+            }
+            for (addr, word) in self.words.iter().enumerate() {
+                let file = match word.file {
+                    Some(ref file) => files.iter().position(|f| f == file).unwrap().to_string(),
+                    None => "-".to_owned(),
+                };
+                let kind = match word.kind {
+                    WordKind::Code => "code",
+                    WordKind::Data => "data",
+                };
+                let line = word.line.map_or_else(|| "-".to_owned(), |line| line.to_string());
+                writeln!(bw,
+                         "{:03x} {:04x} {} {} {} {}",
+                         addr,
+                         word.value,
+                         kind,
+                         file,
+                         line,
+                         word.text)?;
+            }
+            bw.flush()
+        };
+        write().chain_err(|| ErrorKind::Io("could not write to file".into()))
+    }
+
+    /// Reads debug information in the format described above from the given reader.
+    pub fn from_reader<R: Read>(input: R) -> Result<Self> {
+        let mut files = Vec::new();
+        let mut words = Vec::new();
+        for (n, l) in BufReader::new(input).lines().enumerate() {
+            let l = l.chain_err(|| ErrorKind::Io("could not read from debug info".into()))?;
+            let invalid = |msg: &str| {
+                let msg = format!("invalid debug info on line {}: {}", n + 1, msg);
+                Error::from(ErrorKind::UserInput(msg))
+            };
+            if n == 0 {
+                if l != DEBUG_INFO_HEADER {
+                    return Err(invalid(&format!("expected '{}'", DEBUG_INFO_HEADER)));
+                }
+                continue;
+            }
+            if let Some(file) = l.strip_prefix("file ") {
+                files.push(file.to_owned());
+                continue;
+            }
+
+            let mut parts = l.splitn(6, ' ');
+            let mut next = || parts.next().unwrap_or("");
+            let (addr, value, kind, file, line, text) =
+                (next(), next(), next(), next(), next(), next());
+            if usize::from_str_radix(addr, 16).ok() != Some(words.len()) {
+                return Err(invalid(&format!("expected address {:03x}", words.len())));
+            }
+            let value = u16::from_str_radix(value, 16).map_err(|_| invalid("invalid value"))?;
+            let kind = match kind {
+                "code" => WordKind::Code,
+                "data" => WordKind::Data,
+                _ => return Err(invalid("expected 'code' or 'data'")),
+            };
+            let file = match file {
+                "-" => None,
+                _ => {
+                    let file = file.parse::<usize>().ok().and_then(|i| files.get(i));
+                    Some(file.ok_or_else(|| invalid("unknown file"))?.clone())
+                }
+            };
+            let line = match line {
+                "-" => None,
+                _ => Some(line.parse().map_err(|_| invalid("invalid line number"))?),
+            };
+            words.push(WordInfo {
+                           value,
+                           file,
+                           line,
+                           kind,
+                           text: text.to_owned(),
+                       });
+        }
+        Ok(DebugInfo { words })
+    }
+}
@@ -0,0 +1,37 @@
+//! Tests `ibcm ibcmc --fmt --check` on a formatted program with comments and
+//! literals written in every way.
+
+use std::env;
+use std::fs;
+use std::process::{Command, Output};
+
+const FMT_IBCMC: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/programs/fmt.ibcmc");
+
+/// Runs `ibcm ibcmc --fmt` on the given file, with `--check` if requested.
+fn fmt(input: &str, check: bool) -> Output {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_ibcm"));
+    cmd.args(["ibcmc", "--fmt"]);
+    if check {
+        cmd.arg("--check");
+    }
+    cmd.arg(input).output().expect("failed to run ibcm")
+}
+
+#[test]
+fn fmt_check() {
+    // The program is already formatted, so it is printed back unchanged
+    let output = fmt(FMT_IBCMC, false);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, fs::read(FMT_IBCMC).unwrap());
+    assert!(fmt(FMT_IBCMC, true).status.success());
+
+    // Changing the layout (but not the comments or literals) makes the check fail
+    let source = fs::read_to_string(FMT_IBCMC).unwrap();
+    let unformatted = env::temp_dir().join(format!("ibcm-fmt-{}.ibcmc", std::process::id()));
+    fs::write(&unformatted, source.replace("    ", "  ")).unwrap();
+    let output = fmt(unformatted.to_str().unwrap(), true);
+    let reformatted = fmt(unformatted.to_str().unwrap(), false);
+    fs::remove_file(&unformatted).unwrap();
+    assert!(!output.status.success());
+    assert_eq!(reformatted.stdout, source.as_bytes());
+}
@@ -4,10 +4,12 @@
 extern crate ibcm;
 
 use ibcm::{Assembler, Simulator};
+use ibcm::ibcmc;
 
 use std::collections::HashMap;
 
-const MULT_IBCMASM: &'static [u8] = include_bytes!("programs/mult.ibcmasm");
+const MULT_IBCMASM: &[u8] = include_bytes!("programs/mult.ibcmasm");
+const MULT_IBCMC: &[u8] = include_bytes!("programs/mult.ibcmc");
 
 #[test]
 fn asm_mult() {
@@ -35,3 +37,33 @@ fn asm_mult() {
         assert_eq!(expected, String::from_utf8(output).unwrap().trim());
     }
 }
+
+#[test]
+fn ibcmc_mult() {
+    // Test the program on several values, at every optimization level
+    for level in 0..3 {
+        let asm = ibcmc::compile_optimized(MULT_IBCMC, level).unwrap();
+        let values = &[(3, 4), (6, 9), (10, 15), (30, 45)];
+        let mut tests: HashMap<(u32, u32), u32> = HashMap::new();
+        for &v in values {
+            tests.insert(v, v.0 * v.1);
+        }
+
+        for ((m1, m2), sol) in tests {
+            let input = format!("{:04x}\n{:04x}", m1, m2);
+            let expected = format!("{:04x}", sol);
+            let mut output = Vec::<u8>::new();
+
+            {
+                let mut sim =
+                    Simulator::from_instructions(Assembler::assemble(asm.as_bytes()).unwrap().data())
+                        .unwrap();
+                sim.set_input(input.as_bytes());
+                sim.set_output(&mut output, false);
+                sim.run().expect("failed to run program");
+            }
+
+            assert_eq!(expected, String::from_utf8(output).unwrap().trim());
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Tests that a program compiled by `ibcm ibcmc` to a hexadecimal file reports
+//! the IBCMC source locations of its runtime errors when run by `ibcm execute`.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Runs `ibcm` with the given arguments and input, returning whether it succeeded and what it
+/// printed to stderr.
+fn ibcm(args: &[&str], input: &str) -> (bool, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ibcm"))
+        .args(args)
+        .env("RUST_BACKTRACE", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run ibcm");
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    (output.status.success(), String::from_utf8(output.stderr).unwrap())
+}
+
+#[test]
+fn execute_compiled() {
+    let dir = env::temp_dir().join(format!("ibcm-source-map-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("prog.ibcmc");
+    let hex = dir.join("prog.hex");
+    let (source, hex) = (source.to_str().unwrap(), hex.to_str().unwrap());
+    fs::write(source, "int a = 1;\nint b = readh();\nprinth(a + b);\n").unwrap();
+
+    // The error is reported on the line of `readh`, at any optimization level
+    let mut results = Vec::new();
+    for level in &["0", "2"] {
+        let (ok, stderr) = ibcm(&["ibcmc", "-O", level, source, "-o", hex], "");
+        assert!(ok, "{}", stderr);
+        results.push(ibcm(&["execute", hex], "oops\n"));
+    }
+
+    // Outputting assembly removes the debug info file, which no longer matches
+    let (ok, stderr) = ibcm(&["ibcmc", "-s", source, "-o", hex], "");
+    assert!(ok, "{}", stderr);
+    let sidecar_exists = fs::metadata(format!("{}.dbg", hex)).is_ok();
+    fs::remove_dir_all(&dir).unwrap();
+
+    for (ok, stderr) in results {
+        assert!(!ok);
+        assert!(stderr.contains(&format!("runtime error in {} line 2", source)),
+                "{}",
+                stderr);
+    }
+    assert!(!sidecar_exists);
+}
@@ -3,12 +3,14 @@
 
 extern crate ibcm;
 
-use ibcm::{Assembler, Simulator};
+use ibcm::{Assembler, Disassembly, Simulator};
+use ibcm::ibcmc;
 
 use std::collections::HashMap;
 
-const SUM_IBCM: &'static [u8] = include_bytes!("programs/sum.ibcm");
-const SUM_IBCMASM: &'static [u8] = include_bytes!("programs/sum.ibcmasm");
+const SUM_IBCM: &[u8] = include_bytes!("programs/sum.ibcm");
+const SUM_IBCMASM: &[u8] = include_bytes!("programs/sum.ibcmasm");
+const SUM_IBCMC: &[u8] = include_bytes!("programs/sum.ibcmc");
 
 #[test]
 fn sum() {
@@ -60,3 +62,40 @@ fn asm_sum() {
         assert_eq!(expected, String::from_utf8(output).unwrap().trim());
     }
 }
+
+#[test]
+fn disasm_sum() {
+    // The disassembly should assemble back to the same program
+    let sim = Simulator::from_hex(SUM_IBCM).unwrap();
+    let asm = Disassembly::new(sim.program()).to_string();
+    assert_eq!(sim.program(), Assembler::assemble(asm.as_bytes()).unwrap().data());
+}
+
+#[test]
+fn ibcmc_sum() {
+    // Test the program on several values, at every optimization level
+    for level in 0..3 {
+        let asm = ibcmc::compile_optimized(SUM_IBCMC, level).unwrap();
+        let values = &[4, 8, 12, 16];
+        let mut tests: HashMap<u32, u32> = HashMap::new();
+        for &v in values {
+            tests.insert(v, (1..v + 1).sum());
+        }
+
+        for (test, sol) in tests {
+            let input = format!("{:04x}", test);
+            let expected = format!("{:04x}", sol);
+            let mut output = Vec::<u8>::new();
+
+            {
+                let mut sim = Simulator::from_instructions(Assembler::assemble(asm.as_bytes()).unwrap().data())
+                    .unwrap();
+                sim.set_input(input.as_bytes());
+                sim.set_output(&mut output, false);
+                sim.run().expect("failed to run program");
+            }
+
+            assert_eq!(expected, String::from_utf8(output).unwrap().trim());
+        }
+    }
+}